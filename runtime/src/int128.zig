@@ -0,0 +1,62 @@
+// 128 bit division for compiled code, like compiler-rt's `__divti3` family.
+// Targets such as wasm32 don't have those, so the compiler calls these instead.
+
+fn udivmod(a: u128, b: u128, remainder: *u128) u128 {
+    if (b == 0) @panic("division by zero");
+    if (b > a) {
+        remainder.* = a;
+        return 0;
+    }
+
+    // long division, a bit at a time
+    var quotient: u128 = 0;
+    var rem: u128 = 0;
+    var i: u8 = 128;
+    while (i > 0) {
+        i -= 1;
+        // the bit shifted out means the remainder is bigger than any divisor
+        const carry = rem >> 127;
+        rem = (rem << 1) | ((a >> @intCast(u7, i)) & 1);
+        if (carry == 1 or rem >= b) {
+            rem -%= b;
+            quotient |= @as(u128, 1) << @intCast(u7, i);
+        }
+    }
+    remainder.* = rem;
+    return quotient;
+}
+
+fn magnitude(x: i128) u128 {
+    const bits = @bitCast(u128, x);
+    return if (x < 0) 0 -% bits else bits;
+}
+
+fn withSign(x: u128, negative: bool) i128 {
+    const bits = if (negative) 0 -% x else x;
+    return @bitCast(i128, bits);
+}
+
+export fn ns_udivti3(a: u128, b: u128) u128 {
+    var remainder: u128 = undefined;
+    return udivmod(a, b, &remainder);
+}
+
+export fn ns_umodti3(a: u128, b: u128) u128 {
+    var remainder: u128 = undefined;
+    _ = udivmod(a, b, &remainder);
+    return remainder;
+}
+
+// rounds towards zero, `min / -1` wraps like the other integer operations
+export fn ns_divti3(a: i128, b: i128) i128 {
+    var remainder: u128 = undefined;
+    const quotient = udivmod(magnitude(a), magnitude(b), &remainder);
+    return withSign(quotient, (a < 0) != (b < 0));
+}
+
+// the remainder has the sign of the dividend
+export fn ns_modti3(a: i128, b: i128) i128 {
+    var remainder: u128 = undefined;
+    _ = udivmod(magnitude(a), magnitude(b), &remainder);
+    return withSign(remainder, a < 0);
+}
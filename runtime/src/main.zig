@@ -3,6 +3,11 @@ const pony = @cImport({
     @cInclude("pony.h");
 });
 
+// helpers the compiler calls into, exported so they're linked in
+comptime {
+    _ = @import("int128.zig");
+}
+
 // Pony runtime Type
 const pony_type = pony.pony_type_t;
 
@@ -1,4 +1,6 @@
-use crate::lang::{Path, refcap::ReferenceCapability, ptr::PointerKind};
+use crate::lang::{Path, Span, refcap::ReferenceCapability, ptr::PointerKind};
+use crate::diagnostic::FileId;
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Formatter;
 use generational_arena::{Arena, Index};
@@ -14,6 +16,12 @@ pub struct ProgramArena {
     pub node_arena: Arena<Node>,
     pub statement_arena: Arena<Statement>,
     pub expression_arena: Arena<Expression>,
+    // source locations, filled in by the parser
+    pub node_spans: HashMap<NodeIndex, Span>,
+    pub statement_spans: HashMap<StatementIndex, Span>,
+    pub expression_spans: HashMap<ExpressionIndex, Span>,
+    /// One for each of the program's imports, in the same order.
+    pub import_spans: Vec<Span>,
 }
 
 impl ProgramArena {
@@ -23,6 +31,10 @@ impl ProgramArena {
             node_arena: Arena::new(),
             statement_arena: Arena::new(),
             expression_arena: Arena::new(),
+            node_spans: HashMap::new(),
+            statement_spans: HashMap::new(),
+            expression_spans: HashMap::new(),
+            import_spans: vec![],
         }
     }
 }
@@ -31,8 +43,11 @@ impl ProgramArena {
 pub struct Program {
     pub path: Path,
     pub file_name: String,
+    pub file_id: FileId,
     pub imports: Vec<Path>,
     pub program_arena: ProgramArena,
+    /// False when the parser had to skip over errors, the skipped parts are `Error` nodes.
+    pub complete: bool,
 }
 
 impl Program {
@@ -43,12 +58,29 @@ impl Program {
     pub fn expression(&self, index: ExpressionIndex) -> &Expression {
         self.program_arena.expression_arena.get(index).unwrap()
     }
+
+    pub fn typ(&self, index: TypeIndex) -> &Type {
+        self.program_arena.type_arena.get(index).unwrap()
+    }
+
+    pub fn node_span(&self, index: NodeIndex) -> Span {
+        self.program_arena.node_spans.get(&index).cloned().unwrap_or(0..0)
+    }
+
+    pub fn statement_span(&self, index: StatementIndex) -> Span {
+        self.program_arena.statement_spans.get(&index).cloned().unwrap_or(0..0)
+    }
+
+    pub fn expression_span(&self, index: ExpressionIndex) -> Span {
+        self.program_arena.expression_spans.get(&index).cloned().unwrap_or(0..0)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct TypedName {
     pub name: String,
     pub typ: Option<TypeIndex>,
+    pub span: Span,
 }
 
 #[derive(Clone, Debug)]
@@ -92,6 +124,51 @@ impl From<(Path, String)> for TypeName {
     }
 }
 
+/// An argument to an annotation, like `unused_variables` in `@allow(unused_variables)`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnnotationArg {
+    Name(String),
+    String(String),
+    Number(u128),
+}
+
+impl fmt::Display for AnnotationArg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AnnotationArg::Name(name) => write!(f, "{}", name),
+            AnnotationArg::String(s) => write!(f, "{:?}", s),
+            AnnotationArg::Number(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// Metadata written above a declaration, like `@tailrec` or `@allow(unused_variables)`.
+/// The parser accepts any name, the `AnnotationChecker` knows which ones mean something.
+#[derive(Clone, Debug)]
+pub struct Annotation {
+    pub name: String,
+    pub args: Vec<AnnotationArg>,
+    pub span: Span,
+}
+
+impl Annotation {
+    /// Find an annotation by name.
+    pub fn find<'a>(annotations: &'a [Annotation], name: &str) -> Option<&'a Annotation> {
+        annotations.iter().find(|annotation| annotation.name == name)
+    }
+}
+
+impl fmt::Display for Annotation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "@{}", self.name)?;
+        if !self.args.is_empty() {
+            let args: Vec<String> = self.args.iter().map(|arg| arg.to_string()).collect();
+            write!(f, "({})", args.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum Access {
     Public,
@@ -107,6 +184,7 @@ pub struct AstFunction {
     pub params: Vec<TypedName>,
     pub return_type: TypeIndex,
     pub statements: Vec<StatementIndex>,
+    pub annotations: Vec<Annotation>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -128,11 +206,13 @@ pub enum Node {
         unique: bool,
         name: String,
         value: TypeIndex,
+        annotations: Vec<Annotation>,
     },
     Variable {
         access: Access,
         name: TypedName,
         value: Option<ExpressionIndex>,
+        annotations: Vec<Annotation>,
     },
     Function(AstFunction),
     FunctionPrototype {
@@ -141,6 +221,9 @@ pub enum Node {
         type_params: Vec<TypedName>,
         params: Vec<TypedName>,
         return_type: TypeIndex,
+        annotations: Vec<Annotation>,
+        /// The calling convention of an `extern` declaration, like `C` in `extern "C"`.
+        abi: Option<String>,
     },
     Struct {
         access: Access,
@@ -148,26 +231,66 @@ pub enum Node {
         name: String,
         params: Vec<TypedName>,
         children: Vec<NodeIndex>,
+        annotations: Vec<Annotation>,
     },
     Enum {
         access: Access,
         name: String,
         params: Vec<TypedName>,
         variants: Vec<EnumVariant>,
+        annotations: Vec<Annotation>,
     },
     Interface {
         access: Access,
         name: String,
         params: Vec<TypedName>,
         children: Vec<NodeIndex>,
+        annotations: Vec<Annotation>,
     },
     Error,
 }
 
+impl Node {
+    /// Annotations written above the declaration, the parser fills these in once the node is built.
+    pub fn annotations(&self) -> &[Annotation] {
+        match self {
+            Node::Function(func) => &func.annotations,
+            Node::TypeAlias { annotations, .. } | Node::Variable { annotations, .. } | Node::FunctionPrototype { annotations, .. }
+            | Node::Struct { annotations, .. } | Node::Enum { annotations, .. } | Node::Interface { annotations, .. } => annotations,
+            Node::Error => &[],
+        }
+    }
+
+    pub fn annotations_mut(&mut self) -> Option<&mut Vec<Annotation>> {
+        match self {
+            Node::Function(func) => Some(&mut func.annotations),
+            Node::TypeAlias { annotations, .. } | Node::Variable { annotations, .. } | Node::FunctionPrototype { annotations, .. }
+            | Node::Struct { annotations, .. } | Node::Enum { annotations, .. } | Node::Interface { annotations, .. } => Some(annotations),
+            Node::Error => None,
+        }
+    }
+
+    /// What kind of declaration this is, for messages.
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            Node::TypeAlias { .. } => "type",
+            Node::Variable { .. } => "global",
+            Node::Function(_) => "function",
+            Node::FunctionPrototype { abi: Some(_), .. } => "extern function",
+            Node::FunctionPrototype { .. } => "function prototype",
+            Node::Struct { .. } => "struct",
+            Node::Enum { .. } => "enum",
+            Node::Interface { .. } => "interface",
+            Node::Error => "declaration",
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct EnumVariant {
     pub name: String,
     pub params: Vec<TypedName>,
+    pub span: Span,
 }
 
 #[derive(Clone, Debug)]
@@ -189,20 +312,49 @@ pub enum Statement {
         name: String,
         value: ExpressionIndex,
     },
+    Store {
+        pointer: ExpressionIndex,
+        value: ExpressionIndex,
+    },
     Return {
         value: ExpressionIndex,
     },
     Unsafe {
         body: Vec<StatementIndex>,
     },
+    While {
+        condition: ExpressionIndex,
+        body: Vec<StatementIndex>,
+    },
+    /// Counts up from `start` to `end`, not including `end`.
+    For {
+        variable: String,
+        start: ExpressionIndex,
+        end: ExpressionIndex,
+        body: Vec<StatementIndex>,
+    },
+    Break,
+    Continue,
+    /// A statement that couldn't be parsed, the error has already been reported.
+    Error,
 }
 
 #[derive(Clone, Debug)]
 pub enum Expression {
     Ref(String),
-    NatLiteral(i64),
+    /// Suffixes are kept as written, like `u8` in `10u8`.
+    NatLiteral(u128, Option<String>),
+    FloatLiteral(f64, Option<String>),
+    StringLiteral(String),
+    CharLiteral(char),
     BoolLiteral(bool),
     BinOp(ExpressionIndex, BinOpType, ExpressionIndex),
+    UnOp(UnOpType, ExpressionIndex),
+    /// `value as typ`, a numeric conversion that truncates or saturates instead of failing.
+    Cast {
+        value: ExpressionIndex,
+        typ: TypeIndex,
+    },
     FieldAccessor {
         aggregate: ExpressionIndex,
         value: ExpressionIndex,
@@ -227,6 +379,13 @@ pub enum Expression {
     Unsafe {
         value: ExpressionIndex,
     },
+    Lambda {
+        params: Vec<TypedName>,
+        return_type: TypeIndex,
+        body: Vec<StatementIndex>,
+    },
+    /// An expression that couldn't be parsed, the error has already been reported.
+    Error,
 }
 
 impl Expression {
@@ -272,8 +431,17 @@ impl fmt::Display for Expression {
             Ref(r) => {
                 write!(f, "{}", r)
             }
-            NatLiteral(n) => {
-                write!(f, "{}", n)
+            NatLiteral(n, suffix) => {
+                write!(f, "{}{}", n, suffix.as_deref().unwrap_or(""))
+            }
+            FloatLiteral(n, suffix) => {
+                write!(f, "{:?}{}", n, suffix.as_deref().unwrap_or(""))
+            }
+            StringLiteral(s) => {
+                write!(f, "{:?}", s)
+            }
+            CharLiteral(c) => {
+                write!(f, "{:?}", c)
             }
             BoolLiteral(b) => {
                 write!(f, "{}", b)
@@ -283,6 +451,15 @@ impl fmt::Display for Expression {
                 let (b_index, _) = b.into_raw_parts();
                 write!(f, "#{} {} #{}", a_index, o, b_index)
             }
+            UnOp(o, a) => {
+                let (a_index, _) = a.into_raw_parts();
+                write!(f, "{} #{}", o, a_index)
+            }
+            Cast { value, typ } => {
+                let (value_index, _) = value.into_raw_parts();
+                let (type_index, _) = typ.into_raw_parts();
+                write!(f, "#{} as #{}", value_index, type_index)
+            }
             FieldAccessor { aggregate, value } => {
                 let (agg_index, _) = aggregate.into_raw_parts();
                 let (value_index, _) = value.into_raw_parts();
@@ -314,6 +491,13 @@ impl fmt::Display for Expression {
                 let (value_index, _) = value.into_raw_parts();
                 write!(f, "unsafe {}", value_index)
             }
+            Lambda { params, .. } => {
+                let param_names: Vec<&str> = params.iter().map(|param| param.name.as_str()).collect();
+                write!(f, "fun({})", param_names.join(", "))
+            }
+            Error => {
+                write!(f, "<error>")
+            }
         }
     }
 }
@@ -330,6 +514,28 @@ pub enum BinOpType {
     GreaterThanEqualTo,
     And,
     Or,
+    Percent,
+    EqualTo,
+    NotEqualTo,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+}
+
+impl BinOpType {
+    /// Does this operator produce a `Bool` no matter what it's given?
+    pub fn is_comparison(&self) -> bool {
+        use BinOpType::*;
+        matches!(self, LessThan | GreaterThan | LessThanEqualTo | GreaterThanEqualTo | EqualTo | NotEqualTo)
+    }
+
+    /// Does this operator only make sense on integers?
+    pub fn is_bitwise(&self) -> bool {
+        use BinOpType::*;
+        matches!(self, BitAnd | BitOr | BitXor | ShiftLeft | ShiftRight)
+    }
 }
 
 impl fmt::Display for BinOpType {
@@ -346,6 +552,32 @@ impl fmt::Display for BinOpType {
             GreaterThanEqualTo => ">=",
             And => "and",
             Or => "or",
+            Percent => "%",
+            EqualTo => "==",
+            NotEqualTo => "!=",
+            BitAnd => "&",
+            BitOr => "|",
+            BitXor => "^",
+            ShiftLeft => "<<",
+            ShiftRight => ">>",
+        })
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum UnOpType {
+    Negate,
+    Not,
+    BitNot,
+}
+
+impl fmt::Display for UnOpType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        use UnOpType::*;
+        write!(f, "{}", match self {
+            Negate => "-",
+            Not => "not",
+            BitNot => "~",
         })
     }
 }
@@ -0,0 +1,344 @@
+use std::collections::{HashMap, HashSet};
+
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+
+use crate::ast::*;
+use crate::diagnostic::{codes, DiagnosticManager, FileId};
+use crate::lang::{Path, Span};
+
+/// What other modules can see of a module.
+pub struct ModuleInterface {
+    /// Top level items by name, with what kind of item they are.
+    items: HashMap<String, (&'static str, Access)>,
+    /// Fields and methods of each struct.
+    members: HashMap<String, HashMap<String, Access>>,
+}
+
+impl ModuleInterface {
+    pub fn of(program: &Program) -> Self {
+        let mut items = HashMap::new();
+        let mut members = HashMap::new();
+        let children = nested_nodes(program);
+        for (index, node) in program.program_arena.node_arena.iter() {
+            if children.contains(&index) {
+                continue;
+            }
+            let (name, kind, access) = match node {
+                Node::TypeAlias { name, access, .. } => (name, "type", access),
+                Node::Variable { name, access, .. } => (&name.name, "global", access),
+                Node::Function(func) => (&func.name, "function", &func.access),
+                Node::Struct { name, access, children, .. } => {
+                    let struct_members = children.iter().filter_map(|child| match program.program_arena.node_arena.get(*child)? {
+                        Node::Variable { name, access, .. } => Some((name.name.clone(), *access)),
+                        Node::Function(func) => Some((func.name.clone(), func.access)),
+                        _ => None,
+                    }).collect();
+                    members.insert(name.clone(), struct_members);
+                    (name, "struct", access)
+                }
+                Node::Enum { name, access, .. } => (name, "enum", access),
+                Node::Interface { name, access, .. } => (name, "interface", access),
+                Node::FunctionPrototype { .. } | Node::Error => continue,
+            };
+            items.insert(name.clone(), (kind, *access));
+        }
+        Self { items, members }
+    }
+
+    /// Is there a top level item with this name, whether or not it's public?
+    pub fn provides(&self, name: &str) -> bool {
+        self.items.contains_key(name)
+    }
+}
+
+/// Nodes declared inside structs and interfaces, everything else is top level.
+fn nested_nodes(program: &Program) -> HashSet<NodeIndex> {
+    program.program_arena.node_arena.iter()
+        .flat_map(|(_, node)| match node {
+            Node::Struct { children, .. } | Node::Interface { children, .. } => children.clone(),
+            _ => vec![],
+        })
+        .collect()
+}
+
+/// Makes sure items a module uses from the modules it imports are `public`.
+pub struct AccessChecker<'a> {
+    program: &'a Program,
+    interfaces: &'a HashMap<Path, ModuleInterface>,
+    /// Names declared at the top level of this module, these shadow imported ones.
+    own_items: HashSet<String>,
+    /// Declared types of the locals in scope, `None` when the type isn't written down.
+    locals: HashMap<String, Option<TypeIndex>>,
+    diagnostics: Vec<Diagnostic<FileId>>,
+}
+
+impl<'a> AccessChecker<'a> {
+    pub fn new(program: &'a Program, interfaces: &'a HashMap<Path, ModuleInterface>) -> Self {
+        let own_items = ModuleInterface::of(program).items.into_keys().collect();
+        Self {
+            program,
+            interfaces,
+            own_items,
+            locals: HashMap::new(),
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn check(mut self, diagnostics: &mut DiagnosticManager) {
+        for (index, node) in self.program.program_arena.node_arena.iter() {
+            let span = self.program.node_span(index);
+            match node {
+                Node::TypeAlias { value, .. } => self.check_type(*value, &span),
+                Node::Variable { name, value, .. } => {
+                    self.locals.clear();
+                    self.check_typed_names(std::slice::from_ref(name), &span);
+                    if let Some(value) = value {
+                        self.check_expression(*value);
+                    }
+                }
+                Node::Function(func) => {
+                    self.locals.clear();
+                    self.check_typed_names(&func.type_params, &span);
+                    self.check_typed_names(&func.params, &span);
+                    self.check_type(func.return_type, &span);
+                    self.declare_params(&func.params);
+                    for statement in &func.statements {
+                        self.check_statement(*statement);
+                    }
+                }
+                Node::FunctionPrototype { type_params, params, return_type, .. } => {
+                    self.check_typed_names(type_params, &span);
+                    self.check_typed_names(params, &span);
+                    self.check_type(*return_type, &span);
+                }
+                Node::Struct { params, .. } | Node::Interface { params, .. } => self.check_typed_names(params, &span),
+                Node::Enum { params, variants, .. } => {
+                    self.check_typed_names(params, &span);
+                    for variant in variants {
+                        self.check_typed_names(&variant.params, &span);
+                    }
+                }
+                Node::Error => {}
+            }
+        }
+        for diagnostic in self.diagnostics {
+            diagnostics.add_diagnostic(diagnostic);
+        }
+    }
+
+    fn declare_params(&mut self, params: &[TypedName]) {
+        for param in params {
+            self.locals.insert(param.name.clone(), param.typ);
+        }
+    }
+
+    /// Find the imported module an unqualified name comes from, names in this module win.
+    fn find_import(&self, name: &str) -> Option<&'a Path> {
+        if self.own_items.contains(name) {
+            return None;
+        }
+        let interfaces = self.interfaces;
+        self.program.imports.iter()
+            .find(|import| interfaces.get(*import).map_or(false, |interface| interface.items.contains_key(name)))
+    }
+
+    fn check_item(&mut self, module: &Path, name: &str, span: &Span) {
+        let Some((kind, Access::Internal)) = self.interfaces.get(module).and_then(|interface| interface.items.get(name)) else {
+            return;
+        };
+        self.diagnostics.push(Diagnostic::error()
+            .with_code(codes::INTERNAL_ITEM)
+            .with_message(format!("{} `{}` is internal to module `{}`", kind, name, module.to_string()))
+            .with_labels(vec![Label::primary(self.program.file_id, span.clone()).with_message(format!("`{}` isn't public", name))])
+            .with_notes(vec![format!("mark `{}` as `public` in `{}` to use it from other modules", name, module.to_string())]));
+    }
+
+    fn check_typed_names(&mut self, names: &[TypedName], span: &Span) {
+        for name in names {
+            if let Some(typ) = name.typ {
+                self.check_type(typ, span);
+            }
+        }
+    }
+
+    /// Types don't have spans, so problems are reported on whatever they're part of.
+    fn check_type(&mut self, index: TypeIndex, span: &Span) {
+        match self.program.typ(index) {
+            Type::Base(name) => {
+                let module = match name.path.0.is_empty() {
+                    true => self.find_import(&name.name).cloned(),
+                    false => Some(name.path.clone()),
+                };
+                if let Some(module) = module {
+                    self.check_item(&module, &name.name, span);
+                }
+                for argument in &name.arguments {
+                    self.check_type(*argument, span);
+                }
+            }
+            Type::Refinement(_, inner, _) | Type::Reference(inner, _, _) | Type::Optional(inner) => self.check_type(*inner, span),
+            Type::Row(fields) => self.check_typed_names(fields, span),
+            Type::Function(params, return_type) => {
+                for param in params {
+                    self.check_type(*param, span);
+                }
+                self.check_type(*return_type, span);
+            }
+        }
+    }
+
+    /// The struct a local's declared type names, looking through references and optionals.
+    fn struct_of(&self, index: ExpressionIndex) -> Option<(Path, String)> {
+        let mut typ = match self.program.expression(index) {
+            Expression::Ref(name) => self.locals.get(name).cloned().flatten()?,
+            _ => return None,
+        };
+        loop {
+            match self.program.typ(typ) {
+                Type::Reference(inner, _, _) | Type::Optional(inner) | Type::Refinement(_, inner, _) => typ = *inner,
+                Type::Base(name) => {
+                    let module = match name.path.0.is_empty() {
+                        true => self.find_import(&name.name)?.clone(),
+                        false => name.path.clone(),
+                    };
+                    return Some((module, name.name.clone()));
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn check_member(&mut self, aggregate: ExpressionIndex, member: &str, span: &Span) {
+        let Some((module, struct_name)) = self.struct_of(aggregate) else {
+            return;
+        };
+        let access = self.interfaces.get(&module)
+            .and_then(|interface| interface.members.get(&struct_name))
+            .and_then(|members| members.get(member));
+        if let Some(Access::Internal) = access {
+            self.diagnostics.push(Diagnostic::error()
+                .with_code(codes::INTERNAL_MEMBER)
+                .with_message(format!("`{}.{}` is internal to module `{}`", struct_name, member, module.to_string()))
+                .with_labels(vec![Label::primary(self.program.file_id, span.clone()).with_message(format!("`{}` isn't public", member))])
+                .with_notes(vec![format!("mark `{}` as `public` in `{}` to use it from other modules", member, struct_name)]));
+        }
+    }
+
+    fn check_statement(&mut self, index: StatementIndex) {
+        use Statement::*;
+        let span = self.program.statement_span(index);
+        match self.program.statement(index) {
+            If { condition, body, else_if } => {
+                self.check_expression(*condition);
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+                if let Some(else_if) = else_if {
+                    self.check_statement(*else_if);
+                }
+            }
+            Call { function, args } => {
+                self.check_expression(*function);
+                for arg in args {
+                    self.check_expression(*arg);
+                }
+            }
+            Let { name, value } => {
+                self.check_expression(*value);
+                if let Some(typ) = name.typ {
+                    self.check_type(typ, &span);
+                }
+                self.locals.insert(name.name.clone(), name.typ);
+            }
+            Assign { value, .. } | Return { value } => self.check_expression(*value),
+            Store { pointer, value } => {
+                self.check_expression(*pointer);
+                self.check_expression(*value);
+            }
+            While { condition, body } => {
+                self.check_expression(*condition);
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+            }
+            Unsafe { body } => {
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+            }
+            For { variable, start, end, body } => {
+                self.check_expression(*start);
+                self.check_expression(*end);
+                self.locals.insert(variable.clone(), None);
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+            }
+            Break | Continue | Error => {}
+        }
+    }
+
+    fn check_expression(&mut self, index: ExpressionIndex) {
+        use Expression::*;
+        let span = self.program.expression_span(index);
+        match self.program.expression(index) {
+            Ref(name) => {
+                if !self.locals.contains_key(name) {
+                    if let Some(module) = self.find_import(name) {
+                        self.check_item(module, name, &span);
+                    }
+                }
+            }
+            NatLiteral(..) | FloatLiteral(..) | StringLiteral(_) | CharLiteral(_) | BoolLiteral(_) | Error => {}
+            BinOp(lhs, _, rhs) => {
+                self.check_expression(*lhs);
+                self.check_expression(*rhs);
+            }
+            UnOp(_, operand) => self.check_expression(*operand),
+            FieldAccessor { aggregate, value } => {
+                self.check_expression(*aggregate);
+                // the field or method name isn't a local, only the arguments of a method call are checked as usual
+                match self.program.expression(*value) {
+                    Ref(member) => self.check_member(*aggregate, member, &span),
+                    FunctionCall { function, args } if matches!(self.program.expression(*function), Ref(_)) => {
+                        if let Ref(member) = self.program.expression(*function) {
+                            self.check_member(*aggregate, member, &span);
+                        }
+                        for arg in args {
+                            self.check_expression(*arg);
+                        }
+                    }
+                    _ => self.check_expression(*value),
+                }
+            }
+            FunctionCall { function, args } => {
+                self.check_expression(*function);
+                for arg in args {
+                    self.check_expression(*arg);
+                }
+            }
+            New { typ, allocator } => {
+                self.check_type(*typ, &span);
+                self.check_expression(*allocator);
+            }
+            Dereference { pointer } => self.check_expression(*pointer),
+            Denull { optional } => self.check_expression(*optional),
+            Cast { value, typ } => {
+                self.check_expression(*value);
+                self.check_type(*typ, &span);
+            }
+            Borrow { value } => self.check_expression(*value),
+            Unsafe { value } => self.check_expression(*value),
+            Lambda { params, return_type, body } => {
+                self.check_typed_names(params, &span);
+                self.check_type(*return_type, &span);
+                let outer_locals = self.locals.clone();
+                self.declare_params(params);
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+                self.locals = outer_locals;
+            }
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+
+use crate::ast::*;
+use crate::check::lints::Lint;
+use crate::diagnostic::{codes, DiagnosticManager, FileId};
+use crate::ir::tailcall::TAILREC;
+use crate::ir::translate::EXPORT;
+use crate::lang::Span;
+
+/// What an annotation's arguments have to be.
+#[derive(Clone, Copy)]
+enum Args {
+    None,
+    /// One or more lint names.
+    Lints,
+}
+
+/// An annotation the compiler understands.
+struct KnownAnnotation {
+    name: &'static str,
+    /// Kinds of declaration it can be written on, as named by `Node::kind_name`.
+    targets: &'static [&'static str],
+    args: Args,
+}
+
+static KNOWN: &[KnownAnnotation] = &[
+    KnownAnnotation { name: TAILREC, targets: &["function"], args: Args::None },
+    KnownAnnotation { name: EXPORT, targets: &["function"], args: Args::None },
+    KnownAnnotation { name: "allow", targets: &["function"], args: Args::Lints },
+    KnownAnnotation { name: "warn", targets: &["function"], args: Args::Lints },
+    KnownAnnotation { name: "deny", targets: &["function"], args: Args::Lints },
+];
+
+/// Makes sure every annotation is one the compiler knows, on something it applies to.
+pub struct AnnotationChecker<'a> {
+    program: &'a Program,
+    diagnostics: Vec<Diagnostic<FileId>>,
+}
+
+impl<'a> AnnotationChecker<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Self {
+            program,
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn check(mut self, diagnostics: &mut DiagnosticManager) {
+        for (_, node) in self.program.program_arena.node_arena.iter() {
+            for annotation in node.annotations() {
+                self.check_annotation(annotation, node.kind_name());
+            }
+        }
+        for diagnostic in self.diagnostics {
+            diagnostics.add_diagnostic(diagnostic);
+        }
+    }
+
+    fn check_annotation(&mut self, annotation: &Annotation, target: &str) {
+        let Some(known) = KNOWN.iter().find(|known| known.name == annotation.name) else {
+            let names: Vec<String> = KNOWN.iter().map(|known| format!("`@{}`", known.name)).collect();
+            self.report(format!("unknown annotation `@{}`", annotation.name), "not an annotation the compiler knows", annotation.span.clone(),
+                Some(format!("the known annotations are {}", names.join(", "))));
+            return;
+        };
+        if !known.targets.contains(&target) {
+            self.report(format!("`@{}` can't be used on a {}", annotation.name, target), "misplaced annotation", annotation.span.clone(),
+                Some(format!("it can be used on a {}", known.targets.join(" or "))));
+        }
+        match known.args {
+            Args::None if !annotation.args.is_empty() => {
+                self.report(format!("`@{}` doesn't take arguments", annotation.name), "unexpected arguments", annotation.span.clone(), None);
+            }
+            Args::Lints if annotation.args.is_empty() => {
+                self.report(format!("`@{}` needs the names of the lints it applies to", annotation.name), "no lints named", annotation.span.clone(),
+                    Some(format!("like `@{}(unused_variables)`", annotation.name)));
+            }
+            Args::Lints => {
+                for arg in &annotation.args {
+                    let known_lint = match arg {
+                        AnnotationArg::Name(name) => name == "all" || Lint::from_name(name).is_some(),
+                        _ => false,
+                    };
+                    if !known_lint {
+                        let names: Vec<String> = Lint::ALL.iter().map(|lint| format!("`{}`", lint.name())).collect();
+                        self.report(format!("unknown lint `{}`", arg), "not a lint", annotation.span.clone(),
+                            Some(format!("the lints are {} and `all`", names.join(", "))));
+                    }
+                }
+            }
+            Args::None => {}
+        }
+    }
+
+    fn report(&mut self, message: String, label: &str, span: Span, note: Option<String>) {
+        self.diagnostics.push(Diagnostic::error()
+            .with_code(codes::INVALID_ANNOTATION)
+            .with_message(message)
+            .with_labels(vec![Label::primary(self.program.file_id, span).with_message(label)])
+            .with_notes(note.into_iter().collect()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::AnnotationArg;
+    use crate::compiler::Compiler;
+    use crate::ir::IrNode;
+    use crate::lang::Path;
+
+    fn compile(source: &str) -> Compiler {
+        let mut compiler = Compiler::new();
+        compiler.parse_module(Path::of("x"), "x.ns".to_string(), source.to_string());
+        compiler.compile();
+        compiler
+    }
+
+    #[test]
+    fn annotations_are_checked() {
+        let compiler = compile("
+            @inline
+            fun a() {}
+
+            @tailrec(1)
+            @allow
+            fun b(n: Int32): Int32 {
+                return b(n);
+            }
+
+            @allow(unused_variables, \"all\", nothing)
+            fun c() {}
+
+            @tailrec
+            struct D {
+                let x: Int32;
+            }
+        ");
+        let messages: Vec<&str> = compiler.diagnostics.messages.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "unknown annotation `@inline`",
+            "`@tailrec` doesn't take arguments",
+            "`@allow` needs the names of the lints it applies to",
+            "unknown lint `\"all\"`",
+            "unknown lint `nothing`",
+            "`@tailrec` can't be used on a struct",
+        ]);
+    }
+
+    #[test]
+    fn annotations_are_carried_into_the_ir() {
+        let compiler = compile("
+            @allow(unused_variables, shadowed_names)
+            @tailrec
+            fun count(n: Int32): Int32 {
+                if n == 0 {
+                    return 0;
+                }
+                return count(n - 1);
+            }
+        ");
+        assert!(!compiler.diagnostics.has_errors(), "{}", compiler.diagnostics.emit_to_string());
+        let (_, module) = compiler.modules.iter().next().unwrap();
+        let annotations = module.module_arena.node_arena.iter()
+            .find_map(|(_, node)| match node {
+                IrNode::Function(func) => Some(&func.annotations),
+                _ => None,
+            })
+            .unwrap();
+        let annotations: Vec<(&str, &[AnnotationArg])> = annotations.iter().map(|annotation| (annotation.name.as_str(), annotation.args.as_slice())).collect();
+        assert_eq!(annotations, vec![
+            ("allow", &[AnnotationArg::Name("unused_variables".to_string()), AnnotationArg::Name("shadowed_names".to_string())][..]),
+            ("tailrec", &[][..]),
+        ]);
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::{HashMap, HashSet};
+
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+
+use crate::ast::*;
+use crate::check::{resolve_type, type_aliases};
+use crate::diagnostic::{codes, DiagnosticManager, FileId};
+
+/// Locals from outside of a lambda that its body uses.
+pub struct Captures {
+    /// In the order they are first used.
+    pub names: Vec<String>,
+    /// Captures the body assigns to.
+    pub assigned: Vec<String>,
+}
+
+/// Find the locals a lambda captures.
+/// Every name that isn't bound inside the lambda is included, callers filter out globals.
+pub fn find_captures(program: &Program, params: &[TypedName], body: &[StatementIndex]) -> Captures {
+    let mut finder = CaptureFinder {
+        program,
+        scopes: vec![params.iter().map(|param| param.name.clone()).collect()],
+        captures: Captures {
+            names: vec![],
+            assigned: vec![],
+        },
+    };
+    finder.statements(body);
+    finder.captures
+}
+
+struct CaptureFinder<'a> {
+    program: &'a Program,
+    scopes: Vec<HashSet<String>>,
+    captures: Captures,
+}
+
+impl<'a> CaptureFinder<'a> {
+    fn is_bound(&self, name: &str) -> bool {
+        self.scopes.iter().any(|scope| scope.contains(name))
+    }
+
+    fn bind(&mut self, name: &str) {
+        self.scopes.last_mut().unwrap().insert(name.to_string());
+    }
+
+    fn use_name(&mut self, name: &str) {
+        if !self.is_bound(name) && !self.captures.names.iter().any(|n| n == name) {
+            self.captures.names.push(name.to_string());
+        }
+    }
+
+    fn statements(&mut self, statements: &[StatementIndex]) {
+        self.scopes.push(HashSet::new());
+        for statement in statements {
+            self.statement(*statement);
+        }
+        self.scopes.pop();
+    }
+
+    fn statement(&mut self, index: StatementIndex) {
+        use Statement::*;
+        match self.program.statement(index) {
+            If { condition, body, else_if } => {
+                self.expression(*condition);
+                self.statements(body);
+                if let Some(else_if) = else_if {
+                    self.statement(*else_if);
+                }
+            }
+            Call { function, args } => {
+                self.expression(*function);
+                for arg in args {
+                    self.expression(*arg);
+                }
+            }
+            Let { name, value } => {
+                self.expression(*value);
+                self.bind(&name.name);
+            }
+            Assign { name, value } => {
+                self.expression(*value);
+                if !self.is_bound(name) {
+                    self.use_name(name);
+                    self.captures.assigned.push(name.clone());
+                }
+            }
+            Store { pointer, value } => {
+                self.expression(*pointer);
+                self.expression(*value);
+            }
+            Return { value } => self.expression(*value),
+            While { condition, body } => {
+                self.expression(*condition);
+                self.statements(body);
+            }
+            For { variable, start, end, body } => {
+                self.expression(*start);
+                self.expression(*end);
+                self.scopes.push(HashSet::new());
+                self.bind(variable);
+                self.statements(body);
+                self.scopes.pop();
+            }
+            Unsafe { body } => self.statements(body),
+            Break | Continue | Error => {}
+        }
+    }
+
+    fn expression(&mut self, index: ExpressionIndex) {
+        use Expression::*;
+        match self.program.expression(index) {
+            Ref(name) => self.use_name(name),
+            NatLiteral(..) | FloatLiteral(..) | StringLiteral(_) | CharLiteral(_) | BoolLiteral(_) | Error => {}
+            BinOp(lhs, _, rhs) => {
+                self.expression(*lhs);
+                self.expression(*rhs);
+            }
+            UnOp(_, operand) => self.expression(*operand),
+            FieldAccessor { aggregate, value } => {
+                self.expression(*aggregate);
+                // field names aren't locals
+                match self.program.expression(*value) {
+                    Ref(_) => {}
+                    FunctionCall { function, args } if matches!(self.program.expression(*function), Ref(_)) => {
+                        for arg in args {
+                            self.expression(*arg);
+                        }
+                    }
+                    _ => self.expression(*value),
+                }
+            }
+            FunctionCall { function, args } => {
+                self.expression(*function);
+                for arg in args {
+                    self.expression(*arg);
+                }
+            }
+            New { allocator, .. } => self.expression(*allocator),
+            Dereference { pointer } => self.expression(*pointer),
+            Denull { optional } => self.expression(*optional),
+            Cast { value, .. } => self.expression(*value),
+            Borrow { value } => self.expression(*value),
+            Unsafe { value } => self.expression(*value),
+            Lambda { params, body, .. } => {
+                // whatever a nested lambda captures from out here is captured by this one too
+                let inner = find_captures(self.program, params, body);
+                for name in inner.names {
+                    self.use_name(&name);
+                }
+                for name in inner.assigned {
+                    if !self.is_bound(&name) {
+                        self.captures.assigned.push(name);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Checks that what lambdas capture can safely be copied into their environment.
+/// Captures are copies, so capturing a reference aliases it.
+pub struct CaptureChecker<'a> {
+    program: &'a Program,
+    aliases: HashMap<String, TypeIndex>,
+    /// Declared types of the locals in scope, `None` when the type isn't written down.
+    locals: HashMap<String, Option<TypeIndex>>,
+    diagnostics: Vec<Diagnostic<FileId>>,
+}
+
+impl<'a> CaptureChecker<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Self {
+            program,
+            aliases: type_aliases(program),
+            locals: HashMap::new(),
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn check(mut self, diagnostics: &mut DiagnosticManager) {
+        for (_, node) in self.program.program_arena.node_arena.iter() {
+            if let Node::Function(func) = node {
+                self.locals.clear();
+                self.declare_params(&func.params);
+                for statement in &func.statements {
+                    self.check_statement(*statement);
+                }
+            }
+        }
+        for diagnostic in self.diagnostics {
+            diagnostics.add_diagnostic(diagnostic);
+        }
+    }
+
+    fn declare_params(&mut self, params: &[TypedName]) {
+        for param in params {
+            self.locals.insert(param.name.clone(), param.typ);
+        }
+    }
+
+    fn check_statement(&mut self, index: StatementIndex) {
+        use Statement::*;
+        match self.program.statement(index) {
+            If { condition, body, else_if } => {
+                self.check_expression(*condition);
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+                if let Some(else_if) = else_if {
+                    self.check_statement(*else_if);
+                }
+            }
+            Call { function, args } => {
+                self.check_expression(*function);
+                for arg in args {
+                    self.check_expression(*arg);
+                }
+            }
+            Let { name, value } => {
+                self.check_expression(*value);
+                let typ = name.typ.or_else(|| match self.program.expression(*value) {
+                    Expression::Ref(other) => self.locals.get(other).cloned().flatten(),
+                    _ => None,
+                });
+                self.locals.insert(name.name.clone(), typ);
+            }
+            Assign { value, .. } | Return { value } => self.check_expression(*value),
+            Store { pointer, value } => {
+                self.check_expression(*pointer);
+                self.check_expression(*value);
+            }
+            While { condition, body } => {
+                self.check_expression(*condition);
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+            }
+            Unsafe { body } => {
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+            }
+            For { variable, start, end, body } => {
+                self.check_expression(*start);
+                self.check_expression(*end);
+                self.locals.insert(variable.clone(), None);
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+            }
+            Break | Continue | Error => {}
+        }
+    }
+
+    fn check_expression(&mut self, index: ExpressionIndex) {
+        use Expression::*;
+        match self.program.expression(index) {
+            Ref(_) | NatLiteral(..) | FloatLiteral(..) | StringLiteral(_) | CharLiteral(_) | BoolLiteral(_) | Error => {}
+            BinOp(lhs, _, rhs) => {
+                self.check_expression(*lhs);
+                self.check_expression(*rhs);
+            }
+            UnOp(_, operand) => self.check_expression(*operand),
+            FieldAccessor { aggregate, .. } => self.check_expression(*aggregate),
+            FunctionCall { function, args } => {
+                self.check_expression(*function);
+                for arg in args {
+                    self.check_expression(*arg);
+                }
+            }
+            New { allocator, .. } => self.check_expression(*allocator),
+            Dereference { pointer } => self.check_expression(*pointer),
+            Denull { optional } => self.check_expression(*optional),
+            Cast { value, .. } => self.check_expression(*value),
+            Borrow { value } => self.check_expression(*value),
+            Unsafe { value } => self.check_expression(*value),
+            Lambda { params, body, .. } => {
+                self.check_lambda(index, params, body);
+
+                let outer_locals = self.locals.clone();
+                self.declare_params(params);
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+                self.locals = outer_locals;
+            }
+        }
+    }
+
+    fn check_lambda(&mut self, index: ExpressionIndex, params: &[TypedName], body: &[StatementIndex]) {
+        let span = self.program.expression_span(index);
+        let captures = find_captures(self.program, params, body);
+
+        for name in captures.names.iter().filter(|name| self.locals.contains_key(*name)) {
+            let typ = match self.locals[name] {
+                Some(typ) => resolve_type(self.program, &self.aliases, typ),
+                None => continue,
+            };
+            if let Type::Reference(_, _, refcap) = self.program.typ(typ) {
+                if !refcap.can_alias(*refcap) {
+                    self.diagnostics.push(Diagnostic::error()
+                        .with_code(codes::CAPTURED_UNALIASABLE)
+                        .with_message(format!("cannot capture `{}` because `&{}` references can't be aliased", name, refcap.to_string()))
+                        .with_labels(vec![Label::primary(self.program.file_id, span.clone()).with_message(format!("`{}` is captured here", name))])
+                        .with_notes(vec!["closures capture a copy of every local they use".to_string()]));
+                }
+            }
+        }
+
+        for name in captures.assigned.iter().filter(|name| self.locals.contains_key(*name)) {
+            self.diagnostics.push(Diagnostic::error()
+                .with_code(codes::ASSIGNED_CAPTURE)
+                .with_message(format!("cannot assign to captured local `{}`", name))
+                .with_labels(vec![Label::primary(self.program.file_id, span.clone()).with_message(format!("`{}` is captured by this closure", name))])
+                .with_notes(vec!["closures capture a copy of every local they use, so the assignment would be lost".to_string()]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codespan_reporting::diagnostic::Severity;
+
+    use crate::compiler::Compiler;
+    use crate::lang::Path;
+
+    fn errors(source: &str) -> Vec<String> {
+        let mut compiler = Compiler::new();
+        compiler.parse_module(Path::of("x"), "x.ns".to_string(), source.to_string());
+        compiler.compile();
+        compiler.diagnostics.messages.iter()
+            .filter(|diagnostic| diagnostic.severity >= Severity::Error)
+            .map(|diagnostic| diagnostic.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn captures_are_copies() {
+        assert_eq!(errors("
+            fun f(p: &iso Int64, v: &val Int64, q: Int64): Int64 {
+                let read = fun(): Int64 { return q; };
+                let shared = fun() { let t = v; };
+                let aliased = fun() { let t = p; };
+                let assigned = fun() { q = 4; };
+                let local = fun() { let q = 1; q = 2; };
+                return read();
+            }
+        "), vec![
+            "cannot capture `p` because `&iso` references can't be aliased",
+            "cannot assign to captured local `q`",
+        ]);
+    }
+}
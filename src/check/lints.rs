@@ -0,0 +1,493 @@
+use std::collections::{HashMap, HashSet};
+
+use codespan_reporting::diagnostic::{Diagnostic, Label, Severity};
+
+use crate::ast::*;
+use crate::check::access::ModuleInterface;
+use crate::diagnostic::{codes, DiagnosticManager, FileId};
+use crate::lang::{Path, Span};
+
+/// Problems that are allowed by the language but are usually mistakes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Lint {
+    UnusedVariables,
+    UnusedImports,
+    UnreachableCode,
+    ShadowedNames,
+    UnusedUnsafe,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 5] = [
+        Lint::UnusedVariables,
+        Lint::UnusedImports,
+        Lint::UnreachableCode,
+        Lint::ShadowedNames,
+        Lint::UnusedUnsafe,
+    ];
+
+    /// The name used in `--warn` style flags and `@allow` style annotations.
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::UnusedVariables => "unused_variables",
+            Lint::UnusedImports => "unused_imports",
+            Lint::UnreachableCode => "unreachable_code",
+            Lint::ShadowedNames => "shadowed_names",
+            Lint::UnusedUnsafe => "unused_unsafe",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|lint| lint.name() == name)
+    }
+
+    fn code(self) -> &'static str {
+        match self {
+            Lint::UnusedVariables => codes::UNUSED_VARIABLE,
+            Lint::UnusedImports => codes::UNUSED_IMPORT,
+            Lint::UnreachableCode => codes::UNREACHABLE_CODE,
+            Lint::ShadowedNames => codes::SHADOWED_NAME,
+            Lint::UnusedUnsafe => codes::UNUSED_UNSAFE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LintLevel {
+    Allow,
+    Warn,
+    /// Reported as an error, so the build fails.
+    Deny,
+}
+
+impl LintLevel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "allow" => Some(LintLevel::Allow),
+            "warn" => Some(LintLevel::Warn),
+            "deny" => Some(LintLevel::Deny),
+            _ => None,
+        }
+    }
+}
+
+/// How each lint is reported, every lint warns unless it's been changed.
+#[derive(Clone, Debug, Default)]
+pub struct LintLevels {
+    levels: HashMap<Lint, LintLevel>,
+}
+
+impl LintLevels {
+    pub fn level(&self, lint: Lint) -> LintLevel {
+        self.levels.get(&lint).cloned().unwrap_or(LintLevel::Warn)
+    }
+
+    pub fn set(&mut self, lint: Lint, level: LintLevel) {
+        self.levels.insert(lint, level);
+    }
+
+    /// Set the level of a lint by name, `all` sets every lint.
+    pub fn set_by_name(&mut self, name: &str, level: LintLevel) -> Result<(), String> {
+        if name == "all" {
+            for lint in Lint::ALL {
+                self.set(lint, level);
+            }
+            return Ok(());
+        }
+        let lint = Lint::from_name(name).ok_or(format!("unknown lint `{}`", name))?;
+        self.set(lint, level);
+        Ok(())
+    }
+
+    /// The levels inside a declaration, after annotations like `@allow(unused_variables)` above it.
+    pub fn with_annotations(&self, annotations: &[Annotation]) -> LintLevels {
+        let mut levels = self.clone();
+        for annotation in annotations {
+            let Some(level) = LintLevel::from_name(&annotation.name) else {
+                continue;
+            };
+            for arg in &annotation.args {
+                // the annotation checker reports anything that isn't a lint
+                if let AnnotationArg::Name(name) = arg {
+                    let _ = levels.set_by_name(name, level);
+                }
+            }
+        }
+        levels
+    }
+
+    /// Start the diagnostic for a lint at its level, `None` when the lint is allowed.
+    pub fn diagnostic(&self, lint: Lint) -> Option<Diagnostic<FileId>> {
+        let severity = match self.level(lint) {
+            LintLevel::Allow => return None,
+            LintLevel::Warn => Severity::Warning,
+            LintLevel::Deny => Severity::Error,
+        };
+        // imports aren't inside a function, so only the flag can change their level
+        let silence = match lint {
+            Lint::UnusedImports => format!("`--allow={}`", lint.name()),
+            _ => format!("`@allow({})` above the function", lint.name()),
+        };
+        Some(Diagnostic::new(severity)
+            .with_code(lint.code())
+            .with_notes(vec![format!("this is the `{}` lint, {} turns it off", lint.name(), silence)]))
+    }
+}
+
+/// A local or parameter in scope, with whether anything has read it yet.
+struct Local {
+    name: String,
+    span: Span,
+    used: bool,
+}
+
+/// Warns about unused locals, parameters and imports, unreachable statements and shadowed names.
+/// Unnecessary `unsafe` is found by the `UnsafetyChecker`, which already knows what needs it.
+pub struct Linter<'a> {
+    program: &'a Program,
+    interfaces: &'a HashMap<Path, ModuleInterface>,
+    levels: &'a LintLevels,
+    /// Levels for the function being checked.
+    function_levels: LintLevels,
+    scopes: Vec<Vec<Local>>,
+    /// Names that weren't locals, imports are used if they provide one of these.
+    free_names: HashSet<String>,
+    /// Modules named in qualified types, like `geo::Point`.
+    used_modules: HashSet<Path>,
+    diagnostics: Vec<Diagnostic<FileId>>,
+}
+
+impl<'a> Linter<'a> {
+    pub fn new(program: &'a Program, interfaces: &'a HashMap<Path, ModuleInterface>, levels: &'a LintLevels) -> Self {
+        Self {
+            program,
+            interfaces,
+            levels,
+            function_levels: levels.clone(),
+            scopes: vec![],
+            free_names: HashSet::new(),
+            used_modules: HashSet::new(),
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn check(mut self, diagnostics: &mut DiagnosticManager) {
+        for (_, node) in self.program.program_arena.node_arena.iter() {
+            match node {
+                Node::Function(func) => self.check_function(func),
+                Node::Variable { name, value, .. } => {
+                    self.use_typed_names(std::slice::from_ref(name));
+                    if let Some(value) = value {
+                        self.check_expression(*value);
+                    }
+                }
+                Node::TypeAlias { value, .. } => self.use_type(*value),
+                Node::FunctionPrototype { type_params, params, return_type, .. } => {
+                    self.use_typed_names(type_params);
+                    self.use_typed_names(params);
+                    self.use_type(*return_type);
+                }
+                Node::Struct { params, .. } | Node::Interface { params, .. } => self.use_typed_names(params),
+                Node::Enum { params, variants, .. } => {
+                    self.use_typed_names(params);
+                    for variant in variants {
+                        self.use_typed_names(&variant.params);
+                    }
+                }
+                Node::Error => {}
+            }
+        }
+        self.check_imports();
+        for diagnostic in self.diagnostics {
+            diagnostics.add_diagnostic(diagnostic);
+        }
+    }
+
+    fn report(&mut self, lint: Lint, message: String, labels: Vec<Label<FileId>>) {
+        let levels = if lint == Lint::UnusedImports { self.levels } else { &self.function_levels };
+        if let Some(diagnostic) = levels.diagnostic(lint) {
+            self.diagnostics.push(diagnostic.with_message(message).with_labels(labels));
+        }
+    }
+
+    fn check_imports(&mut self) {
+        let own_items = ModuleInterface::of(self.program);
+        for (import, span) in self.program.imports.iter().zip(self.program.program_arena.import_spans.clone()) {
+            // modules that haven't been compiled can't be checked, they might provide anything
+            let Some(interface) = self.interfaces.get(import) else {
+                continue;
+            };
+            let used = self.used_modules.contains(import)
+                || self.free_names.iter().any(|name| !own_items.provides(name) && interface.provides(name));
+            if !used {
+                self.report(Lint::UnusedImports, format!("unused import `{}`", import.to_string()),
+                    vec![Label::primary(self.program.file_id, span).with_message("nothing from this module is used")]);
+            }
+        }
+    }
+
+    fn check_function(&mut self, func: &AstFunction) {
+        self.function_levels = self.levels.with_annotations(&func.annotations);
+        self.use_typed_names(&func.type_params);
+        self.use_typed_names(&func.params);
+        self.use_type(func.return_type);
+        self.scopes.push(vec![]);
+        for param in &func.params {
+            self.declare(&param.name, param.span.clone());
+        }
+        self.check_block(&func.statements);
+        self.pop_scope();
+    }
+
+    fn pop_scope(&mut self) {
+        for local in self.scopes.pop().unwrap_or_default() {
+            if !local.used && !local.name.starts_with('_') {
+                self.report(Lint::UnusedVariables, format!("unused variable `{}`", local.name),
+                    vec![Label::primary(self.program.file_id, local.span)
+                        .with_message(format!("if this is intentional, rename it to `_{}`", local.name))]);
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, span: Span) {
+        let earlier = self.scopes.iter().flatten().rev().find(|local| local.name == name).map(|local| local.span.clone());
+        if let Some(earlier) = earlier {
+            self.report(Lint::ShadowedNames, format!("`{}` shadows an earlier declaration", name), vec![
+                Label::primary(self.program.file_id, span.clone()).with_message(format!("this `{}`", name)),
+                Label::secondary(self.program.file_id, earlier).with_message("shadows this one"),
+            ]);
+        }
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push(Local { name: name.to_string(), span, used: false });
+        }
+    }
+
+    fn use_name(&mut self, name: &str) {
+        match self.scopes.iter_mut().flatten().rev().find(|local| local.name == name) {
+            Some(local) => local.used = true,
+            None => {
+                self.free_names.insert(name.to_string());
+            }
+        }
+    }
+
+    fn use_typed_names(&mut self, names: &[TypedName]) {
+        for name in names {
+            if let Some(typ) = name.typ {
+                self.use_type(typ);
+            }
+        }
+    }
+
+    fn use_type(&mut self, index: TypeIndex) {
+        match self.program.typ(index) {
+            Type::Base(name) => {
+                match name.path.0.is_empty() {
+                    true => self.free_names.insert(name.name.clone()),
+                    false => self.used_modules.insert(name.path.clone()),
+                };
+                for argument in &name.arguments {
+                    self.use_type(*argument);
+                }
+            }
+            Type::Refinement(_, inner, _) | Type::Reference(inner, _, _) | Type::Optional(inner) => self.use_type(*inner),
+            Type::Row(fields) => self.use_typed_names(fields),
+            Type::Function(params, return_type) => {
+                for param in params {
+                    self.use_type(*param);
+                }
+                self.use_type(*return_type);
+            }
+        }
+    }
+
+    /// Check the statements of a block in a scope of their own.
+    fn check_block(&mut self, statements: &[StatementIndex]) {
+        self.scopes.push(vec![]);
+        let mut exit: Option<StatementIndex> = None;
+        let mut reported = false;
+        for statement in statements {
+            // only the first unreachable statement is reported, the rest follow from it
+            if let (Some(exit), false) = (exit, reported) {
+                self.report(Lint::UnreachableCode, "unreachable statement".to_string(), vec![
+                    Label::primary(self.program.file_id, self.program.statement_span(*statement)).with_message("this is never run"),
+                    Label::secondary(self.program.file_id, self.program.statement_span(exit)).with_message("any code following this is unreachable"),
+                ]);
+                reported = true;
+            }
+            self.check_statement(*statement);
+            if exit.is_none() && matches!(self.program.statement(*statement), Statement::Return { .. } | Statement::Break | Statement::Continue) {
+                exit = Some(*statement);
+            }
+        }
+        self.pop_scope();
+    }
+
+    fn check_statement(&mut self, index: StatementIndex) {
+        use Statement::*;
+        match self.program.statement(index) {
+            If { condition, body, else_if } => {
+                self.check_expression(*condition);
+                self.check_block(body);
+                if let Some(else_if) = else_if {
+                    self.check_statement(*else_if);
+                }
+            }
+            Call { function, args } => {
+                self.check_expression(*function);
+                for arg in args {
+                    self.check_expression(*arg);
+                }
+            }
+            Let { name, value } => {
+                self.check_expression(*value);
+                self.use_typed_names(std::slice::from_ref(name));
+                self.declare(&name.name, name.span.clone());
+            }
+            // assigning isn't reading, a local that's only assigned to is still unused
+            Assign { value, .. } | Return { value } => self.check_expression(*value),
+            Store { pointer, value } => {
+                self.check_expression(*pointer);
+                self.check_expression(*value);
+            }
+            While { condition, body } => {
+                self.check_expression(*condition);
+                self.check_block(body);
+            }
+            Unsafe { body } => self.check_block(body),
+            For { variable, start, end, body } => {
+                self.check_expression(*start);
+                self.check_expression(*end);
+                self.scopes.push(vec![]);
+                self.declare(variable, self.program.statement_span(index));
+                self.check_block(body);
+                self.pop_scope();
+            }
+            Break | Continue | Error => {}
+        }
+    }
+
+    fn check_expression(&mut self, index: ExpressionIndex) {
+        use Expression::*;
+        match self.program.expression(index) {
+            Ref(name) => self.use_name(name),
+            NatLiteral(..) | FloatLiteral(..) | StringLiteral(_) | CharLiteral(_) | BoolLiteral(_) | Error => {}
+            BinOp(lhs, _, rhs) => {
+                self.check_expression(*lhs);
+                self.check_expression(*rhs);
+            }
+            UnOp(_, operand) => self.check_expression(*operand),
+            FieldAccessor { aggregate, value } => {
+                self.check_expression(*aggregate);
+                // the field or method name isn't a local, only the arguments of a method call are
+                match self.program.expression(*value) {
+                    Ref(_) => {}
+                    FunctionCall { function, args } if matches!(self.program.expression(*function), Ref(_)) => {
+                        for arg in args {
+                            self.check_expression(*arg);
+                        }
+                    }
+                    _ => self.check_expression(*value),
+                }
+            }
+            FunctionCall { function, args } => {
+                self.check_expression(*function);
+                for arg in args {
+                    self.check_expression(*arg);
+                }
+            }
+            New { typ, allocator } => {
+                self.use_type(*typ);
+                self.check_expression(*allocator);
+            }
+            Dereference { pointer } => self.check_expression(*pointer),
+            Denull { optional } => self.check_expression(*optional),
+            Cast { value, typ } => {
+                self.check_expression(*value);
+                self.use_type(*typ);
+            }
+            Borrow { value } | Unsafe { value } => self.check_expression(*value),
+            Lambda { params, return_type, body } => {
+                self.use_typed_names(params);
+                self.use_type(*return_type);
+                self.scopes.push(vec![]);
+                for param in params {
+                    self.declare(&param.name, param.span.clone());
+                }
+                self.check_block(body);
+                self.pop_scope();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codespan_reporting::diagnostic::Severity;
+
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::options::CompilerOptions;
+
+    const SOURCE: &str = "
+fun f(x: Int32, unused: Int32): Int32 {
+    let y = x;
+    if x > 1 {
+        let y = 2;
+        return y;
+    }
+    let p = unsafe { 1 };
+    return y;
+    let z = 3;
+}
+";
+
+    /// Every diagnostic compiling the modules reports, with its severity.
+    fn lint(lints: LintLevels, modules: &[(&str, &str)]) -> Vec<(Severity, String)> {
+        let mut compiler = Compiler::with_options(CompilerOptions { lints, ..CompilerOptions::default() });
+        for (name, source) in modules {
+            compiler.parse_module(Path::of(name), format!("{}.ns", name), source.to_string());
+        }
+        compiler.compile();
+        compiler.diagnostics.messages.iter()
+            .map(|diagnostic| (diagnostic.severity, diagnostic.message.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn lints_warn_by_default() {
+        assert_eq!(lint(LintLevels::default(), &[("x", SOURCE)]), vec![
+            (Severity::Warning, "unnecessary `unsafe`".to_string()),
+            (Severity::Warning, "`y` shadows an earlier declaration".to_string()),
+            (Severity::Warning, "unreachable statement".to_string()),
+            (Severity::Warning, "unused variable `p`".to_string()),
+            (Severity::Warning, "unused variable `z`".to_string()),
+            (Severity::Warning, "unused variable `unused`".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn levels_can_be_changed_by_name() {
+        let mut lints = LintLevels::default();
+        lints.set_by_name("all", LintLevel::Deny).unwrap();
+        lints.set_by_name("unused_variables", LintLevel::Allow).unwrap();
+        lints.set_by_name("shadowed_names", LintLevel::Warn).unwrap();
+        assert_eq!(lint(lints, &[("x", SOURCE)]), vec![
+            (Severity::Error, "unnecessary `unsafe`".to_string()),
+            (Severity::Warning, "`y` shadows an earlier declaration".to_string()),
+            (Severity::Error, "unreachable statement".to_string()),
+        ]);
+        assert!(LintLevels::default().set_by_name("unused", LintLevel::Allow).is_err());
+    }
+
+    #[test]
+    fn imports_are_used_when_something_they_provide_is() {
+        let lib = "public fun answer(): Int32 {\n    return 42;\n}\n";
+        let unused = "import lib\n\nfun main(): Int32 {\n    return 1;\n}\n";
+        let used = "import lib\n\nfun main(): Int32 {\n    return answer();\n}\n";
+        assert_eq!(lint(LintLevels::default(), &[("lib", lib), ("app", unused)]), vec![
+            (Severity::Warning, "unused import `lib`".to_string()),
+        ]);
+        assert_eq!(lint(LintLevels::default(), &[("lib", lib), ("app", used)]), vec![]);
+    }
+}
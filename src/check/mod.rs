@@ -0,0 +1,36 @@
+// checks that run over the ast before it is lowered to ir
+
+use std::collections::HashMap;
+
+use crate::ast::{Node, Program, Type, TypeIndex};
+
+pub mod access;
+pub mod annotations;
+pub mod captures;
+pub mod lints;
+pub mod unsafety;
+
+/// Names of every type alias in a program.
+pub fn type_aliases(program: &Program) -> HashMap<String, TypeIndex> {
+    let mut aliases = HashMap::new();
+    for (_, node) in program.program_arena.node_arena.iter() {
+        if let Node::TypeAlias { name, value, .. } = node {
+            aliases.insert(name.clone(), *value);
+        }
+    }
+    aliases
+}
+
+/// Look through aliases and refinements to find the underlying type.
+pub fn resolve_type(program: &Program, aliases: &HashMap<String, TypeIndex>, typ: TypeIndex) -> TypeIndex {
+    match program.typ(typ) {
+        Type::Base(name) if name.path.0.is_empty() => {
+            match aliases.get(&name.name) {
+                Some(alias) if *alias != typ => resolve_type(program, aliases, *alias),
+                _ => typ,
+            }
+        }
+        Type::Refinement(_, inner, _) => resolve_type(program, aliases, *inner),
+        _ => typ,
+    }
+}
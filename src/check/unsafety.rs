@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+
+use crate::ast::*;
+use crate::check::{resolve_type, type_aliases};
+use crate::check::lints::{Lint, LintLevels};
+use crate::diagnostic::{codes, DiagnosticManager, FileId};
+use crate::lang::{Span, ptr::PointerKind};
+
+/// Makes sure raw pointers are only dereferenced, offset or cast inside `unsafe`,
+/// and warns about `unsafe` that isn't needed.
+pub struct UnsafetyChecker<'a> {
+    program: &'a Program,
+    aliases: HashMap<String, TypeIndex>,
+    locals: HashMap<String, TypeIndex>,
+    /// The outermost `unsafe` being checked, with whether anything in it needed it.
+    unsafe_block: Option<(Span, bool)>,
+    levels: &'a LintLevels,
+    /// Levels for the function being checked.
+    function_levels: LintLevels,
+    diagnostics: Vec<Diagnostic<FileId>>,
+}
+
+impl<'a> UnsafetyChecker<'a> {
+    pub fn new(program: &'a Program, levels: &'a LintLevels) -> Self {
+        Self {
+            program,
+            aliases: type_aliases(program),
+            locals: HashMap::new(),
+            unsafe_block: None,
+            levels,
+            function_levels: levels.clone(),
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn check(mut self, diagnostics: &mut DiagnosticManager) {
+        for (_, node) in self.program.program_arena.node_arena.iter() {
+            if let Node::Function(func) = node {
+                self.check_function(func);
+            }
+        }
+        for diagnostic in self.diagnostics {
+            diagnostics.add_diagnostic(diagnostic);
+        }
+    }
+
+    fn check_function(&mut self, func: &AstFunction) {
+        self.locals.clear();
+        self.unsafe_block = None;
+        self.function_levels = self.levels.with_annotations(&func.annotations);
+        for param in &func.params {
+            if let Some(typ) = param.typ {
+                self.locals.insert(param.name.clone(), typ);
+            }
+        }
+        for statement in &func.statements {
+            self.check_statement(*statement);
+        }
+    }
+
+    fn check_statement(&mut self, index: StatementIndex) {
+        use Statement::*;
+        match self.program.statement(index) {
+            If { condition, body, else_if } => {
+                self.check_expression(*condition);
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+                if let Some(else_if) = else_if {
+                    self.check_statement(*else_if);
+                }
+            }
+            Call { function, args } => {
+                self.check_expression(*function);
+                for arg in args {
+                    self.check_expression(*arg);
+                }
+            }
+            Let { name, value } => {
+                self.check_expression(*value);
+                match name.typ.or_else(|| self.expression_type(*value)) {
+                    Some(typ) => self.locals.insert(name.name.clone(), typ),
+                    None => self.locals.remove(&name.name),
+                };
+            }
+            Assign { value, .. } => self.check_expression(*value),
+            Store { pointer, value } => {
+                self.check_expression(*pointer);
+                self.check_expression(*value);
+                if self.is_raw_pointer(*pointer) {
+                    let span = self.program.statement_span(index);
+                    self.report("write through raw pointer requires an `unsafe` block", "write through raw pointer", span);
+                }
+            }
+            Return { value } => self.check_expression(*value),
+            Unsafe { body } => {
+                let entered = self.enter_unsafe(self.program.statement_span(index));
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+                if entered {
+                    self.exit_unsafe();
+                }
+            }
+            While { condition, body } => {
+                self.check_expression(*condition);
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+            }
+            For { start, end, body, .. } => {
+                self.check_expression(*start);
+                self.check_expression(*end);
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+            }
+            Break | Continue | Error => {}
+        }
+    }
+
+    fn check_expression(&mut self, index: ExpressionIndex) {
+        use Expression::*;
+        match self.program.expression(index) {
+            Ref(_) | NatLiteral(..) | FloatLiteral(..) | StringLiteral(_) | CharLiteral(_) | BoolLiteral(_) | Error => {}
+            BinOp(lhs, op, rhs) => {
+                self.check_expression(*lhs);
+                self.check_expression(*rhs);
+                if let BinOpType::Plus | BinOpType::Minus = op {
+                    if self.is_raw_pointer(*lhs) || self.is_raw_pointer(*rhs) {
+                        let span = self.program.expression_span(index);
+                        self.report("pointer arithmetic requires an `unsafe` block", "arithmetic on raw pointer", span);
+                    }
+                }
+            }
+            UnOp(_, operand) => self.check_expression(*operand),
+            FieldAccessor { aggregate, .. } => self.check_expression(*aggregate),
+            FunctionCall { function, args } => {
+                self.check_expression(*function);
+                for arg in args {
+                    self.check_expression(*arg);
+                }
+            }
+            New { allocator, .. } => self.check_expression(*allocator),
+            Dereference { pointer } => {
+                self.check_expression(*pointer);
+                if self.is_raw_pointer(*pointer) {
+                    let span = self.program.expression_span(index);
+                    self.report("dereference of raw pointer requires an `unsafe` block", "dereference of raw pointer", span);
+                }
+            }
+            Denull { optional } => self.check_expression(*optional),
+            Cast { value, typ } => {
+                self.check_expression(*value);
+                // the pointer says nothing about what it points at, so it may as well point at anything
+                let to_raw = matches!(self.program.typ(self.resolve(*typ)), Type::Reference(_, PointerKind::Raw, _));
+                if to_raw || self.is_raw_pointer(*value) {
+                    let span = self.program.expression_span(index);
+                    self.report("raw pointer cast requires an `unsafe` block", "cast of raw pointer", span);
+                }
+            }
+            Borrow { value } => self.check_expression(*value),
+            Unsafe { value } => {
+                let entered = self.enter_unsafe(self.program.expression_span(index));
+                self.check_expression(*value);
+                if entered {
+                    self.exit_unsafe();
+                }
+            }
+            Lambda { params, body, .. } => {
+                // the body runs later, but it keeps the unsafety of where it was written
+                let outer_locals = self.locals.clone();
+                for param in params {
+                    match param.typ {
+                        Some(typ) => self.locals.insert(param.name.clone(), typ),
+                        None => self.locals.remove(&param.name),
+                    };
+                }
+                for statement in body {
+                    self.check_statement(*statement);
+                }
+                self.locals = outer_locals;
+            }
+        }
+    }
+
+    /// Start checking an `unsafe` block or expression, false if it's inside another one and so does nothing.
+    fn enter_unsafe(&mut self, span: Span) -> bool {
+        if let Some((outer, _)) = &self.unsafe_block {
+            let outer = outer.clone();
+            self.report_unused_unsafe(span, Some(outer));
+            return false;
+        }
+        self.unsafe_block = Some((span, false));
+        true
+    }
+
+    fn exit_unsafe(&mut self) {
+        if let Some((span, false)) = self.unsafe_block.take() {
+            self.report_unused_unsafe(span, None);
+        }
+    }
+
+    fn report_unused_unsafe(&mut self, span: Span, outer: Option<Span>) {
+        let Some(diagnostic) = self.function_levels.diagnostic(Lint::UnusedUnsafe) else {
+            return;
+        };
+        let mut labels = vec![Label::primary(self.program.file_id, span).with_message("nothing in here needs `unsafe`")];
+        if let Some(outer) = outer {
+            labels.push(Label::secondary(self.program.file_id, outer).with_message("because it's already inside this `unsafe`"));
+        }
+        self.diagnostics.push(diagnostic.with_message("unnecessary `unsafe`").with_labels(labels));
+    }
+
+    fn report(&mut self, message: &str, label: &str, span: Span) {
+        if let Some((_, used)) = &mut self.unsafe_block {
+            *used = true;
+            return;
+        }
+        self.diagnostics.push(Diagnostic::error()
+            .with_code(codes::UNSAFE_OPERATION)
+            .with_message(message)
+            .with_labels(vec![Label::primary(self.program.file_id, span).with_message(label)])
+            .with_notes(vec!["raw pointers may be null, dangling or unaligned".to_string()]));
+    }
+
+    fn resolve(&self, typ: TypeIndex) -> TypeIndex {
+        resolve_type(self.program, &self.aliases, typ)
+    }
+
+    /// Best effort type of an expression, only as precise as this checker needs.
+    fn expression_type(&self, index: ExpressionIndex) -> Option<TypeIndex> {
+        use Expression::*;
+        match self.program.expression(index) {
+            Ref(name) => self.locals.get(name).map(|typ| self.resolve(*typ)),
+            BinOp(lhs, BinOpType::Plus | BinOpType::Minus, rhs) => {
+                if self.is_raw_pointer(*lhs) {
+                    self.expression_type(*lhs)
+                } else if self.is_raw_pointer(*rhs) {
+                    self.expression_type(*rhs)
+                } else {
+                    None
+                }
+            }
+            Dereference { pointer } => match self.expression_type(*pointer).map(|typ| self.program.typ(typ)) {
+                Some(Type::Reference(inner, _, _)) => Some(self.resolve(*inner)),
+                _ => None,
+            },
+            Denull { optional } => match self.expression_type(*optional).map(|typ| self.program.typ(typ)) {
+                Some(Type::Optional(inner)) => Some(self.resolve(*inner)),
+                _ => None,
+            },
+            Unsafe { value } => self.expression_type(*value),
+            Cast { typ, .. } => Some(self.resolve(*typ)),
+            _ => None,
+        }
+    }
+
+    fn is_raw_pointer(&self, index: ExpressionIndex) -> bool {
+        match self.expression_type(index).map(|typ| self.program.typ(typ)) {
+            Some(Type::Reference(_, PointerKind::Raw, _)) => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codespan_reporting::diagnostic::Severity;
+
+    use crate::compiler::Compiler;
+    use crate::lang::Path;
+
+    /// The messages of the errors compiling a module reports, lint warnings are left out.
+    fn errors(source: &str) -> Vec<String> {
+        let mut compiler = Compiler::new();
+        compiler.parse_module(Path::of("x"), "x.ns".to_string(), source.to_string());
+        compiler.compile();
+        compiler.diagnostics.messages.iter()
+            .filter(|diagnostic| diagnostic.severity >= Severity::Error)
+            .map(|diagnostic| diagnostic.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn raw_pointers_need_unsafe() {
+        let source = "fun f(p: *Int32, r: &Int32): Int32 {\n    p.* = r.*;\n    let q = p + 1;\n    return p.*;\n}\n";
+        assert_eq!(errors(source), vec![
+            "write through raw pointer requires an `unsafe` block",
+            "pointer arithmetic requires an `unsafe` block",
+            "dereference of raw pointer requires an `unsafe` block",
+        ]);
+
+        let source = "fun f(p: *Int32, r: &Int32): Int32 {\n    unsafe {\n        p.* = r.*;\n        let q = p + 1;\n    }\n    return unsafe { p.* };\n}\n";
+        assert_eq!(errors(source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn locals_keep_the_type_they_are_declared_with() {
+        let source = "type Ptr = *Int32;\n\nfun f(p: Ptr): Int32 {\n    let q = p;\n    let r: Int32 = 1;\n    return q.* + r;\n}\n";
+        assert_eq!(errors(source), vec!["dereference of raw pointer requires an `unsafe` block"]);
+    }
+
+    #[test]
+    fn raw_pointer_casts_need_unsafe() {
+        for cast in ["p as *UInt8", "p as UInt64", "r as *Int32"] {
+            let source = format!("fun f(p: *Int32, r: &Int32) {{\n    let q = {};\n}}\n", cast);
+            assert_eq!(errors(&source), vec!["raw pointer cast requires an `unsafe` block"], "`{}` was allowed outside `unsafe`", cast);
+            let source = format!("fun f(p: *Int32, r: &Int32) {{\n    let q = unsafe {{ {} }};\n}}\n", cast);
+            assert_eq!(errors(&source), Vec::<String>::new(), "`{}` wasn't allowed inside `unsafe`", cast);
+        }
+    }
+}
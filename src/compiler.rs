@@ -1,29 +1,105 @@
+use std::collections::HashMap;
 use generational_arena::Arena;
+use crate::ast::Program;
+use crate::check::access::{AccessChecker, ModuleInterface};
+use crate::check::annotations::AnnotationChecker;
+use crate::check::captures::CaptureChecker;
+use crate::check::lints::Linter;
+use crate::check::unsafety::UnsafetyChecker;
+use crate::diagnostic::DiagnosticManager;
 use crate::lang::Path;
 use crate::ir::Module;
 use crate::ir::translate::IrBuilder;
+use crate::ir::tailcall::TailCallOptimizer;
+use crate::ir::verify::IrVerifier;
+use crate::options::CompilerOptions;
 use crate::parser::Parser;
 
 pub struct Compiler {
     pub modules: Arena<Module>,
+    /// The checked syntax tree of every module, including ones with syntax errors.
+    pub programs: HashMap<Path, Program>,
+    pub diagnostics: DiagnosticManager,
+    pub options: CompilerOptions,
     ir_builder: IrBuilder,
+    /// What each parsed module makes visible to the modules importing it.
+    interfaces: HashMap<Path, ModuleInterface>,
+    /// Modules parsed but not checked yet, in the order they were given.
+    parsed: Vec<Path>,
 }
 
 impl Compiler {
     pub fn new() -> Compiler {
+        Self::with_options(CompilerOptions::default())
+    }
+
+    pub fn with_options(options: CompilerOptions) -> Compiler {
         Compiler {
             modules: Default::default(),
-            ir_builder: IrBuilder::new(),
+            programs: HashMap::new(),
+            diagnostics: DiagnosticManager::new(),
+            ir_builder: IrBuilder::new(options.target.clone()),
+            options,
+            interfaces: HashMap::new(),
+            parsed: vec![],
         }
     }
 
     pub fn parse_module(&mut self, path: Path, file_name: String, code: String) {
-        let mut parser = Parser::new();
+        let diagnostics = std::mem::replace(&mut self.diagnostics, DiagnosticManager::new());
+        let mut parser = Parser::with_diagnostics(diagnostics);
         let parsed_program = parser.parse(path, file_name, code);
-        parser.diagnostics.emit_errors();
+        self.diagnostics = parser.diagnostics;
         if let Some(program) = parsed_program {
-            let module = self.ir_builder.convert(program);
+            self.interfaces.insert(program.path.clone(), ModuleInterface::of(&program));
+            self.parsed.push(program.path.clone());
+            self.programs.insert(program.path.clone(), program);
+        }
+    }
+
+    /// Check and lower the modules parsed since the last call. Every module is parsed first,
+    /// so what a module imports is known no matter which order the files were given in.
+    pub fn compile(&mut self) {
+        for path in std::mem::take(&mut self.parsed) {
+            let program = self.programs[&path].clone();
+            AnnotationChecker::new(&program).check(&mut self.diagnostics);
+            UnsafetyChecker::new(&program, &self.options.lints).check(&mut self.diagnostics);
+            CaptureChecker::new(&program).check(&mut self.diagnostics);
+            AccessChecker::new(&program, &self.interfaces).check(&mut self.diagnostics);
+            Linter::new(&program, &self.interfaces, &self.options.lints).check(&mut self.diagnostics);
+            // types and control flow can't be trusted with parts of the program missing
+            if !program.complete {
+                continue;
+            }
+            let mut module = self.ir_builder.convert(program, &mut self.diagnostics);
+            for diagnostic in TailCallOptimizer::new(&mut module).optimize() {
+                self.diagnostics.add_diagnostic(diagnostic);
+            }
+            for diagnostic in IrVerifier::new(&module).verify() {
+                self.diagnostics.add_diagnostic(diagnostic);
+            }
             self.modules.insert(module);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Compiler;
+    use crate::lang::Path;
+
+    const LIB: &str = "fun hidden(): Int32 {\n    return 1;\n}\n";
+    const APP: &str = "import lib\n\nfun main(): Int32 {\n    return hidden();\n}\n";
+
+    #[test]
+    fn access_is_checked_in_any_order() {
+        for modules in [[("lib", LIB), ("app", APP)], [("app", APP), ("lib", LIB)]] {
+            let mut compiler = Compiler::new();
+            for (name, source) in modules {
+                compiler.parse_module(Path::of(name), format!("{}.ns", name), source.to_string());
+            }
+            compiler.compile();
+            assert!(compiler.diagnostics.has_errors(), "`hidden` was accessible with {:?} first", modules[0].0);
+        }
+    }
+}
@@ -1,12 +1,38 @@
 use std::collections::HashMap;
 
-use codespan_reporting::diagnostic::{Diagnostic, Severity};
-use codespan_reporting::files::SimpleFiles;
+use codespan_reporting::diagnostic::{Diagnostic, LabelStyle, Severity};
+use codespan_reporting::files::{Files, SimpleFiles};
 use codespan_reporting::term::Config;
 use codespan_reporting::term::termcolor::{Buffer, ColorChoice, StandardStream};
 
+use serde_json::{json, Value};
+
+pub mod codes;
+
 pub type FileId = usize;
 
+/// How diagnostics are written out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiagnosticFormat {
+    /// Rendered with source snippets for people to read.
+    Human,
+    /// An array with an object for every diagnostic.
+    Json,
+    /// SARIF 2.1.0, which code review tools can show next to the code.
+    Sarif,
+}
+
+impl DiagnosticFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "human" => Some(DiagnosticFormat::Human),
+            "json" => Some(DiagnosticFormat::Json),
+            "sarif" => Some(DiagnosticFormat::Sarif),
+            _ => None,
+        }
+    }
+}
+
 // const ASCII_CHARS: Chars = Chars {
 //     source_border_top_left: '/',
 //     source_border_top: '-',
@@ -57,7 +83,7 @@ impl DiagnosticManager {
 
     pub fn has_errors(&self) -> bool {
         for message in self.messages.iter() {
-            if message.severity == Severity::Error {
+            if message.severity >= Severity::Error {
                 return true;
             }
         }
@@ -73,6 +99,127 @@ impl DiagnosticManager {
         String::from_utf8_lossy(writer.as_slice()).to_string()
     }
 
+    /// Write every diagnostic to stderr in the given format.
+    pub fn emit(&self, format: DiagnosticFormat) {
+        match format {
+            DiagnosticFormat::Human => self.emit_errors(),
+            DiagnosticFormat::Json => eprintln!("{}", serde_json::to_string_pretty(&self.to_json()).unwrap()),
+            DiagnosticFormat::Sarif => eprintln!("{}", serde_json::to_string_pretty(&self.to_sarif()).unwrap()),
+        }
+    }
+
+    fn severity_name(severity: Severity) -> &'static str {
+        match severity {
+            Severity::Bug => "bug",
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+            Severity::Help => "help",
+        }
+    }
+
+    fn file_name(&self, file_id: FileId) -> String {
+        self.files.get(file_id).map_or(String::new(), |file| file.name().clone())
+    }
+
+    /// Line and column of a byte offset, both counted from 1.
+    fn line_column(&self, file_id: FileId, offset: usize) -> (usize, usize) {
+        self.files.location(file_id, offset)
+            .map_or((1, 1), |location| (location.line_number, location.column_number))
+    }
+
+    pub fn to_json(&self) -> Value {
+        let diagnostics: Vec<Value> = self.messages.iter().map(|message| {
+            let labels: Vec<Value> = message.labels.iter().map(|label| {
+                let (start_line, start_column) = self.line_column(label.file_id, label.range.start);
+                let (end_line, end_column) = self.line_column(label.file_id, label.range.end);
+                json!({
+                    "file": self.file_name(label.file_id),
+                    "primary": label.style == LabelStyle::Primary,
+                    "message": label.message,
+                    "start": { "line": start_line, "column": start_column, "offset": label.range.start },
+                    "end": { "line": end_line, "column": end_column, "offset": label.range.end },
+                })
+            }).collect();
+            json!({
+                "severity": Self::severity_name(message.severity),
+                "code": message.code,
+                "message": message.message,
+                "labels": labels,
+                "notes": message.notes,
+            })
+        }).collect();
+        Value::Array(diagnostics)
+    }
+
+    /// See https://docs.oasis-open.org/sarif/sarif/v2.1.0/sarif-v2.1.0.html
+    pub fn to_sarif(&self) -> Value {
+        let location = |file_id: FileId, range: &std::ops::Range<usize>, message: &str| {
+            let (start_line, start_column) = self.line_column(file_id, range.start);
+            let (end_line, end_column) = self.line_column(file_id, range.end);
+            let mut location = json!({
+                "physicalLocation": {
+                    "artifactLocation": { "uri": self.file_name(file_id) },
+                    "region": {
+                        "startLine": start_line,
+                        "startColumn": start_column,
+                        "endLine": end_line,
+                        "endColumn": end_column,
+                    },
+                },
+            });
+            if !message.is_empty() {
+                location["message"] = json!({ "text": message });
+            }
+            location
+        };
+
+        let mut rules: Vec<&String> = self.messages.iter().filter_map(|message| message.code.as_ref()).collect();
+        rules.sort();
+        rules.dedup();
+        let results: Vec<Value> = self.messages.iter().map(|message| {
+            let level = match message.severity {
+                Severity::Bug | Severity::Error => "error",
+                Severity::Warning => "warning",
+                Severity::Note | Severity::Help => "note",
+            };
+            // sarif has nowhere else to put notes
+            let mut text = message.message.clone();
+            for note in &message.notes {
+                text.push_str(&format!("\nnote: {}", note));
+            }
+            let (primary, secondary): (Vec<_>, Vec<_>) = message.labels.iter().partition(|label| label.style == LabelStyle::Primary);
+            let mut result = json!({
+                "level": level,
+                "message": { "text": text },
+                "locations": primary.iter().map(|label| location(label.file_id, &label.range, &label.message)).collect::<Vec<_>>(),
+                "relatedLocations": secondary.iter().map(|label| location(label.file_id, &label.range, &label.message)).collect::<Vec<_>>(),
+            });
+            if let Some(code) = &message.code {
+                result["ruleId"] = json!(code);
+            }
+            result
+        }).collect();
+
+        json!({
+            "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "rules": rules.iter().map(|code| {
+                            let title = codes::lookup(code).map_or("", |entry| entry.title);
+                            json!({ "id": code, "shortDescription": { "text": title } })
+                        }).collect::<Vec<_>>(),
+                    },
+                },
+                "results": results,
+            }],
+        })
+    }
+
     pub fn emit_errors(&self) {
         let writer = StandardStream::stderr(ColorChoice::Always);
         let config = codespan_reporting::term::Config::default();
@@ -81,3 +228,112 @@ impl DiagnosticManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use codespan_reporting::diagnostic::{Diagnostic, Label};
+    use serde_json::json;
+
+    use super::DiagnosticManager;
+
+    fn diagnostics() -> DiagnosticManager {
+        let mut diagnostics = DiagnosticManager::new();
+        let file_id = diagnostics.add_file("src/main.ns".to_string(), "fun f() {\n    let x = y;\n}\n".to_string());
+        diagnostics.add_diagnostic(Diagnostic::error()
+            .with_code("NS0205")
+            .with_message("`y` isn't declared")
+            .with_labels(vec![
+                Label::primary(file_id, 22..23).with_message("used here"),
+                Label::secondary(file_id, 0..3),
+            ])
+            .with_notes(vec!["declare it with `let`".to_string()]));
+        diagnostics.add_diagnostic(Diagnostic::warning()
+            .with_message("unused variable `x`")
+            .with_labels(vec![Label::primary(file_id, 18..19)]));
+        diagnostics
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(diagnostics().to_json(), json!([
+            {
+                "severity": "error",
+                "code": "NS0205",
+                "message": "`y` isn't declared",
+                "labels": [
+                    {
+                        "file": "src/main.ns",
+                        "primary": true,
+                        "message": "used here",
+                        "start": { "line": 2, "column": 13, "offset": 22 },
+                        "end": { "line": 2, "column": 14, "offset": 23 },
+                    },
+                    {
+                        "file": "src/main.ns",
+                        "primary": false,
+                        "message": "",
+                        "start": { "line": 1, "column": 1, "offset": 0 },
+                        "end": { "line": 1, "column": 4, "offset": 3 },
+                    },
+                ],
+                "notes": ["declare it with `let`"],
+            },
+            {
+                "severity": "warning",
+                "code": null,
+                "message": "unused variable `x`",
+                "labels": [
+                    {
+                        "file": "src/main.ns",
+                        "primary": true,
+                        "message": "",
+                        "start": { "line": 2, "column": 9, "offset": 18 },
+                        "end": { "line": 2, "column": 10, "offset": 19 },
+                    },
+                ],
+                "notes": [],
+            },
+        ]));
+    }
+
+    #[test]
+    fn sarif() {
+        let sarif = diagnostics().to_sarif();
+        assert_eq!(sarif["version"], "2.1.0");
+        let run = &sarif["runs"][0];
+        assert_eq!(run["tool"]["driver"]["rules"], json!([
+            { "id": "NS0205", "shortDescription": { "text": "`break` or `continue` outside of a loop" } },
+        ]));
+        assert_eq!(run["results"], json!([
+            {
+                "ruleId": "NS0205",
+                "level": "error",
+                "message": { "text": "`y` isn't declared\nnote: declare it with `let`" },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": "src/main.ns" },
+                        "region": { "startLine": 2, "startColumn": 13, "endLine": 2, "endColumn": 14 },
+                    },
+                    "message": { "text": "used here" },
+                }],
+                "relatedLocations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": "src/main.ns" },
+                        "region": { "startLine": 1, "startColumn": 1, "endLine": 1, "endColumn": 4 },
+                    },
+                }],
+            },
+            {
+                "level": "warning",
+                "message": { "text": "unused variable `x`" },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": "src/main.ns" },
+                        "region": { "startLine": 2, "startColumn": 9, "endLine": 2, "endColumn": 10 },
+                    },
+                }],
+                "relatedLocations": [],
+            },
+        ]));
+    }
+}
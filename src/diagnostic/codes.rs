@@ -0,0 +1,439 @@
+// stable codes for every kind of diagnostic, with the long explanations `neutron-star explain` prints
+// codes are never reused, retired ones stay in the registry so old links keep working
+
+pub struct ErrorCode {
+    pub code: &'static str,
+    /// One line, used where there's no room for the explanation.
+    pub title: &'static str,
+    pub explanation: &'static str,
+}
+
+// parsing
+pub const INVALID_TOKEN: &str = "NS0001";
+pub const UNEXPECTED_TOKEN: &str = "NS0002";
+pub const UNEXPECTED_EOF: &str = "NS0003";
+pub const EXTRA_TOKEN: &str = "NS0004";
+pub const MALFORMED_LITERAL: &str = "NS0005";
+
+// checks on the ast
+pub const UNSAFE_OPERATION: &str = "NS0101";
+pub const CAPTURED_UNALIASABLE: &str = "NS0102";
+pub const ASSIGNED_CAPTURE: &str = "NS0103";
+pub const INTERNAL_ITEM: &str = "NS0104";
+pub const INTERNAL_MEMBER: &str = "NS0105";
+pub const INVALID_ANNOTATION: &str = "NS0106";
+
+// building ir
+pub const GLOBAL_WITHOUT_VALUE: &str = "NS0201";
+pub const GLOBAL_TYPE_MISMATCH: &str = "NS0202";
+pub const NOT_CONSTANT: &str = "NS0203";
+pub const MISSING_RETURN: &str = "NS0204";
+pub const OUTSIDE_LOOP: &str = "NS0205";
+pub const INVALID_CONVERSION: &str = "NS0206";
+pub const INVALID_OPERANDS: &str = "NS0207";
+pub const LITERAL_OUT_OF_RANGE: &str = "NS0208";
+pub const NOT_TAIL_RECURSIVE: &str = "NS0209";
+pub const UNSUPPORTED_ABI: &str = "NS0210";
+pub const NOT_C_COMPATIBLE: &str = "NS0211";
+
+// lints, these are warnings unless they've been denied
+pub const UNUSED_VARIABLE: &str = "NS0301";
+pub const UNUSED_IMPORT: &str = "NS0302";
+pub const UNREACHABLE_CODE: &str = "NS0303";
+pub const SHADOWED_NAME: &str = "NS0304";
+pub const UNUSED_UNSAFE: &str = "NS0305";
+
+// compiler bugs
+pub const INVALID_IR: &str = "NS9001";
+
+pub static REGISTRY: &[ErrorCode] = &[
+    ErrorCode {
+        code: INVALID_TOKEN,
+        title: "invalid token",
+        explanation: "\
+The source contains characters that don't make up any token of the language.
+
+This is usually a stray symbol, like `$` or a backtick, or a quote that was never closed.
+Remove the character or close the string or character literal it belongs to.",
+    },
+    ErrorCode {
+        code: UNEXPECTED_TOKEN,
+        title: "unexpected token",
+        explanation: "\
+The parser found a token that can't appear at this point.
+
+    fun add(x: Int32, y: Int32): Int32 {
+        return x + y
+    }
+
+Statements end with `;`, so the `}` above is unexpected. The diagnostic lists the tokens
+that could have come next, which usually points at what's missing.",
+    },
+    ErrorCode {
+        code: UNEXPECTED_EOF,
+        title: "unexpected end of file",
+        explanation: "\
+The file ended in the middle of a declaration.
+
+This almost always means a `{`, `(` or `[` was never closed. Count the brackets of the
+declaration the diagnostic points at.",
+    },
+    ErrorCode {
+        code: EXTRA_TOKEN,
+        title: "extra token",
+        explanation: "\
+The parser finished a complete program but there was more input after it.
+
+Remove whatever follows the last declaration, or check for a `}` that closes a block too early.",
+    },
+    ErrorCode {
+        code: MALFORMED_LITERAL,
+        title: "malformed literal",
+        explanation: "\
+A number, string or character literal couldn't be read.
+
+Number literals can use `_` between digits and a type suffix like `255u8` or `1.5f32`.
+Strings and characters support the escapes `\\n`, `\\r`, `\\t`, `\\\\`, `\\0`, `\\'`, `\\\"`,
+`\\x00` to `\\x7f` and `\\u{...}`, anything else after a backslash is an error.",
+    },
+    ErrorCode {
+        code: UNSAFE_OPERATION,
+        title: "raw pointer used outside of `unsafe`",
+        explanation: "\
+Raw pointers (`*T`) aren't tracked by the compiler, so nothing stops them from dangling or
+pointing at the wrong type. Reading or writing through one, pointer arithmetic and casts to or
+from raw pointers are only allowed inside `unsafe`, which marks the code that has to be checked
+by hand.
+
+    fun first(p: *Int32): Int32 {
+        return unsafe { p.* };
+    }
+
+Tracked references (`&T`) can be used anywhere.",
+    },
+    ErrorCode {
+        code: CAPTURED_UNALIASABLE,
+        title: "closure captures a reference that can't be aliased",
+        explanation: "\
+Closures capture a copy of every local they use. Copying a reference makes a second
+reference to the same value, which some reference capabilities forbid:
+
+    `&iso`  the only reference to the value, no other reference may exist
+    `&trn`  the only writable reference to the value
+
+Capturing one of these would break that guarantee. Pass the value to the closure as a
+parameter instead, or capture a `&val` or `&box` reference, which can be shared.",
+    },
+    ErrorCode {
+        code: ASSIGNED_CAPTURE,
+        title: "assignment to a captured local",
+        explanation: "\
+Closures capture a copy of every local they use, so assigning to a captured local inside
+the closure would only change the copy and the assignment would be lost.
+
+    let count = 0;
+    let increment = fun() { count = count + 1; };
+
+Return the new value from the closure instead, or keep it behind a reference.",
+    },
+    ErrorCode {
+        code: INTERNAL_ITEM,
+        title: "internal item used from another module",
+        explanation: "\
+Functions, structs, type aliases and globals are internal to the module they're declared
+in unless they're marked `public`.
+
+    // geometry.ns
+    public fun area(w: Int32, h: Int32): Int32 { return w * h; }
+    fun helper(): Int32 { return 1; }
+
+    // main.ns
+    import geometry
+    ... area(2, 3) ...   // fine
+    ... helper() ...     // error, `helper` is internal to `geometry`
+
+Mark the item `public` if it's meant to be used by other modules. Internal items also get
+internal linkage, so they can't be linked against from other object files either.",
+    },
+    ErrorCode {
+        code: INTERNAL_MEMBER,
+        title: "internal field or method used from another module",
+        explanation: "\
+The fields and methods of a struct are internal to its module unless they're marked
+`public`, even when the struct itself is public.
+
+    public struct Account {
+        public let id: Int64;
+        let balance: Int64;
+    }
+
+Other modules can read `id` but not `balance`. Add `public` to the `let` or `fun` to expose it.",
+    },
+    ErrorCode {
+        code: INVALID_ANNOTATION,
+        title: "invalid annotation",
+        explanation: "\
+An annotation isn't one the compiler knows, is written on a kind of declaration it doesn't
+apply to, or has the wrong arguments.
+
+    @tailrec                          functions, every recursive call has to be a tail call
+    @export                           functions, callable from C with the C calling convention
+    @allow(lint, ...)                 functions, turn lints off inside the function
+    @warn(lint, ...)                  functions, report lints as warnings
+    @deny(lint, ...)                  functions, report lints as errors
+
+Lints are named like `unused_variables`, `neutron-star explain` lists what each one reports.",
+    },
+    ErrorCode {
+        code: GLOBAL_WITHOUT_VALUE,
+        title: "global without a value",
+        explanation: "\
+Module level `let`s are constants, so they need a value where they're declared.
+
+    let LIMIT: Int32;        // error
+    let LIMIT: Int32 = 100;  // fine
+
+Struct fields are different, a `let` without a value inside a struct is a field without a default.",
+    },
+    ErrorCode {
+        code: GLOBAL_TYPE_MISMATCH,
+        title: "global's value doesn't match its type",
+        explanation: "\
+The value of a module level `let` or a field default has a different type from the one declared.
+
+    let RATIO: Int32 = 2.5;
+
+Unsuffixed literals take the declared type when they can, but a float literal can't become an
+integer. Convert the value explicitly, like `Int32(2.5)`, or change the declared type.",
+    },
+    ErrorCode {
+        code: NOT_CONSTANT,
+        title: "value can't be worked out at compile time",
+        explanation: "\
+Globals and field defaults are worked out when compiling, by running their initializer in
+the compiler. Only arithmetic, comparisons, conversions, control flow and calls to functions
+that stick to those can be run this way. Allocating, using pointers, strings or closures,
+overflowing, dividing by zero, or a global that depends on itself all stop evaluation.
+
+    let A: Int32 = B + 1;
+    let B: Int32 = A;        // error, `A` and `B` depend on each other
+
+The label says what stopped evaluation. Evaluation also gives up after a million steps,
+in case a function called by the initializer never returns.",
+    },
+    ErrorCode {
+        code: MISSING_RETURN,
+        title: "function can reach its end without returning",
+        explanation: "\
+A function with a return type has to return a value on every path through its body.
+
+    fun sign(x: Int32): Int32 {
+        if x < 0 {
+            return -1;
+        } else if x > 0 {
+            return 1;
+        }
+    }
+
+When `x` is `0` neither branch returns. Add a `return` at the end, or an `else` that returns.",
+    },
+    ErrorCode {
+        code: OUTSIDE_LOOP,
+        title: "`break` or `continue` outside of a loop",
+        explanation: "\
+`break` and `continue` only make sense inside `while` and `for` loops. A lambda's body is
+a separate function, so a loop around the lambda doesn't count.",
+    },
+    ErrorCode {
+        code: INVALID_CONVERSION,
+        title: "invalid conversion",
+        explanation: "\
+Conversions with `as` and calls like `Int32(x)` only work between number types.
+
+    let flag = x as Bool;    // error
+    let flag = x != 0;       // fine
+
+`as` truncates and saturates, the call form traps when the value doesn't fit in the new type.
+Inside `unsafe`, `as` also turns any pointer into a raw pointer and raw pointers into integers
+and back.",
+    },
+    ErrorCode {
+        code: INVALID_OPERANDS,
+        title: "invalid operands for an operator",
+        explanation: "\
+The operands of an operator have types it doesn't work on, or types that don't match each other.
+
+Arithmetic needs two numbers of the same type, there are no implicit conversions, so
+`x + y` with an `Int32` and an `Int64` is an error. Convert one side with `as` or a
+conversion call. Bitwise operators and shifts need integers, `and`, `or` and `not` need `Bool`s.",
+    },
+    ErrorCode {
+        code: LITERAL_OUT_OF_RANGE,
+        title: "literal out of range for its type",
+        explanation: "\
+A number literal is bigger than the largest value of the type it ended up with.
+
+    let x: UInt8 = 300;
+
+Unsuffixed literals take the type they're used as, and default to `Int64` or `Float64`.
+Use a wider type, or `wrapping` arithmetic if the value is meant to wrap around.",
+    },
+    ErrorCode {
+        code: NOT_TAIL_RECURSIVE,
+        title: "`@tailrec` function isn't tail recursive",
+        explanation: "\
+Functions marked `@tailrec` promise that their recursive calls are turned into loops, so
+they can recurse any number of times without growing the stack. That's only possible when
+every recursive call is the last thing the function does, with its result returned as is.
+
+    @tailrec
+    fun sum(n: Int64, total: Int64): Int64 {
+        if n == 0 { return total; }
+        return sum(n - 1, total + n);    // fine, the call is in tail position
+    }
+
+`return n + sum(n - 1)` isn't a tail call, the addition happens after the call returns.
+Move the pending work into an accumulator parameter, like `total` above.",
+    },
+    ErrorCode {
+        code: UNSUPPORTED_ABI,
+        title: "unsupported calling convention",
+        explanation: "\
+`extern` declarations name the calling convention of the function they declare, and C is
+the only one supported.
+
+    extern \"C\" fun puts(s: *UInt8): Int32;       // fine
+    extern \"stdcall\" fun Beep(f: UInt32): Bool;   // error",
+    },
+    ErrorCode {
+        code: NOT_C_COMPATIBLE,
+        title: "type can't be passed to or from C",
+        explanation: "\
+Functions declared with `extern \"C\"` and functions marked `@export` are called across the
+boundary with C, so their parameters and results need types C has a matching type for:
+integers, `Float32`, `Float64`, `Bool`, pointers and structs made of those. `Void` is fine
+as a result.
+
+    extern \"C\" fun abs(x: Int32): Int32;                  // fine
+    extern \"C\" fun hypot(p: Point): Float64;              // fine, passed like a C struct
+    extern \"C\" fun atexit(callback: () -> Void): Int32;    // error, closures can't cross
+
+Closures, optionals, enums and parameters without a type can't cross. Structs are passed
+the way the target's C compiler passes them, which is known for x86_64, aarch64, riscv64,
+i686 and wasm, on other targets they can't cross by value either. These values can be
+passed behind a raw pointer, like `*Point`, and C functions can't call neutron-star
+closures at all.",
+    },
+    ErrorCode {
+        code: UNUSED_VARIABLE,
+        title: "unused variable",
+        explanation: "\
+A local or parameter is never read. Assigning to it doesn't count, the value is still never used.
+
+    fun area(w: Int32, h: Int32): Int32 {
+        let unused = w + h;    // warning
+        return w * h;
+    }
+
+Remove it, or start its name with `_` if it's there on purpose, like a parameter that's only
+needed to match a function type. This is the `unused_variables` lint.",
+    },
+    ErrorCode {
+        code: UNUSED_IMPORT,
+        title: "unused import",
+        explanation: "\
+Nothing from an imported module is used, so the `import` can be removed.
+
+Only modules compiled before this one can be checked, so imports of modules the compiler
+hasn't seen are never reported. This is the `unused_imports` lint.",
+    },
+    ErrorCode {
+        code: UNREACHABLE_CODE,
+        title: "unreachable statement",
+        explanation: "\
+A statement comes after a `return`, `break` or `continue` in the same block, so it's never run.
+
+    fun f(): Int32 {
+        return 1;
+        let x = 2;    // warning
+    }
+
+Only the first unreachable statement in a block is reported. This is the `unreachable_code` lint.",
+    },
+    ErrorCode {
+        code: SHADOWED_NAME,
+        title: "name shadows an earlier declaration",
+        explanation: "\
+A `let`, parameter or loop variable has the same name as a local that's still in scope,
+so the earlier one can't be used anymore.
+
+    fun f(x: Int32): Int32 {
+        let x = x * 2;    // warning, shadows the parameter
+        return x;
+    }
+
+Give one of them a different name. This is the `shadowed_names` lint.",
+    },
+    ErrorCode {
+        code: UNUSED_UNSAFE,
+        title: "unnecessary `unsafe`",
+        explanation: "\
+An `unsafe` block or expression doesn't contain anything that needs it, or it's inside
+another `unsafe` already. Removing it keeps `unsafe` pointing at the code that really has
+to be checked by hand. This is the `unused_unsafe` lint.",
+    },
+    ErrorCode {
+        code: INVALID_IR,
+        title: "the compiler produced invalid ir",
+        explanation: "\
+This is a bug in the compiler, not in your code. The ir built from the program broke one
+of its own rules, which the verifier checks before code is generated.
+
+Please report it with the program that triggered it.",
+    },
+];
+
+pub fn lookup(code: &str) -> Option<&'static ErrorCode> {
+    REGISTRY.iter().find(|entry| entry.code.eq_ignore_ascii_case(code))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lang::Path;
+
+    #[test]
+    fn registry() {
+        let mut seen = HashSet::new();
+        for entry in REGISTRY {
+            assert!(seen.insert(entry.code), "`{}` is registered twice", entry.code);
+            assert!(entry.code.len() == 6 && entry.code.starts_with("NS") && entry.code[2..].chars().all(|c| c.is_ascii_digit()),
+                "`{}` isn't written like `NS0001`", entry.code);
+            assert!(!entry.title.is_empty() && !entry.explanation.is_empty(), "`{}` isn't explained", entry.code);
+        }
+        assert_eq!(lookup("ns0205").map(|entry| entry.code), Some(OUTSIDE_LOOP));
+        assert!(lookup("NS9999").is_none());
+    }
+
+    #[test]
+    fn diagnostics_have_registered_codes() {
+        let mut compiler = Compiler::new();
+        compiler.parse_module(Path::of("x"), "x.ns".to_string(), "
+            fun f(p: *Int32, a: Int32): Int32 {
+                let b: UInt8 = 256;
+                let q = p.*;
+                let c = a + 1u8;
+                break;
+            }
+        ".to_string());
+        compiler.compile();
+        assert!(compiler.diagnostics.messages.len() > 3, "{}", compiler.diagnostics.emit_to_string());
+        for diagnostic in &compiler.diagnostics.messages {
+            let code = diagnostic.code.as_deref().unwrap_or_else(|| panic!("`{}` has no code", diagnostic.message));
+            assert!(lookup(code).is_some(), "`{}` isn't registered", code);
+        }
+    }
+}
@@ -0,0 +1,876 @@
+use std::collections::HashSet;
+use std::io::Read;
+use crate::ast::*;
+use crate::diagnostic::{DiagnosticFormat, DiagnosticManager};
+use crate::lang::{Path, Span, ptr::PointerKind, refcap::ReferenceCapability};
+use crate::parser::Parser;
+
+const INDENT: &str = "    ";
+
+// how tightly each kind of expression binds, an operand that binds looser than its position allows gets parentheses
+const LOGIC: u8 = 0;
+const CAST: u8 = 8;
+const UNARY: u8 = 9;
+const POSTFIX: u8 = 10;
+const CALL: u8 = 11;
+const TERM: u8 = 12;
+
+/// Why a file couldn't be formatted.
+pub enum FormatError {
+    /// The file doesn't parse, nothing is formatted until it does.
+    Syntax(DiagnosticManager),
+    /// Formatting the output again changed it or lost a comment, a bug in the formatter.
+    Unstable,
+}
+
+/// `neutron-star fmt [--check] [file.ns...]`, formats files in place or stdin to stdout.
+/// With `--check` nothing is written, the files that would change are listed and the exit code is 1.
+pub fn run(args: &[String]) -> i32 {
+    let mut check = false;
+    let mut files = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "--check" => check = true,
+            _ if arg.starts_with('-') => {
+                eprintln!("error: unknown option `{}`", arg);
+                return 1;
+            }
+            _ => files.push(arg.clone()),
+        }
+    }
+
+    if files.is_empty() {
+        let mut source = String::new();
+        if let Err(error) = std::io::stdin().read_to_string(&mut source) {
+            eprintln!("error: can't read stdin: {}", error);
+            return 1;
+        }
+        return match format_file("<stdin>", &source) {
+            Some(formatted) if check && formatted != source => {
+                println!("<stdin>");
+                1
+            }
+            Some(_) if check => 0,
+            Some(formatted) => {
+                print!("{}", formatted);
+                0
+            }
+            None => 1,
+        };
+    }
+
+    let mut code = 0;
+    for file in &files {
+        let source = match std::fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("error: can't read `{}`: {}", file, error);
+                code = 1;
+                continue;
+            }
+        };
+        let Some(formatted) = format_file(file, &source) else {
+            code = 1;
+            continue;
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{}", file);
+            code = 1;
+        } else if let Err(error) = std::fs::write(file, formatted) {
+            eprintln!("error: can't write `{}`: {}", file, error);
+            code = 1;
+        }
+    }
+    code
+}
+
+fn format_file(file_name: &str, source: &str) -> Option<String> {
+    match format(file_name, source) {
+        Ok(formatted) => Some(formatted),
+        Err(FormatError::Syntax(diagnostics)) => {
+            diagnostics.emit(DiagnosticFormat::Human);
+            None
+        }
+        Err(FormatError::Unstable) => {
+            eprintln!("error: formatting `{}` isn't stable, this is a bug in the formatter", file_name);
+            None
+        }
+    }
+}
+
+/// Format a whole file, keeping its comments. The output is formatted a second time and has to come back unchanged.
+pub fn format(file_name: &str, source: &str) -> Result<String, FormatError> {
+    let formatted = format_once(file_name, source)?;
+    match format_once(file_name, &formatted) {
+        Ok(again) if again == formatted && comments(&formatted).len() == comments(source).len() => Ok(formatted),
+        _ => Err(FormatError::Unstable),
+    }
+}
+
+fn format_once(file_name: &str, source: &str) -> Result<String, FormatError> {
+    let mut parser = Parser::new();
+    match parser.parse(Path::new(), file_name.to_string(), source.to_string()) {
+        Some(program) if program.complete => Ok(SourceFormatter::new(&program, source).program()),
+        _ => Err(FormatError::Syntax(parser.diagnostics)),
+    }
+}
+
+/// A `//` comment, the grammar skips them so they're found again in the source.
+struct Comment {
+    span: Span,
+    text: String,
+}
+
+/// Every comment in a file, skipping over `//` inside string and character literals.
+fn comments(source: &str) -> Vec<Comment> {
+    let bytes = source.as_bytes();
+    let mut comments = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != quote && bytes[i] != b'\n' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                let end = source[i..].find(['\n', '\r']).map_or(bytes.len(), |length| i + length);
+                comments.push(Comment {
+                    span: i..end,
+                    text: source[i..end].trim_end().to_string(),
+                });
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+    comments
+}
+
+/// Prints a program back out in the canonical style. Comments between declarations and statements
+/// are written on their own lines before the next one, comments inside one are written before the
+/// token that follows them, and comments at the end of a line stay at the end of it.
+struct SourceFormatter<'a> {
+    program: &'a Program,
+    source: &'a str,
+    /// Comments that haven't been written yet, in source order.
+    comments: Vec<Comment>,
+    /// Where every comment is, tokens are looked for in the source around them.
+    comment_spans: Vec<Span>,
+    /// End of the last thing written, a blank line after it in the source is kept.
+    last_end: Option<usize>,
+    indent: usize,
+    out: String,
+}
+
+impl<'a> SourceFormatter<'a> {
+    fn new(program: &'a Program, source: &'a str) -> Self {
+        let comments = comments(source);
+        Self {
+            program,
+            source,
+            comment_spans: comments.iter().map(|comment| comment.span.clone()).collect(),
+            comments,
+            last_end: None,
+            indent: 0,
+            out: String::new(),
+        }
+    }
+
+    fn program(mut self) -> String {
+        let arena = &self.program.program_arena;
+        for (path, span) in self.program.imports.iter().zip(arena.import_spans.iter()) {
+            self.item_start(span.start);
+            self.write(&format!("import {}", path.to_string()));
+            self.item_end(span.end);
+        }
+
+        // members are written with the struct or interface they're in
+        let mut members = HashSet::new();
+        for (_, node) in arena.node_arena.iter() {
+            if let Node::Struct { children, .. } | Node::Interface { children, .. } = node {
+                members.extend(children.iter().cloned());
+            }
+        }
+        let mut nodes: Vec<NodeIndex> = arena.node_arena.iter()
+            .map(|(index, _)| index)
+            .filter(|index| !members.contains(index))
+            .collect();
+        nodes.sort_by_key(|index| self.program.node_span(*index).start);
+        for node in nodes {
+            self.node(node);
+        }
+
+        self.flush_comments(usize::MAX);
+        self.out
+    }
+
+    fn write(&mut self, s: &str) {
+        self.out.push_str(s);
+    }
+
+    fn indentation(&mut self) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    /// Write the comments before a declaration or statement and start its line.
+    fn item_start(&mut self, start: usize) {
+        self.flush_comments(start);
+        self.blank_line(start);
+        self.indentation();
+    }
+
+    /// End the line of a declaration or statement, taking a comment that follows it on the same line.
+    fn item_end(&mut self, end: usize) {
+        if !self.trailing_comment(end) {
+            self.last_end = Some(end);
+        }
+        self.write("\n");
+    }
+
+    /// Write the comment that follows `end` on the same line, if there is one.
+    fn trailing_comment(&mut self, end: usize) -> bool {
+        let trailing = self.comments.iter().position(|comment| {
+            comment.span.start >= end && self.source[end..comment.span.start].chars().all(|c| c == ' ' || c == '\t')
+        });
+        let Some(index) = trailing else {
+            return false;
+        };
+        let comment = self.comments.remove(index);
+        self.write(" ");
+        self.write(&comment.text);
+        self.last_end = Some(comment.span.end);
+        true
+    }
+
+    /// Write the comments before `until` in the middle of a declaration or statement. Each ends
+    /// its line, so what comes after it continues on the next line one level further in.
+    /// Returns whether any were written.
+    fn inline_comments(&mut self, until: usize) -> bool {
+        let mut written = false;
+        while self.comments.first().is_some_and(|comment| comment.span.start < until) {
+            let comment = self.comments.remove(0);
+            let line_start = self.source[..comment.span.start].rfind('\n').map_or(0, |newline| newline + 1);
+            let own_line = self.source[line_start..comment.span.start].trim().is_empty();
+            self.out.truncate(self.out.trim_end_matches(' ').len());
+            if own_line {
+                self.write("\n");
+                self.continuation();
+            } else {
+                self.write(" ");
+            }
+            self.write(&comment.text);
+            self.write("\n");
+            self.continuation();
+            self.last_end = Some(comment.span.end);
+            written = true;
+        }
+        written
+    }
+
+    fn continuation(&mut self) {
+        self.indentation();
+        self.write(INDENT);
+    }
+
+    /// Where `token` first is between `from` and `to` outside of comments, `to` when it isn't there.
+    fn find(&self, token: &str, from: usize, to: usize) -> usize {
+        if from >= to {
+            return to;
+        }
+        self.source[from..to].match_indices(token)
+            .map(|(offset, _)| from + offset)
+            .find(|position| !self.comment_spans.iter().any(|span| span.contains(position)))
+            .unwrap_or(to)
+    }
+
+    /// Where the last `token` before `before` is outside of comments.
+    fn find_back(&self, token: &str, before: usize) -> usize {
+        self.source[..before].rmatch_indices(token)
+            .map(|(position, _)| position)
+            .find(|position| !self.comment_spans.iter().any(|span| span.contains(position)))
+            .unwrap_or(before)
+    }
+
+    /// Write an operator found between `from` and `to` in the source, after the comments before it.
+    fn operator(&mut self, token: &str, from: usize, to: usize) {
+        let position = self.find(token, from, to);
+        if self.inline_comments(position) {
+            self.write(&format!("{} ", token));
+        } else {
+            self.write(&format!(" {} ", token));
+        }
+    }
+
+    /// Write the `{` starting a body whose first thing is at `first`, keeping a comment after it on its line.
+    fn open_brace(&mut self, first: usize) {
+        let brace = self.find_back("{", first);
+        self.inline_comments(brace);
+        self.write("{");
+        self.trailing_comment(brace + 1);
+        self.write("\n");
+    }
+
+    /// Write every comment that starts before `until`, each on its own line.
+    fn flush_comments(&mut self, until: usize) {
+        while self.comments.first().is_some_and(|comment| comment.span.start < until) {
+            let comment = self.comments.remove(0);
+            self.blank_line(comment.span.start);
+            self.indentation();
+            self.write(&comment.text);
+            self.write("\n");
+            self.last_end = Some(self.last_end.map_or(comment.span.end, |end| end.max(comment.span.end)));
+        }
+    }
+
+    fn has_comments_before(&self, end: Option<usize>) -> bool {
+        match (end, self.comments.first()) {
+            (Some(end), Some(comment)) => comment.span.start < end,
+            _ => false,
+        }
+    }
+
+    /// Keep one blank line if the source has any between the last thing written and `start`.
+    fn blank_line(&mut self, start: usize) {
+        let Some(last_end) = self.last_end else {
+            return;
+        };
+        if last_end >= start || self.out.ends_with("\n\n") || self.out.ends_with("{\n") {
+            return;
+        }
+        let lines: Vec<&str> = self.source[last_end..start].split('\n').collect();
+        if lines.len() > 2 && lines[1..lines.len() - 1].iter().any(|line| line.trim().is_empty()) {
+            self.write("\n");
+        }
+    }
+
+    /// Write `{`, the block and `}`. `end` is where the block ends in the source,
+    /// comments before it go inside the block.
+    fn block(&mut self, body: &[StatementIndex], end: Option<usize>) {
+        if body.is_empty() && !self.has_comments_before(end) {
+            self.write("{}");
+            return;
+        }
+        let first = body.first().map(|statement| self.program.statement_span(*statement).start);
+        if let Some(first) = first.or(end) {
+            self.open_brace(first);
+        } else {
+            self.write("{\n");
+        }
+        self.indent += 1;
+        for statement in body {
+            self.statement(*statement);
+        }
+        if let Some(end) = end {
+            self.flush_comments(end);
+        }
+        self.indent -= 1;
+        self.indentation();
+        self.write("}");
+    }
+
+    fn members(&mut self, children: &[NodeIndex], end: usize) {
+        if children.is_empty() && !self.has_comments_before(Some(end)) {
+            self.write("{}");
+            return;
+        }
+        self.open_brace(children.first().map_or(end, |child| self.program.node_span(*child).start));
+        self.indent += 1;
+        for child in children {
+            self.node(*child);
+        }
+        self.flush_comments(end);
+        self.indent -= 1;
+        self.indentation();
+        self.write("}");
+    }
+
+    fn node(&mut self, index: NodeIndex) {
+        let program = self.program;
+        let span = program.node_span(index);
+        let node = &program.program_arena.node_arena[index];
+        self.item_start(span.start);
+        for annotation in node.annotations() {
+            self.write(&annotation.to_string());
+            self.trailing_comment(annotation.span.end);
+            self.write("\n");
+            self.indentation();
+        }
+
+        match node {
+            Node::TypeAlias { access, unique, name, value, .. } => {
+                self.write(&format!("{}{}type {} = ", access_prefix(access), if *unique { "unique " } else { "" }, name));
+                self.typ(*value);
+                self.write(";");
+            }
+            Node::Variable { access, name, value, .. } => {
+                self.write(&format!("{}let ", access_prefix(access)));
+                self.typed_name(name);
+                if let Some(value) = value {
+                    self.write(" = ");
+                    self.expression(*value, LOGIC);
+                }
+                self.write(";");
+            }
+            Node::Function(func) => {
+                self.write(access_prefix(&func.access));
+                self.function_header(&func.kind, &func.name, &func.type_params, &func.params, func.return_type);
+                self.write(" ");
+                self.block(&func.statements, Some(span.end));
+            }
+            Node::FunctionPrototype { name, kind, type_params, params, return_type, abi, .. } => {
+                if let Some(abi) = abi {
+                    self.write(&format!("extern {:?} ", abi));
+                }
+                self.function_header(kind, name, type_params, params, *return_type);
+                self.write(";");
+            }
+            Node::Struct { access, kind, name, params, children, .. } => {
+                let keyword = match kind {
+                    StructKind::Struct => "struct",
+                    StructKind::Actor => "actor",
+                };
+                self.write(&format!("{}{} {}", access_prefix(access), keyword, name));
+                self.type_params(params);
+                self.write(" ");
+                self.members(children, span.end);
+            }
+            Node::Interface { access, name, params, children, .. } => {
+                self.write(&format!("{}interface {}", access_prefix(access), name));
+                self.type_params(params);
+                self.write(" ");
+                self.members(children, span.end);
+            }
+            Node::Enum { access, name, params, variants, .. } => {
+                self.write(&format!("{}enum {}", access_prefix(access), name));
+                self.type_params(params);
+                if variants.is_empty() && !self.has_comments_before(Some(span.end)) {
+                    self.write(" {}");
+                } else {
+                    self.write(" ");
+                    self.open_brace(variants.first().map_or(span.end, |variant| variant.span.start));
+                    self.indent += 1;
+                    for variant in variants {
+                        self.item_start(variant.span.start);
+                        self.write(&variant.name);
+                        if !variant.params.is_empty() {
+                            self.write("(");
+                            self.typed_names(&variant.params);
+                            self.write(")");
+                        }
+                        self.write(",");
+                        // a comment after the comma still ends the variant's line
+                        let rest = &self.source[variant.span.end..];
+                        let comma = rest.trim_start().strip_prefix(',').map(|after| self.source.len() - after.len());
+                        self.item_end(comma.unwrap_or(variant.span.end));
+                    }
+                    self.flush_comments(span.end);
+                    self.indent -= 1;
+                    self.indentation();
+                    self.write("}");
+                }
+            }
+            // incomplete programs aren't formatted
+            Node::Error => self.write(&self.source[span.clone()]),
+        }
+        self.item_end(span.end);
+    }
+
+    fn function_header(&mut self, kind: &FunctionKind, name: &str, type_params: &[TypedName], params: &[TypedName], return_type: TypeIndex) {
+        if let FunctionKind::Behaviour = kind {
+            self.write("async ");
+        }
+        self.write(&format!("fun {}", name));
+        self.type_params(type_params);
+        self.write("(");
+        self.typed_names(params);
+        if let Some(last) = params.last() {
+            let close = self.find(")", last.span.end, self.source.len());
+            self.inline_comments(close);
+        }
+        self.write(")");
+        self.return_type(return_type);
+    }
+
+    fn type_params(&mut self, params: &[TypedName]) {
+        if !params.is_empty() {
+            self.write("[");
+            self.typed_names(params);
+            self.write("]");
+        }
+    }
+
+    /// `: T`, or nothing when it's the `Void` the parser fills in for a missing return type.
+    fn return_type(&mut self, return_type: TypeIndex) {
+        match self.program.typ(return_type) {
+            Type::Base(TypeName { path, name, arguments }) if path.0.is_empty() && name == "Void" && arguments.is_empty() => {}
+            _ => {
+                self.write(": ");
+                self.typ(return_type);
+            }
+        }
+    }
+
+    fn typed_name(&mut self, typed_name: &TypedName) {
+        self.inline_comments(typed_name.span.start);
+        self.write(&typed_name.name);
+        if let Some(typ) = typed_name.typ {
+            self.write(": ");
+            self.typ(typ);
+        }
+    }
+
+    fn typed_names(&mut self, typed_names: &[TypedName]) {
+        for (i, typed_name) in typed_names.iter().enumerate() {
+            if i > 0 {
+                self.write(", ");
+            }
+            self.typed_name(typed_name);
+        }
+    }
+
+    fn types(&mut self, types: &[TypeIndex]) {
+        for (i, typ) in types.iter().enumerate() {
+            if i > 0 {
+                self.write(", ");
+            }
+            self.typ(*typ);
+        }
+    }
+
+    fn typ(&mut self, index: TypeIndex) {
+        match self.program.typ(index) {
+            Type::Base(type_name) => {
+                for part in &type_name.path.0 {
+                    self.write(part);
+                    self.write("::");
+                }
+                self.write(&type_name.name);
+                if !type_name.arguments.is_empty() {
+                    self.write("[");
+                    self.types(&type_name.arguments);
+                    self.write("]");
+                }
+            }
+            // `it` is what the short form names the value
+            Type::Refinement(var, inner, condition) if var == "it" => {
+                self.typ(*inner);
+                self.write(" where ");
+                self.expression(*condition, LOGIC);
+            }
+            Type::Refinement(var, inner, condition) => {
+                self.write(&format!("({}: ", var));
+                self.typ(*inner);
+                self.write(" where ");
+                self.expression(*condition, LOGIC);
+                self.write(")");
+            }
+            Type::Row(fields) => {
+                self.write("{");
+                self.typed_names(fields);
+                self.write("}");
+            }
+            Type::Reference(inner, kind, capability) => {
+                self.write(match kind {
+                    PointerKind::Tracked => "&",
+                    PointerKind::Raw => "*",
+                });
+                if let ReferenceCapability::Box = capability {
+                    // `& isolated` isn't `&iso lated`
+                    let start = self.out.len();
+                    self.typ(*inner);
+                    let capabilities = ["iso", "trn", "val", "mut", "tag"];
+                    if capabilities.iter().any(|capability| self.out[start..].starts_with(capability)) {
+                        self.out.insert(start, ' ');
+                    }
+                } else {
+                    self.write(&format!("{} ", capability.to_string()));
+                    self.typ(*inner);
+                }
+            }
+            Type::Optional(inner) => {
+                self.write("?");
+                self.typ(*inner);
+            }
+            Type::Function(params, return_type) => {
+                self.write("(");
+                self.types(params);
+                self.write(") -> ");
+                self.typ(*return_type);
+            }
+        }
+    }
+
+    fn statement(&mut self, index: StatementIndex) {
+        let program = self.program;
+        let span = program.statement_span(index);
+        self.item_start(span.start);
+        match program.statement(index) {
+            Statement::If { .. } => self.if_statement(index, span.end),
+            Statement::Call { function, args } => {
+                self.expression(*function, CALL);
+                self.arguments(args, span.end);
+                self.write(";");
+            }
+            Statement::Let { name, value } => {
+                self.write("let ");
+                self.typed_name(name);
+                self.write(" = ");
+                self.expression(*value, LOGIC);
+                self.write(";");
+            }
+            Statement::Assign { name, value } => {
+                self.write(&format!("{} = ", name));
+                self.expression(*value, LOGIC);
+                self.write(";");
+            }
+            Statement::Store { pointer, value } => {
+                self.expression(*pointer, POSTFIX);
+                self.write(".* = ");
+                self.expression(*value, LOGIC);
+                self.write(";");
+            }
+            Statement::Return { value } => {
+                self.write("return ");
+                self.expression(*value, LOGIC);
+                self.write(";");
+            }
+            Statement::Unsafe { body } => {
+                self.write("unsafe ");
+                self.block(body, Some(span.end));
+            }
+            Statement::While { condition, body } => {
+                self.write("while ");
+                self.expression(*condition, LOGIC);
+                self.write(" ");
+                self.block(body, Some(span.end));
+            }
+            Statement::For { variable, start, end, body } => {
+                self.write(&format!("for {} in ", variable));
+                self.expression(*start, LOGIC);
+                self.write("..");
+                self.expression(*end, LOGIC);
+                self.write(" ");
+                self.block(body, Some(span.end));
+            }
+            Statement::Break => self.write("break;"),
+            Statement::Continue => self.write("continue;"),
+            Statement::Error => self.write(&self.source[span.clone()]),
+        }
+        self.item_end(span.end);
+    }
+
+    /// `if`, then `else if` for each nested `If`. The parser turns `else` into an `If` on a
+    /// `true` it made up, which is the only condition without a span.
+    fn if_statement(&mut self, index: StatementIndex, end: usize) {
+        let program = self.program;
+        let mut next = Some(index);
+        let mut first = true;
+        while let Some(index) = next {
+            let Statement::If { condition, body, else_if } = program.statement(index) else {
+                break;
+            };
+            let is_else = !first && !program.program_arena.expression_spans.contains_key(condition);
+            if !first {
+                self.write(" else ");
+            }
+            if !is_else {
+                self.write("if ");
+                self.expression(*condition, LOGIC);
+                self.write(" ");
+            }
+            // a branch ends where the `else` of the next one starts
+            let branch_end = else_if.map_or(end, |next| program.statement_span(next).start);
+            self.block(body, Some(branch_end));
+            first = false;
+            next = *else_if;
+        }
+    }
+
+    /// `(a, b)`, the call ends at `end` in the source.
+    fn arguments(&mut self, args: &[ExpressionIndex], end: usize) {
+        self.write("(");
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                self.write(", ");
+            }
+            self.expression(*arg, LOGIC);
+        }
+        let close = self.find_back(")", end);
+        self.inline_comments(close);
+        self.write(")");
+    }
+
+    /// Write an expression, in parentheses if it binds looser than `min` allows.
+    fn expression(&mut self, index: ExpressionIndex, min: u8) {
+        let program = self.program;
+        let expression = program.expression(index);
+        let span = program.expression_span(index);
+        self.inline_comments(span.start);
+        let parenthesized = precedence(expression) < min;
+        if parenthesized {
+            self.write("(");
+        }
+        match expression {
+            Expression::Ref(name) => self.write(name),
+            // literals are written the way they were, with their suffixes, escapes and digit separators
+            Expression::NatLiteral(..) | Expression::FloatLiteral(..) | Expression::StringLiteral(_) | Expression::CharLiteral(_) => {
+                self.write(&self.source[span.clone()]);
+            }
+            Expression::BoolLiteral(value) => self.write(if *value { "true" } else { "false" }),
+            Expression::BinOp(left, op, right) => {
+                let level = binary_precedence(op);
+                self.expression(*left, level);
+                self.operator(&op.to_string(), program.expression_span(*left).end, program.expression_span(*right).start);
+                self.expression(*right, level + 1);
+            }
+            Expression::UnOp(op, operand) => {
+                self.write(&op.to_string());
+                if let UnOpType::Not = op {
+                    self.write(" ");
+                }
+                self.expression(*operand, UNARY);
+            }
+            Expression::Cast { value, typ } => {
+                self.expression(*value, CAST);
+                self.operator("as", program.expression_span(*value).end, span.end);
+                self.typ(*typ);
+            }
+            Expression::FieldAccessor { aggregate, value } => {
+                self.expression(*aggregate, POSTFIX);
+                let dot = self.find(".", program.expression_span(*aggregate).end, program.expression_span(*value).start);
+                self.inline_comments(dot);
+                self.write(".");
+                self.expression(*value, CALL);
+            }
+            Expression::FunctionCall { function, args } => {
+                self.expression(*function, CALL);
+                self.arguments(args, span.end);
+            }
+            Expression::New { typ, allocator } => {
+                self.write("new ");
+                self.typ(*typ);
+                self.write(" in ");
+                self.expression(*allocator, TERM);
+            }
+            Expression::Dereference { pointer } => {
+                self.expression(*pointer, POSTFIX);
+                self.write(".*");
+            }
+            Expression::Denull { optional } => {
+                self.expression(*optional, POSTFIX);
+                self.write(".?");
+            }
+            Expression::Borrow { value } => {
+                self.expression(*value, POSTFIX);
+                self.write(".&");
+            }
+            Expression::Unsafe { value } => {
+                self.write("unsafe { ");
+                self.expression(*value, LOGIC);
+                self.write(" }");
+            }
+            Expression::Lambda { params, return_type, body } => {
+                self.write("fun(");
+                self.typed_names(params);
+                self.write(")");
+                self.return_type(*return_type);
+                self.write(" ");
+                self.block(body, Some(span.end));
+            }
+            Expression::Error => self.write(&self.source[span.clone()]),
+        }
+        if parenthesized {
+            self.write(")");
+        }
+    }
+}
+
+fn access_prefix(access: &Access) -> &'static str {
+    match access {
+        Access::Public => "public ",
+        Access::Internal => "",
+    }
+}
+
+/// Binary operators are left associative, so the right operand needs one level tighter.
+fn binary_precedence(op: &BinOpType) -> u8 {
+    use BinOpType::*;
+    match op {
+        And | Or => LOGIC,
+        LessThan | LessThanEqualTo | GreaterThan | GreaterThanEqualTo | EqualTo | NotEqualTo => 1,
+        BitOr => 2,
+        BitXor => 3,
+        BitAnd => 4,
+        ShiftLeft | ShiftRight => 5,
+        Plus | Minus => 6,
+        Star | ForwardSlash | Percent => 7,
+    }
+}
+
+fn precedence(expression: &Expression) -> u8 {
+    match expression {
+        Expression::BinOp(_, op, _) => binary_precedence(op),
+        Expression::Cast { .. } => CAST,
+        Expression::UnOp(..) => UNARY,
+        Expression::Dereference { .. } | Expression::Denull { .. } | Expression::Borrow { .. } | Expression::FieldAccessor { .. } => POSTFIX,
+        // `(new T in arena).x` reads better than `new T in arena.x`, which means the same
+        Expression::New { .. } => UNARY,
+        Expression::FunctionCall { .. } | Expression::Unsafe { .. } | Expression::Lambda { .. } => CALL,
+        _ => TERM,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+
+    fn formatted(source: &str) -> String {
+        match format("test.ns", source) {
+            Ok(formatted) => formatted,
+            Err(_) => panic!("couldn't format:\n{}", source),
+        }
+    }
+
+    #[test]
+    fn fixtures_are_formatted() {
+        let directory = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test/fmt");
+        let mut fixtures = 0;
+        for entry in std::fs::read_dir(&directory).unwrap() {
+            let path = entry.unwrap().path();
+            let source = std::fs::read_to_string(&path).unwrap();
+            let once = formatted(&source);
+            assert_eq!(once, source, "{} isn't formatted", path.display());
+            assert_eq!(formatted(&once), once, "formatting {} twice changed it", path.display());
+            fixtures += 1;
+        }
+        assert!(fixtures > 0, "no fixtures in {}", directory.display());
+    }
+
+    #[test]
+    fn comments_stay_next_to_their_tokens() {
+        let source = "\
+fun g(a: Int32, // first
+      b: Int32): Int32 {
+    return a // left
+    + b;
+}
+";
+        let once = formatted(source);
+        assert_eq!(once, "\
+fun g(a: Int32, // first
+    b: Int32): Int32 {
+    return a // left
+        + b;
+}
+");
+        assert_eq!(formatted(&once), once);
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::HashSet;
+
+use crate::ast::Annotation;
+use crate::ir::*;
+use crate::ir::translate::EXPORT;
+use crate::lang::SEPARATOR;
+use crate::target::{StructLayout, TargetInfo};
+
+/// Writes the public functions, structs and enums of a module as a C header or a Zig file,
+/// so C and Zig hosts can call into it and share its data.
+pub struct BindingWriter<'m> {
+    module: &'m Module,
+    target: &'m TargetInfo,
+    buffer: String,
+}
+
+impl<'m> BindingWriter<'m> {
+    pub fn new(module: &'m Module, target: &'m TargetInfo) -> Self {
+        Self {
+            module,
+            target,
+            buffer: String::new(),
+        }
+    }
+
+    /// Name the files are written to and the header guard is made from, without an extension.
+    pub fn file_stem(&self) -> String {
+        self.module.path.to_string().replace(SEPARATOR, "_")
+    }
+
+    /// A C11 header, every struct has static asserts so a layout that doesn't match fails to compile.
+    pub fn c_header(mut self) -> String {
+        let guard = format!("NEUTRON_STAR_{}_H", self.file_stem().to_uppercase());
+        self.line(format!("/* Generated by neutron-star from {}, do not edit. */", self.module.name));
+        self.line(format!("#ifndef {}\n#define {}\n", guard, guard));
+        self.line("#include <stdbool.h>\n#include <stddef.h>\n#include <stdint.h>\n");
+
+        // pointers can be to structs declared further down
+        let mut forward = false;
+        for node in self.declarations() {
+            match node {
+                IrNode::Struct { name, .. } => self.line(format!("typedef struct {} {};", name, name)),
+                IrNode::Enum { name, variants, .. } if variants.iter().any(|variant| !variant.fields.is_empty()) => {
+                    self.line(format!("typedef struct {} {};", name, name));
+                }
+                _ => continue,
+            }
+            forward = true;
+        }
+        if forward {
+            self.line("");
+        }
+
+        for node in self.declarations() {
+            match node {
+                IrNode::Struct { access: Access::Public, name, fields, .. } => {
+                    let fields: Vec<IrTypedName> = fields.iter().map(|field| IrTypedName { name: field.name.clone(), typ: field.typ }).collect();
+                    self.line(format!("typedef struct {} {{", name));
+                    self.c_fields(&fields, "    ");
+                    self.line(format!("}} {};", name));
+                    let layout = self.target.struct_layout(self.arena(), &fields.iter().map(|field| field.typ).collect::<Vec<_>>());
+                    self.c_asserts(name, &fields, &layout);
+                }
+                IrNode::Enum { access: Access::Public, name, variants, .. } => {
+                    let tags: Vec<String> = variants.iter().map(|variant| format!("{}_{}", name, variant.name)).collect();
+                    let payloads: Vec<&IrVariant> = variants.iter().filter(|variant| !variant.fields.is_empty()).collect();
+                    if payloads.is_empty() {
+                        self.line(format!("typedef enum {} {{ {} }} {};", name, tags.join(", "), name));
+                    } else {
+                        self.line(format!("typedef enum {}_Tag {{ {} }} {}_Tag;", name, tags.join(", "), name));
+                        self.line(format!("typedef struct {} {{\n    {}_Tag tag;\n    union {{", name, name));
+                        for variant in payloads {
+                            self.line("        struct {");
+                            self.c_fields(&variant.fields, "            ");
+                            self.line(format!("        }} {};", variant.name));
+                        }
+                        self.line(format!("    }} payload;\n}} {};", name));
+                    }
+                    let layout = self.target.enum_layout(self.arena(), variants);
+                    self.line(format!("_Static_assert(sizeof({}) == {}, \"{} has the wrong size\");", name, layout.size, name));
+                }
+                _ => continue,
+            }
+            self.line("");
+        }
+
+        for function in self.functions() {
+            match function {
+                Ok(func) => {
+                    let params: Vec<String> = func.params.iter()
+                        .map(|param| Self::c_declaration(&self.c_type(param.typ).unwrap_or_default(), &param.name))
+                        .collect();
+                    let params = if params.is_empty() { "void".to_string() } else { params.join(", ") };
+                    let name = format!("{}({})", func.name, params);
+                    self.line(format!("{};", Self::c_declaration(&self.c_type(func.return_type).unwrap_or_default(), &name)));
+                }
+                Err(skipped) => self.line(format!("/* {} */", skipped)),
+            }
+        }
+        self.line(format!("\n#endif /* {} */", guard));
+        self.buffer
+    }
+
+    /// A Zig file with `extern` structs and functions, for `@import` instead of `@cImport`.
+    pub fn zig(mut self) -> String {
+        self.line(format!("// Generated by neutron-star from {}, do not edit.\n", self.module.name));
+        self.line("const std = @import(\"std\");\n");
+
+        for node in self.declarations() {
+            match node {
+                IrNode::Struct { access: Access::Public, name, fields, .. } => {
+                    let fields: Vec<IrTypedName> = fields.iter().map(|field| IrTypedName { name: field.name.clone(), typ: field.typ }).collect();
+                    self.line(format!("pub const {} = extern struct {{", name));
+                    self.zig_fields(&fields, "    ");
+                    self.line("};");
+                    let layout = self.target.struct_layout(self.arena(), &fields.iter().map(|field| field.typ).collect::<Vec<_>>());
+                    self.zig_asserts(name, &fields, &layout);
+                }
+                IrNode::Enum { access: Access::Public, name, variants, .. } => {
+                    let tags: Vec<&str> = variants.iter().map(|variant| variant.name.as_str()).collect();
+                    let payloads: Vec<&IrVariant> = variants.iter().filter(|variant| !variant.fields.is_empty()).collect();
+                    if payloads.is_empty() {
+                        self.line(format!("pub const {} = enum(c_int) {{ {} }};", name, tags.join(", ")));
+                    } else {
+                        self.line(format!("pub const {}_Tag = enum(c_int) {{ {} }};", name, tags.join(", ")));
+                        self.line(format!("pub const {} = extern struct {{\n    tag: {}_Tag,\n    payload: extern union {{", name, name));
+                        for variant in payloads {
+                            self.line(format!("        {}: extern struct {{", variant.name));
+                            self.zig_fields(&variant.fields, "            ");
+                            self.line("        },");
+                        }
+                        self.line("    },\n};");
+                    }
+                    let layout = self.target.enum_layout(self.arena(), variants);
+                    self.line(format!("comptime {{\n    std.debug.assert(@sizeOf({}) == {});\n}}", name, layout.size));
+                }
+                _ => continue,
+            }
+            self.line("");
+        }
+
+        for function in self.functions() {
+            match function {
+                Ok(func) => {
+                    let params: Vec<String> = func.params.iter()
+                        .map(|param| format!("{}: {}", param.name, self.zig_type(param.typ).unwrap_or_default()))
+                        .collect();
+                    self.line(format!("pub extern fn {}({}) {};", func.name, params.join(", "), self.zig_type(func.return_type).unwrap_or_default()));
+                }
+                Err(skipped) => self.line(format!("// {}", skipped)),
+            }
+        }
+        self.buffer
+    }
+
+    fn arena(&self) -> &'m ModuleArena {
+        &self.module.module_arena
+    }
+
+    /// Public structs and enums, each after the ones it holds by value so C sees complete types.
+    /// Enums without payloads can't be declared ahead like structs, they hold nothing so they go first.
+    fn declarations(&self) -> Vec<&'m IrNode> {
+        let mut ordered = vec![];
+        let mut visited = HashSet::new();
+        for (_, node) in self.arena().node_arena.iter() {
+            if let IrNode::Enum { access: Access::Public, name, variants, .. } = node {
+                if variants.iter().all(|variant| variant.fields.is_empty()) {
+                    visited.insert(name.as_str());
+                    ordered.push(node);
+                }
+            }
+        }
+        for (_, node) in self.arena().node_arena.iter() {
+            self.visit(node, &mut visited, &mut ordered);
+        }
+        ordered
+    }
+
+    fn visit(&self, node: &'m IrNode, visited: &mut HashSet<&'m str>, ordered: &mut Vec<&'m IrNode>) {
+        let (name, held): (&String, Vec<IrTypeIndex>) = match node {
+            IrNode::Struct { access: Access::Public, name, fields, .. } => (name, fields.iter().map(|field| field.typ).collect()),
+            IrNode::Enum { access: Access::Public, name, variants, .. } => {
+                (name, variants.iter().flat_map(|variant| variant.fields.iter().map(|field| field.typ)).collect())
+            }
+            _ => return,
+        };
+        if !visited.insert(name) {
+            return;
+        }
+        for typ in held {
+            if let Some(declaration) = self.declaration(typ) {
+                self.visit(declaration, visited, ordered);
+            }
+        }
+        ordered.push(node);
+    }
+
+    /// The struct or enum a type names, when it's one the bindings declare.
+    fn declaration(&self, typ: IrTypeIndex) -> Option<&'m IrNode> {
+        let IrType::Base(name) = &self.arena().type_arena[typ] else {
+            return None;
+        };
+        self.arena().declaration(name)
+            .filter(|node| matches!(node, IrNode::Struct { access: Access::Public, .. } | IrNode::Enum { access: Access::Public, .. }))
+    }
+
+    fn line<T: AsRef<str>>(&mut self, s: T) {
+        self.buffer.push_str(s.as_ref());
+        self.buffer.push('\n');
+    }
+
+    /// Public and exported functions, or why one can't be called from C.
+    fn functions(&self) -> Vec<Result<&'m IrFunction, String>> {
+        let mut functions = vec![];
+        for (_, node) in self.arena().node_arena.iter() {
+            let IrNode::Function(func) = node else {
+                continue;
+            };
+            if !matches!(func.access, Access::Public) && Annotation::find(&func.annotations, EXPORT).is_none() {
+                continue;
+            }
+            let unsupported = func.params.iter()
+                .find(|param| !self.target.is_c_compatible(self.arena(), param.typ))
+                .map(|param| format!("parameter `{}`", param.name));
+            let return_type = &self.arena().type_arena[func.return_type];
+            let unsupported = unsupported.or_else(|| {
+                let compatible = self.target.is_c_compatible(self.arena(), func.return_type);
+                (!compatible && !matches!(return_type, IrType::Void)).then(|| "the result".to_string())
+            });
+            functions.push(match unsupported {
+                _ if !func.type_params.is_empty() => Err(format!("`{}` is generic, it can't be called from C", func.name)),
+                Some(what) => Err(format!("{} of `{}` has no C type, it can't be called from C", what, func.name)),
+                None => Ok(func),
+            });
+        }
+        functions
+    }
+
+    /// C spelling of a type, `None` when C has nothing that matches.
+    fn c_type(&self, typ: IrTypeIndex) -> Option<String> {
+        use IrType::*;
+        Some(match &self.arena().type_arena[typ] {
+            Bool => "bool".to_string(),
+            Int(IntTy::ISize) => "intptr_t".to_string(),
+            Int(IntTy::I128) => "__int128".to_string(),
+            Int(int) => format!("int{}_t", int.bits(self.target)),
+            UInt(UIntTy::USize) => "uintptr_t".to_string(),
+            UInt(UIntTy::U128) => "unsigned __int128".to_string(),
+            UInt(uint) => format!("uint{}_t", uint.bits(self.target)),
+            Float(FloatTy::F16) => "_Float16".to_string(),
+            Float(FloatTy::F32) => "float".to_string(),
+            Float(FloatTy::F64) => "double".to_string(),
+            Float(FloatTy::F128) => "_Float128".to_string(),
+            Reference(inner, _, _) => match self.c_type(*inner) {
+                Some(inner) if inner != "void" => format!("{} *", inner),
+                _ => "void *".to_string(),
+            },
+            Refinement(_, inner, _) => return self.c_type(*inner),
+            Base(name) if self.declaration(typ).is_some() => name.clone(),
+            Void => "void".to_string(),
+            Base(_) | Row(_) | Optional(_) | Function(_, _) | Unknown => return None,
+        })
+    }
+
+    /// `int32_t x` but `int32_t *x`.
+    fn c_declaration(typ: &str, name: &str) -> String {
+        match typ.ends_with('*') {
+            true => format!("{}{}", typ, name),
+            false => format!("{} {}", typ, name),
+        }
+    }
+
+    fn zig_type(&self, typ: IrTypeIndex) -> Option<String> {
+        use IrType::*;
+        Some(match &self.arena().type_arena[typ] {
+            Bool => "bool".to_string(),
+            Int(IntTy::ISize) => "isize".to_string(),
+            Int(int) => format!("i{}", int.bits(self.target)),
+            UInt(UIntTy::USize) => "usize".to_string(),
+            UInt(uint) => format!("u{}", uint.bits(self.target)),
+            Float(float) => format!("f{}", float.bits()),
+            Reference(inner, _, _) => match self.zig_type(*inner) {
+                Some(inner) if inner != "void" => format!("[*c]{}", inner),
+                _ => "?*anyopaque".to_string(),
+            },
+            Refinement(_, inner, _) => return self.zig_type(*inner),
+            Base(name) if self.declaration(typ).is_some() => name.clone(),
+            Void => "void".to_string(),
+            Base(_) | Row(_) | Optional(_) | Function(_, _) | Unknown => return None,
+        })
+    }
+
+    /// Fields C has no type for are written as bytes with the same size and alignment, so the layout still matches.
+    fn c_fields(&mut self, fields: &[IrTypedName], indent: &str) {
+        for field in fields {
+            match self.c_type(field.typ) {
+                Some(typ) => self.line(format!("{}{};", indent, Self::c_declaration(&typ, &field.name))),
+                None => {
+                    let (size, align) = (self.target.size_of(self.arena(), field.typ), self.target.align_of(self.arena(), field.typ));
+                    if size > 0 {
+                        self.line(format!("{}_Alignas({}) uint8_t {}[{}];", indent, align, field.name, size));
+                    }
+                }
+            }
+        }
+    }
+
+    fn zig_fields(&mut self, fields: &[IrTypedName], indent: &str) {
+        for field in fields {
+            match self.zig_type(field.typ) {
+                Some(typ) => self.line(format!("{}{}: {},", indent, field.name, typ)),
+                None => {
+                    let (size, align) = (self.target.size_of(self.arena(), field.typ), self.target.align_of(self.arena(), field.typ));
+                    if size > 0 {
+                        self.line(format!("{}{}: [{}]u8 align({}),", indent, field.name, size, align));
+                    }
+                }
+            }
+        }
+    }
+
+    fn c_asserts(&mut self, name: &str, fields: &[IrTypedName], layout: &StructLayout) {
+        self.line(format!("_Static_assert(sizeof({}) == {}, \"{} has the wrong size\");", name, layout.size, name));
+        for (field, offset) in fields.iter().zip(&layout.offsets) {
+            if self.target.size_of(self.arena(), field.typ) > 0 {
+                self.line(format!("_Static_assert(offsetof({}, {}) == {}, \"{}.{} is in the wrong place\");", name, field.name, offset, name, field.name));
+            }
+        }
+    }
+
+    fn zig_asserts(&mut self, name: &str, fields: &[IrTypedName], layout: &StructLayout) {
+        self.line("comptime {");
+        self.line(format!("    std.debug.assert(@sizeOf({}) == {});", name, layout.size));
+        for (field, offset) in fields.iter().zip(&layout.offsets) {
+            if self.target.size_of(self.arena(), field.typ) > 0 {
+                self.line(format!("    std.debug.assert(@offsetOf({}, \"{}\") == {});", name, field.name, offset));
+            }
+        }
+        self.line("}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BindingWriter;
+    use crate::compiler::Compiler;
+    use crate::lang::Path;
+
+    const SOURCE: &str = "\
+public fun area(shape: *Shape): Int32 {
+    return 0;
+}
+
+public struct Shape {
+    public let origin: Point;
+    public let next: *Shape;
+    public let secret: *Hidden;
+}
+
+public struct Point {
+    public let x: Int32;
+}
+
+struct Hidden {
+    let x: Int32;
+}
+";
+
+    fn bindings() -> (String, String) {
+        let mut compiler = Compiler::new();
+        compiler.parse_module(Path::of("shapes"), "shapes.ns".to_string(), SOURCE.to_string());
+        compiler.compile();
+        let (_, module) = compiler.modules.iter().next().unwrap();
+        let target = &compiler.options.target;
+        (BindingWriter::new(module, target).c_header(), BindingWriter::new(module, target).zig())
+    }
+
+    #[test]
+    fn structs_keep_their_names() {
+        let (header, zig) = bindings();
+        assert!(header.contains("int32_t area(Shape *shape);"), "{}", header);
+        assert!(header.contains("    Point origin;\n    Shape *next;\n    void *secret;"), "{}", header);
+        assert!(zig.contains("pub extern fn area(shape: [*c]Shape) i32;"), "{}", zig);
+        assert!(zig.contains("    origin: Point,\n    next: [*c]Shape,\n    secret: ?*anyopaque,"), "{}", zig);
+    }
+
+    #[test]
+    fn held_structs_come_first() {
+        let (header, _) = bindings();
+        let point = header.find("typedef struct Point {").unwrap();
+        let shape = header.find("typedef struct Shape {").unwrap();
+        assert!(point < shape, "{}", header);
+    }
+}
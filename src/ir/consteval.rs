@@ -0,0 +1,460 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{BinOpType, UnOpType};
+use crate::ir::*;
+use crate::target::TargetInfo;
+
+/// Instructions run before giving up, so an infinite loop in a constant doesn't hang the compiler.
+static MAX_STEPS: usize = 1_000_000;
+static MAX_DEPTH: usize = 256;
+
+/// Runs ir at compile time to work out the values of globals.
+/// Only what can be done without memory is supported: arithmetic, comparisons, control flow
+/// and calls to functions that stick to those.
+pub struct ConstEvaluator<'a> {
+    arena: &'a ModuleArena,
+    target: &'a TargetInfo,
+    functions: HashMap<&'a str, &'a IrFunction>,
+    /// Functions computing the value of each global.
+    initializers: HashMap<String, &'a IrFunction>,
+    values: HashMap<String, Result<ConstValue, String>>,
+    in_progress: HashSet<String>,
+    steps: usize,
+    depth: usize,
+}
+
+impl<'a> ConstEvaluator<'a> {
+    pub fn new(arena: &'a ModuleArena, target: &'a TargetInfo, initializers: HashMap<String, &'a IrFunction>) -> Self {
+        let mut functions = HashMap::new();
+        for (_, node) in arena.node_arena.iter() {
+            if let IrNode::Function(func) = node {
+                functions.insert(func.name.as_str(), func);
+            }
+        }
+        Self {
+            arena,
+            target,
+            functions,
+            initializers,
+            values: HashMap::new(),
+            in_progress: HashSet::new(),
+            steps: 0,
+            depth: 0,
+        }
+    }
+
+    /// The value of a global, or why it isn't constant.
+    pub fn value(&mut self, name: &str) -> Result<ConstValue, String> {
+        let value = self.global(name)?;
+        let arena = self.arena;
+        Ok(match (value, self.initializers.get(name).map(|init| &arena.type_arena[init.return_type])) {
+            (ConstValue::UInt(bits), Some(IrType::Int(int))) => ConstValue::Int(Self::signed(bits, int.bits(self.target))),
+            _ => value,
+        })
+    }
+
+    /// Integers are kept as their bits while evaluating, the type says whether they're signed.
+    fn global(&mut self, name: &str) -> Result<ConstValue, String> {
+        if let Some(value) = self.values.get(name) {
+            return value.clone();
+        }
+        let init = match self.initializers.get(name) {
+            Some(init) => *init,
+            None => return Err(format!("`{}` isn't a constant", name)),
+        };
+        if !self.in_progress.insert(name.to_string()) {
+            return Err(format!("the value of `{}` depends on itself", name));
+        }
+        self.steps = 0;
+        let value = self.call(init, vec![])
+            .and_then(|value| value.ok_or(format!("`{}` doesn't have a value", name)));
+        self.in_progress.remove(name);
+        self.values.insert(name.to_string(), value.clone());
+        value
+    }
+
+    fn call(&mut self, func: &'a IrFunction, args: Vec<ConstValue>) -> Result<Option<ConstValue>, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(format!("calls to `{}` nest too deeply", func.name));
+        }
+        self.depth += 1;
+        let result = self.run(func, args);
+        self.depth -= 1;
+        result
+    }
+
+    fn run(&mut self, func: &'a IrFunction, args: Vec<ConstValue>) -> Result<Option<ConstValue>, String> {
+        let arena = self.arena;
+        let mut values: HashMap<IrInstructionIndex, ConstValue> = HashMap::new();
+        let mut block = *func.blocks.first().ok_or(format!("`{}` has no body", func.name))?;
+        let mut arguments = args;
+        loop {
+            let mut next = None;
+            for ins_index in &arena.block_arena[block].instructions {
+                self.steps += 1;
+                if self.steps > MAX_STEPS {
+                    return Err("evaluation takes too long, there may be an infinite loop".to_string());
+                }
+                let get = |index: &IrInstructionIndex| values.get(index).cloned()
+                    .ok_or("use of a value that wasn't computed".to_string());
+
+                use IrInstruction::*;
+                let value = match &arena.instruction_arena[*ins_index] {
+                    Argument(n) => arguments[*n],
+                    NatLiteral(n) if self.is_float(*ins_index) => ConstValue::Float(*n as f64),
+                    NatLiteral(n) => ConstValue::UInt(*n & self.mask(*ins_index)),
+                    FloatLiteral(n) => ConstValue::Float(self.round(*ins_index, *n)),
+                    BoolLiteral(b) => ConstValue::Bool(*b),
+                    // direct calls name the function, it's looked up by the call
+                    Ref(name) if self.functions.contains_key(name.as_str()) => continue,
+                    Ref(name) => self.global(name)?,
+                    BinOp(lhs, op, rhs) => self.binop(*ins_index, *lhs, *op, get(lhs)?, get(rhs)?, Overflow::Trap)?,
+                    OverflowOp { op, overflow: Overflow::Checked, .. } => {
+                        return Err(format!("`checked` arithmetic like `{}` can't be evaluated at compile time yet", op));
+                    }
+                    OverflowOp { op, overflow, lhs, rhs } => self.binop(*ins_index, *lhs, *op, get(lhs)?, get(rhs)?, *overflow)?,
+                    UnOp(op, operand) => self.unop(*ins_index, *op, get(operand)?)?,
+                    Convert { value, checked } => self.convert(*value, *ins_index, get(value)?, *checked)?,
+                    Unsafe { value } => get(value)?,
+                    FunctionCall { function, args } => {
+                        let callee = match &arena.instruction_arena[*function] {
+                            Ref(name) => *self.functions.get(name.as_str()).ok_or(format!("`{}` isn't a function", name))?,
+                            _ => return Err("closures can't be called at compile time".to_string()),
+                        };
+                        let args = args.iter().map(get).collect::<Result<Vec<_>, _>>()?;
+                        match self.call(callee, args).map_err(|error| format!("{} (in `{}`)", error, callee.name))? {
+                            Some(value) => value,
+                            None => continue,
+                        }
+                    }
+                    Branch { condition, true_branch, false_branch } => {
+                        next = Some(match get(condition)? {
+                            ConstValue::Bool(true) => (*true_branch, vec![]),
+                            _ => (*false_branch, vec![]),
+                        });
+                        break;
+                    }
+                    Jump { target, args } => {
+                        next = Some((*target, args.iter().map(get).collect::<Result<Vec<_>, _>>()?));
+                        break;
+                    }
+                    Return { value } => return value.as_ref().map(get).transpose(),
+                    other => return Err(format!("{} can't be done at compile time", Self::describe(other))),
+                };
+                values.insert(*ins_index, value);
+            }
+            match next {
+                Some((target, args)) => {
+                    block = target;
+                    arguments = args;
+                }
+                None => return Err(format!("`{}` ends without returning", func.name)),
+            }
+        }
+    }
+
+    fn describe(ins: &IrInstruction) -> &'static str {
+        use IrInstruction::*;
+        match ins {
+            StringLiteral(_) => "making a string",
+            MakeClosure { .. } | CaptureLoad { .. } => "making a closure",
+            New { .. } => "allocating",
+            Load { .. } | Dereference { .. } => "reading memory",
+            Store { .. } => "writing memory",
+            Borrow { .. } => "borrowing",
+            PointerOffset { .. } => "pointer arithmetic",
+            Denull { .. } => "unwrapping an optional",
+            FieldAccessor { .. } => "reading a field",
+            _ => "this",
+        }
+    }
+
+    fn typ(&self, index: IrInstructionIndex) -> &'a IrType {
+        let arena = self.arena;
+        arena.instruction_types.get(&index).map_or(&IrType::Unknown, |typ| &arena.type_arena[*typ])
+    }
+
+    fn is_float(&self, index: IrInstructionIndex) -> bool {
+        self.typ(index).is_float()
+    }
+
+    /// Width and signedness of an integer typed instruction.
+    fn int_type(&self, index: IrInstructionIndex) -> (u32, bool) {
+        match self.typ(index) {
+            IrType::Int(int) => (int.bits(self.target), true),
+            IrType::UInt(uint) => (uint.bits(self.target), false),
+            // unsuffixed literals that were never given a type are `Int64`
+            _ => (64, true),
+        }
+    }
+
+    fn mask(&self, index: IrInstructionIndex) -> u128 {
+        u128::MAX >> (128 - self.int_type(index).0)
+    }
+
+    /// Round a float to the precision of its type.
+    /// `Float16` and `Float128` are kept at `Float64` precision.
+    fn round(&self, index: IrInstructionIndex, value: f64) -> f64 {
+        match self.typ(index) {
+            IrType::Float(FloatTy::F32) => value as f32 as f64,
+            _ => value,
+        }
+    }
+
+    fn signed(bits: u128, width: u32) -> i128 {
+        let shift = 128 - width;
+        ((bits << shift) as i128) >> shift
+    }
+
+    /// Fit an exact result into an integer type, what happens when it doesn't fit depends on `overflow`.
+    /// `exact` is `None` when even 128 bits weren't enough, `wrapped` is the result modulo 2^128.
+    fn fit(&self, index: IrInstructionIndex, exact: Option<i128>, wrapped: u128, negative: bool, overflow: Overflow) -> Result<ConstValue, String> {
+        let (width, signed) = self.int_type(index);
+        let (min, max) = if signed {
+            // shifting -1 instead of negating a power of two keeps `Int128`'s minimum from overflowing
+            ((-1i128) << (width - 1), (u128::MAX >> (129 - width)) as i128)
+        } else {
+            (0, (u128::MAX >> (128 - width)).min(i128::MAX as u128) as i128)
+        };
+        let mask = u128::MAX >> (128 - width);
+        match exact {
+            // unsigned 128 bit values past `i128::MAX` are checked by the caller
+            Some(value) if value >= min && value <= max => Ok(ConstValue::UInt(value as u128 & mask)),
+            _ => match overflow {
+                Overflow::Wrap => Ok(ConstValue::UInt(wrapped & mask)),
+                Overflow::Saturate if negative => Ok(ConstValue::UInt(min as u128 & mask)),
+                Overflow::Saturate => Ok(ConstValue::UInt(if signed { max as u128 } else { mask })),
+                _ => Err(format!("the result overflows `{}`", self.typ(index).name())),
+            },
+        }
+    }
+
+    fn binop(&self, index: IrInstructionIndex, lhs: IrInstructionIndex, op: BinOpType, a: ConstValue, b: ConstValue, overflow: Overflow) -> Result<ConstValue, String> {
+        use BinOpType::*;
+        use ConstValue::*;
+        match (a, b) {
+            (Float(x), Float(y)) => Ok(match op {
+                Plus => Float(self.round(index, x + y)),
+                Minus => Float(self.round(index, x - y)),
+                Star => Float(self.round(index, x * y)),
+                ForwardSlash => Float(self.round(index, x / y)),
+                Percent => Float(self.round(index, x % y)),
+                LessThan => Bool(x < y),
+                GreaterThan => Bool(x > y),
+                LessThanEqualTo => Bool(x <= y),
+                GreaterThanEqualTo => Bool(x >= y),
+                EqualTo => Bool(x == y),
+                NotEqualTo => Bool(x != y),
+                _ => return Err(format!("`{}` can't be used on floats", op)),
+            }),
+            (Bool(x), Bool(y)) => Ok(Bool(match op {
+                EqualTo => x == y,
+                NotEqualTo => x != y,
+                BitAnd | And => x & y,
+                BitOr | Or => x | y,
+                BitXor => x ^ y,
+                _ => return Err(format!("`{}` can't be used on `Bool`", op)),
+            })),
+            (UInt(x), UInt(y)) => {
+                let (width, signed) = self.int_type(lhs);
+                if op.is_comparison() {
+                    let ordering = if signed {
+                        Self::signed(x, width).cmp(&Self::signed(y, width))
+                    } else {
+                        x.cmp(&y)
+                    };
+                    return Ok(Bool(match op {
+                        LessThan => ordering.is_lt(),
+                        GreaterThan => ordering.is_gt(),
+                        LessThanEqualTo => ordering.is_le(),
+                        GreaterThanEqualTo => ordering.is_ge(),
+                        EqualTo => ordering.is_eq(),
+                        _ => ordering.is_ne(),
+                    }));
+                }
+                let mask = u128::MAX >> (128 - width);
+                match op {
+                    BitAnd => return Ok(UInt(x & y)),
+                    BitOr => return Ok(UInt(x | y)),
+                    BitXor => return Ok(UInt(x ^ y)),
+                    ShiftLeft | ShiftRight => {
+                        if y >= width as u128 {
+                            return Err(format!("shifting by {} is too far for a {} bit integer", y, width));
+                        }
+                        return Ok(UInt(match (op, signed) {
+                            (ShiftLeft, _) => (x << y) & mask,
+                            (_, true) => (Self::signed(x, width) >> y) as u128 & mask,
+                            _ => x >> y,
+                        }));
+                    }
+                    ForwardSlash | Percent if y == 0 => return Err("division by zero".to_string()),
+                    _ => {}
+                }
+
+                if signed {
+                    let (x, y) = (Self::signed(x, width), Self::signed(y, width));
+                    let (exact, wrapped) = match op {
+                        Plus => (x.checked_add(y), x.wrapping_add(y)),
+                        Minus => (x.checked_sub(y), x.wrapping_sub(y)),
+                        Star => (x.checked_mul(y), x.wrapping_mul(y)),
+                        ForwardSlash => (x.checked_div(y), x.wrapping_div(y)),
+                        Percent => (x.checked_rem(y), x.wrapping_rem(y)),
+                        _ => return Err(format!("`{}` can't be used on integers", op)),
+                    };
+                    let negative = match op {
+                        Plus => y < 0,
+                        Minus => y > 0,
+                        _ => (x < 0) != (y < 0),
+                    };
+                    self.fit(index, exact, wrapped as u128, negative, overflow)
+                } else {
+                    let (exact, wrapped) = match op {
+                        Plus => (x.checked_add(y), x.wrapping_add(y)),
+                        Minus => (x.checked_sub(y), x.wrapping_sub(y)),
+                        Star => (x.checked_mul(y), x.wrapping_mul(y)),
+                        ForwardSlash => (Some(x / y), x / y),
+                        Percent => (Some(x % y), x % y),
+                        _ => return Err(format!("`{}` can't be used on integers", op)),
+                    };
+                    match exact {
+                        // too big for the i128 `fit` works in, but only `UInt128` can hold it
+                        Some(value) if value <= mask && value > i128::MAX as u128 => Ok(UInt(value)),
+                        _ => self.fit(index, exact.and_then(|value| i128::try_from(value).ok()), wrapped, op == Minus, overflow),
+                    }
+                }
+            }
+            _ => Err(format!("mismatched operands for `{}`", op)),
+        }
+    }
+
+    fn unop(&self, index: IrInstructionIndex, op: UnOpType, value: ConstValue) -> Result<ConstValue, String> {
+        match (op, value) {
+            (UnOpType::Negate, ConstValue::Float(x)) => Ok(ConstValue::Float(-x)),
+            (UnOpType::Negate, ConstValue::UInt(x)) => {
+                let (width, signed) = self.int_type(index);
+                if signed {
+                    let x = Self::signed(x, width);
+                    self.fit(index, x.checked_neg(), x.wrapping_neg() as u128, x > 0, Overflow::Trap)
+                } else {
+                    self.fit(index, if x == 0 { Some(0) } else { None }, x.wrapping_neg(), true, Overflow::Trap)
+                }
+            }
+            (UnOpType::Not, ConstValue::Bool(x)) => Ok(ConstValue::Bool(!x)),
+            (UnOpType::BitNot, ConstValue::UInt(x)) => Ok(ConstValue::UInt(!x & self.mask(index))),
+            (op, _) => Err(format!("`{}` can't be used here", op)),
+        }
+    }
+
+    fn convert(&self, from: IrInstructionIndex, to: IrInstructionIndex, value: ConstValue, checked: bool) -> Result<ConstValue, String> {
+        let target = self.typ(to);
+        let doesnt_fit = || format!("the value doesn't fit in `{}`", target.name());
+        let (from_width, from_signed) = self.int_type(from);
+        let (to_width, to_signed) = self.int_type(to);
+        let mask = self.mask(to);
+        let (min, max) = if to_signed {
+            (-(2f64.powi(to_width as i32 - 1)), 2f64.powi(to_width as i32 - 1))
+        } else {
+            (-1.0, 2f64.powi(to_width as i32))
+        };
+
+        Ok(match (value, target.is_float()) {
+            (ConstValue::Float(x), true) => ConstValue::Float(self.round(to, x)),
+            (ConstValue::Float(x), false) => {
+                let in_range = if to_signed { x >= min && x < max } else { x > min && x < max };
+                if checked && !in_range {
+                    return Err(doesnt_fit());
+                }
+                // like the lowering, out of range values saturate and NaN becomes 0
+                let clamped = if x.is_nan() { 0.0 } else { x.max(if to_signed { min } else { 0.0 }).min(max) };
+                match (clamped >= max, to_signed) {
+                    (true, true) => ConstValue::UInt((u128::MAX >> (129 - to_width)) & mask),
+                    (true, false) => ConstValue::UInt(mask),
+                    (false, true) => ConstValue::UInt(clamped as i128 as u128 & mask),
+                    (false, false) => ConstValue::UInt(clamped as u128 & mask),
+                }
+            }
+            (ConstValue::UInt(x), true) if from_signed => ConstValue::Float(self.round(to, Self::signed(x, from_width) as f64)),
+            (ConstValue::UInt(x), true) => ConstValue::Float(self.round(to, x as f64)),
+            (ConstValue::UInt(x), false) => {
+                let extended = if from_signed { Self::signed(x, from_width) as u128 } else { x };
+                let result = extended & mask;
+                let back = if to_signed { Self::signed(result, to_width) as u128 } else { result };
+                let same_sign = from_signed == to_signed
+                    || (from_signed && Self::signed(x, from_width) >= 0)
+                    || (!from_signed && Self::signed(result, to_width) >= 0);
+                if checked && (back != extended || !same_sign) {
+                    return Err(doesnt_fit());
+                }
+                ConstValue::UInt(result)
+            }
+            (ConstValue::Bool(b), true) => ConstValue::Float(b as u8 as f64),
+            (ConstValue::Bool(b), false) => ConstValue::UInt(b as u128),
+            (ConstValue::Int(_), _) => unreachable!("signed values are only made once evaluation is done"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Compiler;
+    use crate::ir::{ConstValue, IrNode};
+    use crate::lang::Path;
+
+    /// Compile a module and read back the value of each of its globals.
+    fn globals(source: &str) -> Vec<(String, Option<ConstValue>)> {
+        let mut compiler = Compiler::new();
+        compiler.parse_module(Path::of("consts"), "consts.ns".to_string(), source.to_string());
+        compiler.compile();
+        assert!(!compiler.diagnostics.has_errors(), "{}", compiler.diagnostics.emit_to_string());
+        let mut globals = vec![];
+        for (_, module) in compiler.modules.iter() {
+            for (_, node) in module.module_arena.node_arena.iter() {
+                if let IrNode::Global { name, value, .. } = node {
+                    globals.push((name.clone(), *value));
+                }
+            }
+        }
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+        globals
+    }
+
+    #[test]
+    fn int128_constants() {
+        assert_eq!(globals("
+            let BIG: Int128 = 1 + 2;
+            let LOW: Int128 = 0 - 170141183460469231731687303715884105727 - 1;
+            let HIGH: UInt128 = 340282366920938463463374607431768211455u128 - 1;
+        "), vec![
+            ("BIG".to_string(), Some(ConstValue::Int(3))),
+            ("HIGH".to_string(), Some(ConstValue::UInt(u128::MAX - 1))),
+            ("LOW".to_string(), Some(ConstValue::Int(i128::MIN))),
+        ]);
+    }
+
+    #[test]
+    fn saturating_arithmetic() {
+        assert_eq!(globals("
+            let MAX: Int128 = saturating_add(170141183460469231731687303715884105727i128, 1i128);
+            let MIN: Int128 = saturating_sub(0i128 - 170141183460469231731687303715884105727i128, 2i128);
+            let FULL: UInt128 = saturating_mul(340282366920938463463374607431768211455u128, 2u128);
+            let EMPTY: UInt128 = saturating_sub(1u128, 2u128);
+            let SMALL: Int8 = saturating_sub(0 - 100i8, 100i8);
+        "), vec![
+            ("EMPTY".to_string(), Some(ConstValue::UInt(0))),
+            ("FULL".to_string(), Some(ConstValue::UInt(u128::MAX))),
+            ("MAX".to_string(), Some(ConstValue::Int(i128::MAX))),
+            ("MIN".to_string(), Some(ConstValue::Int(i128::MIN))),
+            ("SMALL".to_string(), Some(ConstValue::Int(-128))),
+        ]);
+    }
+
+    #[test]
+    fn float_constants() {
+        let values = globals("
+            let NAN: Float64 = 0.0 / 0.0;
+            let WHOLE: Float64 = 1.5 * 4.0;
+        ");
+        assert!(matches!(values[0], (_, Some(ConstValue::Float(nan))) if nan.is_nan()), "{:?}", values);
+        assert_eq!(values[1], ("WHOLE".to_string(), Some(ConstValue::Float(6.0))));
+    }
+}
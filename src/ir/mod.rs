@@ -1,12 +1,19 @@
+use std::collections::HashMap;
 use generational_arena::{Arena, Index};
-use crate::lang::{Path, ptr::*, refcap::*};
-use crate::ast::{BinOpType, ExpressionIndex};
+use crate::diagnostic::FileId;
+use crate::lang::{Path, Span, ptr::*, refcap::*};
+use crate::ast::{Annotation, BinOpType, UnOpType};
 use crate::ir::FloatTy::*;
 use crate::ir::IntTy::*;
 use crate::ir::UIntTy::*;
+use crate::target::TargetInfo;
 
 pub(crate) mod translate;
 pub(crate) mod print;
+pub(crate) mod verify;
+pub(crate) mod tailcall;
+pub(crate) mod consteval;
+pub(crate) mod bindings;
 
 pub type IrTypeIndex = Index;
 pub type IrNodeIndex = Index;
@@ -18,6 +25,8 @@ pub struct ModuleArena {
     pub node_arena: Arena<IrNode>,
     pub block_arena: Arena<IrBlock>,
     pub instruction_arena: Arena<IrInstruction>,
+    /// Result types of instructions, where they are known.
+    pub instruction_types: HashMap<IrInstructionIndex, IrTypeIndex>,
 }
 
 impl ModuleArena {
@@ -27,9 +36,17 @@ impl ModuleArena {
             node_arena: Arena::new(),
             block_arena: Arena::new(),
             instruction_arena: Arena::new(),
+            instruction_types: HashMap::new(),
         }
     }
 
+    /// The struct or enum an `IrType::Base` names.
+    pub fn declaration(&self, name: &str) -> Option<&IrNode> {
+        self.node_arena.iter()
+            .map(|(_, node)| node)
+            .find(|node| matches!(node, IrNode::Struct { name: declared, .. } | IrNode::Enum { name: declared, .. } if declared == name))
+    }
+
     pub fn _add_instruction(&mut self, block: &mut IrBlock, ins: IrInstruction) {
         let index = self.instruction_arena.insert(ins);
         block.instructions.push(index);
@@ -39,8 +56,12 @@ impl ModuleArena {
 pub struct Module {
     pub path: Path,
     pub name: String,
+    pub file_id: FileId,
     pub imports: Vec<Path>,
     pub module_arena: ModuleArena,
+    /// Where each param and `let` is declared and the value it starts out with,
+    /// so tools can show the types that were inferred.
+    pub locals: Vec<(Span, IrInstructionIndex)>,
 }
 
 impl Module {
@@ -65,7 +86,51 @@ impl Access {
     }
 }
 
-#[derive(Clone, Debug, Copy)]
+/// What integer arithmetic does when the result doesn't fit in its type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Stop the program.
+    Trap,
+    /// Keep the low bits of the result.
+    Wrap,
+    /// Clamp to the smallest or largest value.
+    Saturate,
+    /// Produce an optional that's empty on overflow.
+    Checked,
+}
+
+impl Overflow {
+    /// Intrinsics like `wrapping_add` pick their overflow behaviour by name.
+    pub fn intrinsic(name: &str) -> Option<(Overflow, BinOpType)> {
+        let (overflow, op) = name.split_once('_')?;
+        let overflow = match overflow {
+            "wrapping" => Overflow::Wrap,
+            "checked" => Overflow::Checked,
+            "saturating" => Overflow::Saturate,
+            _ => return None,
+        };
+        let op = match op {
+            "add" => BinOpType::Plus,
+            "sub" => BinOpType::Minus,
+            "mul" => BinOpType::Star,
+            _ => return None,
+        };
+        Some((overflow, op))
+    }
+}
+
+impl ToString for Overflow {
+    fn to_string(&self) -> String {
+        match self {
+            Overflow::Trap => "trap".to_string(),
+            Overflow::Wrap => "wrap".to_string(),
+            Overflow::Saturate => "saturate".to_string(),
+            Overflow::Checked => "checked".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq)]
 pub enum IntTy {
     ISize,
     I8,
@@ -88,9 +153,26 @@ impl IntTy {
         }
     }
 
-    pub fn bits(&self) -> u32 {
+    /// The type of a literal suffix like `i32`.
+    pub fn from_suffix(suffix: &str) -> Option<Self> {
+        match suffix {
+            "isize" => Some(ISize),
+            "i8" => Some(I8),
+            "i16" => Some(I16),
+            "i32" => Some(I32),
+            "i64" => Some(I64),
+            "i128" => Some(I128),
+            _ => None
+        }
+    }
+
+    pub fn max_value(&self, target: &TargetInfo) -> u128 {
+        (1u128 << (self.bits(target) - 1)) - 1
+    }
+
+    pub fn bits(&self, target: &TargetInfo) -> u32 {
         match self {
-            ISize => 64, // todo
+            ISize => target.pointer_width,
             I8 => 8,
             I16 => 16,
             I32 => 32,
@@ -113,7 +195,7 @@ impl ToString for IntTy {
     }
 }
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq)]
 pub enum UIntTy {
     USize,
     U8,
@@ -136,9 +218,26 @@ impl UIntTy {
         }
     }
 
-    pub fn bits(&self) -> u32 {
+    /// The type of a literal suffix like `u8`.
+    pub fn from_suffix(suffix: &str) -> Option<Self> {
+        match suffix {
+            "usize" => Some(USize),
+            "u8" => Some(U8),
+            "u16" => Some(U16),
+            "u32" => Some(U32),
+            "u64" => Some(U64),
+            "u128" => Some(U128),
+            _ => None
+        }
+    }
+
+    pub fn max_value(&self, target: &TargetInfo) -> u128 {
+        u128::MAX >> (128 - self.bits(target))
+    }
+
+    pub fn bits(&self, target: &TargetInfo) -> u32 {
         match self {
-            USize => 64, // todo
+            USize => target.pointer_width,
             U8 => 8,
             U16 => 16,
             U32 => 32,
@@ -161,7 +260,7 @@ impl ToString for UIntTy {
     }
 }
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq)]
 pub enum FloatTy {
     F16,
     F32,
@@ -180,6 +279,26 @@ impl FloatTy {
         }
     }
 
+    /// The type of a literal suffix like `f32`.
+    pub fn from_suffix(suffix: &str) -> Option<Self> {
+        match suffix {
+            "f16" => Some(F16),
+            "f32" => Some(F32),
+            "f64" => Some(F64),
+            "f128" => Some(F128),
+            _ => None
+        }
+    }
+
+    /// Largest finite value, literals are parsed as `f64` so `Float128` can't go past it.
+    pub fn max_value(&self) -> f64 {
+        match self {
+            F16 => 65504.0,
+            F32 => f32::MAX as f64,
+            F64 | F128 => f64::MAX,
+        }
+    }
+
     pub fn bits(&self) -> u32 {
         match self {
             F16 => 16,
@@ -203,8 +322,8 @@ impl ToString for FloatTy {
 
 #[derive(Clone, Debug)]
 pub struct IrTypedName {
-    typ: IrTypeIndex,
-    name: String,
+    pub typ: IrTypeIndex,
+    pub name: String,
 }
 
 #[derive(Clone, Debug)]
@@ -223,6 +342,46 @@ pub enum IrType {
     Unknown,
 }
 
+impl IrType {
+    pub fn is_integer(&self) -> bool {
+        matches!(self, IrType::Int(_) | IrType::UInt(_))
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, IrType::Float(_))
+    }
+
+    /// Is this a type operators work on directly?
+    pub fn is_scalar(&self) -> bool {
+        self.is_integer() || self.is_float() || matches!(self, IrType::Bool)
+    }
+
+    /// Do two scalar types match exactly?
+    pub fn same_scalar(&self, other: &IrType) -> bool {
+        use IrType::*;
+        match (self, other) {
+            (Bool, Bool) => true,
+            (Int(a), Int(b)) => a == b,
+            (UInt(a), UInt(b)) => a == b,
+            (Float(a), Float(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        use IrType::*;
+        match self {
+            Bool => "Bool".to_string(),
+            Int(int) => int.to_string(),
+            UInt(uint) => uint.to_string(),
+            Float(float) => float.to_string(),
+            Void => "Void".to_string(),
+            Base(name) => name.clone(),
+            _ => "Unknown".to_string(),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct IrFunction {
     pub access: Access,
@@ -231,42 +390,135 @@ pub struct IrFunction {
     pub type_params: Vec<IrTypedName>,
     pub return_type: IrTypeIndex,
     pub blocks: Vec<IrBlockIndex>,
+    pub annotations: Vec<Annotation>,
+    pub span: Span,
+}
+
+/// A value worked out at compile time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConstValue {
+    Int(i128),
+    UInt(u128),
+    Float(f64),
+    Bool(bool),
+}
+
+impl ToString for ConstValue {
+    fn to_string(&self) -> String {
+        match self {
+            ConstValue::Int(value) => value.to_string(),
+            ConstValue::UInt(value) => value.to_string(),
+            ConstValue::Float(value) => format!("{:?}", value),
+            ConstValue::Bool(value) => value.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IrField {
+    pub name: String,
+    pub typ: IrTypeIndex,
+    /// From `let x = 2;` in the struct body.
+    pub default: Option<ConstValue>,
+}
+
+/// A case of an enum and the fields it carries.
+#[derive(Clone, Debug)]
+pub struct IrVariant {
+    pub name: String,
+    pub fields: Vec<IrTypedName>,
 }
 
 #[derive(Clone, Debug)]
 pub enum IrNode {
     Function(IrFunction),
+    /// A function from C declared with `extern "C"`, it has no blocks.
+    ExternFunction(IrFunction),
+    /// A module level `let`, the value is `None` when it couldn't be evaluated.
+    Global {
+        access: Access,
+        name: String,
+        typ: IrTypeIndex,
+        value: Option<ConstValue>,
+        span: Span,
+        annotations: Vec<Annotation>,
+    },
     Struct {
+        access: Access,
+        name: String,
+        fields: Vec<IrField>,
         nodes: Vec<IrNodeIndex>,
+        annotations: Vec<Annotation>,
+    },
+    Enum {
+        access: Access,
+        name: String,
+        variants: Vec<IrVariant>,
+        annotations: Vec<Annotation>,
     },
     Error,
 }
 
 #[derive(Clone, Debug)]
 pub struct IrBlock {
-    instructions: Vec<IrInstructionIndex>,
+    /// Types of the values passed in by jumps to this block.
+    /// The entry block takes the function's params.
+    pub arguments: Vec<IrTypeIndex>,
+    pub instructions: Vec<IrInstructionIndex>,
 }
 
 impl IrBlock {
     fn new() -> Self {
-        Self { instructions: vec![] }
+        Self { arguments: vec![], instructions: vec![] }
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum IrInstruction {
     Ref(String),
-    NatLiteral(i64),
+    /// The nth argument of the block this instruction is in.
+    Argument(usize),
+    NatLiteral(u128),
+    FloatLiteral(f64),
+    /// A pointer to constant, nul terminated bytes.
+    StringLiteral(String),
     BoolLiteral(bool),
     BinOp(IrInstructionIndex, BinOpType, IrInstructionIndex),
+    UnOp(UnOpType, IrInstructionIndex),
+    /// Integer `+`, `-` or `*` with explicit overflow behaviour, `BinOp` follows the compiler options.
+    OverflowOp {
+        op: BinOpType,
+        overflow: Overflow,
+        lhs: IrInstructionIndex,
+        rhs: IrInstructionIndex,
+    },
+    /// Convert a number or pointer to the type of this instruction.
+    /// Checked conversions stop the program when the value doesn't fit, the others truncate or saturate.
+    Convert {
+        value: IrInstructionIndex,
+        checked: bool,
+    },
     FieldAccessor {
         aggregate: IrInstructionIndex,
         value: IrInstructionIndex,
     },
+    /// Calls through anything but a global `Ref` are closure calls,
+    /// the closure's environment is passed before the args.
     FunctionCall {
         function: IrInstructionIndex,
         args: Vec<IrInstructionIndex>,
     },
+    /// Allocate an environment holding the captures and pair it with a function
+    /// whose first param is that environment.
+    MakeClosure {
+        function: String,
+        captures: Vec<IrInstructionIndex>,
+    },
+    /// Read the nth capture out of a closure environment.
+    CaptureLoad {
+        env: IrInstructionIndex,
+        index: usize,
+    },
     New {
         typ: IrTypeIndex,
         allocator: IrInstructionIndex,
@@ -280,16 +532,56 @@ pub enum IrInstruction {
     Borrow {
         value: IrInstructionIndex,
     },
+    /// Read through a raw pointer.
+    Load {
+        pointer: IrInstructionIndex,
+    },
+    /// Write through a raw pointer.
+    Store {
+        pointer: IrInstructionIndex,
+        value: IrInstructionIndex,
+    },
+    /// Raw pointer arithmetic, offset is counted in elements of the pointee type.
+    PointerOffset {
+        pointer: IrInstructionIndex,
+        offset: IrInstructionIndex,
+    },
     Branch {
         condition: IrInstructionIndex,
         true_branch: IrBlockIndex,
         false_branch: IrBlockIndex,
     },
+    Jump {
+        target: IrBlockIndex,
+        args: Vec<IrInstructionIndex>,
+    },
+    /// Functions returning `Void` return without a value.
     Return {
-        value: IrInstructionIndex,
+        value: Option<IrInstructionIndex>,
     },
     Unsafe {
         value: IrInstructionIndex,
     },
     Error,
 }
+
+impl IrInstruction {
+    /// Does this instruction end a block?
+    pub fn is_terminator(&self) -> bool {
+        use IrInstruction::*;
+        match self {
+            Branch { .. } | Jump { .. } | Return { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Blocks control can go to after this instruction.
+    pub fn successors(&self) -> Vec<IrBlockIndex> {
+        use IrInstruction::*;
+        match self {
+            Branch { true_branch, false_branch, .. } => vec![*true_branch, *false_branch],
+            Jump { target, .. } => vec![*target],
+            _ => vec![],
+        }
+    }
+}
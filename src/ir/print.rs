@@ -1,7 +1,7 @@
 use std::{ops::Deref, collections::HashMap};
 
 use super::Module;
-use crate::{lang::*, ir::*};
+use crate::{lang::*, ir::*, ast::Annotation};
 
 struct PrintManager {
     buffer: String,
@@ -38,6 +38,11 @@ impl PrintManager {
     }
 }
 
+/// How a type is written in the ir, like `&iso Int32`.
+pub fn type_name(arena: &ModuleArena, typ: IrTypeIndex) -> String {
+    arena.type_arena.get(typ).map_or("unknown_type".to_string(), |typ| IrPrintManager::new().print_type(arena, typ))
+}
+
 pub struct IrPrintManager {
     printer: PrintManager,
 }
@@ -64,6 +69,7 @@ impl IrPrintManager {
     fn print_node(&mut self, arena: &ModuleArena, node: &IrNode) {
         match node {
             IrNode::Function (func) => {
+                self.print_annotations(&func.annotations);
                 // function signature
                 self.printer.write(&format!("function {}(", func.name));
                 for (i, arg) in func.params.iter().enumerate() {
@@ -83,25 +89,91 @@ impl IrPrintManager {
                 // function body
                 self.printer.indent();
                 let mut instruction_names: HashMap<Index, String> = HashMap::new();
-                for (i, block_index) in func.blocks.iter().enumerate() {
+                let block_names: HashMap<Index, String> = func.blocks.iter().enumerate()
+                    .map(|(i, block_index)| (*block_index, format!("block#{}", i)))
+                    .collect();
+                for block_index in func.blocks.iter() {
                     let block = arena.block_arena.get(*block_index).expect(format!("where did block {:?} go??", block_index).as_str());
-                    self.printer.write(format!("block#{}:\n", i));
+                    let arguments: Vec<String> = block.arguments.iter().map(|typ| {
+                        arena.type_arena.get(*typ).map(|typ| self.print_type(arena, typ)).unwrap_or("unknown_type".to_string())
+                    }).collect();
+                    self.printer.write(format!("{}({}):\n", block_names[block_index], arguments.join(", ")));
                     self.printer.indent();
-                    for (j, instruction_index) in block.instructions.iter().enumerate() {
+                    for instruction_index in block.instructions.iter() {
                         let instruction = arena.instruction_arena.get(*instruction_index).expect(format!("where did instruction {:?} go??", instruction_index).as_str());
-                        self.printer.write(format!("%{} = {}\n", j, self.print_instruction(&mut instruction_names, arena, instruction)));
+                        let name = Self::name(&mut instruction_names, instruction_index);
+                        self.printer.write(format!("{} = {}\n", name, self.print_instruction(&mut instruction_names, &block_names, instruction)));
                     }
                     self.printer.dedent();
                 }
                 self.printer.dedent();
                 self.printer.write("\n");
             }
+            IrNode::ExternFunction(func) => {
+                self.print_annotations(&func.annotations);
+                self.printer.write(&format!("extern function {}(", func.name));
+                for (i, arg) in func.params.iter().enumerate() {
+                    if i > 0 {
+                        self.printer.write(", ");
+                    }
+                    self.print_typed_name(arena, arg);
+                }
+                let return_type = arena.type_arena.get(func.return_type).map(|typ| self.print_type(arena, typ)).unwrap_or("unknown_type".to_string());
+                self.printer.write(format!(") -> {}\n\n", return_type));
+            }
+            IrNode::Global { name, typ, value, annotations, .. } => {
+                self.print_annotations(annotations);
+                let typ = arena.type_arena.get(*typ).map(|typ| self.print_type(arena, typ)).unwrap_or("unknown_type".to_string());
+                let value = value.map_or("unknown_value".to_string(), |value| value.to_string());
+                self.printer.write(format!("global {}: {} = {}\n\n", name, typ, value));
+            }
+            IrNode::Struct { name, fields, annotations, .. } => {
+                self.print_annotations(annotations);
+                self.printer.write(format!("struct {}:\n", name));
+                self.printer.indent();
+                for field in fields {
+                    let typ = arena.type_arena.get(field.typ).map(|typ| self.print_type(arena, typ)).unwrap_or("unknown_type".to_string());
+                    match field.default {
+                        Some(default) => self.printer.write(format!("{}: {} = {}\n", field.name, typ, default.to_string())),
+                        None => self.printer.write(format!("{}: {}\n", field.name, typ)),
+                    }
+                }
+                self.printer.dedent();
+                self.printer.write("\n");
+            }
+            IrNode::Enum { name, variants, annotations, .. } => {
+                self.print_annotations(annotations);
+                self.printer.write(format!("enum {}:\n", name));
+                self.printer.indent();
+                for variant in variants {
+                    self.printer.write(&variant.name);
+                    if !variant.fields.is_empty() {
+                        self.printer.write("(");
+                        for (i, field) in variant.fields.iter().enumerate() {
+                            if i > 0 {
+                                self.printer.write(", ");
+                            }
+                            self.print_typed_name(arena, field);
+                        }
+                        self.printer.write(")");
+                    }
+                    self.printer.write("\n");
+                }
+                self.printer.dedent();
+                self.printer.write("\n");
+            }
             n => {
                 self.printer.write(format!("unknown node: {:?}", n));
             }
         }
     }
 
+    fn print_annotations(&mut self, annotations: &[Annotation]) {
+        for annotation in annotations {
+            self.printer.write(format!("{}\n", annotation));
+        }
+    }
+
     fn print_typed_name(&mut self, arena: &ModuleArena, typed_name: &IrTypedName) {
         let type_name = arena.type_arena.get(typed_name.typ).map(|typ| {
             self.print_type(arena, typ)
@@ -130,32 +202,69 @@ impl IrPrintManager {
 
                 format!("{}{} {}", ptr_kind, refcap.to_string(), inner_type)
             },
+            Optional(inner) => {
+                let inner_type = arena.type_arena.get(*inner).map(|typ| self.print_type(arena, typ)).unwrap_or("unknown_type".to_string());
+                format!("?{}", inner_type)
+            }
+            Row(fields) => {
+                let fields: Vec<String> = fields.iter().map(|field| {
+                    let typ = arena.type_arena.get(field.typ).map(|typ| self.print_type(arena, typ)).unwrap_or("unknown_type".to_string());
+                    format!("{}: {}", field.name, typ)
+                }).collect();
+                format!("{{{}}}", fields.join(", "))
+            }
+            Function(params, return_type) => {
+                let params: Vec<String> = params.iter().map(|param| {
+                    arena.type_arena.get(*param).map(|typ| self.print_type(arena, typ)).unwrap_or("unknown_type".to_string())
+                }).collect();
+                let return_type = arena.type_arena.get(*return_type).map(|typ| self.print_type(arena, typ)).unwrap_or("unknown_type".to_string());
+                format!("({}) -> {}", params.join(", "), return_type)
+            }
             x => format!("bad_type[{:?}]", x),
         }
     }
 
-    fn print_instruction(&self, instruction_map: &mut HashMap<Index, String>, arena: &ModuleArena, ins: &IrInstruction) -> String {
-        let mut to_string = |i: &Index| {
-            if let Some(x) = instruction_map.get(i) {
-                return x.clone();
-            }
-            let name = format!("%{}", instruction_map.len());
-            instruction_map.insert(*i, name.clone());
-            return name;
-        };
+    /// Instructions are named in the order they are first seen.
+    fn name(instruction_map: &mut HashMap<Index, String>, i: &Index) -> String {
+        if let Some(x) = instruction_map.get(i) {
+            return x.clone();
+        }
+        let name = format!("%{}", instruction_map.len());
+        instruction_map.insert(*i, name.clone());
+        name
+    }
+
+    fn print_instruction(&self, instruction_map: &mut HashMap<Index, String>, block_names: &HashMap<Index, String>, ins: &IrInstruction) -> String {
+        let mut to_string = |i: &Index| Self::name(instruction_map, i);
+        let block_name = |i: &Index| block_names.get(i).cloned().unwrap_or("detached_block".to_string());
 
         use IrInstruction::*;
         match ins {
             BoolLiteral(b) => format!("{}", b),
             NatLiteral(n) => format!("{}", n),
-            Branch { condition, true_branch, false_branch } => format!("branch {} {} {}", to_string(condition), to_string(true_branch), to_string(false_branch)),
-            Return { value } => format!("return {}", to_string(value)),
+            FloatLiteral(n) => format!("{:?}", n),
+            StringLiteral(s) => format!("{:?}", s),
+            Argument(n) => format!("argument {}", n),
+            Branch { condition, true_branch, false_branch } => format!("branch {} {} {}", to_string(condition), block_name(true_branch), block_name(false_branch)),
+            Jump { target, args } => format!("jump {} ({})", block_name(target), args.iter().map(|i| to_string(i)).collect::<Vec<String>>().join(", ")),
+            Return { value: Some(value) } => format!("return {}", to_string(value)),
+            Return { value: None } => "return".to_string(),
             BinOp(a, op, b) => format!("binop.`{}` {} {}", op, to_string(a), to_string(b)),
+            UnOp(op, a) => format!("unop.`{}` {}", op, to_string(a)),
+            OverflowOp { op, overflow, lhs, rhs } => format!("binop.`{}`.{} {} {}", op, overflow.to_string(), to_string(lhs), to_string(rhs)),
+            Convert { value, checked: false } => format!("convert {}", to_string(value)),
+            Convert { value, checked: true } => format!("convert.checked {}", to_string(value)),
             Ref(a) => format!("ref %{}", a),
             FunctionCall { function, args } => format!("call {} ({})", to_string(function), args.iter().map(|i| to_string(i)).collect::<Vec<String>>().join(", ")),
+            MakeClosure { function, captures } => format!("closure @{} ({})", function, captures.iter().map(|i| to_string(i)).collect::<Vec<String>>().join(", ")),
+            CaptureLoad { env, index } => format!("capture {} {}", to_string(env), index),
             New { typ, allocator } => format!("new {} {}", to_string(typ), to_string(allocator)),
             Dereference { pointer } => format!("deref.`&` {}", to_string(pointer)),
             Denull { optional } => format!("denull.`!!` {}", to_string(optional)),
+            Load { pointer } => format!("load {}", to_string(pointer)),
+            Store { pointer, value } => format!("store {} {}", to_string(pointer), to_string(value)),
+            PointerOffset { pointer, offset } => format!("offset {} {}", to_string(pointer), to_string(offset)),
+            Unsafe { value } => format!("unsafe {}", to_string(value)),
             x => format!("bad_ins[{:?}]", x),
         }
     }
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+use generational_arena::Arena;
+
+use super::Module;
+use crate::ast::Annotation;
+use crate::diagnostic::{codes, FileId};
+use crate::ir::*;
+
+/// Functions annotated with this must have every self call in tail position.
+pub static TAILREC: &str = "tailrec";
+
+/// Rewrites self tail calls into jumps back to the top of the function,
+/// so recursive functions like `loop` run in constant stack space.
+pub struct TailCallOptimizer<'m> {
+    module: &'m mut Module,
+    diagnostics: Vec<Diagnostic<FileId>>,
+}
+
+/// Self calls found in a function.
+struct SelfCalls {
+    /// Blocks ending in `return f(...)`, with the call's instruction.
+    tail_calls: Vec<(IrBlockIndex, IrInstructionIndex)>,
+    other_calls: usize,
+}
+
+impl<'m> TailCallOptimizer<'m> {
+    pub fn new(module: &'m mut Module) -> Self {
+        Self {
+            module,
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn optimize(mut self) -> Vec<Diagnostic<FileId>> {
+        let file_id = self.module.file_id;
+        let arena = &mut self.module.module_arena;
+        for (_, node) in arena.node_arena.iter_mut() {
+            let func = match node {
+                IrNode::Function(func) => func,
+                _ => continue,
+            };
+
+            let self_calls = Self::find_self_calls(func, &arena.block_arena, &arena.instruction_arena);
+            if Annotation::find(&func.annotations, TAILREC).is_some() {
+                let problem = if self_calls.tail_calls.is_empty() && self_calls.other_calls == 0 {
+                    Some("it never calls itself")
+                } else if self_calls.other_calls > 0 {
+                    Some("it calls itself outside of tail position")
+                } else {
+                    None
+                };
+                if let Some(problem) = problem {
+                    self.diagnostics.push(Diagnostic::error()
+                        .with_code(codes::NOT_TAIL_RECURSIVE)
+                        .with_message(format!("function `{}` is marked `@{}` but {}", func.name, TAILREC, problem))
+                        .with_labels(vec![Label::primary(file_id, func.span.clone())])
+                        .with_notes(vec!["a call is in tail position when its result is returned right away".to_string()]));
+                }
+            }
+
+            if !self_calls.tail_calls.is_empty() {
+                Self::rewrite(func, self_calls.tail_calls, &mut arena.block_arena, &mut arena.instruction_arena, &mut arena.instruction_types);
+            }
+        }
+        self.diagnostics
+    }
+
+    fn is_self_call(func: &IrFunction, instructions: &Arena<IrInstruction>, ins: &IrInstruction) -> bool {
+        match ins {
+            IrInstruction::FunctionCall { function, args } => {
+                let callee = instructions.get(*function);
+                callee == Some(&IrInstruction::Ref(func.name.clone())) && args.len() == func.params.len()
+            }
+            _ => false,
+        }
+    }
+
+    fn find_self_calls(func: &IrFunction, blocks: &Arena<IrBlock>, instructions: &Arena<IrInstruction>) -> SelfCalls {
+        let mut self_calls = SelfCalls {
+            tail_calls: vec![],
+            other_calls: 0,
+        };
+        for block_index in &func.blocks {
+            let block = &blocks[*block_index];
+            let tail_call = match block.instructions.as_slice() {
+                [.., call, ret] => match &instructions[*ret] {
+                    IrInstruction::Return { value: Some(value) } if value == call => Some(*call),
+                    _ => None,
+                },
+                _ => None,
+            };
+
+            for ins_index in &block.instructions {
+                if !Self::is_self_call(func, instructions, &instructions[*ins_index]) {
+                    continue;
+                }
+                if tail_call == Some(*ins_index) {
+                    self_calls.tail_calls.push((*block_index, *ins_index));
+                } else {
+                    self_calls.other_calls += 1;
+                }
+            }
+        }
+        self_calls
+    }
+
+    fn rewrite(
+        func: &mut IrFunction,
+        tail_calls: Vec<(IrBlockIndex, IrInstructionIndex)>,
+        blocks: &mut Arena<IrBlock>,
+        instructions: &mut Arena<IrInstruction>,
+        types: &mut HashMap<IrInstructionIndex, IrTypeIndex>,
+    ) {
+        // the entry block can't be jumped to, so a new entry passes the params to the old one
+        let header = func.blocks[0];
+        let mut entry = IrBlock::new();
+        entry.arguments = blocks[header].arguments.clone();
+        let mut params = vec![];
+        for (i, typ) in entry.arguments.iter().enumerate() {
+            let param = instructions.insert(IrInstruction::Argument(i));
+            types.insert(param, *typ);
+            params.push(param);
+        }
+        entry.instructions = params.clone();
+        entry.instructions.push(instructions.insert(IrInstruction::Jump {
+            target: header,
+            args: params,
+        }));
+        func.blocks.insert(0, blocks.insert(entry));
+
+        for (block_index, call) in tail_calls {
+            let (function, args) = match instructions.remove(call) {
+                Some(IrInstruction::FunctionCall { function, args }) => (function, args),
+                _ => unreachable!("tail call went missing"),
+            };
+            types.remove(&call);
+
+            let block = &mut blocks[block_index];
+            let ret = block.instructions.pop().unwrap();
+            instructions.remove(ret);
+            block.instructions.pop();
+
+            // the callee's ref is only used by the call
+            let before = block.instructions.len();
+            block.instructions.retain(|ins| *ins != function);
+            if block.instructions.len() < before {
+                instructions.remove(function);
+            }
+
+            let jump = instructions.insert(IrInstruction::Jump {
+                target: header,
+                args,
+            });
+            block.instructions.push(jump);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Compiler;
+    use crate::ir::{IrInstruction, IrNode};
+    use crate::lang::Path;
+
+    fn compile(source: &str) -> Compiler {
+        let mut compiler = Compiler::new();
+        compiler.parse_module(Path::of("test"), "test.ns".to_string(), source.to_string());
+        compiler.compile();
+        compiler
+    }
+
+    #[test]
+    fn self_tail_calls_jump_back_to_the_top() {
+        let compiler = compile("
+            fun count(n: Int32, total: Int32): Int32 {
+                if n < 1 {
+                    return total;
+                }
+                return count(n - 1, total + n);
+            }
+        ");
+        assert!(!compiler.diagnostics.has_errors(), "{}", compiler.diagnostics.emit_to_string());
+        let (_, module) = compiler.modules.iter().next().unwrap();
+        let arena = &module.module_arena;
+        let func = arena.node_arena.iter()
+            .find_map(|(_, node)| match node {
+                IrNode::Function(func) => Some(func),
+                _ => None,
+            })
+            .unwrap();
+        // the entry block only passes the params on to the loop header
+        let header = func.blocks[1];
+        let mut jumps_back = 0;
+        for block in &func.blocks {
+            for ins in &arena.block_arena[*block].instructions {
+                match &arena.instruction_arena[*ins] {
+                    IrInstruction::FunctionCall { .. } => panic!("`count` still calls itself"),
+                    IrInstruction::Jump { target, args } if *target == header => {
+                        assert_eq!(args.len(), 2);
+                        jumps_back += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(jumps_back, 2, "expected a jump from the entry block and one from the tail call");
+    }
+
+    #[test]
+    fn tailrec_functions_only_call_themselves_in_tail_position() {
+        let compiler = compile("
+            @tailrec
+            fun sum(k: Int32): Int32 {
+                if k < 1 {
+                    return 0;
+                }
+                let s = sum(k - 1);
+                return s + k;
+            }
+
+            @tailrec
+            fun identity(k: Int32): Int32 {
+                return k;
+            }
+
+            @tailrec
+            fun countdown(k: Int32): Int32 {
+                if k < 1 {
+                    return 0;
+                }
+                return countdown(k - 1);
+            }
+        ");
+        let messages: Vec<&str> = compiler.diagnostics.messages.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "function `sum` is marked `@tailrec` but it calls itself outside of tail position",
+            "function `identity` is marked `@tailrec` but it never calls itself",
+        ]);
+    }
+}
@@ -1,11 +1,61 @@
-use crate::ast::{AstFunction, Expression, Node, Program, Statement, StatementIndex, Type, TypedName, TypeIndex};
+use std::collections::{HashMap, HashSet};
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+use crate::ast::{self, Annotation, AstFunction, Expression, ExpressionIndex, FunctionKind, Node, NodeIndex, Program, Statement, StatementIndex, Type, TypedName, TypeIndex, TypeName, UnOpType};
+use crate::check::captures::find_captures;
+use crate::diagnostic::{codes, DiagnosticManager, FileId};
 use crate::ir::*;
+use crate::ir::consteval::ConstEvaluator;
+use crate::lang::{Path, Span};
+use crate::target::TargetInfo;
+
+/// Functions annotated with this can be called from C, they keep their name and use the C calling convention.
+pub static EXPORT: &str = "export";
+
+/// Current SSA value of every local in scope, innermost scope last.
+type Scopes = Vec<HashMap<String, IrInstructionIndex>>;
+
+/// Where `break` and `continue` go in the innermost loop being built.
+struct LoopContext {
+    /// Locals passed around the loop, these are the arguments of the continue target.
+    carried: Vec<(usize, String)>,
+    continue_target: IrBlockIndex,
+    continued: bool,
+    /// Number of scopes outside of the loop.
+    depth: usize,
+    breaks: Vec<(IrBlockIndex, Scopes)>,
+}
 
 pub struct IrBuilderContext<'ctx> {
     program: &'ctx Program,
     module_arena: ModuleArena,
     void_index: IrTypeIndex,
     unknown_index: IrTypeIndex,
+    bool_index: IrTypeIndex,
+    /// Blocks of the function currently being built.
+    blocks: Vec<IrBlockIndex>,
+    scopes: Scopes,
+    loops: Vec<LoopContext>,
+    /// Every function in the program, used when one is passed around as a value.
+    functions: HashMap<String, &'ctx AstFunction>,
+    /// Structs and enums declared in the program, types naming them are `IrType::Base`.
+    type_names: HashSet<String>,
+    /// Functions made from lambdas and closure thunks, they go after the function being built.
+    lifted: Vec<IrNode>,
+    lambda_count: usize,
+    thunks: HashSet<String>,
+    /// Number literals are range checked once their types are settled.
+    literals: Vec<(IrInstructionIndex, Span)>,
+    /// Literals written right after a `-`, these can go one past the largest positive value.
+    negated_literals: HashSet<IrInstructionIndex>,
+    /// Types of module level `let`s, refs to them are typed with these.
+    globals: HashMap<String, IrTypeIndex>,
+    /// Function types of `extern` functions, calls to them are typed with these.
+    extern_types: HashMap<String, IrTypeIndex>,
+    /// Params and `let`s by where they're declared, see `Module::locals`.
+    locals: Vec<(Span, IrInstructionIndex)>,
+    /// The block each `Argument` instruction reads from, so typing one types the block's argument too.
+    argument_blocks: HashMap<IrInstructionIndex, IrBlockIndex>,
+    diagnostics: Vec<Diagnostic<FileId>>,
 }
 
 impl<'ctx> IrBuilderContext<'ctx> {
@@ -14,12 +64,43 @@ impl<'ctx> IrBuilderContext<'ctx> {
 
         let void_index = module_arena.type_arena.insert(IrType::Void);
         let unknown_index = module_arena.type_arena.insert(IrType::Unknown);
+        let bool_index = module_arena.type_arena.insert(IrType::Bool);
+
+        let mut functions = HashMap::new();
+        let mut type_names = HashSet::new();
+        for (_, node) in program.program_arena.node_arena.iter() {
+            match node {
+                Node::Function(func) => {
+                    functions.insert(func.name.clone(), func);
+                }
+                Node::Struct { name, .. } | Node::Enum { name, .. } => {
+                    type_names.insert(name.clone());
+                }
+                _ => {}
+            }
+        }
 
         IrBuilderContext {
             program,
             module_arena,
             void_index,
             unknown_index,
+            bool_index,
+            blocks: vec![],
+            scopes: vec![],
+            loops: vec![],
+            functions,
+            type_names,
+            lifted: vec![],
+            lambda_count: 0,
+            thunks: HashSet::new(),
+            literals: vec![],
+            negated_literals: HashSet::new(),
+            globals: HashMap::new(),
+            extern_types: HashMap::new(),
+            locals: vec![],
+            argument_blocks: HashMap::new(),
+            diagnostics: vec![],
         }
     }
 
@@ -27,6 +108,94 @@ impl<'ctx> IrBuilderContext<'ctx> {
         self.module_arena.block_arena.insert(IrBlock::new())
     }
 
+    /// Create a block that is part of the function currently being built.
+    pub fn new_function_block(&mut self) -> IrBlockIndex {
+        let block = self.new_block();
+        self.blocks.push(block);
+        block
+    }
+
+    /// Create a block that control can reach from another one.
+    /// Blocks only reachable from dead code are left out of the function too.
+    pub fn new_block_from(&mut self, from: IrBlockIndex) -> IrBlockIndex {
+        if self.is_reachable(from) {
+            self.new_function_block()
+        } else {
+            self.new_block()
+        }
+    }
+
+    /// Is this block one of the current function's blocks?
+    /// Code that can't be reached is built into blocks that are not.
+    pub fn is_reachable(&self, block: IrBlockIndex) -> bool {
+        self.blocks.contains(&block)
+    }
+
+    pub fn is_terminated(&self, block: IrBlockIndex) -> bool {
+        self.module_arena.block_arena.get(block).unwrap().instructions.last()
+            .and_then(|ins| self.module_arena.instruction_arena.get(*ins))
+            .map_or(false, |ins| ins.is_terminator())
+    }
+
+    /// Add an argument to a block and get the instruction that reads it.
+    /// Arguments have to be added before anything else is put in the block.
+    pub fn add_argument(&mut self, block: IrBlockIndex, typ: IrTypeIndex) -> IrInstructionIndex {
+        let arguments = &mut self.module_arena.block_arena.get_mut(block).unwrap().arguments;
+        arguments.push(typ);
+        let n = arguments.len() - 1;
+        let argument = self.ins_typed(block, IrInstruction::Argument(n), typ);
+        self.argument_blocks.insert(argument, block);
+        argument
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    pub fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<IrInstructionIndex> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).cloned())
+    }
+
+    pub fn declare(&mut self, name: String, value: IrInstructionIndex) {
+        self.scopes.last_mut().unwrap().insert(name, value);
+    }
+
+    /// Every local in scope, in a stable order.
+    fn carried_locals(&self) -> Vec<(usize, String)> {
+        let mut carried = vec![];
+        for (depth, scope) in self.scopes.iter().enumerate() {
+            let mut names: Vec<String> = scope.keys().cloned().collect();
+            names.sort();
+            carried.extend(names.into_iter().map(|name| (depth, name)));
+        }
+        carried
+    }
+
+    fn carried_values(&self, carried: &[(usize, String)]) -> Vec<IrInstructionIndex> {
+        carried.iter().map(|(depth, name)| self.scopes[*depth][name]).collect()
+    }
+
+    /// Give a block an argument for every carried local and use those from now on.
+    fn bind_carried(&mut self, block: IrBlockIndex, carried: &[(usize, String)]) {
+        for (depth, name) in carried {
+            let typ = self.type_of(self.scopes[*depth][name]);
+            let value = self.add_argument(block, typ);
+            self.scopes[*depth].insert(name.clone(), value);
+        }
+    }
+
+    /// Give an existing local a new value, locals that don't exist yet are declared.
+    pub fn assign(&mut self, name: String, value: IrInstructionIndex) {
+        match self.scopes.iter_mut().rev().find(|scope| scope.contains_key(&name)) {
+            Some(scope) => { scope.insert(name, value); }
+            None => self.declare(name, value),
+        }
+    }
+
     /// Insert an instruction into the instruction arena and add its index to the provided block.
     /// This ensures that all IrInstructions are allocated into some IrBlock.
     /// The returned index can be used in other instructions.
@@ -35,38 +204,400 @@ impl<'ctx> IrBuilderContext<'ctx> {
         self.module_arena.block_arena.get_mut(block).unwrap().instructions.push(index);
         index
     }
+
+    /// Insert an instruction whose result type is known.
+    pub fn ins_typed(&mut self, block: IrBlockIndex, ins: IrInstruction, typ: IrTypeIndex) -> IrInstructionIndex {
+        let index = self.ins(block, ins);
+        self.module_arena.instruction_types.insert(index, typ);
+        index
+    }
+
+    pub fn type_of(&self, ins: IrInstructionIndex) -> IrTypeIndex {
+        self.module_arena.instruction_types.get(&ins).cloned().unwrap_or(self.unknown_index)
+    }
+
+    /// Give an instruction whose type isn't known yet the type it's used as.
+    /// Operators pass it on to their operands, so `let x: Int8 = -1` types the literal too.
+    pub fn infer(&mut self, ins: IrInstructionIndex, typ: IrTypeIndex) {
+        if typ == self.unknown_index || typ == self.void_index || self.type_of(ins) != self.unknown_index {
+            return;
+        }
+        let operands = match &self.module_arena.instruction_arena[ins] {
+            IrInstruction::NatLiteral(_) | IrInstruction::FloatLiteral(_) => {
+                let target = &self.module_arena.type_arena[typ];
+                let fits = target.is_float() || (target.is_integer() && matches!(self.module_arena.instruction_arena[ins], IrInstruction::NatLiteral(_)));
+                if !fits {
+                    return;
+                }
+                vec![]
+            }
+            IrInstruction::Argument(n) => {
+                // params have the types they're declared with, other arguments take the type they're used as
+                match self.argument_blocks.get(&ins) {
+                    Some(block) if self.blocks.first() != Some(block) => {
+                        self.module_arena.block_arena.get_mut(*block).unwrap().arguments[*n] = typ;
+                    }
+                    _ => return,
+                }
+                vec![]
+            }
+            IrInstruction::UnOp(UnOpType::Not, _) => vec![],
+            IrInstruction::UnOp(_, operand) => vec![*operand],
+            // an unsuffixed shift amount is the same type as the value, one with a type of its own keeps it
+            IrInstruction::BinOp(lhs, op, rhs) if !op.is_comparison() && !matches!(op, BinOpType::And | BinOpType::Or) => vec![*lhs, *rhs],
+            _ => vec![],
+        };
+        self.module_arena.instruction_types.insert(ins, typ);
+        for operand in operands {
+            self.infer(operand, typ);
+        }
+    }
+
+    /// Settle the types of the current function's block arguments once all of it is built.
+    /// Values passed to an argument without a type give it theirs, untyped values take the argument's,
+    /// and unsuffixed literals nothing gave a type take their defaults and are passed on too.
+    fn settle_arguments(&mut self) {
+        loop {
+            let mut changed = false;
+            for block in self.blocks.clone() {
+                let Some(last) = self.module_arena.block_arena[block].instructions.last() else {
+                    continue;
+                };
+                let IrInstruction::Jump { target, args } = &self.module_arena.instruction_arena[*last] else {
+                    continue;
+                };
+                let (target, args) = (*target, args.clone());
+                for (n, arg) in args.into_iter().enumerate() {
+                    // arguments are the first instructions of their block
+                    let argument = self.module_arena.block_arena[target].instructions[n];
+                    let (arg_type, argument_type) = (self.type_of(arg), self.type_of(argument));
+                    if argument_type == self.unknown_index && arg_type != self.unknown_index {
+                        self.infer(argument, arg_type);
+                        changed = true;
+                    } else if arg_type == self.unknown_index && argument_type != self.unknown_index {
+                        self.infer(arg, argument_type);
+                        changed |= self.type_of(arg) != self.unknown_index;
+                    }
+                }
+            }
+            if !changed && !self.default_literals() {
+                return;
+            }
+        }
+    }
+
+    /// Give the current function's unsuffixed literals that are still untyped their default type,
+    /// false if there weren't any.
+    fn default_literals(&mut self) -> bool {
+        let untyped: Vec<IrInstructionIndex> = self.blocks.iter()
+            .flat_map(|block| self.module_arena.block_arena[*block].instructions.iter().cloned())
+            .filter(|ins| self.type_of(*ins) == self.unknown_index)
+            .filter(|ins| matches!(self.module_arena.instruction_arena[*ins], IrInstruction::NatLiteral(_) | IrInstruction::FloatLiteral(_)))
+            .collect();
+        for ins in &untyped {
+            let default = match self.module_arena.instruction_arena[*ins] {
+                IrInstruction::FloatLiteral(_) => IrType::Float(FloatTy::F64),
+                _ => IrType::Int(IntTy::I64),
+            };
+            let typ = self.module_arena.type_arena.insert(default);
+            self.module_arena.instruction_types.insert(*ins, typ);
+        }
+        !untyped.is_empty()
+    }
+
+    /// If this instruction produces a raw pointer, get the type it points to.
+    pub fn raw_pointee(&self, ins: IrInstructionIndex) -> Option<IrTypeIndex> {
+        match self.module_arena.type_arena.get(self.type_of(ins)) {
+            Some(IrType::Reference(inner, PointerKind::Raw, _)) => Some(*inner),
+            _ => None,
+        }
+    }
 }
 
-pub struct IrBuilder {}
+pub struct IrBuilder {
+    target: TargetInfo,
+}
 
 impl IrBuilder {
-    pub fn new() -> IrBuilder {
-        IrBuilder {}
+    pub fn new(target: TargetInfo) -> IrBuilder {
+        IrBuilder {
+            target,
+        }
     }
 
-    pub fn convert(&self, program: Program) -> Module {
+    pub fn convert(&self, mut program: Program, diagnostics: &mut DiagnosticManager) -> Module {
+        // the return type initializers are built with, they return whatever the value is
+        let void = program.program_arena.type_arena.insert(Type::Base(TypeName {
+            path: Path::new(),
+            name: "Void".to_string(),
+            arguments: vec![],
+        }));
+        let program = program;
         let mut ctx = IrBuilderContext::new(&program);
-        for (_index, node) in program.program_arena.node_arena.iter() {
+
+        // `let`s in a struct are its fields, the others are globals
+        let fields: HashSet<NodeIndex> = program.program_arena.node_arena.iter()
+            .flat_map(|(_, node)| match node {
+                Node::Struct { children, .. } => children.clone(),
+                _ => vec![],
+            })
+            .collect();
+        // globals with a declared type and extern functions can be used before they're declared
+        for (index, node) in program.program_arena.node_arena.iter() {
+            match node {
+                Node::Variable { name: TypedName { name, typ: Some(typ), .. }, .. } if !fields.contains(&index) => {
+                    let typ = self.build_type(&mut ctx, typ);
+                    ctx.globals.insert(name.clone(), typ);
+                }
+                Node::FunctionPrototype { name, params, return_type, abi: Some(_), .. } => {
+                    let typ = self.function_type(&mut ctx, params, *return_type);
+                    ctx.extern_types.insert(name.clone(), typ);
+                }
+                _ => {}
+            }
+        }
+
+        let mut initializers = HashMap::new();
+        let mut constants = vec![];
+        let mut nodes = HashMap::new();
+        let mut c_signatures = vec![];
+        for (index, node) in program.program_arena.node_arena.iter() {
             use Node::*;
             match node {
                 TypeAlias { .. } => {}
-                Variable { .. } => {}
+                Variable { .. } if fields.contains(&index) => {}
+                Variable { access, name, value, annotations } => {
+                    let span = program.node_span(index);
+                    let Some(value) = value else {
+                        ctx.diagnostics.push(Diagnostic::error()
+                            .with_code(codes::GLOBAL_WITHOUT_VALUE)
+                            .with_message(format!("global `{}` has no value", name.name))
+                            .with_labels(vec![Label::primary(program.file_id, span).with_message("module level `let`s need a value")]));
+                        continue;
+                    };
+                    let (init, typ, result) = self.build_initializer(&mut ctx, &name.name, name, *value, void);
+                    ctx.globals.insert(name.name.clone(), typ);
+                    initializers.insert(name.name.clone(), init);
+                    let node = ctx.module_arena.node_arena.insert(IrNode::Global {
+                        access: Access::from(*access),
+                        name: name.name.clone(),
+                        typ,
+                        value: None,
+                        span,
+                        annotations: annotations.clone(),
+                    });
+                    constants.push((name.name.clone(), node, None, result, program.expression_span(*value)));
+                }
                 Function(ast_function) => {
-                    let node = self.build_function(&mut ctx, ast_function);
-                    ctx.module_arena.node_arena.insert(node);
+                    let node = self.build_function(&mut ctx, ast_function, program.node_span(index), None);
+                    let node = ctx.module_arena.node_arena.insert(node);
+                    if Annotation::find(&ast_function.annotations, EXPORT).is_some() {
+                        c_signatures.push((node, &ast_function.params));
+                    }
+                    nodes.insert(index, node);
+                    for lifted in std::mem::take(&mut ctx.lifted) {
+                        ctx.module_arena.node_arena.insert(lifted);
+                    }
+                }
+                FunctionPrototype { name, params, return_type, annotations, abi: Some(abi), .. } => {
+                    let span = program.node_span(index);
+                    if abi != "C" {
+                        ctx.diagnostics.push(Diagnostic::error()
+                            .with_code(codes::UNSUPPORTED_ABI)
+                            .with_message(format!("unsupported calling convention `{}`", abi))
+                            .with_labels(vec![Label::primary(program.file_id, span).with_message("only `extern \"C\"` is supported")]));
+                        continue;
+                    }
+                    let func = IrFunction {
+                        access: Access::Internal,
+                        name: name.clone(),
+                        params: params.iter().map(|param| self.build_typed_name(&mut ctx, param)).collect(),
+                        type_params: vec![],
+                        return_type: self.build_type(&mut ctx, return_type),
+                        blocks: vec![],
+                        annotations: annotations.clone(),
+                        span,
+                    };
+                    c_signatures.push((ctx.module_arena.node_arena.insert(IrNode::ExternFunction(func)), params));
                 }
+                // prototypes without a calling convention have no body anywhere, there's nothing to lower
                 FunctionPrototype { .. } => {}
-                Struct { .. } => {}
-                Enum { .. } => {}
+                Struct { access, name: struct_name, children, annotations, .. } => {
+                    let mut ir_fields = vec![];
+                    let mut defaults = vec![];
+                    for child in children {
+                        if let Some(Variable { name, value, .. }) = program.program_arena.node_arena.get(*child) {
+                            let typ = match value {
+                                Some(value) => {
+                                    let key = format!("{}.{}", struct_name, name.name);
+                                    let (init, typ, result) = self.build_initializer(&mut ctx, &key, name, *value, void);
+                                    initializers.insert(key.clone(), init);
+                                    defaults.push((key, ir_fields.len(), result, program.expression_span(*value)));
+                                    typ
+                                }
+                                None => name.typ.map_or(ctx.unknown_index, |typ| self.build_type(&mut ctx, &typ)),
+                            };
+                            ir_fields.push(IrField {
+                                name: name.name.clone(),
+                                typ,
+                                default: None,
+                            });
+                        }
+                    }
+                    let node = ctx.module_arena.node_arena.insert(IrNode::Struct {
+                        access: Access::from(*access),
+                        name: struct_name.clone(),
+                        fields: ir_fields,
+                        nodes: children.iter().filter_map(|child| nodes.get(child).cloned()).collect(),
+                        annotations: annotations.clone(),
+                    });
+                    for (key, field, result, span) in defaults {
+                        constants.push((key, node, Some(field), result, span));
+                    }
+                }
+                Enum { access, name, variants, annotations, .. } => {
+                    let variants = variants.iter().map(|variant| IrVariant {
+                        name: variant.name.clone(),
+                        fields: variant.params.iter().map(|param| IrTypedName {
+                            name: param.name.clone(),
+                            typ: param.typ.map_or(ctx.unknown_index, |typ| self.build_type(&mut ctx, &typ)),
+                        }).collect(),
+                    }).collect();
+                    ctx.module_arena.node_arena.insert(IrNode::Enum {
+                        access: Access::from(*access),
+                        name: name.clone(),
+                        variants,
+                        annotations: annotations.clone(),
+                    });
+                }
                 Interface { .. } => {}
                 Error => {}
             }
         }
+        // structs can be declared after the functions that pass them
+        for (node, params) in c_signatures {
+            self.check_c_signature(&mut ctx, node, params);
+        }
+        self.check_literals(&mut ctx);
+        self.evaluate_constants(&mut ctx, &initializers, constants);
+        for diagnostic in ctx.diagnostics.drain(..) {
+            diagnostics.add_diagnostic(diagnostic);
+        }
         Module {
             path: program.path.clone(),
             name: program.file_name.clone(),
+            file_id: program.file_id,
             imports: program.imports.clone(),
             module_arena: ctx.module_arena,
+            locals: ctx.locals,
+        }
+    }
+
+    /// Build the value of a global or field default as a function taking nothing,
+    /// it's run by the const evaluator and never lowered.
+    /// Returns the function, the type of the value and the instruction computing it.
+    fn build_initializer(&self, ctx: &mut IrBuilderContext, key: &str, name: &TypedName, value: ExpressionIndex, void: TypeIndex) -> (IrFunction, IrTypeIndex, IrInstructionIndex) {
+        let func = AstFunction {
+            access: ast::Access::Internal,
+            kind: FunctionKind::Function,
+            name: format!("{}$init", key),
+            type_params: vec![],
+            params: vec![],
+            return_type: void,
+            statements: vec![],
+            annotations: vec![],
+        };
+        ctx.blocks.clear();
+        ctx.scopes.clear();
+        ctx.push_scope();
+        let mut current_block = ctx.new_function_block();
+        let stmt = Statement::Return { value };
+        let result = self.build_expression(ctx, &func, &stmt, &value, &mut current_block);
+        ctx.ins(current_block, IrInstruction::Return { value: Some(result) });
+        for lifted in std::mem::take(&mut ctx.lifted) {
+            ctx.module_arena.node_arena.insert(lifted);
+        }
+
+        let typ = match name.typ {
+            Some(typ) => self.build_type(ctx, &typ),
+            None => ctx.type_of(result),
+        };
+        let typ = match typ == ctx.unknown_index {
+            // unsuffixed literals default the same way they do everywhere else
+            true if Self::has_float_literal(ctx, result) => ctx.module_arena.type_arena.insert(IrType::Float(FloatTy::F64)),
+            true => ctx.module_arena.type_arena.insert(IrType::Int(IntTy::I64)),
+            false => typ,
+        };
+        ctx.infer(result, typ);
+
+        let init = IrFunction {
+            access: Access::Generated,
+            name: func.name,
+            params: vec![],
+            type_params: vec![],
+            return_type: typ,
+            blocks: std::mem::take(&mut ctx.blocks),
+            annotations: vec![],
+            span: ctx.program.expression_span(value),
+        };
+        (init, typ, result)
+    }
+
+    /// Work out the values of globals and field defaults, then use the values of globals in place of refs to them.
+    fn evaluate_constants(&self, ctx: &mut IrBuilderContext, initializers: &HashMap<String, IrFunction>,
+                          constants: Vec<(String, IrNodeIndex, Option<usize>, IrInstructionIndex, Span)>) {
+        let mut evaluator = ConstEvaluator::new(&ctx.module_arena, &self.target,
+            initializers.iter().map(|(key, init)| (key.clone(), init)).collect());
+        let mut values = vec![];
+        for (key, node, field, result, span) in constants {
+            let typ = &ctx.module_arena.type_arena[ctx.module_arena.instruction_types[&result]];
+            let declared = &ctx.module_arena.type_arena[initializers[&key].return_type];
+            if typ.name() != declared.name() {
+                ctx.diagnostics.push(Diagnostic::error()
+                    .with_code(codes::GLOBAL_TYPE_MISMATCH)
+                    .with_message(format!("`{}` is declared as `{}` but its value is a `{}`", key, declared.name(), typ.name()))
+                    .with_labels(vec![Label::primary(ctx.program.file_id, span).with_message(format!("expected `{}`", declared.name()))]));
+                continue;
+            }
+            match evaluator.value(&key) {
+                Ok(value) => values.push((key, node, field, value)),
+                Err(reason) => ctx.diagnostics.push(Diagnostic::error()
+                    .with_code(codes::NOT_CONSTANT)
+                    .with_message(format!("the value of `{}` can't be worked out at compile time", key))
+                    .with_labels(vec![Label::primary(ctx.program.file_id, span).with_message(reason)])),
+            }
+        }
+        drop(evaluator);
+
+        let mut globals = HashMap::new();
+        for (key, node, field, value) in values {
+            match (&mut ctx.module_arena.node_arena[node], field) {
+                (IrNode::Struct { fields, .. }, Some(field)) => fields[field].default = Some(value),
+                (IrNode::Global { value: global, .. }, None) => {
+                    *global = Some(value);
+                    globals.insert(key, value);
+                }
+                _ => {}
+            }
+        }
+        for (index, ins) in ctx.module_arena.instruction_arena.iter_mut() {
+            let value = match ins {
+                IrInstruction::Ref(name) => match globals.get(name) {
+                    Some(value) => *value,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            *ins = match value {
+                // literals hold the bits of negative numbers
+                ConstValue::Int(value) => match &ctx.module_arena.type_arena[ctx.module_arena.instruction_types[&index]] {
+                    IrType::Int(int) => IrInstruction::NatLiteral(value as u128 & (u128::MAX >> (128 - int.bits(&self.target)))),
+                    _ => IrInstruction::NatLiteral(value as u128),
+                },
+                ConstValue::UInt(value) => IrInstruction::NatLiteral(value),
+                ConstValue::Float(value) => IrInstruction::FloatLiteral(value),
+                ConstValue::Bool(value) => IrInstruction::BoolLiteral(value),
+            };
         }
     }
 
@@ -81,8 +612,12 @@ impl IrBuilder {
                         ctx.module_arena.type_arena.insert(IrType::UInt(int_type))
                     } else if let Some(float_type) = FloatTy::from(&name.name) {
                         ctx.module_arena.type_arena.insert(IrType::Float(float_type))
+                    } else if "Bool" == name.name {
+                        ctx.bool_index
                     } else if "Void" == name.name {
                         ctx.void_index
+                    } else if ctx.type_names.contains(&name.name) {
+                        ctx.module_arena.type_arena.insert(IrType::Base(name.name.clone()))
                     } else {
                         ctx.unknown_index
                     }
@@ -93,7 +628,10 @@ impl IrBuilder {
                     let inner_type = self.build_type(ctx, base_type);
                     ctx.module_arena.type_arena.insert(IrType::Reference(inner_type, *ptr_kind, *refcap))
                 },
-                Optional(_) => ctx.unknown_index,
+                Optional(inner) => {
+                    let inner_type = self.build_type(ctx, inner);
+                    ctx.module_arena.type_arena.insert(IrType::Optional(inner_type))
+                },
                 Function(args, return_type) => {
                     let mut arg_types = Vec::with_capacity(args.len());
                     for arg in args {
@@ -115,41 +653,142 @@ impl IrBuilder {
         }
     }
 
-    fn build_function(&self, ctx: &mut IrBuilderContext, func: &AstFunction) -> IrNode {
-        let mut blocks = vec![];
-        let mut current_block = ctx.new_block();
-        blocks.push(current_block.clone());
+    /// Make sure every param and the result of a function that crosses into C has a type C understands.
+    fn check_c_signature(&self, ctx: &mut IrBuilderContext, node: IrNodeIndex, ast_params: &[TypedName]) {
+        let (IrNode::Function(func) | IrNode::ExternFunction(func)) = &ctx.module_arena.node_arena[node] else {
+            return;
+        };
+        let mut problems = vec![];
+        for (param, ast_param) in func.params.iter().zip(ast_params) {
+            if !self.target.is_c_compatible(&ctx.module_arena, param.typ) {
+                problems.push((format!("parameter `{}` of `{}` can't be passed to or from C", param.name, func.name), ast_param.span.clone()));
+            }
+        }
+        let compatible = self.target.is_c_compatible(&ctx.module_arena, func.return_type);
+        if !compatible && !matches!(ctx.module_arena.type_arena[func.return_type], IrType::Void) {
+            problems.push((format!("the result of `{}` can't be passed to or from C", func.name), func.span.clone()));
+        }
+        for (message, span) in problems {
+            ctx.diagnostics.push(Diagnostic::error()
+                .with_code(codes::NOT_C_COMPATIBLE)
+                .with_message(message)
+                .with_labels(vec![Label::primary(ctx.program.file_id, span).with_message("C doesn't have this type")])
+                .with_notes(vec!["numbers, `Bool`, pointers and structs of those can cross into C, pass anything else behind a raw pointer".to_string()]));
+        }
+    }
+
+    /// The environment a closure's function takes as its first param.
+    fn env_type(&self, ctx: &mut IrBuilderContext, captures: &[(String, IrTypeIndex)]) -> IrTypeIndex {
+        let fields = captures.iter().map(|(name, typ)| IrTypedName {
+            name: name.clone(),
+            typ: *typ,
+        }).collect();
+        let row = ctx.module_arena.type_arena.insert(IrType::Row(fields));
+        ctx.module_arena.type_arena.insert(IrType::Reference(row, PointerKind::Raw, ReferenceCapability::Val))
+    }
+
+    /// Build a function, closures get an environment param holding their captures.
+    fn build_function(&self, ctx: &mut IrBuilderContext, func: &AstFunction, span: Span, captures: Option<&[(String, IrTypeIndex)]>) -> IrNode {
+        ctx.blocks.clear();
+        ctx.scopes.clear();
+        ctx.push_scope();
+        let mut current_block = ctx.new_function_block();
 
-        let ir_params: Vec<IrTypedName> = func.params.iter().map(|param| {
+        let mut ir_params = vec![];
+        if let Some(captures) = captures {
+            ir_params.push(IrTypedName {
+                name: "env".to_string(),
+                typ: self.env_type(ctx, captures),
+            });
+        }
+        for param in &func.params {
             let param_ir_type = param.typ.map_or(ctx.unknown_index, |ty| self.build_type(ctx, &ty));
-            IrTypedName {
+            ir_params.push(IrTypedName {
                 name: param.name.clone(),
                 typ: param_ir_type,
+            });
+        }
+
+        // params are passed in as the arguments of the entry block
+        let mut arguments = vec![];
+        for param in &ir_params {
+            arguments.push(ctx.add_argument(current_block, param.typ));
+        }
+        let skipped = match captures {
+            Some(captures) => {
+                for (index, (name, typ)) in captures.iter().enumerate() {
+                    let value = ctx.ins_typed(current_block, IrInstruction::CaptureLoad {
+                        env: arguments[0],
+                        index,
+                    }, *typ);
+                    ctx.declare(name.clone(), value);
+                }
+                1
             }
-        }).collect();
+            None => 0,
+        };
+        for ((param, value), ast_param) in ir_params.iter().zip(arguments).skip(skipped).zip(&func.params) {
+            ctx.declare(param.name.clone(), value);
+            ctx.locals.push((ast_param.span.clone(), value));
+        }
 
         for s_index in &func.statements {
             self.build_statement(ctx, func, s_index, &mut current_block);
         }
+
+        let return_type = self.build_type(ctx, &func.return_type);
+        if ctx.is_reachable(current_block) && !ctx.is_terminated(current_block) {
+            if return_type == ctx.void_index {
+                ctx.ins(current_block, IrInstruction::Return { value: None });
+            } else {
+                ctx.diagnostics.push(Diagnostic::error()
+                    .with_code(codes::MISSING_RETURN)
+                    .with_message(format!("function `{}` can reach its end without returning a value", func.name))
+                    .with_labels(vec![Label::primary(ctx.program.file_id, span.clone()).with_message("missing `return` at the end of this function")]));
+                // keep the cfg well formed, the error stops compilation before this is lowered
+                ctx.ins(current_block, IrInstruction::Return { value: None });
+            }
+        }
+        ctx.settle_arguments();
+
         IrNode::Function(IrFunction {
-            access: Access::from(func.access),
+            access: match captures {
+                Some(_) => Access::Generated,
+                None => Access::from(func.access),
+            },
             name: func.name.clone(),
             params: ir_params,
             type_params: vec![],
-            return_type: self.build_type(ctx, &func.return_type),
-            blocks,
+            return_type,
+            blocks: std::mem::take(&mut ctx.blocks),
+            annotations: func.annotations.clone(),
+            span,
         })
     }
 
     fn build_statement(&self, ctx: &mut IrBuilderContext, func: &AstFunction, s_index: &StatementIndex, current_block: &mut IrBlockIndex) {
         use Statement::*;
         let stmt = ctx.program.statement(s_index.clone());
+
+        // anything after a return or branch can't be reached
+        if ctx.is_terminated(*current_block) {
+            *current_block = ctx.new_block();
+        }
+
         match stmt {
+            If { condition, body, else_if: None } if self.is_always_true(ctx, condition) => {
+                // `else` blocks are parsed as `else if true`
+                ctx.push_scope();
+                for stmt in body {
+                    self.build_statement(ctx, func, stmt, current_block);
+                }
+                ctx.pop_scope();
+            }
             If { condition, body, else_if } => {
                 let cond_ins = self.build_expression(ctx, func, stmt, condition, current_block);
                 // make the blocks we can branch to
-                let true_branch = ctx.new_block();
-                let false_branch = ctx.new_block();
+                let true_branch = ctx.new_block_from(*current_block);
+                let false_branch = ctx.new_block_from(*current_block);
 
                 // add the branch ins to the current block
                 let branch = IrInstruction::Branch {
@@ -159,17 +798,25 @@ impl IrBuilder {
                 };
                 ctx.ins(*current_block, branch);
 
+                let outer_scopes = ctx.scopes.clone();
+
                 // build the true block
                 *current_block = true_branch;
+                ctx.push_scope();
                 for stmt in body {
                     self.build_statement(ctx, func, stmt, current_block);
                 }
+                ctx.pop_scope();
+                let true_exit = (*current_block, std::mem::replace(&mut ctx.scopes, outer_scopes.clone()));
 
                 // build the false block
                 *current_block = false_branch;
                 if let Some(stmt) = else_if {
                     self.build_statement(ctx, func, stmt, current_block);
                 }
+                let false_exit = (*current_block, std::mem::replace(&mut ctx.scopes, outer_scopes));
+
+                self.build_merge(ctx, vec![true_exit, false_exit], current_block);
             }
             Call { function, args } => {
                 let fun_ins = self.build_expression(ctx, func, stmt, function, current_block);
@@ -183,31 +830,385 @@ impl IrBuilder {
                     args: arg_insx,
                 });
             }
-            Let { .. } => {}
-            Assign { .. } => {}
+            Let { name, value } => {
+                let value_ins = self.build_expression(ctx, func, stmt, value, current_block);
+                if let Some(typ) = name.typ {
+                    let typ = self.build_type(ctx, &typ);
+                    ctx.infer(value_ins, typ);
+                }
+                ctx.declare(name.name.clone(), value_ins);
+                ctx.locals.push((name.span.clone(), value_ins));
+            }
+            Assign { name, value } => {
+                let value_ins = self.build_expression(ctx, func, stmt, value, current_block);
+                ctx.assign(name.clone(), value_ins);
+            }
+            Store { pointer, value } => {
+                let pointer_ins = self.build_expression(ctx, func, stmt, pointer, current_block);
+                let value_ins = self.build_expression(ctx, func, stmt, value, current_block);
+                ctx.ins(*current_block, IrInstruction::Store {
+                    pointer: pointer_ins,
+                    value: value_ins,
+                });
+            }
             Return { value } => {
                 let value_ins = self.build_expression(ctx, func, stmt, value, current_block);
+                let return_type = self.build_type(ctx, &func.return_type);
+                ctx.infer(value_ins, return_type);
                 ctx.ins(*current_block, IrInstruction::Return {
-                    value: value_ins
+                    value: Some(value_ins)
                 });
             }
-            Unsafe { .. } => {}
+            Unsafe { body } => {
+                // unsafety is checked before lowering, the body is emitted in place
+                ctx.push_scope();
+                for stmt in body {
+                    self.build_statement(ctx, func, stmt, current_block);
+                }
+                ctx.pop_scope();
+            }
+            While { condition, body } => {
+                // every local goes around the loop as an argument of the header
+                let carried = ctx.carried_locals();
+                let header = ctx.new_block_from(*current_block);
+                let args = ctx.carried_values(&carried);
+                ctx.ins(*current_block, IrInstruction::Jump { target: header, args });
+                ctx.bind_carried(header, &carried);
+                *current_block = header;
+
+                let cond_ins = self.build_expression(ctx, func, stmt, condition, current_block);
+                let body_block = ctx.new_block_from(header);
+                let exit_block = ctx.new_block_from(header);
+                ctx.ins(*current_block, IrInstruction::Branch {
+                    condition: cond_ins,
+                    true_branch: body_block,
+                    false_branch: exit_block,
+                });
+                let exit_scopes = ctx.scopes.clone();
+
+                ctx.loops.push(LoopContext {
+                    carried,
+                    continue_target: header,
+                    continued: false,
+                    depth: ctx.scopes.len(),
+                    breaks: vec![],
+                });
+                *current_block = body_block;
+                self.build_loop_body(ctx, func, body, current_block);
+                let loop_context = ctx.loops.pop().unwrap();
+
+                ctx.scopes = exit_scopes.clone();
+                let mut exits = vec![(exit_block, exit_scopes)];
+                exits.extend(loop_context.breaks);
+                self.build_merge(ctx, exits, current_block);
+            }
+            For { variable, start, end, body } => {
+                let start_ins = self.build_expression(ctx, func, stmt, start, current_block);
+                let end_ins = self.build_expression(ctx, func, stmt, end, current_block);
+                // the counter is compared with the end, so an unsuffixed bound takes the other one's type
+                ctx.infer(start_ins, ctx.type_of(end_ins));
+                ctx.infer(end_ins, ctx.type_of(start_ins));
+                let depth = ctx.scopes.len();
+                ctx.push_scope();
+                ctx.declare(variable.clone(), start_ins);
+
+                let carried = ctx.carried_locals();
+                let header = ctx.new_block_from(*current_block);
+                let args = ctx.carried_values(&carried);
+                ctx.ins(*current_block, IrInstruction::Jump { target: header, args });
+                ctx.bind_carried(header, &carried);
+                *current_block = header;
+
+                let counter = ctx.lookup(variable).unwrap();
+                let bool_index = ctx.bool_index;
+                let cond_ins = ctx.ins_typed(header, IrInstruction::BinOp(counter, BinOpType::LessThan, end_ins), bool_index);
+                let body_block = ctx.new_block_from(header);
+                let exit_block = ctx.new_block_from(header);
+                ctx.ins(header, IrInstruction::Branch {
+                    condition: cond_ins,
+                    true_branch: body_block,
+                    false_branch: exit_block,
+                });
+                // the counter is out of scope after the loop
+                let exit_scopes = ctx.scopes[..depth].to_vec();
+
+                // the latch steps the counter, it only exists if something jumps to it
+                let latch = ctx.new_block();
+                ctx.loops.push(LoopContext {
+                    carried,
+                    continue_target: latch,
+                    continued: false,
+                    depth,
+                    breaks: vec![],
+                });
+                *current_block = body_block;
+                self.build_loop_body(ctx, func, body, current_block);
+                let loop_context = ctx.loops.pop().unwrap();
+
+                if loop_context.continued && ctx.is_reachable(header) {
+                    ctx.blocks.push(latch);
+                    ctx.bind_carried(latch, &loop_context.carried);
+                    let counter = ctx.lookup(variable).unwrap();
+                    let counter_type = ctx.type_of(counter);
+                    let one = ctx.ins_typed(latch, IrInstruction::NatLiteral(1), counter_type);
+                    let next = ctx.ins_typed(latch, IrInstruction::BinOp(counter, BinOpType::Plus, one), counter_type);
+                    ctx.assign(variable.clone(), next);
+                    let args = ctx.carried_values(&loop_context.carried);
+                    ctx.ins(latch, IrInstruction::Jump { target: header, args });
+                }
+
+                ctx.scopes = exit_scopes.clone();
+                let mut exits = vec![(exit_block, exit_scopes)];
+                exits.extend(loop_context.breaks);
+                self.build_merge(ctx, exits, current_block);
+            }
+            Break => {
+                match ctx.loops.last() {
+                    Some(loop_context) => {
+                        if ctx.is_reachable(*current_block) {
+                            let scopes = ctx.scopes[..loop_context.depth].to_vec();
+                            ctx.loops.last_mut().unwrap().breaks.push((*current_block, scopes));
+                        }
+                        // the jump out is added once the loop's exit is built
+                        *current_block = ctx.new_block();
+                    }
+                    None => self.report_outside_loop(ctx, "break", s_index),
+                }
+            }
+            Continue => {
+                if ctx.loops.is_empty() {
+                    self.report_outside_loop(ctx, "continue", s_index);
+                } else {
+                    self.build_continue(ctx, current_block);
+                }
+            }
+            Error => unreachable!("modules with syntax errors aren't lowered"),
+        }
+    }
+
+    fn build_loop_body(&self, ctx: &mut IrBuilderContext, func: &AstFunction, body: &Vec<StatementIndex>, current_block: &mut IrBlockIndex) {
+        ctx.push_scope();
+        for stmt in body {
+            self.build_statement(ctx, func, stmt, current_block);
+        }
+        ctx.pop_scope();
+        // falling off the end of the body goes around again
+        if ctx.is_reachable(*current_block) && !ctx.is_terminated(*current_block) {
+            self.build_continue(ctx, current_block);
+        }
+    }
+
+    /// Jump back to the top of the innermost loop.
+    fn build_continue(&self, ctx: &mut IrBuilderContext, current_block: &mut IrBlockIndex) {
+        let loop_context = ctx.loops.last_mut().unwrap();
+        loop_context.continued = true;
+        let target = loop_context.continue_target;
+        let carried = loop_context.carried.clone();
+        let args = ctx.carried_values(&carried);
+        ctx.ins(*current_block, IrInstruction::Jump { target, args });
+    }
+
+    fn report_outside_loop(&self, ctx: &mut IrBuilderContext, keyword: &str, s_index: &StatementIndex) {
+        let span = ctx.program.statement_span(*s_index);
+        ctx.diagnostics.push(Diagnostic::error()
+            .with_code(codes::OUTSIDE_LOOP)
+            .with_message(format!("`{}` outside of a loop", keyword))
+            .with_labels(vec![Label::primary(ctx.program.file_id, span).with_message(format!("`{}` can only be used inside `while` or `for`", keyword))]));
+    }
+
+    fn is_always_true(&self, ctx: &IrBuilderContext, condition: &ExpressionIndex) -> bool {
+        match ctx.program.expression(*condition) {
+            Expression::BoolLiteral(true) => true,
+            _ => false,
+        }
+    }
+
+    /// Join the exits of some branches into a new block that becomes the current block.
+    /// Locals that were given different values along the way are passed in as block arguments.
+    fn build_merge(&self, ctx: &mut IrBuilderContext, exits: Vec<(IrBlockIndex, Scopes)>, current_block: &mut IrBlockIndex) {
+        // branches that returned don't reach the merge
+        let exits: Vec<(IrBlockIndex, Scopes)> = exits.into_iter()
+            .filter(|(block, _)| ctx.is_reachable(*block) && !ctx.is_terminated(*block))
+            .collect();
+        if exits.is_empty() {
+            // nothing after this is reachable, so it goes in a block that isn't part of the function
+            *current_block = ctx.new_block();
+            return;
+        }
+
+        let merge_block = ctx.new_function_block();
+        *current_block = merge_block;
+
+        let mut merged = vec![];
+        for depth in 0..ctx.scopes.len() {
+            let mut names: Vec<String> = ctx.scopes[depth].keys().cloned().collect();
+            names.sort();
+            for name in names {
+                let incoming: Vec<IrInstructionIndex> = exits.iter().map(|(_, scopes)| scopes[depth][&name]).collect();
+                if incoming.iter().all(|value| *value == incoming[0]) {
+                    ctx.scopes[depth].insert(name, incoming[0]);
+                } else {
+                    merged.push((depth, name, incoming));
+                }
+            }
+        }
+
+        for (i, (block, _)) in exits.iter().enumerate() {
+            let args = merged.iter().map(|(_, _, incoming)| incoming[i]).collect();
+            ctx.ins(*block, IrInstruction::Jump {
+                target: merge_block,
+                args,
+            });
+        }
+
+        for (depth, name, incoming) in merged {
+            let typ = incoming.iter()
+                .map(|value| ctx.type_of(*value))
+                .find(|typ| *typ != ctx.unknown_index)
+                .unwrap_or(ctx.unknown_index);
+            for value in &incoming {
+                ctx.infer(*value, typ);
+            }
+            let value = ctx.add_argument(merge_block, typ);
+            ctx.scopes[depth].insert(name, value);
         }
     }
 
     fn build_expression(&self, ctx: &mut IrBuilderContext, func: &AstFunction,
                         stmt: &Statement, exp: &ExpressionIndex, current_block: &mut IrBlockIndex) -> IrInstructionIndex {
         use Expression::*;
-        let exp = ctx.program.expression(*exp);
+        let exp_index = *exp;
+        let exp = ctx.program.expression(exp_index);
         // let todo = IrInstruction::Ref("TODO".to_string());
         let ins = match exp {
-            Ref(s) => IrInstruction::Ref(s.clone()),
-            NatLiteral(i) => IrInstruction::NatLiteral(i.clone()),
-            BoolLiteral(b) => IrInstruction::BoolLiteral(b.clone()),
+            Ref(s) => {
+                // locals are already ssa values, anything else is a global
+                if let Some(value) = ctx.lookup(s) {
+                    return value;
+                }
+                // functions used as values become closures with nothing captured
+                if let Some(global) = ctx.functions.get(s).cloned() {
+                    let thunk = self.build_closure_thunk(ctx, global);
+                    let typ = self.function_type(ctx, &global.params, global.return_type);
+                    return ctx.ins_typed(*current_block, IrInstruction::MakeClosure {
+                        function: thunk,
+                        captures: vec![],
+                    }, typ);
+                }
+                if let Some(typ) = ctx.globals.get(s).cloned() {
+                    return ctx.ins_typed(*current_block, IrInstruction::Ref(s.clone()), typ);
+                }
+                IrInstruction::Ref(s.clone())
+            }
+            NatLiteral(value, suffix) => {
+                // unsuffixed literals take the type of whatever they're assigned to
+                let typ = match suffix.as_deref() {
+                    Some(suffix) => match (IntTy::from_suffix(suffix), UIntTy::from_suffix(suffix)) {
+                        (Some(int), _) => ctx.module_arena.type_arena.insert(IrType::Int(int)),
+                        (_, Some(uint)) => ctx.module_arena.type_arena.insert(IrType::UInt(uint)),
+                        _ => ctx.unknown_index,
+                    },
+                    None => ctx.unknown_index,
+                };
+                let ins = ctx.ins_typed(*current_block, IrInstruction::NatLiteral(*value), typ);
+                ctx.literals.push((ins, ctx.program.expression_span(exp_index)));
+                return ins;
+            }
+            FloatLiteral(value, suffix) => {
+                let typ = match suffix.as_deref().and_then(FloatTy::from_suffix) {
+                    Some(float) => ctx.module_arena.type_arena.insert(IrType::Float(float)),
+                    None => ctx.unknown_index,
+                };
+                let ins = ctx.ins_typed(*current_block, IrInstruction::FloatLiteral(*value), typ);
+                ctx.literals.push((ins, ctx.program.expression_span(exp_index)));
+                return ins;
+            }
+            CharLiteral(c) => {
+                let typ = ctx.module_arena.type_arena.insert(IrType::UInt(UIntTy::U32));
+                return ctx.ins_typed(*current_block, IrInstruction::NatLiteral(*c as u128), typ);
+            }
+            StringLiteral(s) => {
+                let byte = ctx.module_arena.type_arena.insert(IrType::UInt(UIntTy::U8));
+                let typ = ctx.module_arena.type_arena.insert(IrType::Reference(byte, PointerKind::Raw, ReferenceCapability::Val));
+                return ctx.ins_typed(*current_block, IrInstruction::StringLiteral(s.clone()), typ);
+            }
+            BoolLiteral(b) => {
+                let typ = ctx.bool_index;
+                return ctx.ins_typed(*current_block, IrInstruction::BoolLiteral(b.clone()), typ);
+            }
+            BinOp(lhs, op @ (BinOpType::And | BinOpType::Or), rhs) => {
+                let lhs_ins = self.build_expression(ctx, func, stmt, lhs, current_block);
+                ctx.infer(lhs_ins, ctx.bool_index);
+
+                // only evaluate the right side when it decides the result,
+                // otherwise jump straight to the merge with the left side's value
+                let rhs_block = ctx.new_block_from(*current_block);
+                let short_block = ctx.new_block_from(*current_block);
+                let merge_block = ctx.new_block_from(*current_block);
+                let (true_branch, false_branch) = match op {
+                    BinOpType::And => (rhs_block, short_block),
+                    _ => (short_block, rhs_block),
+                };
+                ctx.ins(*current_block, IrInstruction::Branch {
+                    condition: lhs_ins,
+                    true_branch,
+                    false_branch,
+                });
+
+                let typ = ctx.bool_index;
+                let short_value = ctx.ins_typed(short_block, IrInstruction::BoolLiteral(*op == BinOpType::Or), typ);
+                ctx.ins(short_block, IrInstruction::Jump {
+                    target: merge_block,
+                    args: vec![short_value],
+                });
+
+                *current_block = rhs_block;
+                let rhs_ins = self.build_expression(ctx, func, stmt, rhs, current_block);
+                ctx.infer(rhs_ins, ctx.bool_index);
+                ctx.ins(*current_block, IrInstruction::Jump {
+                    target: merge_block,
+                    args: vec![rhs_ins],
+                });
+
+                let span = ctx.program.expression_span(exp_index);
+                self.check_binop(ctx, lhs_ins, *op, rhs_ins, span);
+                *current_block = merge_block;
+                return ctx.add_argument(merge_block, typ);
+            }
             BinOp(lhs, op, rhs) => {
                 let lhs_ins = self.build_expression(ctx, func, stmt, lhs, current_block);
                 let rhs_ins = self.build_expression(ctx, func, stmt, rhs, current_block);
-                IrInstruction::BinOp(lhs_ins, op.clone(), rhs_ins)
+                // pointer arithmetic gets its own instruction so it can become a gep
+                if let (Some(_), BinOpType::Plus | BinOpType::Minus) = (ctx.raw_pointee(lhs_ins), op) {
+                    let offset = match op {
+                        BinOpType::Minus => {
+                            let typ = ctx.type_of(rhs_ins);
+                            ctx.ins_typed(*current_block, IrInstruction::UnOp(UnOpType::Negate, rhs_ins), typ)
+                        }
+                        _ => rhs_ins,
+                    };
+                    let typ = ctx.type_of(lhs_ins);
+                    return ctx.ins_typed(*current_block, IrInstruction::PointerOffset {
+                        pointer: lhs_ins,
+                        offset,
+                    }, typ);
+                }
+                let span = ctx.program.expression_span(exp_index);
+                return self.build_binop(ctx, lhs_ins, *op, rhs_ins, span, *current_block);
+            }
+            Cast { value, typ } => {
+                let value = self.build_expression(ctx, func, stmt, value, current_block);
+                let target = self.build_type(ctx, typ);
+                let span = ctx.program.expression_span(exp_index);
+                return self.build_conversion(ctx, value, target, false, span, *current_block);
+            }
+            UnOp(op, operand) => {
+                let operand_ins = self.build_expression(ctx, func, stmt, operand, current_block);
+                if let (UnOpType::Negate, NatLiteral(..)) = (op, ctx.program.expression(*operand)) {
+                    ctx.negated_literals.insert(operand_ins);
+                }
+                let span = ctx.program.expression_span(exp_index);
+                return self.build_unop(ctx, *op, operand_ins, span, *current_block);
             }
             FieldAccessor { aggregate, value } => {
                 let agg_ins = self.build_expression(ctx, func, stmt, aggregate, current_block);
@@ -217,16 +1218,71 @@ impl IrBuilder {
                     value: value_ins,
                 }
             }
+            FunctionCall { function, args } if self.overflow_intrinsic(ctx, *function, args.len()).is_some() => {
+                let (overflow, op) = self.overflow_intrinsic(ctx, *function, args.len()).unwrap();
+                let lhs = self.build_expression(ctx, func, stmt, &args[0], current_block);
+                let rhs = self.build_expression(ctx, func, stmt, &args[1], current_block);
+                let span = ctx.program.expression_span(exp_index);
+                let typ = self.check_binop(ctx, lhs, op, rhs, span.clone());
+                // unsuffixed float literals don't have their type yet
+                let operand_name = |ins: &IrInstructionIndex| match (&ctx.module_arena.instruction_arena[*ins], &ctx.module_arena.type_arena[ctx.type_of(*ins)]) {
+                    (IrInstruction::FloatLiteral(_), IrType::Unknown) => Some("float literal".to_string()),
+                    (_, typ) if typ.is_scalar() && !typ.is_integer() => Some(format!("`{}`", typ.name())),
+                    _ => None,
+                };
+                if let Some(operand) = [lhs, rhs].iter().find_map(operand_name) {
+                    let message = format!("overflow intrinsics need integer operands, found {}", operand);
+                    self.report_operator(ctx, message, "invalid operands".to_string(), span);
+                }
+                let typ = match overflow {
+                    Overflow::Checked => ctx.module_arena.type_arena.insert(IrType::Optional(typ)),
+                    _ => typ,
+                };
+                return ctx.ins_typed(*current_block, IrInstruction::OverflowOp { op, overflow, lhs, rhs }, typ);
+            }
+            FunctionCall { function, args } if self.conversion(ctx, *function, args.len()).is_some() => {
+                let target = self.conversion(ctx, *function, args.len()).unwrap();
+                let target = ctx.module_arena.type_arena.insert(target);
+                let value = self.build_expression(ctx, func, stmt, &args[0], current_block);
+                let span = ctx.program.expression_span(exp_index);
+                return self.build_conversion(ctx, value, target, true, span, *current_block);
+            }
             FunctionCall { function, args } => {
-                let fun_ins = self.build_expression(ctx, func, stmt, function, current_block);
+                // calling a global by name is a direct call, anything else calls a closure
+                let fun_ins = match ctx.program.expression(*function) {
+                    Ref(name) if ctx.lookup(name).is_none() => ctx.ins(*current_block, IrInstruction::Ref(name.clone())),
+                    _ => self.build_expression(ctx, func, stmt, function, current_block),
+                };
+                // calls to closures and `extern` functions are typed, direct calls to functions in this module are when
+                // the callee declares a result, one without can still return a value nothing gave a type to
+                let (callee_type, typed) = match &ctx.module_arena.instruction_arena[fun_ins] {
+                    IrInstruction::Ref(name) => match (ctx.extern_types.get(name).cloned(), ctx.functions.get(name).cloned()) {
+                        (Some(typ), _) => (Some(typ), true),
+                        (None, Some(callee)) => (Some(self.function_type(ctx, &callee.params, callee.return_type)), false),
+                        (None, None) => (None, false),
+                    },
+                    _ => (Some(ctx.type_of(fun_ins)), true),
+                };
+                let (param_types, return_type) = match callee_type.and_then(|typ| ctx.module_arena.type_arena.get(typ)) {
+                    Some(IrType::Function(params, return_type)) => (params.clone(), (typed || *return_type != ctx.void_index).then_some(*return_type)),
+                    _ => (vec![], None),
+                };
                 let mut arg_insx = Vec::with_capacity(args.len());
-                for arg in args {
+                for (i, arg) in args.iter().enumerate() {
                     let arg_ins = self.build_expression(ctx, func, stmt, arg, current_block);
+                    // unsuffixed literals take the type of the param, so they're range checked against it
+                    if let Some(param_type) = param_types.get(i) {
+                        ctx.infer(arg_ins, *param_type);
+                    }
                     arg_insx.push(arg_ins);
                 }
-                IrInstruction::FunctionCall {
+                let call = IrInstruction::FunctionCall {
                     function: fun_ins,
                     args: arg_insx,
+                };
+                match return_type {
+                    Some(typ) => return ctx.ins_typed(*current_block, call, typ),
+                    None => call,
                 }
             }
             New { typ, allocator } => {
@@ -238,10 +1294,17 @@ impl IrBuilder {
             }
             Dereference { pointer } => {
                 let pointer_ins = self.build_expression(ctx, func, stmt, pointer, current_block);
+                if let Some(pointee) = ctx.raw_pointee(pointer_ins) {
+                    return ctx.ins_typed(*current_block, IrInstruction::Load { pointer: pointer_ins }, pointee);
+                }
                 IrInstruction::Dereference { pointer: pointer_ins }
             }
             Denull { optional } => {
                 let optional_ins = self.build_expression(ctx, func, stmt, optional, current_block);
+                if let Some(IrType::Optional(inner)) = ctx.module_arena.type_arena.get(ctx.type_of(optional_ins)) {
+                    let inner = *inner;
+                    return ctx.ins_typed(*current_block, IrInstruction::Denull { optional: optional_ins }, inner);
+                }
                 IrInstruction::Denull { optional: optional_ins }
             }
             Borrow { value } => {
@@ -250,9 +1313,761 @@ impl IrBuilder {
             }
             Unsafe { value } => {
                 let value_ins = self.build_expression(ctx, func, stmt, value, current_block);
-                IrInstruction::Unsafe { value: value_ins }
+                let typ = ctx.type_of(value_ins);
+                return ctx.ins_typed(*current_block, IrInstruction::Unsafe { value: value_ins }, typ);
             }
+            Lambda { params, return_type, body } => {
+                // globals aren't captured, they're still reachable from the lifted function
+                let captures: Vec<(String, IrInstructionIndex)> = find_captures(ctx.program, params, body).names.into_iter()
+                    .filter_map(|name| ctx.lookup(&name).map(|value| (name, value)))
+                    .collect();
+                let captured: Vec<(String, IrTypeIndex)> = captures.iter()
+                    .map(|(name, value)| (name.clone(), ctx.type_of(*value)))
+                    .collect();
+
+                ctx.lambda_count += 1;
+                let lifted = AstFunction {
+                    access: ast::Access::Internal,
+                    kind: FunctionKind::Function,
+                    name: format!("{}$lambda{}", func.name, ctx.lambda_count),
+                    type_params: vec![],
+                    params: params.clone(),
+                    return_type: *return_type,
+                    statements: body.clone(),
+                    annotations: vec![],
+                };
+
+                // the lambda is built as its own function in the middle of this one
+                let blocks = std::mem::take(&mut ctx.blocks);
+                let scopes = std::mem::take(&mut ctx.scopes);
+                let loops = std::mem::take(&mut ctx.loops);
+                let span = ctx.program.expression_span(exp_index);
+                let node = self.build_function(ctx, &lifted, span, Some(&captured));
+                ctx.blocks = blocks;
+                ctx.scopes = scopes;
+                ctx.loops = loops;
+                ctx.lifted.push(node);
+
+                let typ = self.function_type(ctx, params, *return_type);
+                return ctx.ins_typed(*current_block, IrInstruction::MakeClosure {
+                    function: lifted.name,
+                    captures: captures.into_iter().map(|(_, value)| value).collect(),
+                }, typ);
+            }
+            Error => unreachable!("modules with syntax errors aren't lowered"),
         };
         ctx.ins(*current_block, ins)
     }
+
+    /// Is this callee one of the `wrapping_add` style intrinsics?
+    /// Locals and functions in the program with the same name take precedence.
+    fn overflow_intrinsic(&self, ctx: &IrBuilderContext, function: ExpressionIndex, arg_count: usize) -> Option<(Overflow, BinOpType)> {
+        match ctx.program.expression(function) {
+            Expression::Ref(name) if arg_count == 2 && ctx.lookup(name).is_none() && !ctx.functions.contains_key(name) => Overflow::intrinsic(name),
+            _ => None,
+        }
+    }
+
+    /// Is this callee a number type like `Int64`, which converts its argument?
+    fn conversion(&self, ctx: &IrBuilderContext, function: ExpressionIndex, arg_count: usize) -> Option<IrType> {
+        let name = match ctx.program.expression(function) {
+            Expression::Ref(name) if arg_count == 1 && ctx.lookup(name).is_none() && !ctx.functions.contains_key(name) => name,
+            _ => return None,
+        };
+        IntTy::from(name).map(IrType::Int)
+            .or(UIntTy::from(name).map(IrType::UInt))
+            .or(FloatTy::from(name).map(IrType::Float))
+    }
+
+    /// Convert a number to another number type, `checked` conversions trap when the value doesn't fit.
+    fn build_conversion(&self, ctx: &mut IrBuilderContext, value: IrInstructionIndex, target: IrTypeIndex, checked: bool, span: Span, block: IrBlockIndex) -> IrInstructionIndex {
+        if ctx.type_of(value) == ctx.unknown_index {
+            // unsuffixed literals take the target type when they can, so `UInt8(200)` is just a constant
+            let is_float = Self::has_float_literal(ctx, value);
+            let negated = matches!(ctx.module_arena.instruction_arena[value], IrInstruction::UnOp(UnOpType::Negate, _));
+            let fits = match &ctx.module_arena.type_arena[target] {
+                IrType::Float(_) => true,
+                IrType::Int(_) => !is_float,
+                IrType::UInt(_) => !is_float && !negated,
+                _ => false,
+            };
+            if fits {
+                ctx.infer(value, target);
+                return value;
+            }
+            let default = if is_float { IrType::Float(FloatTy::F64) } else { IrType::Int(IntTy::I64) };
+            let default = ctx.module_arena.type_arena.insert(default);
+            ctx.infer(value, default);
+        }
+
+        let source = &ctx.module_arena.type_arena[ctx.type_of(value)];
+        let target_type = &ctx.module_arena.type_arena[target];
+        // `as` can also reinterpret pointers, the unsafety checker makes sure it's inside `unsafe`
+        let pointer_cast = !checked && match (source, target_type) {
+            (IrType::Reference(..), IrType::Reference(_, PointerKind::Raw, _)) => true,
+            (IrType::Reference(_, PointerKind::Raw, _), other) | (other, IrType::Reference(_, PointerKind::Raw, _)) => other.is_integer(),
+            _ => false,
+        };
+        // values whose type we don't know yet are left for later
+        let convertible = pointer_cast || (source.is_integer() || source.is_float() || matches!(source, IrType::Bool | IrType::Unknown))
+            && (target_type.is_integer() || target_type.is_float());
+        if !convertible {
+            let mut diagnostic = Diagnostic::error()
+                .with_code(codes::INVALID_CONVERSION)
+                .with_message(format!("can't convert `{}` to `{}`", source.name(), target_type.name()))
+                .with_labels(vec![Label::primary(ctx.program.file_id, span).with_message("only numbers can be converted")]);
+            if matches!(target_type, IrType::Bool) && source.is_scalar() {
+                diagnostic = diagnostic.with_notes(vec!["compare with `0` to get a `Bool`".to_string()]);
+            } else if matches!(source, IrType::Reference(..)) || matches!(target_type, IrType::Reference(..)) {
+                diagnostic = diagnostic.with_notes(vec!["`as` casts pointers to raw pointers, and raw pointers to and from integers".to_string()]);
+            }
+            ctx.diagnostics.push(diagnostic);
+        } else if !pointer_cast && source.name() == target_type.name() {
+            return value;
+        }
+        ctx.ins_typed(block, IrInstruction::Convert { value, checked }, target)
+    }
+
+    /// Is there a float literal in this arithmetic on literals?
+    fn has_float_literal(ctx: &IrBuilderContext, ins: IrInstructionIndex) -> bool {
+        match &ctx.module_arena.instruction_arena[ins] {
+            IrInstruction::FloatLiteral(_) => true,
+            IrInstruction::UnOp(_, operand) => Self::has_float_literal(ctx, *operand),
+            IrInstruction::BinOp(lhs, _, rhs) => Self::has_float_literal(ctx, *lhs) || Self::has_float_literal(ctx, *rhs),
+            _ => false,
+        }
+    }
+
+    fn report_operator(&self, ctx: &mut IrBuilderContext, message: String, label: String, span: Span) {
+        ctx.diagnostics.push(Diagnostic::error()
+            .with_code(codes::INVALID_OPERANDS)
+            .with_message(message)
+            .with_labels(vec![Label::primary(ctx.program.file_id, span).with_message(label)]));
+    }
+
+    fn build_binop(&self, ctx: &mut IrBuilderContext, lhs: IrInstructionIndex, op: BinOpType, rhs: IrInstructionIndex, span: Span, block: IrBlockIndex) -> IrInstructionIndex {
+        let typ = self.check_binop(ctx, lhs, op, rhs, span);
+        ctx.ins_typed(block, IrInstruction::BinOp(lhs, op, rhs), typ)
+    }
+
+    /// Check the operand types of a binary operator and work out what it produces.
+    fn check_binop(&self, ctx: &mut IrBuilderContext, lhs: IrInstructionIndex, op: BinOpType, rhs: IrInstructionIndex, span: Span) -> IrTypeIndex {
+        // an unsuffixed literal takes the type of the other side, a shift's value doesn't take the amount's
+        if !matches!(op, BinOpType::ShiftLeft | BinOpType::ShiftRight) {
+            ctx.infer(lhs, ctx.type_of(rhs));
+        }
+        ctx.infer(rhs, ctx.type_of(lhs));
+
+        let lhs_type = ctx.module_arena.type_arena[ctx.type_of(lhs)].clone();
+        let rhs_type = ctx.module_arena.type_arena[ctx.type_of(rhs)].clone();
+        let is_shift = matches!(op, BinOpType::ShiftLeft | BinOpType::ShiftRight);
+        let is_logical = matches!(op, BinOpType::And | BinOpType::Or);
+        let problem = if is_logical && [&lhs_type, &rhs_type].iter().any(|typ| typ.is_scalar() && !matches!(typ, IrType::Bool)) {
+            Some(format!("`{}` needs `Bool` operands", op))
+        } else if is_shift && [&lhs_type, &rhs_type].iter().any(|typ| typ.is_scalar() && !typ.is_integer()) {
+            Some(format!("`{}` needs integer operands", op))
+        } else if !is_shift && lhs_type.is_scalar() && rhs_type.is_scalar() && !lhs_type.same_scalar(&rhs_type) {
+            Some(format!("mismatched types `{}` and `{}` in `{}`", lhs_type.name(), rhs_type.name(), op))
+        } else if op.is_bitwise() && lhs_type.is_float() {
+            Some(format!("`{}` can't be used on `{}`", op, lhs_type.name()))
+        } else if !op.is_comparison() && !op.is_bitwise() && !is_logical && matches!(lhs_type, IrType::Bool) {
+            Some(format!("`{}` can't be used on `Bool`", op))
+        } else {
+            None
+        };
+        if let Some(message) = problem {
+            self.report_operator(ctx, message, format!("invalid operands for `{}`", op), span);
+        }
+
+        if op.is_comparison() || is_logical {
+            ctx.bool_index
+        } else if ctx.type_of(lhs) != ctx.unknown_index || is_shift {
+            ctx.type_of(lhs)
+        } else {
+            ctx.type_of(rhs)
+        }
+    }
+
+    /// Check the operand type of a unary operator, the result has the same type.
+    fn build_unop(&self, ctx: &mut IrBuilderContext, op: UnOpType, operand: IrInstructionIndex, span: Span, block: IrBlockIndex) -> IrInstructionIndex {
+        if op == UnOpType::Not {
+            ctx.infer(operand, ctx.bool_index);
+        }
+        let typ = ctx.module_arena.type_arena[ctx.type_of(operand)].clone();
+        let problem = match op {
+            UnOpType::Negate if matches!(typ, IrType::UInt(_) | IrType::Bool) => Some(format!("can't negate `{}`", typ.name())),
+            UnOpType::Not if typ.is_scalar() && !matches!(typ, IrType::Bool) => Some(format!("`not` needs a `Bool` operand, found `{}`", typ.name())),
+            UnOpType::BitNot if typ.is_scalar() && !typ.is_integer() => Some(format!("`~` needs an integer operand, found `{}`", typ.name())),
+            _ => None,
+        };
+        if let Some(message) = problem {
+            self.report_operator(ctx, message, format!("invalid operand for `{}`", op), span);
+        }
+
+        let result_type = match op {
+            UnOpType::Not => ctx.bool_index,
+            _ => ctx.type_of(operand),
+        };
+        ctx.ins_typed(block, IrInstruction::UnOp(op, operand), result_type)
+    }
+
+    /// Give number literals that never got a type the default one, then check they fit in their types.
+    fn check_literals(&self, ctx: &mut IrBuilderContext) {
+        for (ins, span) in std::mem::take(&mut ctx.literals) {
+            let is_float = matches!(ctx.module_arena.instruction_arena[ins], IrInstruction::FloatLiteral(_));
+            if ctx.type_of(ins) == ctx.unknown_index {
+                let default = if is_float { IrType::Float(FloatTy::F64) } else { IrType::Int(IntTy::I64) };
+                let typ = ctx.module_arena.type_arena.insert(default);
+                ctx.module_arena.instruction_types.insert(ins, typ);
+            }
+
+            let typ = &ctx.module_arena.type_arena[ctx.type_of(ins)];
+            let problem = match (&ctx.module_arena.instruction_arena[ins], typ) {
+                (IrInstruction::NatLiteral(value), IrType::Int(int)) if *value > int.max_value(&self.target) + ctx.negated_literals.contains(&ins) as u128 =>
+                    Some((value.to_string(), int.to_string(), int.max_value(&self.target).to_string())),
+                (IrInstruction::NatLiteral(value), IrType::UInt(uint)) if *value > uint.max_value(&self.target) =>
+                    Some((value.to_string(), uint.to_string(), uint.max_value(&self.target).to_string())),
+                (IrInstruction::FloatLiteral(value), IrType::Float(float)) if *value > float.max_value() =>
+                    Some((format!("{:e}", value), float.to_string(), format!("{:e}", float.max_value()))),
+                _ => None,
+            };
+            if let Some((value, typ, max)) = problem {
+                ctx.diagnostics.push(Diagnostic::error()
+                    .with_code(codes::LITERAL_OUT_OF_RANGE)
+                    .with_message(format!("literal `{}` is out of range for `{}`", value, typ))
+                    .with_labels(vec![Label::primary(ctx.program.file_id, span).with_message(format!("`{}` holds values up to {}", typ, max))]));
+            }
+        }
+    }
+
+    fn function_type(&self, ctx: &mut IrBuilderContext, params: &[TypedName], return_type: TypeIndex) -> IrTypeIndex {
+        let params = params.iter()
+            .map(|param| param.typ.map_or(ctx.unknown_index, |typ| self.build_type(ctx, &typ)))
+            .collect();
+        let return_type = self.build_type(ctx, &return_type);
+        ctx.module_arena.type_arena.insert(IrType::Function(params, return_type))
+    }
+
+    /// A function taking an empty environment that calls a global function,
+    /// so globals can be called like any other closure.
+    fn build_closure_thunk(&self, ctx: &mut IrBuilderContext, global: &AstFunction) -> String {
+        let name = format!("{}$closure", global.name);
+        if !ctx.thunks.insert(name.clone()) {
+            return name;
+        }
+
+        let block = ctx.new_block();
+        let mut params = vec![IrTypedName {
+            name: "env".to_string(),
+            typ: self.env_type(ctx, &[]),
+        }];
+        for param in &global.params {
+            params.push(IrTypedName {
+                name: param.name.clone(),
+                typ: param.typ.map_or(ctx.unknown_index, |typ| self.build_type(ctx, &typ)),
+            });
+        }
+        let arguments: Vec<IrInstructionIndex> = params.iter()
+            .map(|param| ctx.add_argument(block, param.typ))
+            .collect();
+
+        let return_type = self.build_type(ctx, &global.return_type);
+        let function = ctx.ins(block, IrInstruction::Ref(global.name.clone()));
+        let call = ctx.ins_typed(block, IrInstruction::FunctionCall {
+            function,
+            args: arguments[1..].to_vec(),
+        }, return_type);
+        let value = if return_type == ctx.void_index { None } else { Some(call) };
+        ctx.ins(block, IrInstruction::Return { value });
+
+        ctx.lifted.push(IrNode::Function(IrFunction {
+            access: Access::Generated,
+            name: name.clone(),
+            params,
+            type_params: vec![],
+            return_type,
+            blocks: vec![block],
+            annotations: vec![],
+            span: 0..0,
+        }));
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codespan_reporting::diagnostic::Severity;
+
+    use crate::ast::BinOpType;
+    use crate::compiler::Compiler;
+    use crate::ir::{IrFunction, IrInstruction, IrInstructionIndex, IrNode, ModuleArena, Overflow};
+    use crate::ir::print::type_name;
+    use crate::lang::Path;
+
+    fn compile(source: &str) -> Compiler {
+        let mut compiler = Compiler::new();
+        compiler.parse_module(Path::of("test"), "test.ns".to_string(), source.to_string());
+        compiler.compile();
+        compiler
+    }
+
+    /// The messages of the errors compiling a module reports, warnings are left out.
+    fn errors(source: &str) -> Vec<String> {
+        compile(source).diagnostics.messages.iter()
+            .filter(|diagnostic| diagnostic.severity >= Severity::Error)
+            .map(|diagnostic| diagnostic.message.clone())
+            .collect()
+    }
+
+    fn function<'c>(compiler: &'c Compiler, name: &str) -> (&'c ModuleArena, &'c IrFunction) {
+        assert!(!compiler.diagnostics.has_errors(), "{}", compiler.diagnostics.emit_to_string());
+        let (_, module) = compiler.modules.iter().next().unwrap();
+        let arena = &module.module_arena;
+        let func = arena.node_arena.iter()
+            .find_map(|(_, node)| match node {
+                IrNode::Function(func) if func.name == name => Some(func),
+                _ => None,
+            })
+            .unwrap_or_else(|| panic!("no function `{}`", name));
+        (arena, func)
+    }
+
+    /// The instructions of a function, block by block.
+    fn instructions<'c>(arena: &'c ModuleArena, func: &'c IrFunction) -> impl Iterator<Item = (IrInstructionIndex, &'c IrInstruction)> {
+        func.blocks.iter()
+            .flat_map(|block| arena.block_arena[*block].instructions.iter())
+            .map(|ins| (*ins, &arena.instruction_arena[*ins]))
+    }
+
+    /// Every block of a function with the types of its arguments, then where it goes and the types
+    /// of the values it passes along, like `block#1(Int32) -> block#2(Int32)`.
+    fn shape(source: &str, name: &str) -> Vec<String> {
+        let compiler = compile(source);
+        let (arena, func) = function(&compiler, name);
+        let number = |block| func.blocks.iter().position(|other| *other == block).unwrap();
+        let types = |values: &[IrInstructionIndex]| values.iter()
+            .map(|value| arena.instruction_types.get(value).map_or("Unknown".to_string(), |typ| type_name(arena, *typ)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        func.blocks.iter().enumerate().map(|(i, block)| {
+            let block = &arena.block_arena[*block];
+            let arguments = block.arguments.iter().map(|typ| type_name(arena, *typ)).collect::<Vec<_>>().join(", ");
+            let exit = match block.instructions.last().map(|ins| &arena.instruction_arena[*ins]) {
+                Some(IrInstruction::Jump { target, args }) => format!("block#{}({})", number(*target), types(args)),
+                Some(IrInstruction::Branch { true_branch, false_branch, .. }) => format!("block#{} or block#{}", number(*true_branch), number(*false_branch)),
+                Some(IrInstruction::Return { .. }) => "return".to_string(),
+                _ => "nowhere".to_string(),
+            };
+            format!("block#{}({}) -> {}", i, arguments, exit)
+        }).collect()
+    }
+
+    #[test]
+    fn branches_merge_into_block_arguments() {
+        assert_eq!(shape("
+            fun pick(a: Int32, b: Int32): Int32 {
+                let y = 0;
+                if a < b {
+                    y = 7;
+                } else {
+                    y = b;
+                }
+                return y;
+            }
+        ", "pick"), vec![
+            "block#0(Int32, Int32) -> block#1 or block#2",
+            "block#1() -> block#3(Int32)",
+            "block#2() -> block#3(Int32)",
+            "block#3(Int32) -> return",
+        ]);
+    }
+
+    #[test]
+    fn locals_a_branch_leaves_alone_are_not_merged() {
+        assert_eq!(shape("
+            fun keep(a: Int32, b: Int32): Int32 {
+                let x = a;
+                if a < b {
+                    let t = b;
+                }
+                return x;
+            }
+        ", "keep"), vec![
+            "block#0(Int32, Int32) -> block#1 or block#2",
+            "block#1() -> block#3()",
+            "block#2() -> block#3()",
+            "block#3() -> return",
+        ]);
+    }
+
+    #[test]
+    fn else_if_chains_merge_into_the_enclosing_if() {
+        assert_eq!(shape("
+            fun sign(x: Int32): Int32 {
+                let s: Int32 = 0;
+                if x < 0 {
+                    s = x;
+                } else if x > 0 {
+                    s = 1;
+                }
+                return s;
+            }
+        ", "sign"), vec![
+            "block#0(Int32) -> block#1 or block#2",
+            "block#1() -> block#6(Int32)",
+            "block#2() -> block#3 or block#4",
+            "block#3() -> block#5(Int32)",
+            "block#4() -> block#5(Int32)",
+            "block#5(Int32) -> block#6(Int32)",
+            "block#6(Int32) -> return",
+        ]);
+    }
+
+    #[test]
+    fn code_after_a_return_is_not_built() {
+        assert_eq!(shape("
+            fun early(x: Int32): Int32 {
+                if x > 1 {
+                    return 1;
+                    x = 5;
+                }
+                return x;
+            }
+        ", "early"), vec![
+            "block#0(Int32) -> block#1 or block#2",
+            "block#1() -> return",
+            "block#2() -> block#3()",
+            "block#3() -> return",
+        ]);
+    }
+
+    #[test]
+    fn for_loops_count_in_the_type_of_their_bound() {
+        assert_eq!(shape("
+            fun sum(n: Int32): Int32 {
+                let s: Int32 = 0;
+                for i in 0..n {
+                    s = s + i;
+                }
+                return s;
+            }
+        ", "sum"), vec![
+            "block#0(Int32) -> block#1(Int32, Int32, Int32)",
+            "block#1(Int32, Int32, Int32) -> block#2 or block#3",
+            "block#2() -> block#4(Int32, Int32, Int32)",
+            "block#3() -> block#5()",
+            "block#4(Int32, Int32, Int32) -> block#1(Int32, Int32, Int32)",
+            "block#5() -> return",
+        ]);
+    }
+
+    #[test]
+    fn while_loops_carry_locals_in_the_type_they_are_used_as() {
+        assert_eq!(shape("
+            fun count(n: Int32): Int32 {
+                let i = 0;
+                while i < n {
+                    i = i + 1;
+                }
+                return i;
+            }
+        ", "count"), vec![
+            "block#0(Int32) -> block#1(Int32, Int32)",
+            "block#1(Int32, Int32) -> block#2 or block#3",
+            "block#2() -> block#1(Int32, Int32)",
+            "block#3() -> block#4()",
+            "block#4() -> return",
+        ]);
+    }
+
+    #[test]
+    fn break_and_continue_jump_out_of_and_back_to_the_loop() {
+        assert_eq!(shape("
+            fun evens(n: Int32): Int32 {
+                let total = 0;
+                for i in 0..n {
+                    if i > 100 {
+                        break;
+                    }
+                    if i % 2 == 1 {
+                        continue;
+                    }
+                    total = total + i;
+                }
+                return total;
+            }
+        ", "evens"), vec![
+            "block#0(Int32) -> block#1(Int32, Int32, Int32)",
+            "block#1(Int32, Int32, Int32) -> block#2 or block#3",
+            "block#2() -> block#4 or block#5",
+            "block#3() -> block#11()",
+            "block#4() -> block#11()",
+            "block#5() -> block#6()",
+            "block#6() -> block#7 or block#8",
+            "block#7() -> block#10(Int32, Int32, Int32)",
+            "block#8() -> block#9()",
+            "block#9() -> block#10(Int32, Int32, Int32)",
+            "block#10(Int32, Int32, Int32) -> block#1(Int32, Int32, Int32)",
+            "block#11() -> return",
+        ]);
+    }
+
+    #[test]
+    fn break_and_continue_outside_of_a_loop() {
+        assert_eq!(errors("
+            fun f(x: Int32) {
+                if x > 1 {
+                    break;
+                }
+                continue;
+            }
+        "), vec!["`break` outside of a loop", "`continue` outside of a loop"]);
+    }
+
+    #[test]
+    fn lambdas_become_functions_taking_their_environment() {
+        let compiler = compile("
+            fun scale(n: Int64, k: Int64): Int64 {
+                let f = fun(x: Int64): Int64 { return x * k; };
+                return f(n);
+            }
+        ");
+        let (arena, lambda) = function(&compiler, "scale$lambda1");
+        let params: Vec<(&str, String)> = lambda.params.iter().map(|param| (param.name.as_str(), type_name(arena, param.typ))).collect();
+        assert_eq!(params, vec![("env", "*val {k: Int64}".to_string()), ("x", "Int64".to_string())]);
+
+        let (arena, scale) = function(&compiler, "scale");
+        let closures: Vec<(&str, usize)> = instructions(arena, scale)
+            .filter_map(|(_, ins)| match ins {
+                IrInstruction::MakeClosure { function, captures } => Some((function.as_str(), captures.len())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(closures, vec![("scale$lambda1", 1)]);
+    }
+
+    #[test]
+    fn literals_must_fit_their_type() {
+        assert_eq!(errors("
+            fun f() {
+                let a: UInt8 = 255;
+                let b: UInt8 = 256;
+                let c = 300u8;
+                let d = 0xFFFF_FFFF_FFFF_FFFFu64;
+                let e = 0xFFFF_FFFF_FFFF_FFFF;
+                let g: Float32 = 3.5;
+                let h = 1e39f32;
+            }
+        "), vec![
+            "literal `256` is out of range for `UInt8`",
+            "literal `300` is out of range for `UInt8`",
+            "literal `18446744073709551615` is out of range for `Int64`",
+            "literal `1e39` is out of range for `Float32`",
+        ]);
+    }
+
+    #[test]
+    fn literals_take_the_type_they_are_used_as() {
+        assert_eq!(errors("
+            fun small(): UInt8 {
+                return 256;
+            }
+
+            fun take(x: Int8): Int8 {
+                return x;
+            }
+
+            fun f() {
+                let a = take(128);
+                let b = take(127);
+            }
+        "), vec![
+            "literal `256` is out of range for `UInt8`",
+            "literal `128` is out of range for `Int8`",
+        ]);
+        // a local starting from a literal is carried around a loop in the type it's assigned later
+        assert_eq!(shape("
+            fun follow(n: Int32, next: Int32): Int32 {
+                let i = 0;
+                while i < n {
+                    i = next;
+                }
+                return i;
+            }
+        ", "follow"), vec![
+            "block#0(Int32, Int32) -> block#1(Int32, Int32, Int32)",
+            "block#1(Int32, Int32, Int32) -> block#2 or block#3",
+            "block#2() -> block#1(Int32, Int32, Int32)",
+            "block#3() -> block#4()",
+            "block#4() -> return",
+        ]);
+    }
+
+    #[test]
+    fn operators_need_operands_they_work_on() {
+        assert_eq!(errors("
+            fun f(a: Int32, u: UInt8, x: Float64, ok: Bool) {
+                let p = a + u;
+                let q = x & 1;
+                let r = -u;
+                let s = not a;
+                let t = a and ok;
+                let v = ok + ok;
+                let w = a << u;
+                let y: Int8 = -128;
+                let z = -129i8;
+            }
+        "), vec![
+            "mismatched types `Int32` and `UInt8` in `+`",
+            "`&` can't be used on `Float64`",
+            "can't negate `UInt8`",
+            "`not` needs a `Bool` operand, found `Int32`",
+            "`and` needs `Bool` operands",
+            "`+` can't be used on `Bool`",
+            "literal `129` is out of range for `Int8`",
+        ]);
+    }
+
+    #[test]
+    fn shift_amounts_keep_their_own_type() {
+        let compiler = compile("
+            fun f(a: UInt8, n: Int64): UInt8 {
+                let b = a << n;
+                return b >> 3;
+            }
+        ");
+        let (arena, func) = function(&compiler, "f");
+        let shifts: Vec<(String, String)> = instructions(arena, func)
+            .filter_map(|(index, ins)| match ins {
+                IrInstruction::BinOp(_, BinOpType::ShiftLeft | BinOpType::ShiftRight, amount) => Some((
+                    type_name(arena, arena.instruction_types[&index]),
+                    type_name(arena, arena.instruction_types[amount]),
+                )),
+                _ => None,
+            })
+            .collect();
+        // an unsuffixed amount takes the type of the value being shifted
+        assert_eq!(shifts, vec![
+            ("UInt8".to_string(), "Int64".to_string()),
+            ("UInt8".to_string(), "UInt8".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn and_or_only_evaluate_their_right_operand_when_needed() {
+        assert_eq!(shape("
+            fun inRange(x: Int32, a: Int32, b: Int32): Bool {
+                return x >= a and x < b;
+            }
+        ", "inRange"), vec![
+            "block#0(Int32, Int32, Int32) -> block#1 or block#2",
+            "block#1() -> block#3(Bool)",
+            "block#2() -> block#3(Bool)",
+            "block#3(Bool) -> return",
+        ]);
+        assert_eq!(shape("
+            fun outside(x: Int32, a: Int32, b: Int32): Bool {
+                return x < a or x >= b;
+            }
+        ", "outside"), vec![
+            "block#0(Int32, Int32, Int32) -> block#2 or block#1",
+            "block#1() -> block#3(Bool)",
+            "block#2() -> block#3(Bool)",
+            "block#3(Bool) -> return",
+        ]);
+    }
+
+    #[test]
+    fn overflow_intrinsics_pick_their_mode_by_name() {
+        let compiler = compile("
+            fun f(a: Int32, b: Int32): Int32 {
+                let w = wrapping_add(a, b);
+                let s = saturating_sub(a, b);
+                let c = checked_mul(a, b);
+                return w + s;
+            }
+        ");
+        let (arena, func) = function(&compiler, "f");
+        let ops: Vec<(BinOpType, Option<Overflow>)> = instructions(arena, func)
+            .filter_map(|(_, ins)| match ins {
+                IrInstruction::OverflowOp { op, overflow, .. } => Some((*op, Some(*overflow))),
+                // plain operators do what the build mode says, that's decided when lowering
+                IrInstruction::BinOp(_, op, _) => Some((*op, None)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ops, vec![
+            (BinOpType::Plus, Some(Overflow::Wrap)),
+            (BinOpType::Minus, Some(Overflow::Saturate)),
+            (BinOpType::Star, Some(Overflow::Checked)),
+            (BinOpType::Plus, None),
+        ]);
+        assert_eq!(errors("
+            fun f(): Float64 {
+                return wrapping_add(1.5, 2.5);
+            }
+        "), vec!["overflow intrinsics need integer operands, found float literal"]);
+    }
+
+    #[test]
+    fn casts_are_unchecked_and_conversion_calls_are_checked() {
+        let compiler = compile("
+            fun conv(a: Int32, c: Float64): Int64 {
+                let n = a as UInt8;
+                let f = c as Int16;
+                let g = Int8(a);
+                let h = Float32(c);
+                return a as Int64;
+            }
+        ");
+        let (arena, func) = function(&compiler, "conv");
+        let conversions: Vec<(String, String, bool)> = instructions(arena, func)
+            .filter_map(|(index, ins)| match ins {
+                IrInstruction::Convert { value, checked } => Some((
+                    type_name(arena, arena.instruction_types[value]),
+                    type_name(arena, arena.instruction_types[&index]),
+                    *checked,
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(conversions, vec![
+            ("Int32".to_string(), "UInt8".to_string(), false),
+            ("Float64".to_string(), "Int16".to_string(), false),
+            ("Int32".to_string(), "Int8".to_string(), true),
+            ("Float64".to_string(), "Float32".to_string(), true),
+            ("Int32".to_string(), "Int64".to_string(), false),
+        ]);
+        assert_eq!(errors("
+            fun f(a: Int32): Bool {
+                return a as Bool;
+            }
+        "), vec!["can't convert `Int32` to `Bool`"]);
+    }
+
+    #[test]
+    fn extern_calls_are_typed_by_their_declaration() {
+        assert_eq!(errors("
+            fun f(): Int32 {
+                let a = put(128);
+                return put(1);
+            }
+
+            extern \"C\" fun put(c: Int8): Int32;
+        "), vec!["literal `128` is out of range for `Int8`"]);
+        let compiler = compile("
+            extern \"C\" fun get(): Int64;
+
+            fun f(): Int64 {
+                let a = get();
+                return a;
+            }
+        ");
+        let (arena, func) = function(&compiler, "f");
+        let calls: Vec<String> = instructions(arena, func)
+            .filter(|(_, ins)| matches!(ins, IrInstruction::FunctionCall { .. }))
+            .map(|(index, _)| type_name(arena, arena.instruction_types[&index]))
+            .collect();
+        assert_eq!(calls, vec!["Int64"]);
+    }
 }
@@ -0,0 +1,177 @@
+use std::collections::{HashMap, HashSet};
+
+use codespan_reporting::diagnostic::Diagnostic;
+
+use super::Module;
+use super::print::type_name;
+use crate::diagnostic::{codes, FileId};
+use crate::ir::*;
+
+/// Checks the control flow graph of every function in a module.
+/// Anything reported here is a bug in whatever built the ir.
+pub struct IrVerifier<'m> {
+    module: &'m Module,
+    diagnostics: Vec<Diagnostic<FileId>>,
+}
+
+impl<'m> IrVerifier<'m> {
+    pub fn new(module: &'m Module) -> Self {
+        Self {
+            module,
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn verify(mut self) -> Vec<Diagnostic<FileId>> {
+        let module = self.module;
+        for (_, node) in module.module_arena.node_arena.iter() {
+            if let IrNode::Function(func) = node {
+                self.verify_function(func);
+            }
+        }
+        self.diagnostics
+    }
+
+    fn report(&mut self, func: &IrFunction, message: String) {
+        self.diagnostics.push(Diagnostic::bug()
+            .with_code(codes::INVALID_IR)
+            .with_message(format!("invalid ir in function `{}`: {}", func.name, message)));
+    }
+
+    fn verify_function(&mut self, func: &IrFunction) {
+        let module = self.module;
+        let arena = &module.module_arena;
+        let entry = match func.blocks.first() {
+            Some(entry) => *entry,
+            None => return self.report(func, "function has no entry block".to_string()),
+        };
+
+        // params without a declared type don't get one yet, so only in functions without those
+        // is every value that's passed around expected to have a type
+        let declared = func.params.iter().all(|param| !matches!(arena.type_arena.get(param.typ), Some(IrType::Unknown)));
+        let block_numbers: HashMap<IrBlockIndex, usize> = func.blocks.iter().enumerate()
+            .map(|(i, block)| (*block, i))
+            .collect();
+
+        if arena.block_arena[entry].arguments.len() != func.params.len() {
+            self.report(func, "entry block arguments don't match the function's params".to_string());
+        }
+
+        for (i, block_index) in func.blocks.iter().enumerate() {
+            let block = &arena.block_arena[*block_index];
+            for (j, ins_index) in block.instructions.iter().enumerate() {
+                let ins = &arena.instruction_arena[*ins_index];
+                let is_last = j + 1 == block.instructions.len();
+                if ins.is_terminator() && !is_last {
+                    self.report(func, format!("block#{} has instructions after its terminator", i));
+                }
+                if let IrInstruction::Argument(n) = ins {
+                    if *n >= block.arguments.len() {
+                        self.report(func, format!("block#{} reads argument {} but only has {}", i, n, block.arguments.len()));
+                    }
+                }
+
+                let passed: &[IrInstructionIndex] = match ins {
+                    IrInstruction::Jump { args, .. } => args,
+                    _ => &[],
+                };
+                for successor in ins.successors() {
+                    match block_numbers.get(&successor) {
+                        None => self.report(func, format!("block#{} branches to a block outside of the function", i)),
+                        Some(0) => self.report(func, format!("block#{} branches to the entry block", i)),
+                        Some(target) => {
+                            let expected = &arena.block_arena[successor].arguments;
+                            if passed.len() != expected.len() {
+                                self.report(func, format!("block#{} passes {} arguments to block#{} which takes {}", i, passed.len(), target, expected.len()));
+                            }
+                            for (n, (arg, typ)) in passed.iter().zip(expected).enumerate() {
+                                let passed_type = arena.instruction_types.get(arg).map_or("Unknown".to_string(), |typ| type_name(arena, *typ));
+                                let expected_type = type_name(arena, *typ);
+                                let untyped = passed_type == "Unknown" || expected_type == "Unknown";
+                                if passed_type != expected_type && (declared || !untyped) {
+                                    self.report(func, format!("block#{} passes `{}` to argument {} of block#{} which takes `{}`", i, passed_type, n, target, expected_type));
+                                } else if declared && expected_type == "Unknown" {
+                                    self.report(func, format!("argument {} of block#{} has no type", n, target));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            let terminated = block.instructions.last().map_or(false, |ins| arena.instruction_arena[*ins].is_terminator());
+            if !terminated {
+                self.report(func, format!("block#{} is not terminated", i));
+            }
+        }
+
+        // walk the graph from the entry block, anything not visited is unreachable
+        let mut visited = HashSet::new();
+        let mut stack = vec![entry];
+        while let Some(block_index) = stack.pop() {
+            if !visited.insert(block_index) {
+                continue;
+            }
+            if let Some(ins) = arena.block_arena.get(block_index).and_then(|block| block.instructions.last()) {
+                stack.extend(arena.instruction_arena[*ins].successors());
+            }
+        }
+        for (i, block_index) in func.blocks.iter().enumerate() {
+            if !visited.contains(block_index) {
+                self.report(func, format!("block#{} is unreachable", i));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lang::Path;
+
+    /// Compiles an if/else, retypes the arguments of the block it merges into and returns what the verifier says.
+    fn verify_retyped(typ: IrType) -> Vec<String> {
+        let mut compiler = Compiler::new();
+        compiler.parse_module(Path::of("test"), "test.ns".to_string(), "
+            fun pick(a: Int32, b: Int32): Int32 {
+                let y = a;
+                if a < b {
+                    y = b;
+                } else {
+                    y = a;
+                }
+                return y;
+            }
+        ".to_string());
+        compiler.compile();
+        assert!(!compiler.diagnostics.has_errors(), "{}", compiler.diagnostics.emit_to_string());
+
+        let (_, module) = compiler.modules.iter_mut().next().unwrap();
+        let arena = &mut module.module_arena;
+        let typ = arena.type_arena.insert(typ);
+        let merge = arena.node_arena.iter()
+            .find_map(|(_, node)| match node {
+                IrNode::Function(func) => func.blocks.last().copied(),
+                _ => None,
+            })
+            .unwrap();
+        for argument in &mut arena.block_arena[merge].arguments {
+            *argument = typ;
+        }
+        IrVerifier::new(module).verify().into_iter().map(|diagnostic| diagnostic.message).collect()
+    }
+
+    #[test]
+    fn arguments_must_match_the_types_passed() {
+        let messages = verify_retyped(IrType::Bool);
+        assert!(messages.iter().any(|message| message.contains("block#1 passes `Int32` to argument 0 of block#3 which takes `Bool`")), "{:?}", messages);
+        assert!(messages.iter().any(|message| message.contains("block#2 passes `Int32` to argument 0 of block#3 which takes `Bool`")), "{:?}", messages);
+    }
+
+    #[test]
+    fn arguments_must_have_a_type() {
+        let messages = verify_retyped(IrType::Unknown);
+        assert!(messages.iter().any(|message| message.contains("block#1 passes `Int32` to argument 0 of block#3 which takes `Unknown`")), "{:?}", messages);
+    }
+}
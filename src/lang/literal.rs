@@ -0,0 +1,149 @@
+// turning literal tokens into values, the lexer only checks their shape
+
+static INT_SUFFIXES: [&str; 12] = ["i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize"];
+static FLOAT_SUFFIXES: [&str; 4] = ["f16", "f32", "f64", "f128"];
+
+/// Split a suffix like `u8` off the end of a number.
+fn split_suffix<'a>(text: &'a str, suffixes: &[&str]) -> (&'a str, Option<String>) {
+    // longest first so `i128` isn't read as `i8`
+    let mut suffixes = suffixes.to_vec();
+    suffixes.sort_by_key(|suffix| std::cmp::Reverse(suffix.len()));
+    for suffix in suffixes {
+        if let Some(digits) = text.strip_suffix(suffix) {
+            return (digits, Some(suffix.to_string()));
+        }
+    }
+    (text, None)
+}
+
+/// Parse an integer literal like `1_000`, `0xff`, `0b1010` or `10u8`.
+pub fn parse_int(text: &str) -> Result<(u128, Option<String>), String> {
+    let (radix, rest) = match text.get(..2) {
+        Some("0x") => (16, &text[2..]),
+        Some("0b") => (2, &text[2..]),
+        Some("0o") => (8, &text[2..]),
+        _ => (10, text),
+    };
+    // hex digits include what would be a float suffix, but never an integer one
+    let (digits, suffix) = split_suffix(rest, &INT_SUFFIXES);
+    let digits: String = digits.chars().filter(|c| *c != '_').collect();
+    if digits.is_empty() {
+        return Err(format!("integer literal `{}` has no digits", text));
+    }
+    u128::from_str_radix(&digits, radix)
+        .map(|value| (value, suffix))
+        .map_err(|_| format!("integer literal `{}` is too large", text))
+}
+
+/// Parse a float literal like `1.5`, `2e10` or `1.5f32`.
+pub fn parse_float(text: &str) -> Result<(f64, Option<String>), String> {
+    let (digits, suffix) = split_suffix(text, &FLOAT_SUFFIXES);
+    let digits: String = digits.chars().filter(|c| *c != '_').collect();
+    match digits.parse::<f64>() {
+        Ok(value) if value.is_finite() => Ok((value, suffix)),
+        Ok(_) => Err(format!("float literal `{}` is too large", text)),
+        Err(_) => Err(format!("invalid float literal `{}`", text)),
+    }
+}
+
+/// Replace escapes in the text between the quotes of a string or char literal.
+pub fn unescape(text: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('\'') => '\'',
+            Some('"') => '"',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if hex.len() == 2 && byte <= 0x7f => byte as char,
+                    _ => return Err(format!("`\\x{}` is not an ascii escape, use `\\x00` to `\\x7f`", hex)),
+                }
+            }
+            Some('u') => {
+                let rest = chars.as_str();
+                let end = match (rest.starts_with('{'), rest.find('}')) {
+                    (true, Some(end)) => end,
+                    _ => return Err("unicode escapes are written like `\\u{1F600}`".to_string()),
+                };
+                let hex = &rest[1..end];
+                let c = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+                    .ok_or(format!("`\\u{{{}}}` is not a unicode scalar value", hex))?;
+                chars = rest[end + 1..].chars();
+                c
+            }
+            Some(other) => return Err(format!("unknown escape `\\{}`", other)),
+            None => return Err("literal ends with a lone `\\`".to_string()),
+        };
+        result.push(escaped);
+    }
+    Ok(result)
+}
+
+/// Parse a char literal from the text between its quotes.
+pub fn parse_char(text: &str) -> Result<char, String> {
+    let value = unescape(text)?;
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(format!("char literal `'{}'` must hold exactly one character", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers() {
+        assert_eq!(parse_int("1_000"), Ok((1000, None)));
+        assert_eq!(parse_int("0xFFu8"), Ok((255, Some("u8".to_string()))));
+        assert_eq!(parse_int("0b1010_1010i16"), Ok((170, Some("i16".to_string()))));
+        assert_eq!(parse_int("0o777"), Ok((511, None)));
+        assert_eq!(parse_int("7i128"), Ok((7, Some("i128".to_string()))));
+        // `f32` isn't an integer suffix, so these are hex digits
+        assert_eq!(parse_int("0x1f32"), Ok((0x1f32, None)));
+        assert_eq!(parse_int("340282366920938463463374607431768211455"), Ok((u128::MAX, None)));
+        assert!(parse_int("340282366920938463463374607431768211456").is_err());
+        assert!(parse_int("0x_u8").is_err());
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(parse_float("1.5"), Ok((1.5, None)));
+        assert_eq!(parse_float("2.5e-3"), Ok((0.0025, None)));
+        assert_eq!(parse_float("3f64"), Ok((3.0, Some("f64".to_string()))));
+        assert_eq!(parse_float("1_000.25f16"), Ok((1000.25, Some("f16".to_string()))));
+        assert!(parse_float("1e400").is_err());
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(unescape(r#"hi\n\t\"there\" \u{1F600}"#), Ok("hi\n\t\"there\" \u{1F600}".to_string()));
+        assert_eq!(unescape(r"\x41\0\\"), Ok("A\0\\".to_string()));
+        assert!(unescape(r"\q").is_err());
+        assert!(unescape(r"\xff").is_err());
+        assert!(unescape(r"\u{D800}").is_err());
+        assert!(unescape(r"\u1F600").is_err());
+        assert!(unescape("\\").is_err());
+    }
+
+    #[test]
+    fn chars() {
+        assert_eq!(parse_char("x"), Ok('x'));
+        assert_eq!(parse_char(r"\n"), Ok('\n'));
+        assert_eq!(parse_char(r"\u{e9}"), Ok('é'));
+        assert!(parse_char("ab").is_err());
+        assert!(parse_char("").is_err());
+    }
+}
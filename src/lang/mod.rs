@@ -3,6 +3,10 @@
 pub mod refcap;
 pub mod ptr;
 pub mod primitive;
+pub mod literal;
+
+/// Byte range into a source file.
+pub type Span = std::ops::Range<usize>;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Path(pub Vec<String>);
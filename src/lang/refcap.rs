@@ -10,7 +10,7 @@ pub enum ReferenceCapability {
 
 impl ReferenceCapability {
     /// Can this refcap be aliased as this other refcap?
-    fn _can_alias(&self, other: Self) -> bool {
+    pub fn can_alias(&self, other: Self) -> bool {
         use ReferenceCapability::*;
         match (*self, other) {
             (Iso, Tag) => true,
@@ -23,7 +23,7 @@ impl ReferenceCapability {
     }
 
     /// Can this refcap be sent to another actor?
-    fn _sendable(&self) -> bool {
+    pub fn sendable(&self) -> bool {
         use ReferenceCapability::*;
         match self {
             Iso | Tag | Val => true,
@@ -0,0 +1,756 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Write};
+
+use codespan_reporting::diagnostic::{Diagnostic, LabelStyle, Severity};
+use codespan_reporting::files::Files;
+use serde_json::{json, Value};
+
+use crate::ast::*;
+use crate::compiler::Compiler;
+use crate::diagnostic::FileId;
+use crate::ir::{IrNode, Module};
+use crate::ir::print::type_name;
+use crate::lang::{Path, Span};
+use crate::options::CompilerOptions;
+use crate::parser::Parser;
+
+/// JSON-RPC error code for requests the server doesn't handle.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// See https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#symbolKind
+mod symbol_kind {
+    pub const CLASS: u32 = 5;
+    pub const METHOD: u32 = 6;
+    pub const FIELD: u32 = 8;
+    pub const ENUM: u32 = 10;
+    pub const INTERFACE: u32 = 11;
+    pub const FUNCTION: u32 = 12;
+    pub const CONSTANT: u32 = 14;
+    pub const ENUM_MEMBER: u32 = 22;
+    pub const STRUCT: u32 = 23;
+    pub const TYPE_PARAMETER: u32 = 26;
+}
+
+/// Speaks LSP over stdin and stdout, every document is compiled again whenever it changes.
+pub struct LanguageServer {
+    options: CompilerOptions,
+    /// Text of the open documents by uri, these are newer than what's on disk.
+    documents: HashMap<String, String>,
+    shutdown: bool,
+}
+
+/// What a name in the source refers to.
+#[derive(Clone, Debug)]
+enum Target {
+    /// A param or `let`, by where it's declared.
+    Local(Span),
+    /// Something declared at the top of a module, or in a struct.
+    Global(String),
+}
+
+/// A document compiled along with the modules it imports.
+struct Analysis {
+    compiler: Compiler,
+    path: Path,
+    /// The uri of every module that was compiled.
+    uris: HashMap<Path, String>,
+}
+
+impl LanguageServer {
+    pub fn new(options: CompilerOptions) -> Self {
+        Self {
+            options,
+            documents: HashMap::new(),
+            shutdown: false,
+        }
+    }
+
+    /// Serve until the client says to exit, returns the exit code.
+    pub fn run(mut self) -> i32 {
+        let stdin = std::io::stdin();
+        let mut input = stdin.lock();
+        while let Some(message) = read_message(&mut input) {
+            let method = message["method"].as_str().unwrap_or_default();
+            let params = &message["params"];
+            match message.get("id") {
+                Some(id) => {
+                    let response = match self.request(method, params) {
+                        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                        Err((code, error)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": error } }),
+                    };
+                    send(&response);
+                }
+                None if method == "exit" => return if self.shutdown { 0 } else { 1 },
+                None => self.notification(method, params),
+            }
+        }
+        1
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // full text on every change, documents are small enough to compile from scratch
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => Ok(self.definition(params).unwrap_or(Value::Null)),
+            "textDocument/hover" => Ok(self.hover(params).unwrap_or(Value::Null)),
+            "textDocument/documentSymbol" => Ok(self.document_symbols(params).unwrap_or(Value::Array(vec![]))),
+            _ => Err((METHOD_NOT_FOUND, format!("`{}` isn't supported", method))),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.clone(), text.to_string());
+                self.publish_diagnostics(&uri);
+            }
+            "textDocument/didChange" => {
+                let Some(text) = params["contentChanges"].as_array().and_then(|changes| changes.last()).and_then(|change| change["text"].as_str()) else {
+                    return;
+                };
+                self.documents.insert(uri.clone(), text.to_string());
+                self.publish_diagnostics(&uri);
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                send(&json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                }));
+            }
+            _ => {}
+        }
+    }
+
+    /// The text of a document, from the editor when it's open and from disk otherwise.
+    fn source(&self, uri: &str) -> Option<String> {
+        match self.documents.get(uri) {
+            Some(text) => Some(text.clone()),
+            None => std::fs::read_to_string(uri_to_path(uri)).ok(),
+        }
+    }
+
+    /// Compile a document together with the modules it imports, which are looked for next to it.
+    fn analyze(&self, uri: &str) -> Option<Analysis> {
+        let mut analysis = Analysis {
+            compiler: Compiler::with_options(self.options.clone()),
+            path: Path::of(file_name(uri).split('.').next().unwrap_or("main")),
+            uris: HashMap::new(),
+        };
+        let path = analysis.path.clone();
+        self.load(&mut analysis, path, uri, &mut HashSet::new())?;
+        analysis.compiler.compile();
+        Some(analysis)
+    }
+
+    fn load(&self, analysis: &mut Analysis, path: Path, uri: &str, loading: &mut HashSet<Path>) -> Option<()> {
+        if !loading.insert(path.clone()) {
+            return Some(());
+        }
+        let source = self.source(uri)?;
+        // imports are parsed too so their interfaces are known
+        let imports = Parser::new().parse(path.clone(), file_name(uri), source.clone()).map_or(vec![], |program| program.imports);
+        let directory = uri.rsplit_once('/').map_or("", |(directory, _)| directory);
+        for import in imports {
+            let import_uri = format!("{}/{}.ns", directory, import.0.join("/"));
+            self.load(analysis, import, &import_uri, loading);
+        }
+        analysis.compiler.parse_module(path.clone(), file_name(uri), source);
+        analysis.uris.insert(path, uri.to_string());
+        Some(())
+    }
+
+    fn publish_diagnostics(&self, uri: &str) {
+        let Some(analysis) = self.analyze(uri) else {
+            return;
+        };
+        let diagnostics: Vec<Value> = match analysis.file_id(&analysis.path) {
+            Some(file_id) => analysis.compiler.diagnostics.messages.iter()
+                .filter(|diagnostic| diagnostic.labels.iter().any(|label| label.style == LabelStyle::Primary && label.file_id == file_id))
+                .map(|diagnostic| analysis.diagnostic(file_id, uri, diagnostic))
+                .collect(),
+            None => vec![],
+        };
+        send(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }));
+    }
+
+    /// The analysis of a document and the byte offset of a position in it.
+    fn locate(&self, params: &Value) -> Option<(Analysis, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let analysis = self.analyze(uri)?;
+        let source = analysis.source(&analysis.path)?;
+        let position = &params["position"];
+        let offset = offset(&source, position["line"].as_u64()? as usize, position["character"].as_u64()? as usize);
+        Some((analysis, offset))
+    }
+
+    fn definition(&self, params: &Value) -> Option<Value> {
+        let (analysis, offset) = self.locate(params)?;
+        let (path, span) = match analysis.reference_at(offset)?.1 {
+            Target::Local(span) => (analysis.path.clone(), span),
+            Target::Global(name) => {
+                let (path, index) = analysis.definition(&name)?;
+                let span = analysis.name_span(&path, index, &name)?;
+                (path, span)
+            }
+        };
+        let source = analysis.source(&path)?;
+        Some(json!({ "uri": analysis.uris.get(&path)?, "range": range(&source, &span) }))
+    }
+
+    fn hover(&self, params: &Value) -> Option<Value> {
+        let (analysis, offset) = self.locate(params)?;
+        let (span, target) = analysis.reference_at(offset)?;
+        let text = match target {
+            Target::Local(declaration) => analysis.describe_local(&declaration)?,
+            Target::Global(name) => analysis.describe_global(&name)?,
+        };
+        let source = analysis.source(&analysis.path)?;
+        Some(json!({
+            "contents": { "kind": "markdown", "value": format!("```neutron-star\n{}\n```", text) },
+            "range": range(&source, &span),
+        }))
+    }
+
+    fn document_symbols(&self, params: &Value) -> Option<Value> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let analysis = self.analyze(uri)?;
+        let program = analysis.compiler.programs.get(&analysis.path)?;
+        let children: HashSet<NodeIndex> = program.program_arena.node_arena.iter()
+            .flat_map(|(_, node)| match node {
+                Node::Struct { children, .. } | Node::Interface { children, .. } => children.clone(),
+                _ => vec![],
+            })
+            .collect();
+        let mut nodes: Vec<NodeIndex> = program.program_arena.node_arena.iter()
+            .map(|(index, _)| index)
+            .filter(|index| !children.contains(index))
+            .collect();
+        nodes.sort_by_key(|index| program.node_span(*index).start);
+        let symbols: Vec<Value> = nodes.into_iter().filter_map(|index| analysis.symbol(program, index)).collect();
+        Some(Value::Array(symbols))
+    }
+}
+
+impl Analysis {
+    fn program(&self, path: &Path) -> Option<&Program> {
+        self.compiler.programs.get(path)
+    }
+
+    fn module(&self, path: &Path) -> Option<&Module> {
+        self.compiler.modules.iter().map(|(_, module)| module).find(|module| module.path == *path)
+    }
+
+    fn file_id(&self, path: &Path) -> Option<FileId> {
+        self.program(path).map(|program| program.file_id)
+    }
+
+    fn source(&self, path: &Path) -> Option<String> {
+        let file_id = self.file_id(path)?;
+        self.compiler.diagnostics.files.source(file_id).ok().map(str::to_string)
+    }
+
+    fn diagnostic(&self, file_id: FileId, uri: &str, diagnostic: &Diagnostic<FileId>) -> Value {
+        let source = self.compiler.diagnostics.files.source(file_id).map_or(String::new(), str::to_string);
+        let severity = match diagnostic.severity {
+            Severity::Bug | Severity::Error => 1,
+            Severity::Warning => 2,
+            Severity::Note => 3,
+            Severity::Help => 4,
+        };
+        let mut message = diagnostic.message.clone();
+        for note in &diagnostic.notes {
+            message.push_str(&format!("\n{}", note));
+        }
+        let primary = diagnostic.labels.iter()
+            .find(|label| label.style == LabelStyle::Primary && label.file_id == file_id)
+            .map_or(0..0, |label| label.range.clone());
+        let related: Vec<Value> = diagnostic.labels.iter()
+            .filter(|label| label.style == LabelStyle::Secondary && label.file_id == file_id)
+            .map(|label| json!({ "location": { "uri": uri, "range": range(&source, &label.range) }, "message": label.message }))
+            .collect();
+        let mut value = json!({
+            "range": range(&source, &primary),
+            "severity": severity,
+            "source": env!("CARGO_PKG_NAME"),
+            "message": message,
+            "relatedInformation": related,
+        });
+        if let Some(code) = &diagnostic.code {
+            value["code"] = json!(code);
+        }
+        value
+    }
+
+    /// The innermost name at an offset in the document and what it refers to.
+    fn reference_at(&self, offset: usize) -> Option<(Span, Target)> {
+        let program = self.program(&self.path)?;
+        let source = self.source(&self.path)?;
+        Resolver::new(program, &source).resolve().into_iter()
+            .filter(|(span, _)| span.start <= offset && offset <= span.end)
+            .min_by_key(|(span, _)| span.end - span.start)
+    }
+
+    /// Where a global is declared, looking in the document and then in what it imports.
+    fn definition(&self, name: &str) -> Option<(Path, NodeIndex)> {
+        let program = self.program(&self.path)?;
+        std::iter::once(&self.path).chain(program.imports.iter())
+            .filter_map(|path| self.program(path).map(|program| (path, program)))
+            .find_map(|(path, program)| {
+                program.program_arena.node_arena.iter()
+                    .find(|(_, node)| node_name(node) == Some(name))
+                    .map(|(index, _)| (path.clone(), index))
+            })
+    }
+
+    fn name_span(&self, path: &Path, index: NodeIndex, name: &str) -> Option<Span> {
+        let program = self.program(path)?;
+        Some(name_span(&self.source(path)?, program.node_span(index), name))
+    }
+
+    /// `name: Type` with the type that was inferred, references show their capability like `&iso Int32`.
+    fn describe_local(&self, declaration: &Span) -> Option<String> {
+        let source = self.source(&self.path)?;
+        let written = source.get(declaration.clone())?.trim();
+        let name: String = written.chars().take_while(|c| c.is_alphanumeric() || *c == '_').collect();
+        let inferred = self.module(&self.path).and_then(|module| {
+            let (_, value) = module.locals.iter().find(|(span, _)| span == declaration)?;
+            let typ = module.module_arena.instruction_types.get(value)?;
+            Some(type_name(&module.module_arena, *typ)).filter(|typ| typ != "Unknown")
+        });
+        Some(match inferred {
+            Some(typ) => format!("{}: {}", name, typ),
+            None => written.to_string(),
+        })
+    }
+
+    /// The declaration as written up to its body, globals also show their type and value.
+    fn describe_global(&self, name: &str) -> Option<String> {
+        let (path, index) = self.definition(name)?;
+        let program = self.program(&path)?;
+        if let (Some(Node::Variable { .. }), Some(module)) = (program.program_arena.node_arena.get(index), self.module(&path)) {
+            let global = module.module_arena.node_arena.iter().find_map(|(_, node)| match node {
+                IrNode::Global { name: global, typ, value, .. } if global == name => Some((typ, value)),
+                _ => None,
+            });
+            if let Some((typ, value)) = global {
+                let value = value.map_or(String::new(), |value| format!(" = {}", value.to_string()));
+                return Some(format!("let {}: {}{}", name, type_name(&module.module_arena, *typ), value));
+            }
+        }
+        let source = self.source(&path)?;
+        let declaration = source.get(program.node_span(index))?;
+        let header = declaration.split(['{', ';']).next().unwrap_or(declaration);
+        Some(header.split_whitespace().collect::<Vec<_>>().join(" "))
+    }
+
+    /// A declaration and what's declared inside it, for the outline.
+    fn symbol(&self, program: &Program, index: NodeIndex) -> Option<Value> {
+        let node = program.program_arena.node_arena.get(index)?;
+        let name = node_name(node)?;
+        let source = self.source(&self.path)?;
+        let span = program.node_span(index);
+        let (kind, children): (u32, Vec<Value>) = match node {
+            Node::TypeAlias { .. } => (symbol_kind::TYPE_PARAMETER, vec![]),
+            Node::Variable { .. } => (symbol_kind::CONSTANT, vec![]),
+            Node::Function(_) | Node::FunctionPrototype { .. } => (symbol_kind::FUNCTION, vec![]),
+            Node::Struct { kind: StructKind::Struct, children, .. } => (symbol_kind::STRUCT, self.members(program, children)),
+            Node::Struct { kind: StructKind::Actor, children, .. } => (symbol_kind::CLASS, self.members(program, children)),
+            Node::Interface { children, .. } => (symbol_kind::INTERFACE, self.members(program, children)),
+            Node::Enum { variants, .. } => {
+                let variants = variants.iter().map(|variant| {
+                    let name_range = range(&source, &name_span(&source, variant.span.clone(), &variant.name));
+                    json!({ "name": variant.name, "kind": symbol_kind::ENUM_MEMBER, "range": name_range, "selectionRange": name_range })
+                }).collect();
+                (symbol_kind::ENUM, variants)
+            }
+            Node::Error => return None,
+        };
+        Some(json!({
+            "name": name,
+            "detail": node.kind_name(),
+            "kind": kind,
+            "range": range(&source, &span),
+            "selectionRange": range(&source, &name_span(&source, span.clone(), name)),
+            "children": children,
+        }))
+    }
+
+    /// Fields and methods of a struct, actor or interface.
+    fn members(&self, program: &Program, children: &[NodeIndex]) -> Vec<Value> {
+        children.iter().filter_map(|child| {
+            let mut member = self.symbol(program, *child)?;
+            if let Node::Variable { .. } = program.program_arena.node_arena.get(*child)? {
+                member["kind"] = json!(symbol_kind::FIELD);
+                member["detail"] = json!("field");
+            } else {
+                member["kind"] = json!(symbol_kind::METHOD);
+            }
+            Some(member)
+        }).collect()
+    }
+}
+
+/// Finds every name in a program and what it refers to, scoping locals the way the checkers do.
+struct Resolver<'a> {
+    program: &'a Program,
+    source: &'a str,
+    scopes: Vec<Vec<(String, Span)>>,
+    references: Vec<(Span, Target)>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(program: &'a Program, source: &'a str) -> Self {
+        Self {
+            program,
+            source,
+            scopes: vec![],
+            references: vec![],
+        }
+    }
+
+    fn resolve(mut self) -> Vec<(Span, Target)> {
+        for (index, node) in self.program.program_arena.node_arena.iter() {
+            if let Some(name) = node_name(node) {
+                let span = name_span(self.source, self.program.node_span(index), name);
+                self.references.push((span, Target::Global(name.to_string())));
+            }
+            match node {
+                Node::Function(func) => {
+                    self.scopes.push(vec![]);
+                    self.declare_all(&func.params);
+                    self.statements(&func.statements);
+                    self.scopes.pop();
+                }
+                Node::Variable { value: Some(value), .. } => self.expression(*value),
+                _ => {}
+            }
+        }
+        self.references
+    }
+
+    fn declare(&mut self, name: &str, span: Span) {
+        self.references.push((span.clone(), Target::Local(span.clone())));
+        self.scopes.last_mut().unwrap().push((name.to_string(), span));
+    }
+
+    fn declare_all(&mut self, names: &[TypedName]) {
+        for name in names {
+            self.declare(&name.name, name.span.clone());
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Target {
+        self.scopes.iter().rev().flat_map(|scope| scope.iter().rev())
+            .find(|(local, _)| local == name)
+            .map_or(Target::Global(name.to_string()), |(_, span)| Target::Local(span.clone()))
+    }
+
+    fn block(&mut self, body: &[StatementIndex]) {
+        self.scopes.push(vec![]);
+        self.statements(body);
+        self.scopes.pop();
+    }
+
+    fn statements(&mut self, body: &[StatementIndex]) {
+        for statement in body {
+            self.statement(*statement);
+        }
+    }
+
+    fn statement(&mut self, index: StatementIndex) {
+        use Statement::*;
+        let span = self.program.statement_span(index);
+        match self.program.statement(index) {
+            If { condition, body, else_if } => {
+                self.expression(*condition);
+                self.block(body);
+                if let Some(else_if) = else_if {
+                    self.statement(*else_if);
+                }
+            }
+            Call { function, args } => {
+                self.expression(*function);
+                for arg in args {
+                    self.expression(*arg);
+                }
+            }
+            Let { name, value } => {
+                self.expression(*value);
+                self.declare(&name.name, name.span.clone());
+            }
+            Assign { name, value } => {
+                // assignments start with the name
+                self.references.push((span.start..span.start + name.len(), self.lookup(name)));
+                self.expression(*value);
+            }
+            Store { pointer, value } => {
+                self.expression(*pointer);
+                self.expression(*value);
+            }
+            Return { value } => self.expression(*value),
+            Unsafe { body } => self.block(body),
+            While { condition, body } => {
+                self.expression(*condition);
+                self.block(body);
+            }
+            For { variable, start, end, body } => {
+                self.expression(*start);
+                self.expression(*end);
+                self.scopes.push(vec![]);
+                self.declare(variable, name_span(self.source, span, variable));
+                self.statements(body);
+                self.scopes.pop();
+            }
+            Break | Continue | Error => {}
+        }
+    }
+
+    fn expression(&mut self, index: ExpressionIndex) {
+        use Expression::*;
+        match self.program.expression(index) {
+            Ref(name) => {
+                let target = self.lookup(name);
+                self.references.push((self.program.expression_span(index), target));
+            }
+            NatLiteral(..) | FloatLiteral(..) | StringLiteral(_) | CharLiteral(_) | BoolLiteral(_) | Error => {}
+            BinOp(lhs, _, rhs) => {
+                self.expression(*lhs);
+                self.expression(*rhs);
+            }
+            UnOp(_, value) | Cast { value, .. } | Borrow { value } | Unsafe { value } => self.expression(*value),
+            // the field is a name in the aggregate, not in scope
+            FieldAccessor { aggregate, .. } => self.expression(*aggregate),
+            FunctionCall { function, args } => {
+                self.expression(*function);
+                for arg in args {
+                    self.expression(*arg);
+                }
+            }
+            New { allocator, .. } => self.expression(*allocator),
+            Dereference { pointer } => self.expression(*pointer),
+            Denull { optional } => self.expression(*optional),
+            Lambda { params, body, .. } => {
+                self.scopes.push(vec![]);
+                self.declare_all(params);
+                self.statements(body);
+                self.scopes.pop();
+            }
+        }
+    }
+}
+
+fn node_name(node: &Node) -> Option<&str> {
+    match node {
+        Node::TypeAlias { name, .. } | Node::FunctionPrototype { name, .. } | Node::Struct { name, .. }
+        | Node::Enum { name, .. } | Node::Interface { name, .. } => Some(name),
+        Node::Variable { name, .. } => Some(&name.name),
+        Node::Function(func) => Some(&func.name),
+        Node::Error => None,
+    }
+}
+
+/// Where a name is first written as a whole word inside a span, or the whole span when it isn't.
+fn name_span(source: &str, span: Span, name: &str) -> Span {
+    let is_name = |c: char| c.is_alphanumeric() || c == '_';
+    let text = source.get(span.clone()).unwrap_or_default();
+    text.match_indices(name)
+        .find(|(start, _)| {
+            !text[..*start].ends_with(is_name) && !text[start + name.len()..].starts_with(is_name)
+        })
+        .map_or(span.clone(), |(start, _)| span.start + start..span.start + start + name.len())
+}
+
+/// LSP positions count lines and UTF-16 code units from 0.
+fn position(source: &str, offset: usize) -> Value {
+    let before = &source[..offset.min(source.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    let line = before.matches('\n').count();
+    let character: usize = before[line_start..].chars().map(char::len_utf16).sum();
+    json!({ "line": line, "character": character })
+}
+
+fn range(source: &str, span: &Span) -> Value {
+    json!({ "start": position(source, span.start), "end": position(source, span.end) })
+}
+
+fn offset(source: &str, line: usize, character: usize) -> usize {
+    let line_start = source.split_inclusive('\n').take(line).map(str::len).sum::<usize>();
+    let mut units = 0;
+    for (index, c) in source[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + index;
+        }
+        units += c.len_utf16();
+    }
+    source.len()
+}
+
+/// Only `file` uris are supported, with `%XX` escapes.
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let mut bytes = vec![];
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match (byte, tail.get(..2).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+fn file_name(uri: &str) -> String {
+    let path = uri_to_path(uri);
+    path.rsplit('/').next().unwrap_or(&path).to_string()
+}
+
+/// Messages have a `Content-Length` header then a blank line, `None` at the end of the input.
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    Some(serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+fn send(message: &Value) {
+    let body = message.to_string();
+    let mut stdout = std::io::stdout().lock();
+    let _ = write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = stdout.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const LIB: &str = "public let LIMIT: Int32 = 2 * 5;\n\npublic fun clamp(x: Int32): Int32 {\n    return x;\n}\n";
+    const APP: &str = "import lib\n\nstruct Point {\n    let x: Int32;\n}\n\nfun main(n: Int32): Int32 {\n    let total = n + LIMIT;\n    return clamp(total);\n}\n";
+
+    /// A server with both modules open in the editor.
+    fn server() -> LanguageServer {
+        let mut server = LanguageServer::new(CompilerOptions::default());
+        server.documents.insert("file:///project/lib.ns".to_string(), LIB.to_string());
+        server.documents.insert("file:///project/app.ns".to_string(), APP.to_string());
+        server
+    }
+
+    fn at(line: u32, character: u32) -> Value {
+        json!({ "textDocument": { "uri": "file:///project/app.ns" }, "position": { "line": line, "character": character } })
+    }
+
+    fn range(start: (u32, u32), end: (u32, u32)) -> Value {
+        json!({ "start": { "line": start.0, "character": start.1 }, "end": { "line": end.0, "character": end.1 } })
+    }
+
+    #[test]
+    fn definition() {
+        let mut server = server();
+        let definition = |server: &mut LanguageServer, line, character| server.request("textDocument/definition", &at(line, character)).unwrap();
+        // `total` in `clamp(total)`
+        assert_eq!(definition(&mut server, 8, 18), json!({ "uri": "file:///project/app.ns", "range": range((7, 8), (7, 13)) }));
+        assert_eq!(definition(&mut server, 8, 12), json!({ "uri": "file:///project/lib.ns", "range": range((2, 11), (2, 16)) }));
+        assert_eq!(definition(&mut server, 7, 20), json!({ "uri": "file:///project/lib.ns", "range": range((0, 11), (0, 16)) }));
+        // `+` isn't a name
+        assert_eq!(definition(&mut server, 7, 18), Value::Null);
+    }
+
+    #[test]
+    fn hover() {
+        let mut server = server();
+        let mut hover = |line, character| server.request("textDocument/hover", &at(line, character)).unwrap()["contents"]["value"].clone();
+        assert_eq!(hover(7, 10), "```neutron-star\ntotal: Int32\n```");
+        assert_eq!(hover(8, 12), "```neutron-star\npublic fun clamp(x: Int32): Int32\n```");
+        // globals show the value they were evaluated to
+        assert_eq!(hover(7, 20), "```neutron-star\nlet LIMIT: Int32 = 10\n```");
+    }
+
+    #[test]
+    fn document_symbols() {
+        let mut server = server();
+        let symbols = server.request("textDocument/documentSymbol", &json!({ "textDocument": { "uri": "file:///project/app.ns" } })).unwrap();
+        assert_eq!(symbols, json!([
+            {
+                "name": "Point",
+                "detail": "struct",
+                "kind": symbol_kind::STRUCT,
+                "range": range((2, 0), (4, 1)),
+                "selectionRange": range((2, 7), (2, 12)),
+                "children": [{
+                    "name": "x",
+                    "detail": "field",
+                    "kind": symbol_kind::FIELD,
+                    "range": range((3, 4), (3, 17)),
+                    "selectionRange": range((3, 8), (3, 9)),
+                    "children": [],
+                }],
+            },
+            {
+                "name": "main",
+                "detail": "function",
+                "kind": symbol_kind::FUNCTION,
+                "range": range((6, 0), (9, 1)),
+                "selectionRange": range((6, 4), (6, 8)),
+                "children": [],
+            },
+        ]));
+    }
+
+    #[test]
+    fn positions_count_utf16_units() {
+        let source = "let s = \"\u{1F600}\";\nlet t = 1;\n";
+        // the emoji is four bytes but two utf-16 units
+        let quote = source.find('\u{1F600}').unwrap() + 4;
+        assert_eq!(position(source, quote), json!({ "line": 0, "character": 11 }));
+        assert_eq!(offset(source, 0, 11), quote);
+        assert_eq!(offset(source, 1, 4), source.find("t =").unwrap());
+        // past the end of a line is the end of that line
+        assert_eq!(offset(source, 0, 100), source.find('\n').unwrap());
+    }
+
+    #[test]
+    fn uris() {
+        assert_eq!(uri_to_path("file:///home/me/my%20project/app.ns"), "/home/me/my project/app.ns");
+        assert_eq!(file_name("file:///home/me/my%20project/app.ns"), "app.ns");
+    }
+}
@@ -9,18 +9,52 @@ use mlir_sys::*;
 use crate::{
     compiler::*,
     lang::*,
-    ir::print::*,
+    ir::{bindings::BindingWriter, print::*},
 };
 
 mod ast;
+mod check;
 mod parser;
 mod ir;
 mod compiler;
 mod diagnostic;
+mod format;
 mod lang;
+mod lsp;
 mod mlir;
+mod options;
+mod target;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("explain") {
+        std::process::exit(explain(args.get(1).map(String::as_str)));
+    }
+    if args.first().map(String::as_str) == Some("fmt") {
+        std::process::exit(format::run(&args[1..]));
+    }
+    if args.first().map(String::as_str) == Some("lsp") {
+        match options::CompilerOptions::from_args(args[1..].iter().cloned()) {
+            Ok(options) => std::process::exit(lsp::LanguageServer::new(options).run()),
+            Err(error) => {
+                eprintln!("error: {}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let options = match options::CompilerOptions::from_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            eprintln!("usage: neutron-star [--debug | --release] [--overflow=trap|wrap] [--target=<triple>] [--diagnostics-format=human|json|sarif] [--allow|--warn|--deny=<lint>] [--emit=mlir,c-header,zig] [file.ns...]");
+            eprintln!("       neutron-star explain [NS0001]");
+            eprintln!("       neutron-star fmt [--check] [file.ns...]");
+            eprintln!("       neutron-star lsp [options]");
+            std::process::exit(1);
+        }
+    };
+
     let test_file = "test.ns";
     let test_source = "\
     actor Foo {
@@ -143,6 +177,7 @@ fn main() {
         }
     }
 
+    @tailrec
     fun loop(n, i, c, f) {
         if i < n {
             return loop(n, i + 1, f(i, c), f);
@@ -161,9 +196,64 @@ fn main() {
 
     ".to_string();
 
-    let mut compiler = Compiler::new();
-    compiler.parse_module(Path::of("test"), test_file.to_string(), test_source);
-    for (_index, module) in compiler.modules.iter() {
+    let mut sources = vec![];
+    for input in &options.inputs {
+        match std::fs::read_to_string(input) {
+            Ok(source) => {
+                sources.push((input.clone(), source));
+            }
+            Err(error) => {
+                eprintln!("error: can't read `{}`: {}", input, error);
+                std::process::exit(1);
+            }
+        }
+    }
+    if sources.is_empty() {
+        sources.push((test_file.to_string(), test_source));
+    }
+
+    let mut compiler = Compiler::with_options(options);
+    for (file_name, source) in sources {
+        // the path is kept as given for diagnostics, only its last component names the module
+        let module_name = std::path::Path::new(&file_name).file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split('.').next())
+            .unwrap_or("main")
+            .to_string();
+        compiler.parse_module(Path::of(&module_name), file_name, source);
+    }
+    compiler.compile();
+    compiler.diagnostics.emit(compiler.options.diagnostics_format);
+
+    let emit = compiler.options.emit.clone();
+    if emit.contains(&options::Emit::CHeader) || emit.contains(&options::Emit::Zig) {
+        if compiler.diagnostics.has_errors() {
+            std::process::exit(1);
+        }
+        for (_, module) in compiler.modules.iter() {
+            let writer = BindingWriter::new(module, &compiler.options.target);
+            let stem = writer.file_stem();
+            let mut outputs = vec![];
+            if emit.contains(&options::Emit::CHeader) {
+                outputs.push((format!("{}.h", stem), BindingWriter::new(module, &compiler.options.target).c_header()));
+            }
+            if emit.contains(&options::Emit::Zig) {
+                outputs.push((format!("{}.zig", stem), writer.zig()));
+            }
+            for (file_name, contents) in outputs {
+                if let Err(error) = std::fs::write(&file_name, contents) {
+                    eprintln!("error: can't write `{}`: {}", file_name, error);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    if !emit.contains(&options::Emit::Mlir) {
+        return;
+    }
+
+    let context = mlir::create_context();
+    for (index, module) in compiler.modules.iter() {
         println!("Parsed nodes: {}", module.module_arena.node_arena.len());
         println!("Parsed blocks: {}", module.module_arena.block_arena.len());
         println!("Parsed instructions: {}", module.module_arena.instruction_arena.len());
@@ -176,11 +266,40 @@ fn main() {
         let mut printer = IrPrintManager::new();
         printer.print(&module);
         println!("{}", printer.to_string());
+
+        if compiler.diagnostics.has_errors() {
+            continue;
+        }
+        match compiler.create_mlir_module(&context, index) {
+            Ok(mlir_module) => mlir_module.as_operation().dump(),
+            Err(error) => println!("MLIR lowering failed: {}", error),
+        }
     }
 
     println!("parse complete!")
 }
 
+/// Print the long explanation of an error code, or list every code when there isn't one.
+fn explain(code: Option<&str>) -> i32 {
+    use diagnostic::codes;
+    let Some(code) = code else {
+        for entry in codes::REGISTRY {
+            println!("{}  {}", entry.code, entry.title);
+        }
+        return 0;
+    };
+    match codes::lookup(code) {
+        Some(entry) => {
+            println!("{}: {}\n\n{}", entry.code, entry.title, entry.explanation);
+            0
+        }
+        None => {
+            eprintln!("error: `{}` isn't an error code, run `neutron-star explain` to list them", code);
+            1
+        }
+    }
+}
+
 fn _main() {
     println!("Hello, world!");
 
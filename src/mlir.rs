@@ -1,40 +1,1407 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
 use melior::{
     Context,
-    dialect::{arith, DialectRegistry, func, index},
-    ir::{*, attribute::*, r#type::{FunctionType, MemRefType, IntegerType, RankedTensorType}, operation::*},
+    dialect::{arith, cf, func, DialectRegistry},
+    ir::{*, attribute::*, r#type::{FunctionType, IntegerType}, operation::*},
     utility::register_all_dialects,
     pass::{self, PassManager},
 };
-use mlir_sys::*;
 
 use generational_arena::Index;
+use crate::ast::{Annotation, BinOpType, UnOpType};
 use crate::compiler::Compiler;
+use crate::target::{Piece, StructPassing, TargetInfo};
+use crate::ir::translate::EXPORT;
+use crate::ir::{self, Access, ConstValue, Overflow, IrBlockIndex, IrFunction, IrInstruction, IrInstructionIndex, IrNode, IrType, IrTypeIndex, ModuleArena};
+
+/// Create a context with every dialect the lowering can produce.
+pub fn create_context() -> Context {
+    let registry = DialectRegistry::new();
+    register_all_dialects(&registry);
+
+    let context = Context::new();
+    context.append_dialect_registry(&registry);
+    context.load_all_available_dialects();
+    context
+}
+
+/// Run the conversions that take our output down to the llvm dialect.
+pub fn lower_to_llvm(context: &Context, module: &mut Module) -> Result<(), String> {
+    let pass_manager = PassManager::new(context);
+    pass_manager.add_pass(pass::conversion::create_arith_to_llvm());
+    pass_manager.add_pass(pass::conversion::create_control_flow_to_llvm());
+    pass_manager.add_pass(pass::conversion::create_func_to_llvm());
+    pass_manager.add_pass(pass::conversion::create_index_to_llvm_pass());
+    // closures cast function values to pointers before func is lowered
+    pass_manager.add_pass(pass::conversion::create_reconcile_unrealized_casts());
+    pass_manager.run(module).map_err(|error| error.to_string())
+}
 
 impl Compiler {
-    pub fn create_mlir_module(&mut self, module_index: Index) {
-        if let Some(module) = self.modules.get(module_index) {
-            s
+    pub fn create_mlir_module<'c>(&self, context: &'c Context, module_index: Index) -> Result<Module<'c>, String> {
+        let module = self.modules.get(module_index).ok_or("unknown module".to_string())?;
+        MlirBuilder::new(context, module, &self.options.target, self.options.overflow).build()
+    }
+}
+
+struct MlirBuilder<'c, 'm> {
+    context: &'c Context,
+    module: &'m ir::Module,
+    location: Location<'c>,
+    functions: HashMap<String, &'m IrFunction>,
+    /// Functions following the C calling convention, `extern "C"` and exported ones and the public ones in the C header.
+    c_functions: HashSet<&'m str>,
+    /// Globals holding the bytes of string literals.
+    strings: Vec<(String, &'m str)>,
+    string_globals: HashMap<IrInstructionIndex, String>,
+    target: &'m TargetInfo,
+    /// What plain `+`, `-` and `*` do on overflow.
+    overflow: Overflow,
+}
+
+impl<'c, 'm> MlirBuilder<'c, 'm> {
+    fn new(context: &'c Context, module: &'m ir::Module, target: &'m TargetInfo, overflow: Overflow) -> Self {
+        let mut functions = HashMap::new();
+        let mut c_functions = HashSet::new();
+        for (_, node) in module.module_arena.node_arena.iter() {
+            if let IrNode::Function(func) | IrNode::ExternFunction(func) = node {
+                functions.insert(func.name.clone(), func);
+            }
+            match node {
+                IrNode::ExternFunction(func) => {
+                    c_functions.insert(func.name.as_str());
+                }
+                IrNode::Function(func) if matches!(func.access, Access::Public) || Annotation::find(&func.annotations, EXPORT).is_some() => {
+                    c_functions.insert(func.name.as_str());
+                }
+                _ => {}
+            }
+        }
+
+        let mut strings = vec![];
+        let mut string_globals = HashMap::new();
+        for (index, ins) in module.module_arena.instruction_arena.iter() {
+            if let IrInstruction::StringLiteral(value) = ins {
+                let name = format!("str{}", strings.len());
+                strings.push((name.clone(), value.as_str()));
+                string_globals.insert(index, name);
+            }
+        }
+
+        Self {
+            context,
+            module,
+            location: Location::unknown(context),
+            functions,
+            c_functions,
+            strings,
+            string_globals,
+            target,
+            overflow,
+        }
+    }
+
+    fn build(&self) -> Result<Module<'c>, String> {
+        let body = Region::new();
+        body.append_block(Block::new(&[]));
+        let mlir_module = Module::from_operation(OperationBuilder::new("builtin.module", self.location)
+            .add_attributes(&[
+                (Identifier::new(self.context, "llvm.data_layout"), StringAttribute::new(self.context, &self.target.data_layout()).into()),
+                (Identifier::new(self.context, "llvm.target_triple"), StringAttribute::new(self.context, &self.target.triple).into()),
+            ])
+            .add_regions(vec![body])
+            .build())
+            .ok_or("couldn't create the module")?;
+        for (name, value) in self.strings.iter() {
+            mlir_module.body().append_operation(self.build_string(name, value));
+        }
+        let makes_closures = self.arena().instruction_arena.iter()
+            .any(|(_, ins)| matches!(ins, IrInstruction::MakeClosure { .. }));
+        // the program can declare these itself with `extern "C"`, a second declaration wouldn't verify
+        if makes_closures && !self.functions.contains_key("malloc") {
+            mlir_module.body().append_operation(self.declare_function("malloc", &[self.size_type()], self.pointer_type()));
+        }
+        let mut helpers = BTreeMap::new();
+        for (index, ins) in self.arena().instruction_arena.iter() {
+            if let Some((name, params, result)) = self.runtime_helper(index, ins) {
+                helpers.insert(name, (params, result));
+            }
+        }
+        for (name, (params, result)) in helpers {
+            if self.functions.contains_key(name) {
+                continue;
+            }
+            mlir_module.body().append_operation(self.declare_function(name, &params, result));
+        }
+        for (_, node) in self.module.module_arena.node_arena.iter() {
+            match node {
+                IrNode::Function(func) => {
+                    let operation = self.build_function(func)?;
+                    mlir_module.body().append_operation(operation);
+                }
+                IrNode::ExternFunction(func) => {
+                    let mut attributes = vec![(Identifier::new(self.context, "sym_visibility"), StringAttribute::new(self.context, "private").into())];
+                    attributes.extend(self.c_abi_attributes(func));
+                    mlir_module.body().append_operation(func::func(
+                        self.context,
+                        StringAttribute::new(self.context, &func.name),
+                        TypeAttribute::new(self.function_type(func).into()),
+                        Region::new(),
+                        &attributes,
+                        self.location,
+                    ));
+                }
+                IrNode::Global { access, name, typ, value: Some(value), .. } => {
+                    mlir_module.body().append_operation(self.build_global(*access, name, *typ, value)?);
+                }
+                _ => {}
+            }
+        }
+        Ok(mlir_module)
+    }
+
+    fn arena(&self) -> &'m ModuleArena {
+        &self.module.module_arena
+    }
+
+    /// Types we can't lower yet are treated as machine words.
+    fn default_type(&self) -> Type<'c> {
+        IntegerType::new(self.context, 64).into()
+    }
+
+    fn pointer_type(&self) -> Type<'c> {
+        Type::parse(self.context, "!llvm.ptr").unwrap()
+    }
+
+    /// Lower an ir type, `None` is returned for `Void`.
+    fn convert_type(&self, index: IrTypeIndex) -> Option<Type<'c>> {
+        use IrType::*;
+        match self.arena().type_arena.get(index)? {
+            Bool => Some(IntegerType::new(self.context, 1).into()),
+            Int(int) => Some(IntegerType::new(self.context, int.bits(self.target)).into()),
+            UInt(uint) => Some(IntegerType::new(self.context, uint.bits(self.target)).into()),
+            Float(float) => Type::parse(self.context, &format!("f{}", float.bits())),
+            Reference(_, _, _) => Some(self.pointer_type()),
+            // closures are pointers to their environment
+            Function(_, _) => Some(self.pointer_type()),
+            // llvm lays structs out the same way, enums aren't lowered yet
+            Base(name) => match self.arena().declaration(name) {
+                Some(IrNode::Struct { fields, .. }) => {
+                    let fields: Vec<String> = fields.iter()
+                        .map(|field| self.convert_type(field.typ).unwrap_or(self.default_type()).to_string())
+                        .collect();
+                    Type::parse(self.context, &format!("!llvm.struct<({})>", fields.join(", ")))
+                }
+                _ => Some(self.default_type()),
+            },
+            // the value and whether it's there
+            Optional(inner) => {
+                let inner = self.convert_type(*inner).unwrap_or(self.default_type());
+                Type::parse(self.context, &format!("!llvm.struct<({}, i1)>", inner))
+            }
+            Void => None,
+            _ => Some(self.default_type()),
+        }
+    }
+
+    /// Closure environments start with the code pointer, the captures come after it.
+    fn env_type(&self, captures: &[Type<'c>]) -> Type<'c> {
+        let mut fields = vec![self.pointer_type().to_string()];
+        fields.extend(captures.iter().map(|typ| typ.to_string()));
+        Type::parse(self.context, &format!("!llvm.struct<({})>", fields.join(", "))).unwrap()
+    }
+
+    /// A private constant holding the bytes of a string and its nul terminator.
+    fn build_string(&self, name: &str, value: &str) -> Operation<'c> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        let escaped: String = bytes.iter().map(|byte| format!("\\{:02X}", byte)).collect();
+        let array_type = Type::parse(self.context, &format!("!llvm.array<{} x i8>", bytes.len())).unwrap();
+        OperationBuilder::new("llvm.mlir.global", self.location)
+            .add_attributes(&[
+                (Identifier::new(self.context, "sym_name"), StringAttribute::new(self.context, name).into()),
+                (Identifier::new(self.context, "global_type"), TypeAttribute::new(array_type).into()),
+                (Identifier::new(self.context, "linkage"), Attribute::parse(self.context, "#llvm.linkage<private>").unwrap()),
+                (Identifier::new(self.context, "constant"), Attribute::unit(self.context)),
+                (Identifier::new(self.context, "value"), Attribute::parse(self.context, &format!("\"{}\"", escaped)).unwrap()),
+            ])
+            .add_regions(vec![Region::new()])
+            .build()
+    }
+
+    /// A module level `let`, its value was worked out when the ir was built.
+    /// Uses of it in this module are the value itself, the global is there for other modules.
+    fn build_global(&self, access: Access, name: &str, typ: IrTypeIndex, value: &ConstValue) -> Result<Operation<'c>, String> {
+        let global_type = self.convert_type(typ).ok_or(format!("global `{}` has no type", name))?;
+        let parse = |text: String| Attribute::parse(self.context, &text).ok_or(format!("bad value for global `{}`", name));
+        let value = match value {
+            ConstValue::Int(value) => parse(format!("{} : {}", value, global_type))?,
+            ConstValue::UInt(value) => parse(format!("{} : {}", value, global_type))?,
+            // the attribute syntax needs a `.` in whole numbers and has no spelling for NaN, so floats are built directly
+            ConstValue::Float(value) => FloatAttribute::new(self.context, *value, global_type).into(),
+            ConstValue::Bool(value) => parse(value.to_string())?,
+        };
+        Ok(OperationBuilder::new("llvm.mlir.global", self.location)
+            .add_attributes(&[
+                (Identifier::new(self.context, "sym_name"), StringAttribute::new(self.context, name).into()),
+                (Identifier::new(self.context, "global_type"), TypeAttribute::new(global_type).into()),
+                (Identifier::new(self.context, "linkage"), Attribute::parse(self.context, &format!("#llvm.linkage<{}>", Self::linkage(access, name))).unwrap()),
+                (Identifier::new(self.context, "constant"), Attribute::unit(self.context)),
+                (Identifier::new(self.context, "value"), value),
+            ])
+            .add_regions(vec![Region::new()])
+            .build())
+    }
+
+    /// `size_t`, as wide as a pointer.
+    fn size_type(&self) -> Type<'c> {
+        IntegerType::new(self.context, self.target.pointer_width).into()
+    }
+
+    /// Declare a function from libc or our runtime so calls to it resolve when linking.
+    fn declare_function(&self, name: &str, params: &[Type<'c>], result: Type<'c>) -> Operation<'c> {
+        let function_type = FunctionType::new(self.context, params, &[result]);
+        func::func(
+            self.context,
+            StringAttribute::new(self.context, name),
+            TypeAttribute::new(function_type.into()),
+            Region::new(),
+            &[(Identifier::new(self.context, "sym_visibility"), StringAttribute::new(self.context, "private").into())],
+            self.location,
+        )
+    }
+
+    /// Pointer to a field of a closure environment, field 0 is the code pointer.
+    fn env_field<'a>(&self, block: &'a BlockRef<'c, 'a>, env: Value<'c, 'a>, env_type: Type<'c>, field: usize) -> Value<'c, 'a> {
+        let indices = Attribute::parse(self.context, &format!("array<i32: 0, {}>", field)).unwrap();
+        block.append_operation(OperationBuilder::new("llvm.getelementptr", self.location)
+            .add_attributes(&[
+                (Identifier::new(self.context, "rawConstantIndices"), indices),
+                (Identifier::new(self.context, "elem_type"), TypeAttribute::new(env_type).into()),
+            ])
+            .add_operands(&[env])
+            .add_results(&[self.pointer_type()])
+            .build())
+            .result(0).unwrap().into()
+    }
+
+    /// Bytes a value of this type takes up, LLVM lays it out so this matches every access to it.
+    /// It's the address of the second element of an array of them starting at null.
+    fn size_of<'a>(&self, block: &'a BlockRef<'c, 'a>, typ: Type<'c>) -> Value<'c, 'a> {
+        let zero = self.int_constant(block, 0, self.size_type());
+        let null: Value = block.append_operation(OperationBuilder::new("llvm.inttoptr", self.location)
+            .add_operands(&[zero])
+            .add_results(&[self.pointer_type()])
+            .build())
+            .result(0).unwrap().into();
+        let indices = Attribute::parse(self.context, "array<i32: 1>").unwrap();
+        let end: Value = block.append_operation(OperationBuilder::new("llvm.getelementptr", self.location)
+            .add_attributes(&[
+                (Identifier::new(self.context, "rawConstantIndices"), indices),
+                (Identifier::new(self.context, "elem_type"), TypeAttribute::new(typ).into()),
+            ])
+            .add_operands(&[null])
+            .add_results(&[self.pointer_type()])
+            .build())
+            .result(0).unwrap().into();
+        block.append_operation(OperationBuilder::new("llvm.ptrtoint", self.location)
+            .add_operands(&[end])
+            .add_results(&[self.size_type()])
+            .build())
+            .result(0).unwrap().into()
+    }
+
+    fn cast<'a>(&self, block: &'a BlockRef<'c, 'a>, value: Value<'c, 'a>, typ: Type<'c>) -> Value<'c, 'a> {
+        block.append_operation(OperationBuilder::new("builtin.unrealized_conversion_cast", self.location)
+            .add_operands(&[value])
+            .add_results(&[typ])
+            .build())
+            .result(0).unwrap().into()
+    }
+
+    /// The type of a function in this module, closures take their environment first.
+    fn function_type(&self, func: &IrFunction) -> FunctionType<'c> {
+        let (param_types, return_types) = self.signature(func);
+        FunctionType::new(self.context, &param_types, &return_types)
+    }
+
+    /// Param and result types of a function, C functions take and return structs the way C does.
+    fn signature(&self, func: &IrFunction) -> (Vec<Type<'c>>, Vec<Type<'c>>) {
+        let mut param_types = vec![];
+        let return_types = match self.struct_passing(func, func.return_type, true) {
+            Some(StructPassing::Registers(pieces)) => vec![self.pieces_type(&pieces)],
+            Some(StructPassing::Memory { .. }) => {
+                param_types.push(self.pointer_type());
+                vec![]
+            }
+            None => self.convert_type(func.return_type).into_iter().collect(),
+        };
+        for param in &func.params {
+            param_types.push(match self.struct_passing(func, param.typ, false) {
+                Some(StructPassing::Registers(pieces)) => self.pieces_type(&pieces),
+                Some(StructPassing::Memory { .. }) => self.pointer_type(),
+                None => self.convert_type(param.typ).unwrap_or(self.default_type()),
+            });
+        }
+        (param_types, return_types)
+    }
+
+    /// How a param or result of a C function is passed when it's a struct, `None` when it's passed as it is.
+    fn struct_passing(&self, func: &IrFunction, typ: IrTypeIndex, result: bool) -> Option<StructPassing> {
+        if !self.c_functions.contains(func.name.as_str()) || !self.target.is_c_compatible(self.arena(), typ) {
+            return None;
+        }
+        self.target.struct_passing(self.arena(), typ, result)
+    }
+
+    /// What the pieces of a struct are loaded as to go in registers, a single piece is passed as it is.
+    fn pieces_type(&self, pieces: &[Piece]) -> Type<'c> {
+        let names: Vec<String> = pieces.iter().map(|piece| match piece {
+            Piece::Int(bits) => format!("i{}", bits),
+            Piece::Float(bits) => format!("f{}", bits),
+            Piece::FloatPair => "vector<2xf32>".to_string(),
+        }).collect();
+        let text = match &names[..] {
+            [one] => one.clone(),
+            [first, ..] if names.iter().all(|name| name == first) => format!("!llvm.array<{} x {}>", names.len(), first),
+            _ => format!("!llvm.struct<({})>", names.join(", ")),
+        };
+        Type::parse(self.context, &text).unwrap()
+    }
+
+    /// Size and alignment of the memory a struct is moved between itself and its pieces through,
+    /// the last piece can run into padding or past the end.
+    fn pieces_slot(&self, typ: IrTypeIndex, pieces: &[Piece]) -> (u64, u64) {
+        let layout = TargetInfo::layout(pieces.iter().map(|piece| (piece.size(), piece.size())));
+        (self.target.size_of(self.arena(), typ).max(layout.size), self.target.align_of(self.arena(), typ).max(layout.align))
+    }
+
+    /// Stack memory for the function being built. It goes at the start of the entry block,
+    /// so it's made once per call even when the block using it is in a loop.
+    fn alloca<'a>(&self, entry: &'a BlockRef<'c, 'a>, size: u64, align: u64) -> Value<'c, 'a> {
+        let i64_type: Type<'c> = IntegerType::new(self.context, 64).into();
+        let count = entry.insert_operation(0, arith::constant(self.context, IntegerAttribute::new(1, i64_type).into(), self.location));
+        let bytes = Type::parse(self.context, &format!("!llvm.array<{} x i8>", size)).unwrap();
+        entry.insert_operation(1, OperationBuilder::new("llvm.alloca", self.location)
+            .add_attributes(&[
+                (Identifier::new(self.context, "elem_type"), TypeAttribute::new(bytes).into()),
+                (Identifier::new(self.context, "alignment"), IntegerAttribute::new(align as i64, i64_type).into()),
+            ])
+            .add_operands(&[count.result(0).unwrap().into()])
+            .add_results(&[self.pointer_type()])
+            .build())
+            .result(0).unwrap().into()
+    }
+
+    fn load<'a>(&self, block: &'a BlockRef<'c, 'a>, pointer: Value<'c, 'a>, typ: Type<'c>) -> Value<'c, 'a> {
+        block.append_operation(OperationBuilder::new("llvm.load", self.location)
+            .add_operands(&[pointer])
+            .add_results(&[typ])
+            .build())
+            .result(0).unwrap().into()
+    }
+
+    fn store<'a>(&self, block: &'a BlockRef<'c, 'a>, value: Value<'c, 'a>, pointer: Value<'c, 'a>) {
+        block.append_operation(OperationBuilder::new("llvm.store", self.location)
+            .add_operands(&[value, pointer])
+            .build());
+    }
+
+    /// Reload the bytes of a value as another type, the way C compilers move structs in and out of registers.
+    fn reinterpret<'a>(&self, entry: &'a BlockRef<'c, 'a>, block: &'a BlockRef<'c, 'a>, value: Value<'c, 'a>, typ: Type<'c>, slot: (u64, u64)) -> Value<'c, 'a> {
+        let pointer = self.alloca(entry, slot.0, slot.1);
+        self.store(block, value, pointer);
+        self.load(block, pointer, typ)
+    }
+
+    /// Call a C function, structs go in registers or memory the way the target's C compiler passes them.
+    fn build_c_call<'a>(
+        &self,
+        callee: &IrFunction,
+        entry: &'a BlockRef<'c, 'a>,
+        block: &'a BlockRef<'c, 'a>,
+        arg_values: Vec<Value<'c, 'a>>,
+    ) -> Result<Option<Value<'c, 'a>>, String> {
+        let struct_type = |typ: IrTypeIndex| self.convert_type(typ).unwrap_or(self.default_type());
+        let stack_slot = |typ: IrTypeIndex| (self.target.size_of(self.arena(), typ), self.target.align_of(self.arena(), typ));
+        let passing = self.struct_passing(callee, callee.return_type, true);
+        let mut call_args = vec![];
+        let mut result_pointer = None;
+        if let Some(StructPassing::Memory { .. }) = passing {
+            let (size, align) = stack_slot(callee.return_type);
+            let pointer = self.alloca(entry, size, align);
+            call_args.push(pointer);
+            result_pointer = Some(pointer);
+        }
+        for (param, value) in callee.params.iter().zip(arg_values) {
+            call_args.push(match self.struct_passing(callee, param.typ, false) {
+                Some(StructPassing::Registers(pieces)) => {
+                    self.reinterpret(entry, block, value, self.pieces_type(&pieces), self.pieces_slot(param.typ, &pieces))
+                }
+                // the callee gets a copy, with `byval` llvm makes another one on the stack from it
+                Some(StructPassing::Memory { .. }) => {
+                    let (size, align) = stack_slot(param.typ);
+                    let pointer = self.alloca(entry, size, align);
+                    self.store(block, value, pointer);
+                    pointer
+                }
+                None => value,
+            });
+        }
+        let (_, result_types) = self.signature(callee);
+        let call = block.append_operation(func::call(
+            self.context,
+            FlatSymbolRefAttribute::new(self.context, &callee.name),
+            &call_args,
+            &result_types,
+            self.location,
+        ));
+        let result: Option<Value<'c, 'a>> = call.result(0).ok().map(|result| result.into());
+        Ok(match (passing, result, result_pointer) {
+            (Some(StructPassing::Registers(pieces)), Some(result), _) => {
+                Some(self.reinterpret(entry, block, result, struct_type(callee.return_type), self.pieces_slot(callee.return_type, &pieces)))
+            }
+            (_, _, Some(pointer)) => Some(self.load(block, pointer, struct_type(callee.return_type))),
+            (_, result, _) => result,
+        })
+    }
+
+    fn instruction_type(&self, index: IrInstructionIndex) -> Option<&'m IrType> {
+        self.arena().instruction_types.get(&index).and_then(|typ| self.arena().type_arena.get(*typ))
+    }
+
+    fn is_unsigned(&self, index: IrInstructionIndex) -> bool {
+        match self.instruction_type(index) {
+            Some(IrType::UInt(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn is_integer(&self, index: IrInstructionIndex) -> bool {
+        self.instruction_type(index).map_or(false, |typ| typ.is_integer())
+    }
+
+    fn is_float(&self, index: IrInstructionIndex) -> bool {
+        self.instruction_type(index).map_or(false, |typ| typ.is_float())
+    }
+
+    fn int_constant<'a>(&self, block: &'a BlockRef<'c, 'a>, value: i64, typ: Type<'c>) -> Value<'c, 'a> {
+        block.append_operation(arith::constant(self.context, IntegerAttribute::new(value, typ).into(), self.location))
+            .result(0).unwrap().into()
+    }
+
+    /// Constants that may not fit in an i64, like the bounds of an i128.
+    fn wide_int_constant<'a>(&self, block: &'a BlockRef<'c, 'a>, value: i128, typ: Type<'c>) -> Value<'c, 'a> {
+        let attribute = Attribute::parse(self.context, &format!("{} : {}", value, typ)).unwrap();
+        block.append_operation(arith::constant(self.context, attribute, self.location))
+            .result(0).unwrap().into()
+    }
+
+    fn int_bits(&self, index: IrInstructionIndex) -> u32 {
+        match self.instruction_type(index) {
+            Some(IrType::Int(int)) => int.bits(self.target),
+            Some(IrType::UInt(uint)) => uint.bits(self.target),
+            Some(IrType::Bool) => 1,
+            _ => 64,
+        }
+    }
+
+    fn extract_value<'a>(&self, block: &'a BlockRef<'c, 'a>, aggregate: Value<'c, 'a>, position: usize, typ: Type<'c>) -> Value<'c, 'a> {
+        block.append_operation(OperationBuilder::new("llvm.extractvalue", self.location)
+            .add_attributes(&[(Identifier::new(self.context, "position"), Attribute::parse(self.context, &format!("array<i64: {}>", position)).unwrap())])
+            .add_operands(&[aggregate])
+            .add_results(&[typ])
+            .build())
+            .result(0).unwrap().into()
+    }
+
+    fn insert_value<'a>(&self, block: &'a BlockRef<'c, 'a>, aggregate: Value<'c, 'a>, value: Value<'c, 'a>, position: usize) -> Value<'c, 'a> {
+        block.append_operation(OperationBuilder::new("llvm.insertvalue", self.location)
+            .add_attributes(&[(Identifier::new(self.context, "position"), Attribute::parse(self.context, &format!("array<i64: {}>", position)).unwrap())])
+            .add_operands(&[aggregate, value])
+            .add_results(&[aggregate.r#type()])
+            .build())
+            .result(0).unwrap().into()
+    }
+
+    /// Abort with a message when the condition is false.
+    fn trap_unless<'a>(&self, block: &'a BlockRef<'c, 'a>, condition: Value<'c, 'a>, message: &str) {
+        block.append_operation(cf::assert(self.context, condition, message, self.location));
+    }
+
+    /// `+`, `-` or `*` on integers, doing what `overflow` says when the result doesn't fit.
+    /// Checked results are an optional, empty when it overflowed.
+    fn build_integer_arith<'a>(
+        &self,
+        block: &'a BlockRef<'c, 'a>,
+        op: BinOpType,
+        overflow: Overflow,
+        unsigned: bool,
+        bits: u32,
+        l: Value<'c, 'a>,
+        r: Value<'c, 'a>,
+    ) -> Result<Value<'c, 'a>, String> {
+        let location = self.location;
+        let typ = l.r#type();
+        let name = match op {
+            BinOpType::Plus => "add",
+            BinOpType::Minus => "sub",
+            BinOpType::Star => "mul",
+            _ => return Err(format!("`{}` can't overflow", op)),
+        };
+        let sign = if unsigned { "u" } else { "s" };
+
+        if overflow == Overflow::Wrap {
+            let operation = match op {
+                BinOpType::Plus => arith::addi(l, r, location),
+                BinOpType::Minus => arith::subi(l, r, location),
+                _ => arith::muli(l, r, location),
+            };
+            return Ok(block.append_operation(operation).result(0).map_err(|e| e.to_string())?.into());
+        }
+        // llvm has saturating add and sub, but not mul
+        if overflow == Overflow::Saturate && op != BinOpType::Star {
+            return Ok(block.append_operation(OperationBuilder::new(&format!("llvm.intr.{}{}.sat", sign, name), location)
+                .add_operands(&[l, r])
+                .add_results(&[typ])
+                .build())
+                .result(0).map_err(|e| e.to_string())?.into());
+        }
+
+        let bool_type: Type<'c> = IntegerType::new(self.context, 1).into();
+        let pair_type = Type::parse(self.context, &format!("!llvm.struct<({}, i1)>", typ)).unwrap();
+        let pair = block.append_operation(OperationBuilder::new(&format!("llvm.intr.{}{}.with.overflow", sign, name), location)
+            .add_operands(&[l, r])
+            .add_results(&[pair_type])
+            .build())
+            .result(0).map_err(|e| e.to_string())?.into();
+        let result = self.extract_value(block, pair, 0, typ);
+        let overflowed = self.extract_value(block, pair, 1, bool_type);
+
+        Ok(match overflow {
+            Overflow::Trap => {
+                let fits = block.append_operation(arith::xori(overflowed, self.int_constant(block, 1, bool_type), location))
+                    .result(0).map_err(|e| e.to_string())?.into();
+                self.trap_unless(block, fits, "integer overflow");
+                result
+            }
+            Overflow::Checked => {
+                let fits = block.append_operation(arith::xori(overflowed, self.int_constant(block, 1, bool_type), location))
+                    .result(0).map_err(|e| e.to_string())?.into();
+                let empty = block.append_operation(OperationBuilder::new("llvm.mlir.undef", location)
+                    .add_results(&[pair_type])
+                    .build())
+                    .result(0).map_err(|e| e.to_string())?.into();
+                let with_value = self.insert_value(block, empty, result, 0);
+                self.insert_value(block, with_value, fits, 1)
+            }
+            _ => {
+                // a product only overflows towards the minimum when the signs differ
+                let bound = if unsigned {
+                    self.int_constant(block, -1, typ)
+                } else {
+                    let max = self.wide_int_constant(block, (1i128 << (bits - 1)).wrapping_sub(1), typ);
+                    let min = self.wide_int_constant(block, (1i128 << (bits - 1)).wrapping_neg(), typ);
+                    let signs = block.append_operation(arith::xori(l, r, location)).result(0).map_err(|e| e.to_string())?.into();
+                    let negative = block.append_operation(arith::cmpi(self.context, arith::CmpiPredicate::Slt, signs, self.int_constant(block, 0, typ), location))
+                        .result(0).map_err(|e| e.to_string())?.into();
+                    block.append_operation(arith::select(negative, min, max, location)).result(0).map_err(|e| e.to_string())?.into()
+                };
+                block.append_operation(arith::select(overflowed, bound, result, location)).result(0).map_err(|e| e.to_string())?.into()
+            }
+        })
+    }
+
+    fn float_binop<'a>(&self, func: &IrFunction, op: BinOpType, l: Value<'c, 'a>, r: Value<'c, 'a>) -> Result<Operation<'c>, String> {
+        let location = self.location;
+        use BinOpType::*;
+        Ok(match op {
+            Plus => arith::addf(l, r, location),
+            Minus => arith::subf(l, r, location),
+            Star => arith::mulf(l, r, location),
+            ForwardSlash => arith::divf(l, r, location),
+            Percent => arith::remf(l, r, location),
+            // ordered comparisons are false when either side is NaN, except `!=`
+            LessThan => arith::cmpf(self.context, arith::CmpfPredicate::Olt, l, r, location),
+            GreaterThan => arith::cmpf(self.context, arith::CmpfPredicate::Ogt, l, r, location),
+            LessThanEqualTo => arith::cmpf(self.context, arith::CmpfPredicate::Ole, l, r, location),
+            GreaterThanEqualTo => arith::cmpf(self.context, arith::CmpfPredicate::Oge, l, r, location),
+            EqualTo => arith::cmpf(self.context, arith::CmpfPredicate::Oeq, l, r, location),
+            NotEqualTo => arith::cmpf(self.context, arith::CmpfPredicate::Une, l, r, location),
+            _ => return Err(format!("`{}` can't be used on floats in {}", op, func.name)),
+        })
+    }
+
+    fn float_bits(&self, index: IrInstructionIndex) -> Option<u32> {
+        match self.instruction_type(index) {
+            Some(IrType::Float(float)) => Some(float.bits()),
+            _ => None,
+        }
+    }
+
+    fn float_type(&self, bits: u32) -> Type<'c> {
+        Type::parse(self.context, &format!("f{}", bits)).unwrap()
+    }
+
+    /// The runtime function doing an operation the target has no instructions for, along with its param and result types.
+    /// That's 128 bit division, and `Float128` arithmetic on most targets.
+    fn runtime_helper(&self, index: IrInstructionIndex, ins: &IrInstruction) -> Option<(&'static str, Vec<Type<'c>>, Type<'c>)> {
+        let soft_f128 = !self.target.has_native_float(128);
+        let f128 = self.float_type(128);
+        let i32_type = IntegerType::new(self.context, 32).into();
+        match ins {
+            // llvm would call compiler-rt's `__divti3` and friends, which some targets like wasm32 don't ship
+            IrInstruction::BinOp(lhs, op @ (BinOpType::ForwardSlash | BinOpType::Percent), _) if self.is_integer(*lhs) && self.int_bits(*lhs) == 128 => {
+                let name = match (op, self.is_unsigned(*lhs)) {
+                    (BinOpType::ForwardSlash, false) => "ns_divti3",
+                    (BinOpType::ForwardSlash, true) => "ns_udivti3",
+                    (_, false) => "ns_modti3",
+                    (_, true) => "ns_umodti3",
+                };
+                let i128_type = IntegerType::new(self.context, 128).into();
+                Some((name, vec![i128_type, i128_type], i128_type))
+            }
+            IrInstruction::BinOp(lhs, op, _) if soft_f128 && self.float_bits(*lhs) == Some(128) => {
+                use BinOpType::*;
+                let (name, result) = match op {
+                    Plus => ("__addtf3", f128),
+                    Minus => ("__subtf3", f128),
+                    Star => ("__multf3", f128),
+                    ForwardSlash => ("__divtf3", f128),
+                    Percent => ("fmodf128", f128),
+                    EqualTo => ("__eqtf2", i32_type),
+                    NotEqualTo => ("__netf2", i32_type),
+                    LessThan => ("__lttf2", i32_type),
+                    LessThanEqualTo => ("__letf2", i32_type),
+                    GreaterThan => ("__gttf2", i32_type),
+                    GreaterThanEqualTo => ("__getf2", i32_type),
+                    _ => return None,
+                };
+                Some((name, vec![f128, f128], result))
+            }
+            IrInstruction::Convert { value: converted, .. } if soft_f128 => {
+                let (from, to) = (self.float_bits(*converted)?, self.float_bits(index)?);
+                let name = match (from, to) {
+                    (16, 128) => "__extendhftf2",
+                    (32, 128) => "__extendsftf2",
+                    (64, 128) => "__extenddftf2",
+                    (128, 16) => "__trunctfhf2",
+                    (128, 32) => "__trunctfsf2",
+                    (128, 64) => "__trunctfdf2",
+                    _ => return None,
+                };
+                Some((name, vec![self.float_type(from)], self.float_type(to)))
+            }
+            _ => None,
+        }
+    }
+
+    fn call_runtime<'a>(&self, block: &'a BlockRef<'c, 'a>, name: &str, args: &[Value<'c, 'a>], result: Type<'c>) -> Result<Value<'c, 'a>, String> {
+        Ok(block.append_operation(func::call(self.context, FlatSymbolRefAttribute::new(self.context, name), args, &[result], self.location))
+            .result(0).map_err(|e| e.to_string())?.into())
+    }
+
+    /// Convert between number types, checked conversions assert that the value fits.
+    fn build_conversion<'a>(
+        &self,
+        block: &'a BlockRef<'c, 'a>,
+        from: IrInstructionIndex,
+        to: IrInstructionIndex,
+        operand: Value<'c, 'a>,
+        checked: bool,
+        result_type: Type<'c>,
+    ) -> Result<Value<'c, 'a>, String> {
+        let location = self.location;
+        let append = |operation: Operation<'c>| -> Result<Value<'c, 'a>, String> {
+            Ok(block.append_operation(operation).result(0).map_err(|e| e.to_string())?.into())
+        };
+        // pointers are opaque, so only casts between pointers and integers do anything
+        match (self.instruction_type(from), self.instruction_type(to)) {
+            (Some(IrType::Reference(..)), Some(IrType::Reference(..))) => return Ok(operand),
+            (Some(IrType::Reference(..)), _) => return append(OperationBuilder::new("llvm.ptrtoint", location)
+                .add_operands(&[operand])
+                .add_results(&[result_type])
+                .build()),
+            (_, Some(IrType::Reference(..))) => return append(OperationBuilder::new("llvm.inttoptr", location)
+                .add_operands(&[operand])
+                .add_results(&[result_type])
+                .build()),
+            _ => {}
+        }
+        let source_signed = matches!(self.instruction_type(from), Some(IrType::Int(_)));
+        let target_signed = matches!(self.instruction_type(to), Some(IrType::Int(_)));
+        let message = format!("value doesn't fit in `{}`", self.instruction_type(to).map_or("Unknown".to_string(), |typ| typ.name()));
+
+        match (self.float_bits(from), self.float_bits(to)) {
+            (Some(from_bits), Some(to_bits)) if from_bits < to_bits => append(arith::extf(operand, result_type, location)),
+            (Some(from_bits), Some(to_bits)) if from_bits > to_bits => append(arith::truncf(operand, result_type, location)),
+            (Some(_), Some(_)) => Ok(operand),
+            (None, Some(_)) if source_signed => append(arith::sitofp(operand, result_type, location)),
+            (None, Some(_)) => append(arith::uitofp(operand, result_type, location)),
+            (Some(_), None) if !checked => {
+                // out of range values become the closest integer and NaN becomes 0
+                let name = if target_signed { "llvm.intr.fptosi.sat" } else { "llvm.intr.fptoui.sat" };
+                append(OperationBuilder::new(name, location)
+                    .add_operands(&[operand])
+                    .add_results(&[result_type])
+                    .build())
+            }
+            (Some(_), None) => {
+                // powers of two are exact in every float type, so the bounds are too
+                let bits = self.int_bits(to) as i32;
+                let float_type = operand.r#type();
+                let float = |value: f64| append(arith::constant(self.context, FloatAttribute::new(self.context, value, float_type).into(), location));
+                let (lower, upper) = if target_signed {
+                    let min = float(-(2f64.powi(bits - 1)))?;
+                    (append(arith::cmpf(self.context, arith::CmpfPredicate::Oge, operand, min, location))?, 2f64.powi(bits - 1))
+                } else {
+                    let minus_one = float(-1.0)?;
+                    (append(arith::cmpf(self.context, arith::CmpfPredicate::Ogt, operand, minus_one, location))?, 2f64.powi(bits))
+                };
+                let upper = float(upper)?;
+                let upper = append(arith::cmpf(self.context, arith::CmpfPredicate::Olt, operand, upper, location))?;
+                let fits = append(arith::andi(lower, upper, location))?;
+                self.trap_unless(block, fits, &message);
+                if target_signed {
+                    append(arith::fptosi(operand, result_type, location))
+                } else {
+                    append(arith::fptoui(operand, result_type, location))
+                }
+            }
+            (None, None) => {
+                let (from_bits, to_bits) = (self.int_bits(from), self.int_bits(to));
+                let extend = |value: Value<'c, 'a>, signed: bool, typ: Type<'c>| if signed {
+                    append(arith::extsi(value, typ, location))
+                } else {
+                    append(arith::extui(value, typ, location))
+                };
+                let result = if to_bits > from_bits {
+                    extend(operand, source_signed, result_type)?
+                } else if to_bits < from_bits {
+                    append(arith::trunci(operand, result_type, location))?
+                } else {
+                    operand
+                };
+                if !checked {
+                    return Ok(result);
+                }
+
+                // it fits when converting back gives the same value and the sign didn't change
+                let back = if to_bits > from_bits {
+                    append(arith::trunci(result, operand.r#type(), location))?
+                } else if to_bits < from_bits {
+                    extend(result, target_signed, operand.r#type())?
+                } else {
+                    result
+                };
+                let mut fits = append(arith::cmpi(self.context, arith::CmpiPredicate::Eq, back, operand, location))?;
+                if source_signed != target_signed {
+                    let signed = if source_signed { operand } else { result };
+                    let zero = self.int_constant(block, 0, signed.r#type());
+                    let positive = append(arith::cmpi(self.context, arith::CmpiPredicate::Sge, signed, zero, location))?;
+                    fits = append(arith::andi(fits, positive, location))?;
+                }
+                self.trap_unless(block, fits, &message);
+                Ok(result)
+            }
+        }
+    }
+
+    fn build_function(&self, func: &IrFunction) -> Result<Operation<'c>, String> {
+        let function_type = self.function_type(func);
+        let (param_types, _) = self.signature(func);
+
+        let region = Region::new();
+        {
+            // create every block up front so branches can refer to blocks that come later
+            let mut blocks = Vec::with_capacity(func.blocks.len());
+            let mut block_map = HashMap::new();
+            for (i, block_index) in func.blocks.iter().enumerate() {
+                let ir_block = self.arena().block_arena.get(*block_index).ok_or("missing block")?;
+                let arguments: Vec<(Type<'c>, Location<'c>)> = match i {
+                    0 => param_types.iter().map(|typ| (*typ, self.location)).collect(),
+                    _ => ir_block.arguments.iter()
+                        .map(|typ| (self.convert_type(*typ).unwrap_or(self.default_type()), self.location))
+                        .collect(),
+                };
+                block_map.insert(*block_index, i);
+                blocks.push(region.append_block(Block::new(&arguments)));
+            }
+
+            // the ir's block arguments, the params of a C function are put back together from how C passed them first
+            let mut arguments = vec![];
+            for (i, block) in blocks.iter().enumerate() {
+                let mut block_arguments: Vec<Value> = (0..block.argument_count())
+                    .map(|n| block.argument(n).map(|argument| argument.into()).map_err(|e| e.to_string()))
+                    .collect::<Result<_, _>>()?;
+                if i == 0 {
+                    if let Some(StructPassing::Memory { .. }) = self.struct_passing(func, func.return_type, true) {
+                        block_arguments.remove(0);
+                    }
+                    for (param, argument) in func.params.iter().zip(block_arguments.iter_mut()) {
+                        let struct_type = self.convert_type(param.typ).unwrap_or(self.default_type());
+                        match self.struct_passing(func, param.typ, false) {
+                            Some(StructPassing::Registers(pieces)) => {
+                                *argument = self.reinterpret(block, block, *argument, struct_type, self.pieces_slot(param.typ, &pieces));
+                            }
+                            Some(StructPassing::Memory { .. }) => *argument = self.load(block, *argument, struct_type),
+                            None => {}
+                        }
+                    }
+                }
+                arguments.push(block_arguments);
+            }
+
+            let mut values: HashMap<IrInstructionIndex, Value> = HashMap::new();
+            for (i, block_index) in func.blocks.iter().enumerate() {
+                let ir_block = self.arena().block_arena.get(*block_index).ok_or("missing block")?;
+                for ins_index in &ir_block.instructions {
+                    let ins = self.arena().instruction_arena.get(*ins_index).ok_or("missing instruction")?;
+                    let block = &blocks[i];
+                    let value = self.build_instruction(func, &blocks, &block_map, &values, block, &arguments[i], *ins_index, ins)?;
+                    if let Some(value) = value {
+                        values.insert(*ins_index, value);
+                    }
+                }
+            }
+        }
+
+        // private functions can't be seen outside of the module, the linkage carries that through to llvm
+        let exported = Annotation::find(&func.annotations, EXPORT).is_some();
+        let mut attributes = match Self::linkage(func.access, &func.name) {
+            _ if exported => vec![],
+            "external" => vec![],
+            linkage => vec![
+                (Identifier::new(self.context, "sym_visibility"), StringAttribute::new(self.context, "private").into()),
+                (Identifier::new(self.context, "llvm.linkage"), Attribute::parse(self.context, &format!("#llvm.linkage<{}>", linkage)).unwrap()),
+            ],
+        };
+        // public functions are in the C header too, so they follow C's rules for narrow integers
+        if exported || matches!(func.access, Access::Public) {
+            attributes.extend(self.c_abi_attributes(func));
+        }
+        Ok(func::func(
+            self.context,
+            StringAttribute::new(self.context, &func.name),
+            TypeAttribute::new(function_type.into()),
+            region,
+            &attributes,
+            self.location,
+        ))
+    }
+
+    /// C expects integers narrower than an `int` to be sign or zero extended to it by whoever passes them,
+    /// the attributes tell llvm which one so both sides of a call agree.
+    fn c_abi_attributes(&self, func: &IrFunction) -> Vec<(Identifier<'c>, Attribute<'c>)> {
+        let extension = |typ: IrTypeIndex| match self.arena().type_arena.get(typ) {
+            Some(IrType::Int(int)) if int.bits(self.target) < 32 => "{llvm.signext}",
+            Some(IrType::UInt(uint)) if uint.bits(self.target) < 32 => "{llvm.zeroext}",
+            Some(IrType::Bool) => "{llvm.zeroext}",
+            _ => "{}",
+        };
+        // structs in memory are behind pointers, llvm needs to know what's behind them
+        let struct_type = |typ: IrTypeIndex| self.convert_type(typ).unwrap_or(self.default_type());
+        let mut args = vec![];
+        let sret = matches!(self.struct_passing(func, func.return_type, true), Some(StructPassing::Memory { .. }));
+        if sret {
+            args.push(format!("{{llvm.sret = {}}}", struct_type(func.return_type)));
+        }
+        for param in &func.params {
+            args.push(match self.struct_passing(func, param.typ, false) {
+                Some(StructPassing::Memory { byval: true }) => {
+                    let align = self.target.align_of(self.arena(), param.typ).max(self.target.pointer_size());
+                    format!("{{llvm.byval = {}, llvm.align = {} : i64}}", struct_type(param.typ), align)
+                }
+                _ => extension(param.typ).to_string(),
+            });
         }
+        let mut attributes = vec![
+            (Identifier::new(self.context, "arg_attrs"), Attribute::parse(self.context, &format!("[{}]", args.join(", "))).unwrap()),
+        ];
+        if self.convert_type(func.return_type).is_some() && !sret {
+            let result = extension(func.return_type);
+            attributes.push((Identifier::new(self.context, "res_attrs"), Attribute::parse(self.context, &format!("[{}]", result)).unwrap()));
+        }
+        attributes
+    }
+
+    /// Only `public` items are linked against from other modules, `main` is always visible to the c runtime.
+    fn linkage(access: Access, name: &str) -> &'static str {
+        match access {
+            Access::Public => "external",
+            _ if name == "main" => "external",
+            Access::Internal | Access::Generated => "internal",
+        }
+    }
+
+    fn build_instruction<'a>(
+        &self,
+        func: &IrFunction,
+        blocks: &'a [BlockRef<'c, 'a>],
+        block_map: &HashMap<IrBlockIndex, usize>,
+        values: &HashMap<IrInstructionIndex, Value<'c, 'a>>,
+        block: &'a BlockRef<'c, 'a>,
+        arguments: &[Value<'c, 'a>],
+        index: IrInstructionIndex,
+        ins: &IrInstruction,
+    ) -> Result<Option<Value<'c, 'a>>, String> {
+        let value = |i: &IrInstructionIndex| -> Result<Value<'c, 'a>, String> {
+            values.get(i).cloned().ok_or(format!("use of instruction {:?} that has no value", i))
+        };
+        let successor = |i: &IrBlockIndex| -> Result<&'a BlockRef<'c, 'a>, String> {
+            block_map.get(i).map(|i| &blocks[*i]).ok_or(format!("branch to block {:?} outside of {}", i, func.name))
+        };
+        let result_type = self.arena().instruction_types.get(&index)
+            .and_then(|typ| self.convert_type(*typ))
+            .unwrap_or(self.default_type());
+        let location = self.location;
+
+        use IrInstruction::*;
+        let operation = match ins {
+            // globals are only used as callees for now
+            Ref(_) => return Ok(None),
+            Argument(n) => {
+                let argument = arguments.get(*n).cloned().ok_or(format!("block argument {} doesn't exist in {}", n, func.name))?;
+                return Ok(Some(argument));
+            }
+            // attributes only take an i64 directly, wider constants go through their text form
+            // unsuffixed integer literals can be used as floats
+            NatLiteral(n) if self.is_float(index) => arith::constant(self.context, FloatAttribute::new(self.context, *n as f64, result_type).into(), location),
+            NatLiteral(n) => match i64::try_from(*n) {
+                Ok(n) => arith::constant(self.context, IntegerAttribute::new(n, result_type).into(), location),
+                Err(_) if result_type == IntegerType::new(self.context, 64).into() => {
+                    arith::constant(self.context, IntegerAttribute::new(*n as i64, result_type).into(), location)
+                }
+                Err(_) => {
+                    let attribute = Attribute::parse(self.context, &format!("{} : {}", n, result_type)).ok_or(format!("bad constant {}", n))?;
+                    arith::constant(self.context, attribute, location)
+                }
+            },
+            FloatLiteral(n) => arith::constant(self.context, FloatAttribute::new(self.context, *n, result_type).into(), location),
+            StringLiteral(_) => {
+                let name = self.string_globals.get(&index).ok_or("string literal without a global")?;
+                OperationBuilder::new("llvm.mlir.addressof", location)
+                    .add_attributes(&[(Identifier::new(self.context, "global_name"), FlatSymbolRefAttribute::new(self.context, name).into())])
+                    .add_results(&[self.pointer_type()])
+                    .build()
+            }
+            BoolLiteral(b) => {
+                let bool_type = IntegerType::new(self.context, 1).into();
+                arith::constant(self.context, IntegerAttribute::new(*b as i64, bool_type).into(), location)
+            }
+            BinOp(lhs, op, rhs) if self.is_float(*lhs) => {
+                let (l, r) = (value(lhs)?, value(rhs)?);
+                if let Some((name, _, result)) = self.runtime_helper(index, ins) {
+                    let called = self.call_runtime(block, name, &[l, r], result)?;
+                    if !op.is_comparison() {
+                        return Ok(Some(called));
+                    }
+                    // the comparison helpers return an int to compare with zero
+                    let predicate = match op {
+                        BinOpType::EqualTo => arith::CmpiPredicate::Eq,
+                        BinOpType::NotEqualTo => arith::CmpiPredicate::Ne,
+                        BinOpType::LessThan => arith::CmpiPredicate::Slt,
+                        BinOpType::LessThanEqualTo => arith::CmpiPredicate::Sle,
+                        BinOpType::GreaterThan => arith::CmpiPredicate::Sgt,
+                        _ => arith::CmpiPredicate::Sge,
+                    };
+                    let zero = self.int_constant(block, 0, result);
+                    arith::cmpi(self.context, predicate, called, zero, location)
+                } else if self.float_bits(*lhs) == Some(16) && !self.target.has_native_float(16) {
+                    // do the arithmetic in f32 and round the result back
+                    let f32_type = self.float_type(32);
+                    let l = block.append_operation(arith::extf(l, f32_type, location)).result(0).map_err(|e| e.to_string())?.into();
+                    let r = block.append_operation(arith::extf(r, f32_type, location)).result(0).map_err(|e| e.to_string())?.into();
+                    let result = block.append_operation(self.float_binop(func, *op, l, r)?).result(0).map_err(|e| e.to_string())?.into();
+                    if op.is_comparison() {
+                        return Ok(Some(result));
+                    }
+                    arith::truncf(result, result_type, location)
+                } else {
+                    self.float_binop(func, *op, l, r)?
+                }
+            }
+            Convert { value: converted, checked } => {
+                let operand = value(converted)?;
+                if let Some((name, _, result)) = self.runtime_helper(index, ins) {
+                    return self.call_runtime(block, name, &[operand], result).map(Some);
+                }
+                return self.build_conversion(block, *converted, index, operand, *checked, result_type).map(Some);
+            }
+            BinOp(lhs, BinOpType::ForwardSlash | BinOpType::Percent, rhs) if self.runtime_helper(index, ins).is_some() => {
+                let (name, _, result) = self.runtime_helper(index, ins).unwrap();
+                return self.call_runtime(block, name, &[value(lhs)?, value(rhs)?], result).map(Some);
+            }
+            BinOp(lhs, op @ (BinOpType::Plus | BinOpType::Minus | BinOpType::Star), rhs) if self.is_integer(*lhs) => {
+                let (l, r) = (value(lhs)?, value(rhs)?);
+                return self.build_integer_arith(block, *op, self.overflow, self.is_unsigned(*lhs), self.int_bits(*lhs), l, r).map(Some);
+            }
+            OverflowOp { op, overflow, lhs, rhs } => {
+                let (l, r) = (value(lhs)?, value(rhs)?);
+                return self.build_integer_arith(block, *op, *overflow, self.is_unsigned(*lhs), self.int_bits(*lhs), l, r).map(Some);
+            }
+            BinOp(lhs, op, rhs) => {
+                let unsigned = self.is_unsigned(*lhs);
+                let (l, r) = (value(lhs)?, value(rhs)?);
+                // the amount can be any integer type, arith wants it the same width as the value being shifted
+                let r = match op {
+                    BinOpType::ShiftLeft | BinOpType::ShiftRight => self.build_conversion(block, *rhs, *lhs, r, false, l.r#type())?,
+                    _ => r,
+                };
+                use BinOpType::*;
+                match op {
+                    Plus => arith::addi(l, r, location),
+                    Minus => arith::subi(l, r, location),
+                    Star => arith::muli(l, r, location),
+                    ForwardSlash if unsigned => arith::divui(l, r, location),
+                    ForwardSlash => arith::divsi(l, r, location),
+                    Percent if unsigned => arith::remui(l, r, location),
+                    Percent => arith::remsi(l, r, location),
+                    LessThan | GreaterThan | LessThanEqualTo | GreaterThanEqualTo | EqualTo | NotEqualTo => {
+                        let predicate = match (op, unsigned) {
+                            (EqualTo, _) => arith::CmpiPredicate::Eq,
+                            (NotEqualTo, _) => arith::CmpiPredicate::Ne,
+                            (LessThan, false) => arith::CmpiPredicate::Slt,
+                            (LessThan, true) => arith::CmpiPredicate::Ult,
+                            (GreaterThan, false) => arith::CmpiPredicate::Sgt,
+                            (GreaterThan, true) => arith::CmpiPredicate::Ugt,
+                            (LessThanEqualTo, false) => arith::CmpiPredicate::Sle,
+                            (LessThanEqualTo, true) => arith::CmpiPredicate::Ule,
+                            (_, false) => arith::CmpiPredicate::Sge,
+                            (_, true) => arith::CmpiPredicate::Uge,
+                        };
+                        arith::cmpi(self.context, predicate, l, r, location)
+                    }
+                    BitAnd => arith::andi(l, r, location),
+                    BitOr => arith::ori(l, r, location),
+                    // the ir builder turns these into branches so the right side can be skipped
+                    And | Or => return Err(format!("`{}` wasn't short circuited in {}", op, func.name)),
+                    BitXor => arith::xori(l, r, location),
+                    ShiftLeft => arith::shli(l, r, location),
+                    ShiftRight if unsigned => arith::shrui(l, r, location),
+                    ShiftRight => arith::shrsi(l, r, location),
+                }
+            }
+            UnOp(UnOpType::Negate, operand) if self.is_integer(*operand) => {
+                let (unsigned, bits) = (self.is_unsigned(*operand), self.int_bits(*operand));
+                let operand = value(operand)?;
+                let zero = self.int_constant(block, 0, operand.r#type());
+                return self.build_integer_arith(block, BinOpType::Minus, self.overflow, unsigned, bits, zero, operand).map(Some);
+            }
+            UnOp(op, operand) => {
+                let float = self.is_float(*operand);
+                let operand = value(operand)?;
+                match op {
+                    UnOpType::Negate if float => arith::negf(operand, location),
+                    UnOpType::Negate => arith::subi(self.int_constant(block, 0, operand.r#type()), operand, location),
+                    // flipping every bit of an i1 is `not`
+                    UnOpType::Not | UnOpType::BitNot => arith::xori(operand, self.int_constant(block, -1, operand.r#type()), location),
+                }
+            }
+            FunctionCall { function, args } => {
+                let arg_values = args.iter().map(|arg| value(arg)).collect::<Result<Vec<_>, _>>()?;
+                match self.arena().instruction_arena.get(*function) {
+                    Some(Ref(name)) if self.c_functions.contains(name.as_str()) => {
+                        let callee = self.functions.get(name).ok_or(format!("call to unknown function {}", name))?;
+                        return self.build_c_call(callee, &blocks[0], block, arg_values);
+                    }
+                    Some(Ref(name)) => {
+                        let callee = self.functions.get(name).ok_or(format!("call to unknown function {}", name))?;
+                        let result_types: Vec<Type<'c>> = self.convert_type(callee.return_type).into_iter().collect();
+                        func::call(self.context, FlatSymbolRefAttribute::new(self.context, name), &arg_values, &result_types, location)
+                    }
+                    _ => {
+                        // load the code pointer out of the environment and pass the environment along
+                        let env = value(function)?;
+                        let code = block.append_operation(OperationBuilder::new("llvm.load", location)
+                            .add_operands(&[env])
+                            .add_results(&[self.pointer_type()])
+                            .build())
+                            .result(0).map_err(|e| e.to_string())?.into();
+                        let mut call_args = vec![env];
+                        call_args.extend(arg_values);
+                        let param_types: Vec<Type<'c>> = call_args.iter().map(|arg| arg.r#type()).collect();
+                        let result_types: Vec<Type<'c>> = self.arena().instruction_types.get(&index)
+                            .and_then(|typ| self.convert_type(*typ))
+                            .into_iter()
+                            .collect();
+                        let function_type = FunctionType::new(self.context, &param_types, &result_types);
+                        let callee = self.cast(block, code, function_type.into());
+                        func::call_indirect(callee, &call_args, &result_types, location)
+                    }
+                }
+            }
+            MakeClosure { function, captures } => {
+                let lifted = self.functions.get(function).ok_or(format!("closure of unknown function {}", function))?;
+                let capture_values = captures.iter().map(|capture| value(capture)).collect::<Result<Vec<_>, _>>()?;
+                let env_type = self.env_type(&capture_values.iter().map(|capture| capture.r#type()).collect::<Vec<_>>());
+
+                let size = self.size_of(block, env_type);
+                let env: Value = block.append_operation(func::call(
+                    self.context,
+                    FlatSymbolRefAttribute::new(self.context, "malloc"),
+                    &[size],
+                    &[self.pointer_type()],
+                    location,
+                )).result(0).map_err(|e| e.to_string())?.into();
+
+                let code = block.append_operation(func::constant(
+                    self.context,
+                    FlatSymbolRefAttribute::new(self.context, function),
+                    self.function_type(lifted),
+                    location,
+                )).result(0).map_err(|e| e.to_string())?.into();
+                let code = self.cast(block, code, self.pointer_type());
+                let mut fields = vec![code];
+                fields.extend(capture_values);
+                for (i, field) in fields.into_iter().enumerate() {
+                    let pointer = self.env_field(block, env, env_type, i);
+                    block.append_operation(OperationBuilder::new("llvm.store", location)
+                        .add_operands(&[field, pointer])
+                        .build());
+                }
+                return Ok(Some(env));
+            }
+            CaptureLoad { env, index: capture } => {
+                let captures = match self.instruction_type(*env) {
+                    Some(IrType::Reference(row, _, _)) => match self.arena().type_arena.get(*row) {
+                        Some(IrType::Row(fields)) => fields.iter()
+                            .map(|field| self.convert_type(field.typ).unwrap_or(self.default_type()))
+                            .collect::<Vec<_>>(),
+                        _ => return Err(format!("closure environment in {} isn't a row", func.name)),
+                    },
+                    _ => return Err(format!("closure environment in {} isn't a pointer", func.name)),
+                };
+                let pointer = self.env_field(block, value(env)?, self.env_type(&captures), capture + 1);
+                OperationBuilder::new("llvm.load", location)
+                    .add_operands(&[pointer])
+                    .add_results(&[result_type])
+                    .build()
+            }
+            Load { pointer } => OperationBuilder::new("llvm.load", location)
+                .add_operands(&[value(pointer)?])
+                .add_results(&[result_type])
+                .build(),
+            Store { pointer, value: stored } => OperationBuilder::new("llvm.store", location)
+                .add_operands(&[value(stored)?, value(pointer)?])
+                .build(),
+            PointerOffset { pointer, offset } => {
+                let element_type = match self.instruction_type(*pointer) {
+                    Some(IrType::Reference(inner, _, _)) => self.convert_type(*inner).unwrap_or(self.default_type()),
+                    _ => self.default_type(),
+                };
+                // a single dynamic index, the value comes from the operand list
+                let raw_indices = Attribute::parse(self.context, &format!("array<i32: {}>", i32::MIN)).unwrap();
+                OperationBuilder::new("llvm.getelementptr", location)
+                    .add_attributes(&[
+                        (Identifier::new(self.context, "rawConstantIndices"), raw_indices),
+                        (Identifier::new(self.context, "elem_type"), TypeAttribute::new(element_type).into()),
+                    ])
+                    .add_operands(&[value(pointer)?, value(offset)?])
+                    .add_results(&[self.pointer_type()])
+                    .build()
+            }
+            Denull { optional } => {
+                let optional = value(optional)?;
+                let present = self.extract_value(block, optional, 1, IntegerType::new(self.context, 1).into());
+                self.trap_unless(block, present, "unwrapped an empty optional");
+                return Ok(Some(self.extract_value(block, optional, 0, result_type)));
+            }
+            Unsafe { value: inner } => return Ok(Some(value(inner)?)),
+            Branch { condition, true_branch, false_branch } => cf::cond_br(
+                self.context,
+                value(condition)?,
+                successor(true_branch)?,
+                successor(false_branch)?,
+                &[],
+                &[],
+                location,
+            ),
+            Jump { target, args } => {
+                let arg_values = args.iter().map(|arg| value(arg)).collect::<Result<Vec<_>, _>>()?;
+                cf::br(successor(target)?, &arg_values, location)
+            }
+            Return { value: returned } => {
+                match (returned, self.struct_passing(func, func.return_type, true)) {
+                    // C passed a pointer to put the result in before the params
+                    (Some(returned), Some(StructPassing::Memory { .. })) => {
+                        let pointer = blocks[0].argument(0).map_err(|e| e.to_string())?.into();
+                        self.store(block, value(returned)?, pointer);
+                        func::r#return(&[], location)
+                    }
+                    (Some(returned), Some(StructPassing::Registers(pieces))) => {
+                        let slot = self.pieces_slot(func.return_type, &pieces);
+                        let result = self.reinterpret(&blocks[0], block, value(returned)?, self.pieces_type(&pieces), slot);
+                        func::r#return(&[result], location)
+                    }
+                    (Some(returned), None) if self.convert_type(func.return_type).is_some() => func::r#return(&[value(returned)?], location),
+                    _ => func::r#return(&[], location),
+                }
+            }
+            x => return Err(format!("can't lower {:?} in {} yet", x, func.name)),
+        };
+
+        let operation = block.append_operation(operation);
+        Ok(operation.result(0).ok().map(|result| result.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::create_context;
+    use crate::compiler::Compiler;
+    use crate::lang::Path;
+    use crate::options::{BuildMode, CompilerOptions};
+    use crate::target::TargetInfo;
+
+    /// Compile a module and print the MLIR it lowers to, which has to verify.
+    fn lower(options: CompilerOptions, source: &str) -> String {
+        let mut compiler = Compiler::with_options(options);
+        compiler.parse_module(Path::of("test"), "test.ns".to_string(), source.to_string());
+        compiler.compile();
+        assert!(!compiler.diagnostics.has_errors(), "{}", compiler.diagnostics.emit_to_string());
+        let context = create_context();
+        let (index, _) = compiler.modules.iter().next().unwrap();
+        let module = compiler.create_mlir_module(&context, index).unwrap();
+        assert!(module.as_operation().verify(), "{}", module.as_operation());
+        module.as_operation().to_string()
+    }
+
+    fn for_target(triple: &str) -> CompilerOptions {
+        let mut options = CompilerOptions::default();
+        options.target = TargetInfo::from_triple(triple).unwrap();
+        options
+    }
+
+    #[test]
+    fn overflow_follows_the_build_mode() {
+        let source = "
+            fun add(a: Int32, b: Int32): Int32 {
+                return a + b;
+            }
+        ";
+        let debug = lower(CompilerOptions::for_mode(BuildMode::Debug), source);
+        assert!(debug.contains("llvm.intr.sadd.with.overflow"), "{}", debug);
+        assert!(debug.contains("cf.assert"), "{}", debug);
+        let release = lower(CompilerOptions::for_mode(BuildMode::Release), source);
+        assert!(release.contains("arith.addi"), "{}", release);
+        assert!(!release.contains("with.overflow") && !release.contains("cf.assert"), "{}", release);
+    }
+
+    #[test]
+    fn overflow_intrinsics_ignore_the_build_mode() {
+        let mlir = lower(CompilerOptions::for_mode(BuildMode::Debug), "
+            fun f(a: UInt8, b: UInt8): UInt8 {
+                let w = wrapping_mul(a, b);
+                return saturating_add(w, b);
+            }
+        ");
+        assert!(mlir.contains("arith.muli"), "{}", mlir);
+        assert!(mlir.contains("llvm.intr.uadd.sat"), "{}", mlir);
+        assert!(!mlir.contains("cf.assert"), "{}", mlir);
     }
 
-    fn mlir_module(&mut self) {
-        let registry = DialectRegistry::new();
-        register_all_dialects(&registry);
+    #[test]
+    fn float128_uses_soft_float_helpers_without_hardware_support() {
+        let source = "
+            fun f(a: Float128, b: Float64): Float128 {
+                return a + Float128(b);
+            }
+        ";
+        let soft = lower(for_target("x86_64-unknown-linux-gnu"), source);
+        assert!(soft.contains("call @__extenddftf2"), "{}", soft);
+        assert!(soft.contains("call @__addtf3"), "{}", soft);
+        assert!(!soft.contains("arith.addf"), "{}", soft);
+        let native = lower(for_target("s390x-unknown-linux-gnu"), source);
+        assert!(native.contains("arith.extf") && native.contains("arith.addf"), "{}", native);
+        assert!(!native.contains("__addtf3"), "{}", native);
+    }
+
+    #[test]
+    fn float16_is_computed_in_float32_without_hardware_support() {
+        let source = "
+            fun f(a: Float16, b: Float16): Float16 {
+                return a * b;
+            }
+        ";
+        let soft = lower(for_target("x86_64-unknown-linux-gnu"), source);
+        assert!(soft.contains("arith.extf") && soft.contains("arith.truncf"), "{}", soft);
+        let native = lower(for_target("aarch64-unknown-linux-gnu"), source);
+        assert!(native.contains("arith.mulf"), "{}", native);
+        assert!(!native.contains("arith.extf"), "{}", native);
+    }
+
+    #[test]
+    fn int128_division_calls_the_runtime() {
+        let mlir = lower(CompilerOptions::default(), "
+            fun div(a: Int128, b: Int128): Int128 {
+                return a / b;
+            }
+
+            fun rem(a: UInt128, b: UInt128): UInt128 {
+                return a % b;
+            }
 
-        let context = Context::new();
-        context.append_dialect_registry(&registry);
-        context.load_all_available_dialects();
+            fun small(a: Int64, b: Int64): Int64 {
+                return a / b;
+            }
+        ");
+        assert!(mlir.contains("call @ns_divti3"), "{}", mlir);
+        assert!(mlir.contains("call @ns_umodti3"), "{}", mlir);
+        assert!(!mlir.contains("ns_udivti3") && !mlir.contains("ns_modti3"), "{}", mlir);
+        assert!(mlir.contains("arith.divsi"), "{}", mlir);
+    }
+
+    #[test]
+    fn only_conversion_calls_check_that_values_fit() {
+        let conversion = |body: &str| lower(CompilerOptions::default(), &format!("
+            fun f(a: Int32, x: Float64): Int8 {{
+                return {};
+            }}
+        ", body));
+        let cast = conversion("a as Int8");
+        assert!(cast.contains("arith.trunci") && !cast.contains("cf.assert"), "{}", cast);
+        let checked = conversion("Int8(a)");
+        assert!(checked.contains("arith.trunci") && checked.contains("cf.assert"), "{}", checked);
+        let saturated = conversion("x as Int8");
+        assert!(saturated.contains("llvm.intr.fptosi.sat") && !saturated.contains("cf.assert"), "{}", saturated);
+        let exact = conversion("Int8(x)");
+        assert!(exact.contains("arith.fptosi") && exact.contains("cf.assert"), "{}", exact);
+    }
+
+    #[test]
+    fn float_globals_lower_any_value() {
+        let mlir = lower(CompilerOptions::default(), "
+            let NAN: Float64 = 0.0 / 0.0;
+            let WHOLE: Float64 = 1.5 * 4.0;
+        ");
+        // NaN has no decimal spelling, MLIR prints its bits
+        assert!(mlir.contains("@NAN(0x"), "{}", mlir);
+        assert!(mlir.contains("@WHOLE(6.000000e+00 : f64)"), "{}", mlir);
+    }
 
-        let location = Location::unknown(&context);
-        let mut module = Module::new(location);
+    #[test]
+    fn only_public_items_are_linked_from_other_modules() {
+        let mlir = lower(CompilerOptions::default(), "
+            public fun shown(): Int32 {
+                return 1;
+            }
 
-        let index_type = Type::index(&context);
-        let float32_type = Type::float32(&context);
-        let vector2_float32_type = Type::vector(&[2], float32_type);
-        let i64_type: Type = IntegerType::new(&context, 64).into();
-        let tensor_type: Type = RankedTensorType::new(&[1], float32_type, None).into();
-        let _memref_load_type: Type = MemRefType::new(vector2_float32_type, &[1], None, None).into();
+            fun hidden(): Int32 {
+                return 2;
+            }
 
-        // see https://github.com/raviqqe/melior/issues/180
+            fun main(): Int32 {
+                return hidden();
+            }
+        ");
+        let declaration = |name: &str| {
+            let symbol = format!("@{}(", name);
+            mlir.lines().find(|line| line.contains("func.func") && line.contains(&symbol)).unwrap().to_string()
+        };
+        assert!(!declaration("shown").contains("llvm.linkage"), "{}", mlir);
+        assert!(declaration("hidden").contains("#llvm.linkage<internal>"), "{}", mlir);
+        assert!(!declaration("main").contains("llvm.linkage"), "{}", mlir);
     }
 }
@@ -0,0 +1,148 @@
+use crate::check::lints::{LintLevel, LintLevels};
+use crate::diagnostic::DiagnosticFormat;
+use crate::ir::Overflow;
+use crate::target::TargetInfo;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuildMode {
+    Debug,
+    Release,
+}
+
+/// What the compiler writes out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Emit {
+    /// The ir and the MLIR module, printed to stdout.
+    Mlir,
+    /// A `.h` with the public functions, structs and enums of every module.
+    CHeader,
+    /// The same declarations as a `.zig` file.
+    Zig,
+}
+
+impl Emit {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mlir" => Some(Emit::Mlir),
+            "c-header" => Some(Emit::CHeader),
+            "zig" => Some(Emit::Zig),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for a whole compilation, usually from the command line.
+#[derive(Clone, Debug)]
+pub struct CompilerOptions {
+    pub mode: BuildMode,
+    /// What `+`, `-` and `*` do when the result doesn't fit.
+    pub overflow: Overflow,
+    pub target: TargetInfo,
+    pub diagnostics_format: DiagnosticFormat,
+    /// How each lint is reported, before annotations in the source change it.
+    pub lints: LintLevels,
+    /// Everything to write out, headers go in the working directory named after their module.
+    pub emit: Vec<Emit>,
+    /// Source files to compile in order, a module has to come after the modules it imports.
+    /// The built in example is used when there aren't any.
+    pub inputs: Vec<String>,
+}
+
+impl Default for CompilerOptions {
+    fn default() -> Self {
+        Self::for_mode(BuildMode::Debug)
+    }
+}
+
+impl CompilerOptions {
+    /// Debug builds trap on overflow, release builds wrap.
+    pub fn for_mode(mode: BuildMode) -> Self {
+        Self {
+            mode,
+            overflow: match mode {
+                BuildMode::Debug => Overflow::Trap,
+                BuildMode::Release => Overflow::Wrap,
+            },
+            target: TargetInfo::default(),
+            diagnostics_format: DiagnosticFormat::Human,
+            lints: LintLevels::default(),
+            emit: vec![Emit::Mlir],
+            inputs: vec![],
+        }
+    }
+
+    /// Parse the arguments after the program name.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut mode = BuildMode::Debug;
+        let mut overflow = None;
+        let mut target = TargetInfo::default();
+        let mut diagnostics_format = DiagnosticFormat::Human;
+        let mut lints = LintLevels::default();
+        let mut emit = vec![];
+        let mut inputs = vec![];
+        for arg in args {
+            match arg.as_str() {
+                "--debug" => mode = BuildMode::Debug,
+                "--release" => mode = BuildMode::Release,
+                _ if arg.starts_with("--overflow=") => {
+                    overflow = Some(match &arg["--overflow=".len()..] {
+                        "trap" => Overflow::Trap,
+                        "wrap" => Overflow::Wrap,
+                        other => return Err(format!("unknown overflow mode `{}`, expected `trap` or `wrap`", other)),
+                    });
+                }
+                _ if arg.starts_with("--target=") => target = TargetInfo::from_triple(&arg["--target=".len()..])?,
+                _ if arg.starts_with("--diagnostics-format=") => {
+                    let name = &arg["--diagnostics-format=".len()..];
+                    diagnostics_format = DiagnosticFormat::from_name(name)
+                        .ok_or(format!("unknown diagnostics format `{}`, expected `human`, `json` or `sarif`", name))?;
+                }
+                _ if arg.starts_with("--emit=") => {
+                    for name in arg["--emit=".len()..].split(',') {
+                        emit.push(Emit::from_name(name)
+                            .ok_or(format!("unknown output `{}`, expected `mlir`, `c-header` or `zig`", name))?);
+                    }
+                }
+                // later flags win, so `--deny=all --warn=shadowed_names` denies everything else
+                _ if arg.starts_with("--allow=") => lints.set_by_name(&arg["--allow=".len()..], LintLevel::Allow)?,
+                _ if arg.starts_with("--warn=") => lints.set_by_name(&arg["--warn=".len()..], LintLevel::Warn)?,
+                _ if arg.starts_with("--deny=") => lints.set_by_name(&arg["--deny=".len()..], LintLevel::Deny)?,
+                _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+                _ => inputs.push(arg),
+            }
+        }
+
+        let mut options = Self::for_mode(mode);
+        if let Some(overflow) = overflow {
+            options.overflow = overflow;
+        }
+        options.target = target;
+        options.diagnostics_format = diagnostics_format;
+        options.lints = lints;
+        if !emit.is_empty() {
+            options.emit = emit;
+        }
+        options.inputs = inputs;
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CompilerOptions, String> {
+        CompilerOptions::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn overflow_follows_the_build_mode() {
+        assert_eq!(parse(&[]).unwrap().overflow, Overflow::Trap);
+        assert_eq!(parse(&["--release"]).unwrap().overflow, Overflow::Wrap);
+        assert_eq!(parse(&["--release", "--debug"]).unwrap().overflow, Overflow::Trap);
+        // an explicit mode wins wherever it's given
+        assert_eq!(parse(&["--overflow=trap", "--release"]).unwrap().overflow, Overflow::Trap);
+        assert_eq!(parse(&["--debug", "--overflow=wrap"]).unwrap().overflow, Overflow::Wrap);
+        assert!(parse(&["--overflow=saturate"]).is_err());
+    }
+}
@@ -9,28 +9,93 @@ use crate::diagnostic::*;
 
 lalrpop_mod!(#[allow(clippy::all)] #[allow(warnings)] #[allow(unknown_lints)] pub grammar, "/parser/grammar.rs");
 
+/// An error found while parsing that has its own span, like a malformed literal.
+#[derive(Clone, Debug)]
+pub struct SyntaxError {
+    pub span: Span,
+    pub message: String,
+}
+
+/// Unwrap a literal's value, recording the error and using a placeholder if it's malformed.
+pub fn recover_literal<'input, T: Default>(
+    result: Result<T, String>,
+    span: Span,
+    errors: &mut Vec<ErrorRecovery<usize, Token<'input>, SyntaxError>>,
+) -> T {
+    result.unwrap_or_else(|message| {
+        errors.push(ErrorRecovery {
+            error: ParseError::User { error: SyntaxError { span, message } },
+            dropped_tokens: vec![],
+        });
+        T::default()
+    })
+}
+
+/// How many expected tokens are listed before the rest are just counted.
+const MAX_EXPECTED: usize = 8;
+
+/// Describe a terminal from the grammar the way it's written in source, names and literals by what they are.
+fn describe_terminal(terminal: &str) -> String {
+    if terminal.starts_with('"') {
+        return format!("`{}`", terminal.trim_matches('"').replace("\\\"", "\""));
+    }
+    let pattern = terminal.trim_start_matches('r').trim_start_matches('#');
+    let pattern = pattern.strip_prefix('"').unwrap_or(pattern);
+    match pattern.chars().next() {
+        Some('[') if pattern.starts_with("[a-zA-Z") => "a name".to_string(),
+        Some('[') | Some('0') => "a number".to_string(),
+        Some('"') => "a string".to_string(),
+        Some('\'') => "a character".to_string(),
+        _ => format!("`{}`", terminal),
+    }
+}
+
+/// Turn the parser's list of expected terminals into a note, or nothing if there aren't any.
+fn expected_note(expected: &[String]) -> Option<String> {
+    let mut described: Vec<String> = vec![];
+    for terminal in expected {
+        let description = describe_terminal(terminal);
+        if !described.contains(&description) {
+            described.push(description);
+        }
+    }
+    // names and literals say the most, keep them ahead of keywords and punctuation
+    described.sort_by_key(|description| description.starts_with('`'));
+    match described.len() {
+        0 => None,
+        1 => Some(format!("expected {}", described[0])),
+        n if n > MAX_EXPECTED => Some(format!("expected one of {} or {} others", described[..MAX_EXPECTED].join(", "), n - MAX_EXPECTED)),
+        n => Some(format!("expected one of {} or {}", described[..n - 1].join(", "), described[n - 1])),
+    }
+}
+
 pub struct Parser {
     pub diagnostics: DiagnosticManager,
 }
 
 impl Parser {
     pub fn new() -> Self {
+        Self::with_diagnostics(DiagnosticManager::new())
+    }
+
+    /// Create a parser that reports into an existing set of diagnostics.
+    pub fn with_diagnostics(diagnostics: DiagnosticManager) -> Self {
         Self {
-            diagnostics: DiagnosticManager::new()
+            diagnostics
         }
     }
 
-    fn range(error: &ParseError<usize, Token, &str>) -> Range<usize> {
+    fn range(error: &ParseError<usize, Token, SyntaxError>) -> Range<usize> {
         match error {
             ParseError::InvalidToken { location } => *location..*location,
             ParseError::UnrecognizedEof { location, expected: _ } => *location..*location,
             ParseError::UnrecognizedToken { token, expected: _ } => token.0..token.2,
             ParseError::ExtraToken { token } => token.0..token.2,
-            ParseError::User { error: _ } => 0..0,
+            ParseError::User { error } => error.span.clone(),
         }
     }
 
-    fn add_parse_error(&mut self, file_id: FileId, error: ParseError<usize, Token, &str>) {
+    fn add_parse_error(&mut self, file_id: FileId, error: ParseError<usize, Token, SyntaxError>) {
         let message = match &error {
             ParseError::InvalidToken { location: _ } => "encountered invalid token while parsing".to_string(),
             ParseError::UnrecognizedEof { location: _, expected: _ } => {
@@ -43,7 +108,7 @@ impl Parser {
                 format!("encountered unexpected '{}' while parsing but it is not needed", (token.1).1)
             }
             ParseError::User { error } => {
-                error.to_string()
+                error.message.clone()
             }
         };
         let label_message = match &error {
@@ -51,7 +116,7 @@ impl Parser {
             ParseError::UnrecognizedEof { location: _, expected: _ } => Some("unexpected end of file"),
             ParseError::UnrecognizedToken { token: _, expected: _ } => Some("unexpected token"),
             ParseError::ExtraToken { token: _ } => Some("unexpected token"),
-            ParseError::User { error } => Some(*error),
+            ParseError::User { error: _ } => None,
         };
 
         let mut label = Label::primary(file_id, Self::range(&error));
@@ -59,41 +124,61 @@ impl Parser {
             label = label.with_message(label_message);
         }
 
+        let code = match &error {
+            ParseError::InvalidToken { .. } => codes::INVALID_TOKEN,
+            ParseError::UnrecognizedEof { .. } => codes::UNEXPECTED_EOF,
+            ParseError::UnrecognizedToken { .. } => codes::UNEXPECTED_TOKEN,
+            ParseError::ExtraToken { .. } => codes::EXTRA_TOKEN,
+            ParseError::User { .. } => codes::MALFORMED_LITERAL,
+        };
+
+        let notes = match &error {
+            ParseError::UnrecognizedEof { expected, .. } | ParseError::UnrecognizedToken { expected, .. } => expected_note(expected),
+            _ => None,
+        };
+
         let diagnostic = Diagnostic::error()
+            .with_code(code)
             .with_message(message)
-            .with_labels(vec![label]);
+            .with_labels(vec![label])
+            .with_notes(notes.into_iter().collect());
         self.diagnostics.add_diagnostic(diagnostic);
     }
 
     pub fn parse(&mut self, path: Path, file_name: String, code: String) -> Option<Program> {
         let file_id = self.diagnostics.add_file(file_name.clone(), code.clone());
-        let file_name_parts: Vec<&str> = file_name.splitn(2, ".").collect();
-        let module_name = file_name_parts.get(0).unwrap();
+        // diagnostics name the file as it was given, the module is named after the file alone
+        let module_name = std::path::Path::new(&file_name).file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split('.').next())
+            .unwrap_or("main");
 
-        let mut errors: Vec<ErrorRecovery<usize, Token, &str>> = Vec::new();
+        let mut errors: Vec<ErrorRecovery<usize, Token, SyntaxError>> = Vec::new();
         let mut program_arena = ProgramArena::new();
 
-        let result: Result<Vec<Path>, ParseError<usize, Token, &str>> = grammar::ProgramParser::new().parse(
+        let result: Result<Vec<Path>, ParseError<usize, Token, SyntaxError>> = grammar::ProgramParser::new().parse(
             &mut program_arena,
             &mut errors,
             &code
         );
 
-        if errors.len() > 0 {
-            for error in errors {
-                self.add_parse_error(file_id, error.error);
-            }
-            return None;
+        // statements and declarations that couldn't be parsed are skipped, so the rest of the module
+        // can still be checked, only an error the parser couldn't get past loses the whole program
+        let complete = errors.is_empty();
+        for error in errors {
+            self.add_parse_error(file_id, error.error);
         }
 
-        return match result {
+        match result {
             Ok(imports) => {
                 Some(
                     Program {
                         path,
                         file_name: module_name.to_string(),
+                        file_id,
                         imports,
                         program_arena,
+                        complete,
                     }
                 )
             }
@@ -101,6 +186,71 @@ impl Parser {
                 self.add_parse_error(file_id, error);
                 None
             }
-        };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> (Option<Program>, Vec<Diagnostic<FileId>>) {
+        let mut parser = Parser::new();
+        let program = parser.parse(Path::of("x"), "x.ns".to_string(), source.to_string());
+        (program, parser.diagnostics.messages)
     }
-}
\ No newline at end of file
+
+    /// Names of the functions that were parsed, declarations that couldn't be are left out.
+    fn functions(program: &Program) -> Vec<&str> {
+        program.program_arena.node_arena.iter()
+            .filter_map(|(_, node)| match node {
+                Node::Function(func) => Some(func.name.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn statements_with_errors_are_skipped() {
+        let (program, diagnostics) = parse("
+            fun f(): Int32 {
+                let x = ;
+                let y = 1 +;
+                return 2;
+            }
+
+            fun g(): Int32 {
+                return 3;
+            }
+        ");
+        let program = program.unwrap();
+        assert!(!program.complete);
+        assert_eq!(functions(&program), vec!["f", "g"]);
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, vec!["encountered unexpected ';' while parsing"; 2]);
+    }
+
+    #[test]
+    fn declarations_with_errors_are_skipped() {
+        let (program, diagnostics) = parse("
+            fun f(a: Int32 {
+            }
+
+            fun g(): Int32 {
+                return 3;
+            }
+        ");
+        assert_eq!(functions(&program.unwrap()), vec!["g"]);
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn expected_tokens() {
+        let notes = |source: &str| parse(source).1.into_iter().flat_map(|diagnostic| diagnostic.notes).collect::<Vec<_>>();
+        assert_eq!(notes("fun f(a: Int32 {\n}\n"), vec!["expected one of `)` or `,`"]);
+        assert_eq!(notes("fun f() {\n    let x = 1\n}\n"), vec!["expected `;`"]);
+        // names and literals come first, long lists are cut short
+        assert_eq!(notes("fun f() {\n    let x = ;\n}\n"),
+            vec!["expected one of a character, a number, a name, `(`, `-`, `false`, `fun`, `new` or 5 others"]);
+    }
+}
@@ -0,0 +1,451 @@
+use crate::ir::{FloatTy, IrNode, IrType, IrTypeIndex, IrVariant, ModuleArena};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+/// What the code is being compiled for, decides the size of `IntSize`, pointers and struct layout.
+#[derive(Clone, Debug)]
+pub struct TargetInfo {
+    pub triple: String,
+    /// Bits in a pointer, `IntSize` and `UIntSize` are the same width.
+    pub pointer_width: u32,
+    pub endianness: Endianness,
+    /// Alignment in bytes of 64 bit integers and floats, some 32 bit targets only align them to 4.
+    pub align_64: u64,
+    /// Alignment in bytes of the stack.
+    pub stack_align: u64,
+    /// Integer widths the target does arithmetic on natively.
+    pub native_widths: Vec<u32>,
+    /// Float widths the target has arithmetic instructions for, the others are done in software.
+    pub native_floats: Vec<u32>,
+}
+
+/// Where each field of a struct goes.
+#[derive(Clone, Debug)]
+pub struct StructLayout {
+    pub offsets: Vec<u64>,
+    pub size: u64,
+    pub align: u64,
+}
+
+/// How C passes a struct by value, decided by the target's calling convention.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StructPassing {
+    /// In registers, the struct's bytes are reloaded as these pieces.
+    Registers(Vec<Piece>),
+    /// In memory. A param is a pointer to a copy, `byval` when the copy goes on the stack where the
+    /// callee expects it, a result is written through a pointer the caller passes before the params.
+    Memory { byval: bool },
+}
+
+/// Part of a struct that goes in one register.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Piece {
+    Int(u32),
+    Float(u32),
+    /// Two `Float32`s sharing a vector register.
+    FloatPair,
+}
+
+impl Piece {
+    pub fn size(&self) -> u64 {
+        match self {
+            Piece::Int(bits) | Piece::Float(bits) => *bits as u64 / 8,
+            Piece::FloatPair => 8,
+        }
+    }
+}
+
+/// A number or pointer somewhere inside a struct.
+struct Scalar {
+    offset: u64,
+    size: u64,
+    float: bool,
+}
+
+impl Scalar {
+    fn piece(&self) -> Piece {
+        match self.float {
+            true => Piece::Float(self.size as u32 * 8),
+            false => Piece::Int(self.size as u32 * 8),
+        }
+    }
+}
+
+impl Default for TargetInfo {
+    fn default() -> Self {
+        Self::from_triple("x86_64-unknown-linux-gnu").unwrap()
+    }
+}
+
+impl TargetInfo {
+    /// Targets we know how to lay out, the architecture decides everything we need.
+    pub fn from_triple(triple: &str) -> Result<Self, String> {
+        let arch = triple.split('-').next().unwrap_or(triple);
+        let (pointer_width, endianness, align_64, stack_align, native_widths, native_floats) = match arch {
+            "x86_64" => (64, Endianness::Little, 8, 16, vec![8, 16, 32, 64], vec![32, 64]),
+            "aarch64" => (64, Endianness::Little, 8, 16, vec![32, 64], vec![16, 32, 64]),
+            "riscv64" => (64, Endianness::Little, 8, 16, vec![64], vec![32, 64]),
+            "i686" | "i386" => (32, Endianness::Little, 4, 16, vec![8, 16, 32], vec![32, 64]),
+            "arm" | "armv7" | "thumbv7em" => (32, Endianness::Little, 8, 8, vec![32], vec![32, 64]),
+            "wasm32" => (32, Endianness::Little, 8, 16, vec![32, 64], vec![32, 64]),
+            "wasm64" => (64, Endianness::Little, 8, 16, vec![32, 64], vec![32, 64]),
+            "powerpc" => (32, Endianness::Big, 8, 16, vec![32], vec![32, 64]),
+            "powerpc64" => (64, Endianness::Big, 8, 16, vec![32, 64], vec![32, 64]),
+            "s390x" => (64, Endianness::Big, 8, 16, vec![32, 64], vec![32, 64, 128]),
+            _ => return Err(format!("unknown target `{}`", triple)),
+        };
+        Ok(Self {
+            triple: triple.to_string(),
+            pointer_width,
+            endianness,
+            align_64,
+            stack_align,
+            native_widths,
+            native_floats,
+        })
+    }
+
+    pub fn has_native_float(&self, bits: u32) -> bool {
+        self.native_floats.contains(&bits)
+    }
+
+    pub fn pointer_size(&self) -> u64 {
+        self.pointer_width as u64 / 8
+    }
+
+    /// The LLVM data layout string, see https://llvm.org/docs/LangRef.html#data-layout
+    pub fn data_layout(&self) -> String {
+        let endianness = match self.endianness {
+            Endianness::Little => "e",
+            Endianness::Big => "E",
+        };
+        // how symbol names are mangled follows the object file format
+        let arch = self.triple.split('-').next().unwrap_or(&self.triple);
+        let mangling = if ["apple", "darwin", "macos", "ios"].iter().any(|os| self.triple.contains(os)) {
+            "o"
+        } else if self.triple.contains("windows") && matches!(arch, "i686" | "i386") {
+            "x"
+        } else if self.triple.contains("windows") {
+            "w"
+        } else {
+            "e"
+        };
+        let native: Vec<String> = self.native_widths.iter().map(|width| width.to_string()).collect();
+        format!(
+            "{}-m:{}-p:{}:{}-i64:{}-f64:{}-i128:128-n{}-S{}",
+            endianness,
+            mangling,
+            self.pointer_width,
+            self.pointer_width,
+            self.align_64 * 8,
+            self.align_64 * 8,
+            native.join(":"),
+            self.stack_align * 8,
+        )
+    }
+
+    /// Bytes a value of this type takes up, including padding at the end.
+    pub fn size_of(&self, arena: &ModuleArena, typ: IrTypeIndex) -> u64 {
+        use IrType::*;
+        match &arena.type_arena[typ] {
+            Bool => 1,
+            Int(int) => int.bits(self) as u64 / 8,
+            UInt(uint) => uint.bits(self) as u64 / 8,
+            Float(float) => float.bits() as u64 / 8,
+            Reference(_, _, _) | Function(_, _) => self.pointer_size(),
+            Base(_) | Row(_) | Optional(_) => self.aggregate_layout(arena, typ).size,
+            Refinement(_, inner, _) => self.size_of(arena, *inner),
+            Void => 0,
+            // unresolved types are lowered as 64 bit integers
+            Unknown => 8,
+        }
+    }
+
+    pub fn align_of(&self, arena: &ModuleArena, typ: IrTypeIndex) -> u64 {
+        use IrType::*;
+        match &arena.type_arena[typ] {
+            Base(_) | Row(_) | Optional(_) => self.aggregate_layout(arena, typ).align,
+            Refinement(_, inner, _) => self.align_of(arena, *inner),
+            Void => 1,
+            _ => match self.size_of(arena, typ) {
+                8 => self.align_64,
+                // the data layout aligns i128 to 16 bytes too
+                size => size.max(1),
+            },
+        }
+    }
+
+    /// Lay fields out in order, each at the next offset that suits its alignment.
+    pub fn struct_layout(&self, arena: &ModuleArena, fields: &[IrTypeIndex]) -> StructLayout {
+        Self::layout(fields.iter().map(|field| (self.size_of(arena, *field), self.align_of(arena, *field))))
+    }
+
+    /// Enums are a 32 bit tag like a C `enum`, then the fields of every variant overlapping like a C `union`.
+    /// The offsets are of the tag and of the fields.
+    pub fn enum_layout(&self, arena: &ModuleArena, variants: &[IrVariant]) -> StructLayout {
+        let payloads: Vec<StructLayout> = variants.iter()
+            .map(|variant| self.struct_layout(arena, &variant.fields.iter().map(|field| field.typ).collect::<Vec<_>>()))
+            .collect();
+        let align = payloads.iter().map(|payload| payload.align).max().unwrap_or(1);
+        let size = payloads.iter().map(|payload| payload.size).max().unwrap_or(0).next_multiple_of(align);
+        Self::layout([(4, 4), (size, align)].into_iter())
+    }
+
+    /// Can values of this type be passed to and from C functions? Numbers, `Bool` and pointers can,
+    /// structs of those can on targets we know the C calling convention of.
+    pub fn is_c_compatible(&self, arena: &ModuleArena, typ: IrTypeIndex) -> bool {
+        match &arena.type_arena[typ] {
+            IrType::Bool | IrType::Int(_) | IrType::UInt(_) | IrType::Float(FloatTy::F32 | FloatTy::F64) | IrType::Reference(..) => true,
+            IrType::Base(name) => match arena.declaration(name) {
+                Some(IrNode::Struct { fields, .. }) => !fields.is_empty()
+                    && fields.iter().all(|field| self.is_c_compatible(arena, field.typ))
+                    && self.struct_passing(arena, typ, false).is_some(),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// How C passes a struct as a param or result, `None` for other types and for targets
+    /// whose calling convention we don't know.
+    pub fn struct_passing(&self, arena: &ModuleArena, typ: IrTypeIndex, result: bool) -> Option<StructPassing> {
+        use StructPassing::*;
+        let IrType::Base(name) = &arena.type_arena[typ] else {
+            return None;
+        };
+        if !matches!(arena.declaration(name), Some(IrNode::Struct { .. })) {
+            return None;
+        }
+        let StructLayout { size, align, .. } = self.aggregate_layout(arena, typ);
+        let mut scalars = vec![];
+        self.scalars(arena, typ, 0, &mut scalars);
+        let arch = self.triple.split('-').next().unwrap_or(&self.triple);
+        let windows = self.triple.contains("windows");
+        Some(match arch {
+            // the Microsoft x64 convention, only structs the size of an integer register go in one
+            "x86_64" if windows => match size {
+                1 | 2 | 4 | 8 => Registers(vec![Piece::Int(size as u32 * 8)]),
+                _ => Memory { byval: false },
+            },
+            // System V, each eightbyte goes in a vector register when it holds only floats
+            "x86_64" if size <= 16 => Registers((0..size.div_ceil(8)).map(|eightbyte| {
+                let held: Vec<&Scalar> = scalars.iter().filter(|scalar| scalar.offset / 8 == eightbyte).collect();
+                match held[..] {
+                    [one] if one.float => one.piece(),
+                    [a, b] if a.float && b.float => Piece::FloatPair,
+                    _ => Piece::Int((size - eightbyte * 8).min(8) as u32 * 8),
+                }
+            }).collect()),
+            "x86_64" => Memory { byval: true },
+            // AAPCS64, up to four floats of the same type go in vector registers
+            "aarch64" if scalars.len() <= 4 && scalars.iter().all(|scalar| scalar.float && scalar.size == scalars[0].size) => {
+                Registers(scalars.iter().map(Scalar::piece).collect())
+            }
+            "aarch64" | "riscv64" if size <= 16 && align == 16 => Registers(vec![Piece::Int(128)]),
+            "aarch64" if size <= 16 => Registers(vec![Piece::Int(64); size.div_ceil(8) as usize]),
+            "aarch64" => Memory { byval: false },
+            // the lp64d convention, one or two floats go in float registers, a float and an integer in one of each
+            "riscv64" if matches!(&scalars[..], [_] | [_, _]) && scalars.iter().any(|scalar| scalar.float) && scalars.iter().all(|scalar| scalar.size <= 8) => {
+                Registers(scalars.iter().map(Scalar::piece).collect())
+            }
+            "riscv64" if size <= 16 => Registers(vec![Piece::Int(64); size.div_ceil(8) as usize]),
+            "riscv64" => Memory { byval: false },
+            // Windows returns small structs in `eax` and `edx`, System V returns every struct in memory
+            "i686" | "i386" if result && windows && matches!(size, 1 | 2 | 4 | 8) => Registers(vec![Piece::Int(size as u32 * 8)]),
+            "i686" | "i386" => Memory { byval: true },
+            // structs holding a single number are passed as that number
+            "wasm32" | "wasm64" => match &scalars[..] {
+                [scalar] if scalar.size == size => Registers(vec![scalar.piece()]),
+                _ => Memory { byval: true },
+            },
+            _ => return None,
+        })
+    }
+
+    /// The numbers and pointers a value is made of, with nested structs flattened.
+    fn scalars(&self, arena: &ModuleArena, typ: IrTypeIndex, offset: u64, scalars: &mut Vec<Scalar>) {
+        match &arena.type_arena[typ] {
+            IrType::Base(name) => {
+                if let Some(IrNode::Struct { fields, .. }) = arena.declaration(name) {
+                    let types: Vec<IrTypeIndex> = fields.iter().map(|field| field.typ).collect();
+                    for (field, field_offset) in types.iter().zip(self.struct_layout(arena, &types).offsets) {
+                        self.scalars(arena, *field, offset + field_offset, scalars);
+                    }
+                }
+            }
+            IrType::Refinement(_, inner, _) => self.scalars(arena, *inner, offset, scalars),
+            other => scalars.push(Scalar {
+                offset,
+                size: self.size_of(arena, typ),
+                float: other.is_float(),
+            }),
+        }
+    }
+
+    /// Rows are structs, optionals are the value followed by a flag saying it's there.
+    fn aggregate_layout(&self, arena: &ModuleArena, typ: IrTypeIndex) -> StructLayout {
+        match &arena.type_arena[typ] {
+            IrType::Optional(inner) => Self::layout([(self.size_of(arena, *inner), self.align_of(arena, *inner)), (1, 1)].into_iter()),
+            IrType::Row(fields) => self.struct_layout(arena, &fields.iter().map(|field| field.typ).collect::<Vec<_>>()),
+            IrType::Base(name) => match arena.declaration(name) {
+                Some(IrNode::Struct { fields, .. }) => self.struct_layout(arena, &fields.iter().map(|field| field.typ).collect::<Vec<_>>()),
+                Some(IrNode::Enum { variants, .. }) => self.enum_layout(arena, variants),
+                // unresolved types are lowered as 64 bit integers
+                _ => Self::layout([(8, self.align_64)].into_iter()),
+            },
+            _ => Self::layout(std::iter::empty()),
+        }
+    }
+
+    /// Lay out fields given as their size and alignment.
+    pub fn layout(fields: impl Iterator<Item = (u64, u64)>) -> StructLayout {
+        let mut offsets = vec![];
+        let mut offset = 0u64;
+        let mut align = 1;
+        for (field_size, field_align) in fields {
+            offset = offset.next_multiple_of(field_align);
+            offsets.push(offset);
+            offset += field_size;
+            align = align.max(field_align);
+        }
+        StructLayout {
+            offsets,
+            size: offset.next_multiple_of(align),
+            align,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Piece, StructPassing, TargetInfo};
+    use crate::compiler::Compiler;
+    use crate::ir::{IntTy, IrNode, IrType, IrTypeIndex, Module, ModuleArena};
+    use crate::lang::Path;
+
+    const SOURCE: &str = "\
+extern \"C\" fun take(ints: Ints, floats: Floats, mixed: Mixed, big: Big, tiny: Tiny);
+
+struct Ints {
+    let a: Int32;
+    let b: Int32;
+    let c: Int32;
+}
+
+struct Floats {
+    let x: Float32;
+    let y: Float32;
+    let z: Float32;
+}
+
+struct Mixed {
+    let count: Int64;
+    let scale: Float64;
+}
+
+struct Big {
+    let a: Int64;
+    let b: Int64;
+    let c: Int64;
+}
+
+struct Tiny {
+    let flag: Bool;
+}
+";
+
+    /// How each param of `take` is passed on a target, `None` when it can't be.
+    fn passing(triple: &str) -> Vec<Option<StructPassing>> {
+        let mut compiler = Compiler::new();
+        compiler.parse_module(Path::of("abi"), "abi.ns".to_string(), SOURCE.to_string());
+        compiler.compile();
+        assert!(!compiler.diagnostics.has_errors(), "{}", compiler.diagnostics.emit_to_string());
+        let (_, module) = compiler.modules.iter().next().unwrap();
+        let target = TargetInfo::from_triple(triple).unwrap();
+        params(module).into_iter()
+            .map(|typ| target.struct_passing(&module.module_arena, typ, false))
+            .collect()
+    }
+
+    fn params(module: &Module) -> Vec<IrTypeIndex> {
+        module.module_arena.node_arena.iter()
+            .find_map(|(_, node)| match node {
+                IrNode::ExternFunction(func) => Some(func.params.iter().map(|param| param.typ).collect()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn layout_follows_the_target() {
+        let mut arena = ModuleArena::new();
+        let int_size = arena.type_arena.insert(IrType::Int(IntTy::ISize));
+        let int64 = arena.type_arena.insert(IrType::Int(IntTy::I64));
+        let bool = arena.type_arena.insert(IrType::Bool);
+
+        let x86_64 = TargetInfo::from_triple("x86_64-unknown-linux-gnu").unwrap();
+        assert_eq!(x86_64.size_of(&arena, int_size), 8);
+        assert_eq!(x86_64.struct_layout(&arena, &[bool, int64]).offsets, vec![0, 8]);
+        assert_eq!(x86_64.struct_layout(&arena, &[bool, int64]).size, 16);
+
+        let wasm32 = TargetInfo::from_triple("wasm32-unknown-unknown").unwrap();
+        assert_eq!(wasm32.size_of(&arena, int_size), 4);
+        // 32 bit x86 only aligns 64 bit values to 4
+        let i686 = TargetInfo::from_triple("i686-unknown-linux-gnu").unwrap();
+        assert_eq!(i686.struct_layout(&arena, &[bool, int64]).offsets, vec![0, 4]);
+        assert_eq!(i686.struct_layout(&arena, &[bool, int64]).size, 12);
+
+        assert!(TargetInfo::from_triple("mips-unknown-linux-gnu").is_err());
+    }
+
+    #[test]
+    fn system_v_splits_eightbytes() {
+        use Piece::*;
+        assert_eq!(passing("x86_64-unknown-linux-gnu"), vec![
+            Some(StructPassing::Registers(vec![Int(64), Int(32)])),
+            Some(StructPassing::Registers(vec![FloatPair, Float(32)])),
+            Some(StructPassing::Registers(vec![Int(64), Float(64)])),
+            Some(StructPassing::Memory { byval: true }),
+            Some(StructPassing::Registers(vec![Int(8)])),
+        ]);
+    }
+
+    #[test]
+    fn other_conventions() {
+        use Piece::*;
+        assert_eq!(passing("x86_64-pc-windows-msvc"), vec![
+            Some(StructPassing::Memory { byval: false }),
+            Some(StructPassing::Memory { byval: false }),
+            Some(StructPassing::Memory { byval: false }),
+            Some(StructPassing::Memory { byval: false }),
+            Some(StructPassing::Registers(vec![Int(8)])),
+        ]);
+        assert_eq!(passing("aarch64-apple-darwin"), vec![
+            Some(StructPassing::Registers(vec![Int(64), Int(64)])),
+            Some(StructPassing::Registers(vec![Float(32), Float(32), Float(32)])),
+            Some(StructPassing::Registers(vec![Int(64), Int(64)])),
+            Some(StructPassing::Memory { byval: false }),
+            Some(StructPassing::Registers(vec![Int(64)])),
+        ]);
+        assert_eq!(passing("i686-unknown-linux-gnu")[0], Some(StructPassing::Memory { byval: true }));
+        assert_eq!(passing("powerpc-unknown-linux-gnu")[0], None);
+    }
+
+    #[test]
+    fn mangling_follows_the_object_format() {
+        for (triple, mangling) in [
+            ("x86_64-unknown-linux-gnu", "-m:e-"),
+            ("aarch64-apple-darwin", "-m:o-"),
+            ("x86_64-pc-windows-msvc", "-m:w-"),
+            ("i686-pc-windows-msvc", "-m:x-"),
+            ("wasm32-unknown-unknown", "-m:e-"),
+        ] {
+            let layout = TargetInfo::from_triple(triple).unwrap().data_layout();
+            assert!(layout.contains(mangling), "`{}` has data layout `{}`", triple, layout);
+        }
+    }
+}